@@ -0,0 +1,335 @@
+/* Integers beyond lua_Integer, as BigInt userdata */
+use ::{RumLua, LuaError, LuaRet, LuaPtr, ToLua, FromLua, TypeBuilder, MetaMethod, lfail};
+use convert::conversion_error;
+use types;
+use lua;
+use lua::Index;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// An integer of up to 128 bits and a sign, for values which don't fit in
+/// a Lua integer.  It is pushed as a plain integer if it fits, and
+/// otherwise as a userdata supporting arithmetic and comparisons, whose
+/// results are plain integers again when they fit.  Integer types such as
+/// `u64` and `i128` fall back to it automatically.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct BigInt {
+    negative: bool,
+    magnitude: u128,
+}
+
+impl BigInt {
+    pub fn from_i128(i: i128) -> BigInt {
+        if i < 0 {
+            BigInt{ negative: true, magnitude: (i as u128).wrapping_neg() }
+        } else {
+            BigInt{ negative: false, magnitude: i as u128 }
+        }
+    }
+
+    pub fn from_u128(u: u128) -> BigInt {
+        BigInt{ negative: false, magnitude: u }
+    }
+
+    /* Zero is never negative. */
+    fn new(negative: bool, magnitude: u128) -> BigInt {
+        BigInt{ negative: negative && magnitude != 0, magnitude: magnitude }
+    }
+
+    /// The value as an `i128`, if it fits.
+    pub fn to_i128(&self) -> Option<i128> {
+        if !self.negative && self.magnitude <= i128::max_value() as u128 {
+            Some(self.magnitude as i128)
+        } else if self.negative && self.magnitude <= (i128::max_value() as u128) + 1 {
+            Some((self.magnitude as i128).wrapping_neg())
+        } else {
+            None
+        }
+    }
+
+    /// The value as a `u128`, if it fits.
+    pub fn to_u128(&self) -> Option<u128> {
+        if self.negative { None } else { Some(self.magnitude) }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let f = self.magnitude as f64;
+        if self.negative { -f } else { f }
+    }
+
+    fn neg(self) -> BigInt {
+        BigInt::new(!self.negative, self.magnitude)
+    }
+
+    pub fn checked_add(self, other: BigInt) -> Option<BigInt> {
+        if self.negative == other.negative {
+            self.magnitude.checked_add(other.magnitude).map(|m| BigInt::new(self.negative, m))
+        } else if self.magnitude >= other.magnitude {
+            Some(BigInt::new(self.negative, self.magnitude - other.magnitude))
+        } else {
+            Some(BigInt::new(other.negative, other.magnitude - self.magnitude))
+        }
+    }
+
+    pub fn checked_sub(self, other: BigInt) -> Option<BigInt> {
+        self.checked_add(other.neg())
+    }
+
+    pub fn checked_mul(self, other: BigInt) -> Option<BigInt> {
+        self.magnitude.checked_mul(other.magnitude)
+            .map(|m| BigInt::new(self.negative != other.negative, m))
+    }
+
+    /// Floor division, as Lua's `//`.
+    pub fn checked_div(self, other: BigInt) -> Option<BigInt> {
+        if other.magnitude == 0 {
+            return None;
+        }
+        let q = self.magnitude / other.magnitude;
+        let r = self.magnitude % other.magnitude;
+        if self.negative != other.negative && r != 0 {
+            q.checked_add(1).map(|q| BigInt::new(true, q))
+        } else {
+            Some(BigInt::new(self.negative != other.negative, q))
+        }
+    }
+
+    /// The remainder of floor division, with the sign of `other`, as
+    /// Lua's `%`.
+    pub fn checked_rem(self, other: BigInt) -> Option<BigInt> {
+        if other.magnitude == 0 {
+            return None;
+        }
+        let r = self.magnitude % other.magnitude;
+        if self.negative != other.negative && r != 0 {
+            Some(BigInt::new(other.negative, other.magnitude - r))
+        } else {
+            Some(BigInt::new(other.negative, r))
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => self.magnitude.cmp(&other.magnitude),
+            (true, true) => other.magnitude.cmp(&self.magnitude),
+        }
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.negative {
+            write!(f, "-{}", self.magnitude)
+        } else {
+            write!(f, "{}", self.magnitude)
+        }
+    }
+}
+
+impl ToLua for BigInt {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        match self.to_i128() {
+            Some(i) if i >= lua::Integer::min_value() as i128 && i <= lua::Integer::max_value() as i128 => {
+                rl.state.push_integer(i as lua::Integer);
+                Ok(())
+            },
+            _ => {
+                if !rl.is_registered::<BigInt>() {
+                    register(rl)?;
+                }
+                rl.push(&LuaPtr::new(*self))
+            },
+        }
+    }
+}
+
+/* The BigInt userdata at `index`, if it is one. */
+fn userdata(rl: &mut RumLua, index: Index) -> Option<BigInt> {
+    if !rl.state.is_userdata(index) || !rl.is_registered::<BigInt>() {
+        return None;
+    }
+    rl.with_ref(index, |big: &BigInt| *big).ok()
+}
+
+impl FromLua for BigInt {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<BigInt, LuaError> {
+        if let Some(i) = rl.state.to_integerx(index) {
+            return Ok(BigInt::from_i128(i as i128));
+        }
+        match userdata(rl, index) {
+            Some(big) => Ok(big),
+            None => conversion_error(rl, index, "integer"),
+        }
+    }
+}
+
+impl ToLua for i128 {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        BigInt::from_i128(*self).to_lua(rl)
+    }
+}
+
+impl FromLua for i128 {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<i128, LuaError> {
+        let big = BigInt::from_lua(rl, index)?;
+        big.to_i128().ok_or_else(|| LuaError::ConversionError(format!("{} is out of range for i128", big)))
+    }
+}
+
+impl ToLua for u128 {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        BigInt::from_u128(*self).to_lua(rl)
+    }
+}
+
+impl FromLua for u128 {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<u128, LuaError> {
+        let big = BigInt::from_lua(rl, index)?;
+        big.to_u128().ok_or_else(|| LuaError::ConversionError(format!("{} is out of range for u128", big)))
+    }
+}
+
+/* The value of a BigInt userdata, for the integer types which fall back
+ * to BigInt. */
+pub fn get_i128(rl: &mut RumLua, index: Index) -> Option<i128> {
+    userdata(rl, index).and_then(|big| big.to_i128())
+}
+
+/* An operand of a metamethod */
+enum Operand {
+    Int(BigInt),
+    Float(f64),
+}
+
+impl Operand {
+    fn to_f64(&self) -> f64 {
+        match *self {
+            Operand::Int(ref big) => big.to_f64(),
+            Operand::Float(f) => f,
+        }
+    }
+}
+
+fn operand(rl: &mut RumLua, n: Index) -> Result<Operand, LuaError> {
+    if rl.state.is_number(n) && rl.state.to_integerx(n).is_none() {
+        return Ok(Operand::Float(rl.state.to_number(n)));
+    }
+    rl.check_arg(n).map(Operand::Int)
+}
+
+/* Apply an arithmetic metamethod, giving a float if either operand is. */
+fn arith<I, F>(rl: &mut RumLua, int_op: I, float_op: F) -> LuaRet
+                  where I: Fn(BigInt, BigInt) -> Option<BigInt>, F: Fn(f64, f64) -> f64
+{
+    let (a, b) = (operand(rl, 1)?, operand(rl, 2)?);
+    match (a, b) {
+        (Operand::Int(a), Operand::Int(b)) => match int_op(a, b) {
+            Some(result) => result.to_lua(rl)?,
+            None if b.magnitude == 0 => return lfail("attempt to perform 'n//0' or 'n%0'"),
+            None => return lfail("integer overflow"),
+        },
+        (a, b) => rl.state.push_number(float_op(a.to_f64(), b.to_f64())),
+    }
+    Ok(1)
+}
+
+fn big_add(rl: &mut RumLua) -> LuaRet {
+    arith(rl, BigInt::checked_add, |a, b| a + b)
+}
+
+fn big_sub(rl: &mut RumLua) -> LuaRet {
+    arith(rl, BigInt::checked_sub, |a, b| a - b)
+}
+
+fn big_mul(rl: &mut RumLua) -> LuaRet {
+    arith(rl, BigInt::checked_mul, |a, b| a * b)
+}
+
+fn big_idiv(rl: &mut RumLua) -> LuaRet {
+    arith(rl, BigInt::checked_div, |a, b| (a / b).floor())
+}
+
+fn big_mod(rl: &mut RumLua) -> LuaRet {
+    arith(rl, BigInt::checked_rem, |a, b| a - (a / b).floor() * b)
+}
+
+fn big_div(rl: &mut RumLua) -> LuaRet {
+    let (a, b) = (operand(rl, 1)?, operand(rl, 2)?);
+    rl.state.push_number(a.to_f64() / b.to_f64());
+    Ok(1)
+}
+
+fn big_pow(rl: &mut RumLua) -> LuaRet {
+    let (a, b) = (operand(rl, 1)?, operand(rl, 2)?);
+    rl.state.push_number(a.to_f64().powf(b.to_f64()));
+    Ok(1)
+}
+
+fn big_unm(rl: &mut RumLua) -> LuaRet {
+    let a: BigInt = rl.check_arg(1)?;
+    a.neg().to_lua(rl)?;
+    Ok(1)
+}
+
+/* Compare operands, either of which may be a plain number. */
+fn compare(rl: &mut RumLua) -> Result<Option<Ordering>, LuaError> {
+    let (a, b) = (operand(rl, 1)?, operand(rl, 2)?);
+    Ok(match (a, b) {
+        (Operand::Int(a), Operand::Int(b)) => Some(a.cmp(&b)),
+        (a, b) => a.to_f64().partial_cmp(&b.to_f64()),
+    })
+}
+
+fn big_eq(rl: &mut RumLua) -> LuaRet {
+    let eq = compare(rl)? == Some(Ordering::Equal);
+    rl.state.push_bool(eq);
+    Ok(1)
+}
+
+fn big_lt(rl: &mut RumLua) -> LuaRet {
+    let lt = compare(rl)? == Some(Ordering::Less);
+    rl.state.push_bool(lt);
+    Ok(1)
+}
+
+fn big_le(rl: &mut RumLua) -> LuaRet {
+    let le = match compare(rl)? {
+        Some(Ordering::Less) | Some(Ordering::Equal) => true,
+        _ => false,
+    };
+    rl.state.push_bool(le);
+    Ok(1)
+}
+
+fn big_tostring(rl: &mut RumLua) -> LuaRet {
+    let a: BigInt = rl.check_arg(1)?;
+    a.to_string().to_lua(rl)?;
+    Ok(1)
+}
+
+/* Registered when the first BigInt userdata is pushed. */
+fn register(rl: &mut RumLua) -> Result<(), LuaError> {
+    types::register(rl, TypeBuilder::<BigInt>::new("BigInt")
+        .metamethod(MetaMethod::Add, big_add)
+        .metamethod(MetaMethod::Sub, big_sub)
+        .metamethod(MetaMethod::Mul, big_mul)
+        .metamethod(MetaMethod::Div, big_div)
+        .metamethod(MetaMethod::IDiv, big_idiv)
+        .metamethod(MetaMethod::Mod, big_mod)
+        .metamethod(MetaMethod::Pow, big_pow)
+        .metamethod(MetaMethod::Unm, big_unm)
+        .metamethod(MetaMethod::Eq, big_eq)
+        .metamethod(MetaMethod::Lt, big_lt)
+        .metamethod(MetaMethod::Le, big_le)
+        .metamethod(MetaMethod::ToString, big_tostring))
+}
@@ -0,0 +1,92 @@
+/* Configurable construction of RumLua */
+use ::RumLua;
+use lua;
+use libc::c_int;
+use std::ops::{BitOr, BitOrAssign};
+
+/// A set of Lua standard libraries, combined with `|`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StdLib(u32);
+
+impl StdLib {
+    /// The basic functions, such as `print`, `pairs` and `pcall`.
+    pub const BASE: StdLib = StdLib(1 << 0);
+    pub const COROUTINE: StdLib = StdLib(1 << 1);
+    pub const TABLE: StdLib = StdLib(1 << 2);
+    pub const IO: StdLib = StdLib(1 << 3);
+    pub const OS: StdLib = StdLib(1 << 4);
+    pub const STRING: StdLib = StdLib(1 << 5);
+    pub const UTF8: StdLib = StdLib(1 << 6);
+    pub const MATH: StdLib = StdLib(1 << 7);
+    pub const DEBUG: StdLib = StdLib(1 << 8);
+    pub const PACKAGE: StdLib = StdLib(1 << 9);
+
+    /// No libraries at all.
+    pub const NONE: StdLib = StdLib(0);
+    /// Every library.
+    pub const ALL: StdLib = StdLib((1 << 10) - 1);
+    /// The libraries which don't give access to the host system: all
+    /// except `io`, `os`, `debug` and `package`.
+    pub const SAFE: StdLib = StdLib(StdLib::BASE.0 | StdLib::COROUTINE.0 |
+                                    StdLib::TABLE.0 | StdLib::STRING.0 |
+                                    StdLib::UTF8.0 | StdLib::MATH.0);
+
+    /// Whether every library in `other` is also in `self`.
+    pub fn contains(self, other: StdLib) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for StdLib {
+    type Output = StdLib;
+    fn bitor(self, other: StdLib) -> StdLib {
+        StdLib(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for StdLib {
+    fn bitor_assign(&mut self, other: StdLib) {
+        self.0 |= other.0;
+    }
+}
+
+type OpenFn = unsafe extern "C" fn(*mut lua::ffi::lua_State) -> c_int;
+
+/* The libraries other than base, with their global names. */
+pub const LIBS: &'static [(StdLib, &'static str, OpenFn)] = &[
+    (StdLib::COROUTINE, "coroutine", lua::ffi::luaopen_coroutine),
+    (StdLib::TABLE, "table", lua::ffi::luaopen_table),
+    (StdLib::IO, "io", lua::ffi::luaopen_io),
+    (StdLib::OS, "os", lua::ffi::luaopen_os),
+    (StdLib::STRING, "string", lua::ffi::luaopen_string),
+    (StdLib::UTF8, "utf8", lua::ffi::luaopen_utf8),
+    (StdLib::MATH, "math", lua::ffi::luaopen_math),
+    (StdLib::DEBUG, "debug", lua::ffi::luaopen_debug),
+    (StdLib::PACKAGE, "package", lua::ffi::luaopen_package),
+];
+
+/// Configures and creates a `RumLua`.
+pub struct RumLuaBuilder {
+    libs: StdLib,
+}
+
+impl RumLuaBuilder {
+    /// A builder with the default settings, which opens every standard
+    /// library.
+    pub fn new() -> RumLuaBuilder {
+        RumLuaBuilder {
+            libs: StdLib::ALL,
+        }
+    }
+
+    /// Open only the standard libraries in `libs`.
+    pub fn with_libs(mut self, libs: StdLib) -> RumLuaBuilder {
+        self.libs = libs;
+        self
+    }
+
+    /// Create the `RumLua`.
+    pub fn build<'a>(self) -> RumLua<'a> {
+        RumLua::new_with_libs(self.libs)
+    }
+}
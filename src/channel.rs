@@ -0,0 +1,94 @@
+/* Channels feeding values from other threads into Lua */
+use ::{RumLua, LuaRet, ToLua, CallbackFn};
+use lua;
+use std::rc::Rc;
+use std::sync::mpsc::{sync_channel, SyncSender, TryRecvError};
+
+/// Lua chunk returning a function which makes a receiver's `recv` method
+/// from its poll function.  That returns "value" and the value, "empty"
+/// or "closed"; `recv` yields while the channel is empty.
+const CHANNEL_SHIM: &'static str = r#"
+    local wait = ...
+    return function(poll)
+        return function()
+            while true do
+                local status, value = poll()
+                if status == "value" then
+                    return value
+                elseif status == "closed" then
+                    return nil
+                end
+                wait()
+            end
+        end
+    end
+"#;
+
+fn channel_wait(rl: &mut RumLua) -> LuaRet {
+    rl.request_yield();
+    Ok(0)
+}
+
+/* Push the shim, loading it the first time. */
+fn push_shim(rl: &mut RumLua) {
+    let shim = match rl.shared.channel_shim {
+        Some(ref shim) => shim.value(),
+        None => {
+            rl.state.load_string(CHANNEL_SHIM);
+            rl.push_registered_closure(CallbackFn::Plain(channel_wait), "Receiver.wait");
+            rl.state.call(1, 1);
+            let shim = rl.state.reference(lua::REGISTRYINDEX);
+            let value = shim.value();
+            rl.shared.channel_shim = Some(shim);
+            value
+        },
+    };
+    rl.state.raw_geti(lua::REGISTRYINDEX, shim as lua::Integer);
+}
+
+pub fn create<T: ToLua + Send + 'static>(rl: &mut RumLua, capacity: usize) -> SyncSender<T> {
+    let (sender, receiver) = sync_channel::<T>(capacity);
+    let receiver = Rc::new(receiver);
+    rl.state.create_table(0, 2);
+
+    /* rx:try_recv(): the next value, or nil if there is none yet */
+    let rx = receiver.clone();
+    rl.push_closure("Receiver.try_recv", move |rl| {
+        match rx.try_recv() {
+            Ok(value) => value.to_lua(rl)?,
+            Err(_) => rl.state.push_nil(),
+        }
+        Ok(1)
+    });
+    rl.state.set_field(-2, "try_recv");
+
+    /* rx:recv(): the next value, or nil once every sender has gone.
+     * Outside a coroutine this blocks. */
+    push_shim(rl);
+    rl.push_closure("Receiver.recv", move |rl| {
+        let yieldable = unsafe { lua::ffi::lua_isyieldable(rl.state.as_ptr()) } != 0;
+        let result = if yieldable {
+            receiver.try_recv()
+        } else {
+            receiver.recv().map_err(|_| TryRecvError::Disconnected)
+        };
+        match result {
+            Ok(value) => {
+                rl.state.push_string("value");
+                value.to_lua(rl)?;
+                Ok(2)
+            },
+            Err(TryRecvError::Empty) => {
+                rl.state.push_string("empty");
+                Ok(1)
+            },
+            Err(TryRecvError::Disconnected) => {
+                rl.state.push_string("closed");
+                Ok(1)
+            },
+        }
+    });
+    rl.state.call(1, 1);
+    rl.state.set_field(-2, "recv");
+    sender
+}
@@ -0,0 +1,142 @@
+/* Lua classes derived from registered Rust types, as rum.class */
+use ::{RumLua, LuaRet, CallbackFn, lfail};
+use lua;
+use lua::Index;
+use libc::c_void;
+
+/* Registry key (by address) of the weak-keyed table from each instance
+ * to the userdata it wraps. */
+static BASES_KEY: u8 = 0;
+
+/* Key (by address) in a class table of its base type's name. */
+static BASE_TYPE_KEY: u8 = 0;
+
+fn key(k: &'static u8) -> *const c_void {
+    k as *const u8 as *const c_void
+}
+
+/* Push the table of instances' base objects, creating it if needed. */
+fn push_bases(rl: &mut RumLua) {
+    let l = rl.state.as_ptr();
+    unsafe { lua::ffi::lua_rawgetp(l, lua::REGISTRYINDEX, key(&BASES_KEY)) };
+    if rl.state.is_table(-1) {
+        return;
+    }
+    rl.state.pop(1);
+    rl.state.new_table();
+    rl.state.new_table();
+    rl.state.push_string("k");
+    rl.state.set_field(-2, "__mode");
+    rl.state.set_metatable(-2);
+    rl.state.push_value(-1);
+    unsafe { lua::ffi::lua_rawsetp(l, lua::REGISTRYINDEX, key(&BASES_KEY)) };
+}
+
+/* If the value at `index` is an instance of a class, push the userdata
+ * it wraps and return true.  The userdata lives as long as the instance
+ * does. */
+pub fn push_base(rl: &mut RumLua, index: Index) -> bool {
+    if !rl.state.is_table(index) {
+        return false;
+    }
+    let index = rl.state.abs_index(index);
+    push_bases(rl);
+    rl.state.push_value(index);
+    rl.state.raw_get(-2);
+    rl.state.remove(-2);
+    if rl.state.is_userdata(-1) {
+        true
+    } else {
+        rl.state.pop(1);
+        false
+    }
+}
+
+/* Whether the value at `index` is a userdata of the type `name` or a type
+ * derived from it. */
+fn is_of_type(rl: &mut RumLua, index: Index, name: &str) -> bool {
+    let mut names = vec![name.to_string()];
+    if let Some(id) = rl.shared.types_str_to_id.get(name) {
+        if let Some(derived) = rl.shared.upcasts.get(id) {
+            names.extend(derived.iter().map(|&(ref name, _)| name.clone()));
+        }
+    }
+    if !rl.state.is_userdata(index) || !rl.state.get_metatable(index) {
+        return false;
+    }
+    let found = names.iter().any(|name| {
+        rl.state.get_metatable_from_registry(name);
+        let same = rl.state.raw_equal(-1, -2);
+        rl.state.pop(1);
+        same
+    });
+    rl.state.pop(1);
+    found
+}
+
+/* rum.class(type_name): a class table, whose methods are found before the
+ * Rust type's.  Calling it with a userdata of the type makes an instance
+ * wrapping it. */
+fn rum_class(rl: &mut RumLua) -> LuaRet {
+    let name: String = rl.check_arg(1)?;
+    if !rl.shared.types_str_to_id.contains_key(&name) {
+        return lfail(&format!("'{}' is not a registered type", name));
+    }
+    rl.state.new_table();
+    rl.state.push_string(&name);
+    unsafe { lua::ffi::lua_rawsetp(rl.state.as_ptr(), -2, key(&BASE_TYPE_KEY)) };
+    rl.push_registered_closure(CallbackFn::Plain(class_index), "__index");
+    rl.state.set_field(-2, "__index");
+    rl.state.get_metatable_from_registry(&name);
+    rl.state.set_field(-2, "super");
+
+    rl.state.new_table();
+    rl.push_registered_closure(CallbackFn::Plain(class_new), &name);
+    rl.state.set_field(-2, "__call");
+    rl.state.set_metatable(-2);
+    Ok(1)
+}
+
+/* __call of a class: (class, base object) */
+fn class_new(rl: &mut RumLua) -> LuaRet {
+    unsafe { lua::ffi::lua_rawgetp(rl.state.as_ptr(), 1, key(&BASE_TYPE_KEY)) };
+    let name = rl.state.to_str(-1).unwrap_or("?").to_string();
+    rl.state.pop(1);
+    if !is_of_type(rl, 2, &name) {
+        return Err(rl.arg_error(1, &format!("{} expected", name)));
+    }
+    rl.state.new_table();
+    rl.state.push_value(1);
+    rl.state.set_metatable(-2);
+    push_bases(rl);
+    rl.state.push_value(-2);
+    rl.state.push_value(2);
+    rl.state.raw_set(-3);
+    rl.state.pop(1);
+    Ok(1)
+}
+
+/* __index of instances: (instance, key).  The class comes first, then
+ * the base object, which also finds the Rust type's fields. */
+fn class_index(rl: &mut RumLua) -> LuaRet {
+    if rl.state.get_metatable(1) {
+        rl.state.push_value(2);
+        if rl.state.raw_get(-2) != lua::Type::Nil {
+            return Ok(1);
+        }
+        rl.state.pop(2);
+    }
+    if !push_base(rl, 1) {
+        rl.state.push_nil();
+        return Ok(1);
+    }
+    rl.state.push_value(2);
+    rl.state.get_table(-2);
+    Ok(1)
+}
+
+/* Add rum.class to the rum table on top of the stack. */
+pub fn open(rl: &mut RumLua) {
+    rl.push_registered_closure(CallbackFn::Plain(rum_class), "rum.class");
+    rl.state.set_field(-2, "class");
+}
@@ -0,0 +1,692 @@
+/* Conversions between Rust values and values on the Lua stack */
+use ::{RumLua, LuaError, LuaPtr, LuaWeak, ArcPtr};
+use string::{LuaString, push_bytes};
+use bigint::{self, BigInt};
+use lua;
+use lua::Index;
+use libc::c_int;
+use std::any::Any;
+use std::error::Error;
+use std::collections::{HashMap, BTreeMap, HashSet};
+use std::hash::Hash;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+/// A Rust value which can be pushed onto the Lua stack.
+pub trait ToLua {
+    /// Push this value onto the top of the stack.
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError>;
+}
+
+/// A Rust value which can be converted from a value on the Lua stack.
+pub trait FromLua: Sized {
+    /// Convert the value at `index`, leaving the stack unchanged.
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<Self, LuaError>;
+}
+
+/// A Rust value which can be pushed as any number of Lua values,
+/// such as function arguments.
+pub trait ToLuaMulti {
+    /// Push the values onto the stack, returning how many were pushed.
+    fn to_lua_multi(&self, rl: &mut RumLua) -> Result<c_int, LuaError>;
+}
+
+/// A Rust value which can be converted from any number of Lua values,
+/// such as function results.
+pub trait FromLuaMulti: Sized {
+    /// Convert the top `count` values on the stack, leaving them in place.
+    /// Missing values are treated as nil.
+    fn from_lua_multi(rl: &mut RumLua, count: c_int) -> Result<Self, LuaError>;
+}
+
+/// Return the Lua name of the type of the value at `index`, as used in
+/// error messages.
+pub fn type_name(rl: &mut RumLua, index: Index) -> &'static str {
+    match rl.state.type_of(index) {
+        None | Some(lua::Type::None) => "no value",
+        Some(lua::Type::Nil) => "nil",
+        Some(lua::Type::Boolean) => "boolean",
+        Some(lua::Type::LightUserdata) => "userdata",
+        Some(lua::Type::Number) => "number",
+        Some(lua::Type::String) => "string",
+        Some(lua::Type::Table) => "table",
+        Some(lua::Type::Function) => "function",
+        Some(lua::Type::Userdata) => "userdata",
+        Some(lua::Type::Thread) => "thread",
+    }
+}
+
+/// Return an error describing a failed conversion of the value at `index`.
+pub fn conversion_error<T>(rl: &mut RumLua, index: Index, expected: &str) -> Result<T, LuaError> {
+    let got = type_name(rl, index);
+    Err(LuaError::ConversionError(format!("{} expected, got {}", expected, got)))
+}
+
+/// Convert a Rust sequence position (from 0) to a Lua one (from 1).
+pub fn checked_index(i: usize) -> Result<lua::Integer, LuaError> {
+    let lua_i = (i as lua::Integer).wrapping_add(1);
+    if lua_i < 1 || (lua_i - 1) as usize != i {
+        return Err(LuaError::ConversionError(format!("index {} is too large for Lua", i)));
+    }
+    Ok(lua_i)
+}
+
+impl ToLua for bool {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        rl.state.push_bool(*self);
+        Ok(())
+    }
+}
+
+impl FromLua for bool {
+    /* Follows Lua's notion of truth: only nil and false are false. */
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<bool, LuaError> {
+        Ok(rl.state.to_bool(index))
+    }
+}
+
+/* Whether `i` survives a round trip through `T`, with its sign intact. */
+macro_rules! fits {
+    ($i:expr, $t:ty) => {{
+        let i = $i;
+        (i as $t) as lua::Integer == i && (i < 0) == ((i as $t) < (0 as $t))
+    }}
+}
+
+macro_rules! impl_integer {
+    ($($t:ty)*) => {
+        $(
+            impl ToLua for $t {
+                fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+                    let i = *self as lua::Integer;
+                    if i as $t != *self || (i < 0) != (*self < (0 as $t)) {
+                        return BigInt::from_i128(*self as i128).to_lua(rl);
+                    }
+                    rl.state.push_integer(i);
+                    Ok(())
+                }
+            }
+
+            impl FromLua for $t {
+                /* Floats convert only if integral, and nothing is
+                 * truncated to fit. */
+                fn from_lua(rl: &mut RumLua, index: Index) -> Result<$t, LuaError> {
+                    match rl.state.to_integerx(index) {
+                        Some(i) if fits!(i, $t) => Ok(i as $t),
+                        Some(i) => Err(LuaError::ConversionError(format!("{} is out of range for {}", i, stringify!($t)))),
+                        None if rl.state.is_number(index) => {
+                            Err(LuaError::ConversionError("integer expected, got non-integral number".to_string()))
+                        },
+                        None => match bigint::get_i128(rl, index) {
+                            Some(i) if i >= <$t>::min_value() as i128 && i <= <$t>::max_value() as i128 => Ok(i as $t),
+                            Some(i) => Err(LuaError::ConversionError(format!("{} is out of range for {}", i, stringify!($t)))),
+                            None => conversion_error(rl, index, "integer"),
+                        },
+                    }
+                }
+            }
+        )*
+    }
+}
+
+/* u8 is left out so that Vec<u8> can be pushed as a string. */
+impl_integer!(i8 i16 i32 i64 isize u16 u32 u64 usize);
+
+macro_rules! impl_float {
+    ($($t:ty)*) => {
+        $(
+            impl ToLua for $t {
+                fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+                    rl.state.push_number(*self as lua::Number);
+                    Ok(())
+                }
+            }
+
+            impl FromLua for $t {
+                fn from_lua(rl: &mut RumLua, index: Index) -> Result<$t, LuaError> {
+                    match rl.state.to_numberx(index) {
+                        Some(n) => Ok(n as $t),
+                        None => conversion_error(rl, index, "number"),
+                    }
+                }
+            }
+        )*
+    }
+}
+
+impl_float!(f32 f64);
+
+impl<'a> ToLua for &'a str {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_bytes(rl, self.as_bytes());
+        Ok(())
+    }
+}
+
+impl ToLua for String {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_bytes(rl, self.as_bytes());
+        Ok(())
+    }
+}
+
+impl FromLua for String {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<String, LuaError> {
+        match rl.state.type_of(index) {
+            Some(lua::Type::String) => {},
+            Some(lua::Type::Number) => {
+                /* Convert a copy, as lua_tolstring modifies in place. */
+                rl.state.push_value(index);
+                let s = rl.state.to_str(-1).map(|s| s.to_string());
+                rl.state.pop(1);
+                return s.ok_or_else(|| LuaError::ConversionError("Error converting number to string".to_string()));
+            },
+            _ => return conversion_error(rl, index, "string"),
+        }
+        let s = LuaString::from_lua(rl, index)?;
+        String::from_utf8(s.into_bytes()).map_err(|_| LuaError::ConversionError("string is not valid UTF-8".to_string()))
+    }
+}
+
+impl ToLua for char {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        let mut buf = [0; 4];
+        push_bytes(rl, self.encode_utf8(&mut buf).as_bytes());
+        Ok(())
+    }
+}
+
+impl FromLua for char {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<char, LuaError> {
+        if rl.state.type_of(index) != Some(lua::Type::String) {
+            return conversion_error(rl, index, "character");
+        }
+        let s = String::from_lua(rl, index)?;
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(c),
+            _ => Err(LuaError::ConversionError(format!("character expected, got string of {} characters",
+                                                       s.chars().count()))),
+        }
+    }
+}
+
+/* OS strings are their bytes on Unix, and must be Unicode elsewhere. */
+#[cfg(unix)]
+fn push_os_str(rl: &mut RumLua, s: &OsStr) -> Result<(), LuaError> {
+    push_bytes(rl, s.as_bytes());
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn push_os_str(rl: &mut RumLua, s: &OsStr) -> Result<(), LuaError> {
+    match s.to_str() {
+        Some(s) => s.to_lua(rl),
+        None => Err(LuaError::ConversionError(format!("{:?} is not valid Unicode", s))),
+    }
+}
+
+#[cfg(unix)]
+fn os_string_from_lua(rl: &mut RumLua, index: Index) -> Result<OsString, LuaError> {
+    let s = LuaString::from_lua(rl, index)?;
+    Ok(OsString::from_vec(s.into_bytes()))
+}
+
+#[cfg(not(unix))]
+fn os_string_from_lua(rl: &mut RumLua, index: Index) -> Result<OsString, LuaError> {
+    let s = LuaString::from_lua(rl, index)?;
+    String::from_utf8(s.into_bytes()).map(OsString::from)
+        .map_err(|_| LuaError::ConversionError("string is not valid UTF-8".to_string()))
+}
+
+impl<'a> ToLua for &'a OsStr {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_os_str(rl, self)
+    }
+}
+
+impl ToLua for OsString {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_os_str(rl, self)
+    }
+}
+
+impl FromLua for OsString {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<OsString, LuaError> {
+        if rl.state.type_of(index) != Some(lua::Type::String) {
+            return conversion_error(rl, index, "string");
+        }
+        os_string_from_lua(rl, index)
+    }
+}
+
+impl<'a> ToLua for &'a Path {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_os_str(rl, self.as_os_str())
+    }
+}
+
+impl ToLua for PathBuf {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_os_str(rl, self.as_os_str())
+    }
+}
+
+impl FromLua for PathBuf {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<PathBuf, LuaError> {
+        if rl.state.type_of(index) != Some(lua::Type::String) {
+            return conversion_error(rl, index, "path");
+        }
+        os_string_from_lua(rl, index).map(PathBuf::from)
+    }
+}
+
+impl<T: Any> ToLua for LuaPtr<T> {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        rl.push(self)
+    }
+}
+
+impl<T: Any> FromLua for LuaPtr<T> {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<LuaPtr<T>, LuaError> {
+        rl.get::<T>(index)
+    }
+}
+
+impl<T: Any> ToLua for LuaWeak<T> {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        rl.push_weak(self)
+    }
+}
+
+impl<T: Any> FromLua for LuaWeak<T> {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<LuaWeak<T>, LuaError> {
+        rl.get::<T>(index).map(|p| p.downgrade())
+    }
+}
+
+impl<T: Any> ToLua for ArcPtr<T> {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        rl.push_arc(self)
+    }
+}
+
+impl<T: Any> FromLua for ArcPtr<T> {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<ArcPtr<T>, LuaError> {
+        rl.get_arc::<T>(index)
+    }
+}
+
+impl<T: ToLua> ToLua for Option<T> {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        match *self {
+            Some(ref value) => value.to_lua(rl),
+            None => {
+                rl.state.push_nil();
+                Ok(())
+            },
+        }
+    }
+}
+
+/* A missing value is also None; use `RumLua::has_arg` to tell them apart. */
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<Option<T>, LuaError> {
+        if rl.state.is_none_or_nil(index) {
+            Ok(None)
+        } else {
+            T::from_lua(rl, index).map(Some)
+        }
+    }
+}
+
+/* Add the location within a table to a conversion error. */
+fn element_error(err: LuaError, location: String) -> LuaError {
+    match err {
+        LuaError::ConversionError(msg) => LuaError::ConversionError(format!("{} at {}", msg, location)),
+        other => other,
+    }
+}
+
+/* Push a new table with `seq` as its sequence part. */
+fn push_sequence<'a, T, I>(rl: &mut RumLua, len: usize, seq: I) -> Result<(), LuaError>
+              where T: ToLua + 'a, I: Iterator<Item=&'a T>
+{
+    /* The table and each value in turn */
+    rl.ensure_stack(2)?;
+    let base = rl.state.get_top();
+    rl.state.create_table(len as c_int, 0);
+    for (i, v) in seq.enumerate() {
+        match checked_index(i).and_then(|lua_i| v.to_lua(rl).map(|()| lua_i)) {
+            Ok(lua_i) => rl.state.raw_seti(-2, lua_i),
+            Err(e) => {
+                rl.state.set_top(base);
+                return Err(e);
+            },
+        }
+    }
+    Ok(())
+}
+
+/* Push a new table with the given entries. */
+fn push_entries<'a, K, V, I>(rl: &mut RumLua, len: usize, entries: I) -> Result<(), LuaError>
+              where K: ToLua + 'a, V: ToLua + 'a, I: Iterator<Item=(&'a K, &'a V)>
+{
+    rl.ensure_stack(3)?;
+    let base = rl.state.get_top();
+    rl.state.create_table(0, len as c_int);
+    for (k, v) in entries {
+        if let Err(e) = k.to_lua(rl).and_then(|()| v.to_lua(rl)) {
+            rl.state.set_top(base);
+            return Err(e);
+        }
+        rl.state.raw_set(-3);
+    }
+    Ok(())
+}
+
+/* Call `f` with each key and value (at -2 and -1) of the table at
+ * `index`, without invoking metamethods. */
+fn for_each_entry<F>(rl: &mut RumLua, index: Index, mut f: F) -> Result<(), LuaError>
+              where F: FnMut(&mut RumLua) -> Result<(), LuaError>
+{
+    if !rl.state.is_table(index) {
+        return conversion_error(rl, index, "table");
+    }
+    let table = rl.state.abs_index(index);
+    rl.ensure_stack(2)?;
+    let base = rl.state.get_top();
+    rl.state.push_nil();
+    while rl.state.next(table) {
+        if let Err(e) = f(rl) {
+            rl.state.set_top(base);
+            return Err(e);
+        }
+        rl.state.pop(1);
+    }
+    Ok(())
+}
+
+impl<T: ToLua> ToLua for Vec<T> {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_sequence(rl, self.len(), self.iter())
+    }
+}
+
+impl<'a, T: ToLua> ToLua for &'a [T] {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_sequence(rl, self.len(), self.iter())
+    }
+}
+
+impl<T: FromLua> FromLua for Vec<T> {
+    /* Only a proper sequence converts: every key must be an integer
+     * from 1 to the length. */
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<Vec<T>, LuaError> {
+        if !rl.state.is_table(index) {
+            return conversion_error(rl, index, "sequence table");
+        }
+        let table = rl.state.abs_index(index);
+        let len = rl.state.raw_len(table);
+        let mut result = Vec::with_capacity(len);
+        for_each_entry(rl, table, |rl| {
+            match rl.state.to_integerx(-2) {
+                Some(i) if rl.state.is_integer(-2) && i >= 1 && i as usize <= len => Ok(()),
+                _ => {
+                    let key = String::from_lua(rl, -2).unwrap_or_else(|_| type_name(rl, -2).to_string());
+                    Err(LuaError::ConversionError(format!("sequence table expected, got key {}", key)))
+                },
+            }
+        })?;
+        let base = rl.state.get_top();
+        for i in 1..(len + 1) {
+            rl.state.raw_geti(table, i as lua::Integer);
+            let v = T::from_lua(rl, -1);
+            rl.state.set_top(base);
+            result.push(v.map_err(|e| element_error(e, format!("index {}", i)))?);
+        }
+        Ok(result)
+    }
+}
+
+impl<K: ToLua + Eq + Hash, V: ToLua> ToLua for HashMap<K, V> {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_entries(rl, self.len(), self.iter())
+    }
+}
+
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<HashMap<K, V>, LuaError> {
+        let mut result = HashMap::new();
+        for_each_entry(rl, index, |rl| {
+            let (k, v) = entry_from_lua(rl)?;
+            result.insert(k, v);
+            Ok(())
+        })?;
+        Ok(result)
+    }
+}
+
+impl<K: ToLua + Ord, V: ToLua> ToLua for BTreeMap<K, V> {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_entries(rl, self.len(), self.iter())
+    }
+}
+
+impl<K: FromLua + Ord, V: FromLua> FromLua for BTreeMap<K, V> {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<BTreeMap<K, V>, LuaError> {
+        let mut result = BTreeMap::new();
+        for_each_entry(rl, index, |rl| {
+            let (k, v) = entry_from_lua(rl)?;
+            result.insert(k, v);
+            Ok(())
+        })?;
+        Ok(result)
+    }
+}
+
+/* Convert the key and value at -2 and -1 while iterating a table. */
+fn entry_from_lua<K: FromLua, V: FromLua>(rl: &mut RumLua) -> Result<(K, V), LuaError> {
+    let k = K::from_lua(rl, -2).map_err(|e| element_error(e, "table key".to_string()))?;
+    let v = V::from_lua(rl, -1).map_err(|e| element_error(e, "table value".to_string()))?;
+    Ok((k, v))
+}
+
+impl<T: ToLua + Eq + Hash> ToLua for HashSet<T> {
+    /* Each element becomes a key with the value true. */
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_entries(rl, self.len(), self.iter().map(|k| (k, &true)))
+    }
+}
+
+impl<T: FromLua + Eq + Hash> FromLua for HashSet<T> {
+    /* The elements are the keys whose values are true. */
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<HashSet<T>, LuaError> {
+        let mut result = HashSet::new();
+        for_each_entry(rl, index, |rl| {
+            if rl.state.type_of(-1) != Some(lua::Type::Boolean) || !rl.state.to_bool(-1) {
+                let got = type_name(rl, -1);
+                return Err(LuaError::ConversionError(format!("set table expected, got {} value", got)));
+            }
+            let k = T::from_lua(rl, -2).map_err(|e| element_error(e, "table key".to_string()))?;
+            result.insert(k);
+            Ok(())
+        })?;
+        Ok(result)
+    }
+}
+
+/// The type of an argument of a method declared with `rum_methods!`.
+/// Arguments are converted to `Owned` and then passed as `Self`, which
+/// allows borrowed arguments like `&str`.
+pub trait MethodArg<'a>: Sized {
+    type Owned: FromLua;
+
+    /// Convert the argument at `index`.
+    fn get_arg(rl: &mut RumLua, index: Index) -> Result<Option<Self::Owned>, LuaError> {
+        rl.check_arg::<Self::Owned>(index).map(Some)
+    }
+
+    /// Produce the argument to pass from the converted value.
+    fn pass_arg(owned: &'a mut Option<Self::Owned>) -> Self;
+}
+
+impl<'a, T: FromLua> MethodArg<'a> for T {
+    type Owned = T;
+    fn pass_arg(owned: &'a mut Option<T>) -> T {
+        owned.take().unwrap()
+    }
+}
+
+impl<'a> MethodArg<'a> for &'a str {
+    type Owned = String;
+    fn pass_arg(owned: &'a mut Option<String>) -> &'a str {
+        owned.as_ref().unwrap()
+    }
+}
+
+impl<T: ToLua> ToLuaMulti for T {
+    fn to_lua_multi(&self, rl: &mut RumLua) -> Result<c_int, LuaError> {
+        rl.ensure_stack(1)?;
+        self.to_lua(rl)?;
+        Ok(1)
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(rl: &mut RumLua, count: c_int) -> Result<T, LuaError> {
+        let first = rl.state.get_top() - count + 1;
+        from_lua_or_nil(rl, first, first + count)
+    }
+}
+
+impl ToLuaMulti for () {
+    fn to_lua_multi(&self, _: &mut RumLua) -> Result<c_int, LuaError> {
+        Ok(0)
+    }
+}
+
+/// What a typed callback returns: any `ToLuaMulti` values, or a `Result`
+/// whose error is raised in Lua.  The error is returned as it was from the
+/// Rust code which called into Lua, as a `LuaError` if it was one and
+/// otherwise as `LuaError::CallbackError`.
+pub trait CallbackResult {
+    /// Push the results, returning how many were pushed.
+    fn push_results(self, rl: &mut RumLua) -> Result<c_int, LuaError>;
+}
+
+impl<T: ToLuaMulti> CallbackResult for T {
+    fn push_results(self, rl: &mut RumLua) -> Result<c_int, LuaError> {
+        self.to_lua_multi(rl)
+    }
+}
+
+impl<T: ToLuaMulti, E: Error + Send + Sync + 'static> CallbackResult for Result<T, E> {
+    fn push_results(self, rl: &mut RumLua) -> Result<c_int, LuaError> {
+        match self {
+            Ok(values) => values.to_lua_multi(rl),
+            Err(e) => Err(callback_error(e)),
+        }
+    }
+}
+
+/* An error from a callback, as it was if it is a LuaError. */
+pub fn callback_error<E: Error + Send + Sync + 'static>(e: E) -> LuaError {
+    let cause: Box<dyn Error + Send + Sync> = Box::new(e);
+    match cause.downcast::<LuaError>() {
+        Ok(e) => *e,
+        Err(cause) => LuaError::CallbackError{ cause: cause },
+    }
+}
+
+impl FromLuaMulti for () {
+    fn from_lua_multi(_: &mut RumLua, _: c_int) -> Result<(), LuaError> {
+        Ok(())
+    }
+}
+
+/// Any number of values of the same type, such as the trailing
+/// arguments of a variadic function.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variadic<T>(pub Vec<T>);
+
+impl<T: ToLua> ToLuaMulti for Variadic<T> {
+    fn to_lua_multi(&self, rl: &mut RumLua) -> Result<c_int, LuaError> {
+        rl.ensure_stack(self.0.len())?;
+        for v in &self.0 {
+            v.to_lua(rl)?;
+        }
+        Ok(self.0.len() as c_int)
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for Variadic<T> {
+    fn from_lua_multi(rl: &mut RumLua, count: c_int) -> Result<Variadic<T>, LuaError> {
+        let first = rl.state.get_top() - count + 1;
+        let mut values = Vec::with_capacity(count as usize);
+        for index in first..(first + count) {
+            values.push(T::from_lua(rl, index)?);
+        }
+        Ok(Variadic(values))
+    }
+}
+
+/* Convert the value at absolute `index`, or nil if `index` is not below
+ * `end`. */
+fn from_lua_or_nil<T: FromLua>(rl: &mut RumLua, index: Index, end: Index) -> Result<T, LuaError> {
+    if index < end {
+        T::from_lua(rl, index)
+    } else {
+        rl.state.push_nil();
+        let top = rl.state.get_top();
+        let result = T::from_lua(rl, top);
+        rl.state.pop(1);
+        result
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident)+) => {
+        impl<$($name: ToLua),+> ToLuaMulti for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn to_lua_multi(&self, rl: &mut RumLua) -> Result<c_int, LuaError> {
+                let &($(ref $name,)+) = self;
+                rl.ensure_stack([$(stringify!($name)),+].len())?;
+                let mut count = 0;
+                $(
+                    $name.to_lua(rl)?;
+                    count += 1;
+                )+
+                Ok(count)
+            }
+        }
+
+        impl<$($name: FromLua),+> FromLuaMulti for ($($name,)+) {
+            #[allow(non_snake_case, unused_assignments)]
+            fn from_lua_multi(rl: &mut RumLua, count: c_int) -> Result<Self, LuaError> {
+                let first = rl.state.get_top() - count + 1;
+                let mut index = first;
+                $(
+                    let $name = from_lua_or_nil::<$name>(rl, index, first + count)?;
+                    index += 1;
+                )+
+                Ok(($($name,)+))
+            }
+        }
+    }
+}
+
+impl_tuple!(A);
+impl_tuple!(A B);
+impl_tuple!(A B C);
+impl_tuple!(A B C D);
+impl_tuple!(A B C D E);
+impl_tuple!(A B C D E F);
+impl_tuple!(A B C D E F G);
+impl_tuple!(A B C D E F G H);
+impl_tuple!(A B C D E F G H I);
+impl_tuple!(A B C D E F G H I J);
+impl_tuple!(A B C D E F G H I J K);
+impl_tuple!(A B C D E F G H I J K L);
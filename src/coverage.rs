@@ -0,0 +1,123 @@
+/* Recording which lines of scripts are run, with the debug hook */
+use ::{RumLua, HookTriggers, HookEvent, DebugInfo};
+use lua;
+use std::cell::RefCell;
+use std::collections::{HashMap, BTreeMap, BTreeSet};
+use std::ffi::CStr;
+use std::mem;
+use std::rc::Rc;
+
+#[derive(Default)]
+struct CoverageData {
+    /* Hit counts by chunk (as Lua shows its name) and line */
+    lines: HashMap<String, BTreeMap<u32, u64>>,
+    /* The full chunk names, for names Lua shortens */
+    sources: HashMap<String, String>,
+}
+
+impl CoverageData {
+    fn event(&mut self, rl: &mut RumLua, info: DebugInfo) {
+        match info.event {
+            HookEvent::Call | HookEvent::TailCall if info.what != "C" => self.add_function(rl),
+            HookEvent::Line => {
+                if let Some(line) = info.current_line {
+                    *self.lines.entry(info.source).or_insert_with(BTreeMap::new)
+                                .entry(line).or_insert(0) += 1;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /* Add the lines of the function being called, so that those which
+     * aren't run are reported too. */
+    fn add_function(&mut self, rl: &mut RumLua) {
+        unsafe {
+            let l = rl.state.as_ptr();
+            let mut ar: lua::ffi::lua_Debug = mem::zeroed();
+            if lua::ffi::lua_getstack(l, 0, &mut ar) == 0 {
+                return;
+            }
+            /* "L" pushes a table whose keys are the lines with code. */
+            lua::ffi::lua_getinfo(l, b"SL\0".as_ptr() as *const _, &mut ar);
+            let short = CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy().into_owned();
+            let source = CStr::from_ptr(ar.source).to_string_lossy();
+            let source = if source.starts_with('@') || source.starts_with('=') {
+                source[1..].to_string()
+            } else {
+                short.clone()
+            };
+            self.sources.insert(short.clone(), source);
+            let lines = self.lines.entry(short).or_insert_with(BTreeMap::new);
+            if rl.state.is_table(-1) {
+                rl.state.push_nil();
+                while rl.state.next(-2) {
+                    if let Some(line) = rl.state.to_integerx(-2) {
+                        lines.entry(line as u32).or_insert(0);
+                    }
+                    rl.state.pop(1);
+                }
+            }
+            rl.state.pop(1);
+        }
+    }
+}
+
+/// Records which lines of the scripts run while it is installed are
+/// executed, using the debug hook.  Lines of functions which are never
+/// called are not known, so are not reported.
+pub struct Coverage {
+    data: Rc<RefCell<CoverageData>>,
+}
+
+impl Coverage {
+    /// Start recording coverage of the scripts run by `rl`.  This replaces
+    /// any hook set with `RumLua::set_hook`.
+    pub fn start(rl: &mut RumLua) -> Coverage {
+        let data = Rc::new(RefCell::new(CoverageData::default()));
+        let hook_data = data.clone();
+        rl.set_hook(HookTriggers{ on_call: true, on_return: false, every_line: true,
+                                  every_nth_instruction: None },
+                    Box::new(move |rl, info| hook_data.borrow_mut().event(rl, info)));
+        Coverage{ data: data }
+    }
+
+    /// Stop recording, removing the hook.
+    pub fn stop(&self, rl: &mut RumLua) {
+        rl.remove_hook();
+    }
+
+    /// How many times each line with code was run, by chunk name.
+    pub fn hits(&self) -> HashMap<String, BTreeMap<u32, u64>> {
+        let data = self.data.borrow();
+        data.lines.iter().map(|(short, lines)| (source_name(&data, short), lines.clone())).collect()
+    }
+
+    /// The lines which were run, by chunk name.
+    pub fn executed(&self) -> HashMap<String, BTreeSet<u32>> {
+        self.hits().into_iter().map(|(chunk, lines)| {
+            (chunk, lines.into_iter().filter(|&(_, hits)| hits > 0).map(|(line, _)| line).collect())
+        }).collect()
+    }
+
+    /// The coverage in LCOV's tracefile format, with each chunk name as
+    /// the source file.
+    pub fn lcov(&self) -> String {
+        let mut chunks: Vec<(String, BTreeMap<u32, u64>)> = self.hits().into_iter().collect();
+        chunks.sort();
+        let mut lcov = String::new();
+        for (chunk, lines) in chunks {
+            lcov.push_str(&format!("TN:\nSF:{}\n", chunk));
+            for (line, hits) in &lines {
+                lcov.push_str(&format!("DA:{},{}\n", line, hits));
+            }
+            let hit = lines.values().filter(|&&hits| hits > 0).count();
+            lcov.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit));
+        }
+        lcov
+    }
+}
+
+fn source_name(data: &CoverageData, short: &str) -> String {
+    data.sources.get(short).cloned().unwrap_or_else(|| short.to_string())
+}
@@ -0,0 +1,412 @@
+/* A Debug Adapter Protocol server over TCP, so editors can drive the
+ * debugger */
+use ::{RumLua, Value as LuaValue, LuaTable, Frame};
+use debugger::{Debugger, StepMode, Pause, PauseReason};
+use lua;
+use libc::c_int;
+use serde_json::{self, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+
+/* Scripts all run on one thread as far as the editor is concerned. */
+const THREAD: u64 = 1;
+
+/* What a variablesReference given to the editor while paused stands
+ * for; the functions on the stack are known by their stack levels. */
+enum Reference {
+    Locals(i32),
+    Upvalues(i32),
+    Table(LuaTable),
+}
+
+/* The functions on the stack of the paused script, innermost first, so
+ * that the index of each is its stack level. */
+fn stack_frames(rl: &mut RumLua) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    unsafe {
+        let l = rl.state.as_ptr();
+        let mut ar: lua::ffi::lua_Debug = mem::zeroed();
+        while lua::ffi::lua_getstack(l, frames.len() as c_int, &mut ar) != 0 {
+            lua::ffi::lua_getinfo(l, b"nSl\0".as_ptr() as *const _, &mut ar);
+            frames.push(Frame {
+                source: CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy().into_owned(),
+                line: if ar.currentline > 0 { Some(ar.currentline as u32) } else { None },
+                name: if ar.name.is_null() { None } else {
+                    Some(CStr::from_ptr(ar.name).to_string_lossy().into_owned())
+                },
+            });
+        }
+    }
+    frames
+}
+
+struct Session {
+    input: BufReader<TcpStream>,
+    output: TcpStream,
+    seq: u64,
+    connected: bool,
+    debugger: Option<Debugger>,
+    /* The lines the editor last set breakpoints on, by source */
+    breakpoints: HashMap<String, Vec<u32>>,
+    /* The scopes and tables shown while paused, by variablesReference
+     * less one */
+    references: Vec<Reference>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl Session {
+    /* Read the next message, or None once the editor has gone. */
+    fn read(&mut self) -> io::Result<Option<Value>> {
+        let mut len = None;
+        loop {
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if line.starts_with("Content-Length:") {
+                len = line["Content-Length:".len()..].trim().parse::<u64>().ok();
+            }
+        }
+        let len = len.ok_or_else(|| invalid("message has no Content-Length"))?;
+        /* Read as much as there is, rather than trusting the length. */
+        let mut body = Vec::new();
+        self.input.by_ref().take(len).read_to_end(&mut body)?;
+        if body.len() as u64 != len {
+            return Ok(None);
+        }
+        serde_json::from_slice(&body).map(Some).map_err(|e| invalid(&e.to_string()))
+    }
+
+    /* Whether a message (or the end of the connection) can be read
+     * without waiting. */
+    fn ready(&mut self) -> io::Result<bool> {
+        if !self.input.buffer().is_empty() {
+            return Ok(true);
+        }
+        self.input.get_ref().set_nonblocking(true)?;
+        let result = self.input.fill_buf().map(|_| ());
+        self.input.get_ref().set_nonblocking(false)?;
+        match result {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn send(&mut self, message: Value) -> io::Result<()> {
+        let body = serde_json::to_vec(&message).map_err(|e| invalid(&e.to_string()))?;
+        write!(self.output, "Content-Length: {}\r\n\r\n", body.len())?;
+        self.output.write_all(&body)?;
+        self.output.flush()
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.seq += 1;
+        let message = json!({ "seq": self.seq, "type": "event", "event": event, "body": body });
+        self.send(message)
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> io::Result<()> {
+        self.seq += 1;
+        let message = json!({ "seq": self.seq, "type": "response", "request_seq": request["seq"],
+                              "command": request["command"], "success": true, "body": body });
+        self.send(message)
+    }
+
+    fn fail(&mut self, request: &Value, error: &str) -> io::Result<()> {
+        self.seq += 1;
+        let message = json!({ "seq": self.seq, "type": "response", "request_seq": request["seq"],
+                              "command": request["command"], "success": false, "message": error });
+        self.send(message)
+    }
+
+    /* Drop the editor's breakpoints, so scripts run on undisturbed. */
+    fn close(&mut self) {
+        self.connected = false;
+        if let Some(ref debugger) = self.debugger {
+            for (source, lines) in self.breakpoints.drain() {
+                for line in lines {
+                    debugger.clear_breakpoint(&source, line);
+                }
+            }
+            debugger.set_mode(StepMode::Continue);
+        }
+    }
+
+    fn reference(&mut self, reference: Reference) -> u64 {
+        self.references.push(reference);
+        self.references.len() as u64
+    }
+
+    fn variable(&mut self, name: String, value: LuaValue) -> Value {
+        let reference = match value {
+            LuaValue::Table(ref t) => self.reference(Reference::Table(t.clone())),
+            _ => 0,
+        };
+        json!({ "name": name, "value": format!("{:?}", value), "type": value.type_name(),
+                "variablesReference": reference })
+    }
+
+    fn variables(&mut self, rl: &mut RumLua, reference: u64) -> Vec<Value> {
+        let entries: Vec<(String, LuaValue)> = match self.references.get(reference as usize - 1) {
+            Some(&Reference::Locals(level)) => rl.locals(level).unwrap_or_else(|_| Vec::new()),
+            Some(&Reference::Upvalues(level)) => {
+                rl.stack_function(level).and_then(|f| rl.upvalues(&f)).unwrap_or_else(|_| Vec::new())
+            },
+            Some(&Reference::Table(ref t)) => {
+                t.pairs::<LuaValue, LuaValue>(rl)
+                 .filter_map(|entry| entry.ok())
+                 .map(|(k, v)| (match k {
+                     LuaValue::String(s) => s,
+                     k => format!("[{:?}]", k),
+                 }, v))
+                 .collect()
+            },
+            None => Vec::new(),
+        };
+        entries.into_iter().map(|(name, value)| self.variable(name, value)).collect()
+    }
+
+    /* Answer a request, returning how to carry on if it resumes the
+     * script. */
+    fn handle(&mut self, rl: &mut RumLua, request: &Value, paused: bool) -> io::Result<Option<StepMode>> {
+        let args = &request["arguments"];
+        let mode = match request["command"].as_str().unwrap_or("") {
+            "initialize" => {
+                self.respond(request, json!({ "supportsConfigurationDoneRequest": true }))?;
+                self.event("initialized", json!({}))?;
+                return Ok(None);
+            },
+            "launch" | "attach" | "configurationDone" => {
+                self.respond(request, json!({}))?;
+                return Ok(None);
+            },
+            "setBreakpoints" => {
+                let source = args["source"]["path"].as_str()
+                                 .or_else(|| args["source"]["name"].as_str())
+                                 .unwrap_or("").to_string();
+                let lines: Vec<u32> = args["breakpoints"].as_array()
+                                          .map(|bps| bps.iter().filter_map(|bp| bp["line"].as_u64())
+                                                        .map(|line| line as u32).collect())
+                                          .unwrap_or_else(Vec::new);
+                if let Some(ref debugger) = self.debugger {
+                    for &line in self.breakpoints.get(&source).map(|v| &v[..]).unwrap_or(&[]) {
+                        debugger.clear_breakpoint(&source, line);
+                    }
+                    for &line in &lines {
+                        debugger.set_breakpoint(&source, line);
+                    }
+                }
+                let verified: Vec<Value> = lines.iter().map(|&line| json!({ "verified": true, "line": line })).collect();
+                self.breakpoints.insert(source, lines);
+                self.respond(request, json!({ "breakpoints": verified }))?;
+                return Ok(None);
+            },
+            "threads" => {
+                self.respond(request, json!({ "threads": [{ "id": THREAD, "name": "main" }] }))?;
+                return Ok(None);
+            },
+            "stackTrace" => {
+                let stack = if paused { stack_frames(rl) } else { Vec::new() };
+                let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+                let levels = match args["levels"].as_u64() {
+                    Some(levels) if levels > 0 => levels as usize,
+                    _ => stack.len(),
+                };
+                /* Each frame's id is its stack level. */
+                let frames: Vec<Value> = stack.iter().enumerate().skip(start).take(levels).map(|(level, frame)| json!({
+                    "id": level,
+                    "name": frame.name.clone().unwrap_or_else(|| "?".to_string()),
+                    "source": { "name": frame.source, "path": frame.source },
+                    "line": frame.line.unwrap_or(0),
+                    "column": 1,
+                })).collect();
+                self.respond(request, json!({ "stackFrames": frames, "totalFrames": stack.len() }))?;
+                return Ok(None);
+            },
+            "scopes" => {
+                let scopes = match args["frameId"].as_u64() {
+                    Some(level) if paused => {
+                        let locals = self.reference(Reference::Locals(level as i32));
+                        let upvalues = self.reference(Reference::Upvalues(level as i32));
+                        vec![json!({ "name": "Locals", "variablesReference": locals, "expensive": false }),
+                             json!({ "name": "Upvalues", "variablesReference": upvalues, "expensive": false })]
+                    },
+                    _ => Vec::new(),
+                };
+                self.respond(request, json!({ "scopes": scopes }))?;
+                return Ok(None);
+            },
+            "variables" => {
+                let variables = match args["variablesReference"].as_u64() {
+                    Some(reference) if paused && reference > 0 => self.variables(rl, reference),
+                    _ => Vec::new(),
+                };
+                self.respond(request, json!({ "variables": variables }))?;
+                return Ok(None);
+            },
+            "pause" => {
+                if let Some(ref debugger) = self.debugger {
+                    debugger.set_mode(StepMode::StepInto);
+                }
+                self.respond(request, json!({}))?;
+                return Ok(None);
+            },
+            "continue" => StepMode::Continue,
+            "next" => StepMode::StepOver,
+            "stepIn" => StepMode::StepInto,
+            "stepOut" => StepMode::StepOut,
+            "disconnect" => {
+                self.respond(request, json!({}))?;
+                self.close();
+                return Ok(Some(StepMode::Continue));
+            },
+            command => {
+                self.fail(request, &format!("unsupported request '{}'", command))?;
+                return Ok(None);
+            },
+        };
+        let body = if mode == StepMode::Continue { json!({ "allThreadsContinued": true }) } else { json!({}) };
+        self.respond(request, body)?;
+        if paused {
+            Ok(Some(mode))
+        } else {
+            /* Not paused, so start stepping from the next line run. */
+            if let Some(ref debugger) = self.debugger {
+                debugger.set_mode(mode);
+            }
+            Ok(None)
+        }
+    }
+
+    /* Tell the editor the script has paused, and answer its requests
+     * until it resumes it. */
+    fn paused(&mut self, rl: &mut RumLua, pause: &Pause) -> io::Result<StepMode> {
+        self.references.clear();
+        let reason = match pause.reason {
+            PauseReason::Breakpoint => "breakpoint",
+            PauseReason::Step => "step",
+        };
+        self.event("stopped", json!({ "reason": reason, "threadId": THREAD, "allThreadsStopped": true }))?;
+        while let Some(request) = self.read()? {
+            if let Some(mode) = self.handle(rl, &request, true)? {
+                return Ok(mode);
+            }
+        }
+        self.close();
+        Ok(StepMode::Continue)
+    }
+}
+
+/// Lets an editor debug the scripts a state runs, speaking the Debug
+/// Adapter Protocol over a TCP connection.  The editor's requests are
+/// answered while a script is paused, and otherwise by `poll`.
+/// Breakpoints are matched against chunk names as Lua shows them, so
+/// scripts should be loaded with their paths as chunk names (`@path`).
+pub struct DapServer {
+    session: Rc<RefCell<Session>>,
+}
+
+impl DapServer {
+    /// Wait for an editor to connect on `addr`, then `attach` to it.
+    pub fn listen<A: ToSocketAddrs>(rl: &mut RumLua, addr: A) -> io::Result<DapServer> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        DapServer::attach(rl, stream)
+    }
+
+    /// Start debugging the scripts run by `rl` for the editor connected
+    /// on `stream`, returning once the editor has finished setting up
+    /// its breakpoints.  As with `Debugger::attach`, this replaces any
+    /// hook set with `RumLua::set_hook`.
+    pub fn attach(rl: &mut RumLua, stream: TcpStream) -> io::Result<DapServer> {
+        let output = stream.try_clone()?;
+        let session = Rc::new(RefCell::new(Session {
+            input: BufReader::new(stream),
+            output: output,
+            seq: 0,
+            connected: true,
+            debugger: None,
+            breakpoints: HashMap::new(),
+            references: Vec::new(),
+        }));
+        let pause_session = session.clone();
+        let debugger = Debugger::attach(rl, Box::new(move |rl, pause| {
+            let mut session = pause_session.borrow_mut();
+            if !session.connected {
+                return StepMode::Continue;
+            }
+            session.paused(rl, pause).unwrap_or_else(|_| {
+                session.close();
+                StepMode::Continue
+            })
+        }));
+        session.borrow_mut().debugger = Some(debugger);
+        let server = DapServer{ session: session };
+        loop {
+            let mut session = server.session.borrow_mut();
+            let request = match session.read()? {
+                Some(request) => request,
+                None => { session.close(); break; },
+            };
+            session.handle(rl, &request, false)?;
+            if !session.connected || request["command"].as_str() == Some("configurationDone") {
+                break;
+            }
+        }
+        Ok(server)
+    }
+
+    /// Answer the requests the editor has sent since scripts last
+    /// paused, without waiting for more.
+    pub fn poll(&self, rl: &mut RumLua) -> io::Result<()> {
+        let mut session = self.session.borrow_mut();
+        while session.connected && session.ready()? {
+            match session.read()? {
+                Some(request) => { session.handle(rl, &request, false)?; },
+                None => session.close(),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the editor is still attached.
+    pub fn is_connected(&self) -> bool {
+        self.session.borrow().connected
+    }
+
+    /// Stop debugging, telling the editor the session is over and
+    /// removing the hook.
+    pub fn detach(&self, rl: &mut RumLua) {
+        let mut session = self.session.borrow_mut();
+        if session.connected {
+            let _ = session.event("terminated", json!({}));
+        }
+        session.close();
+        if let Some(debugger) = session.debugger.take() {
+            debugger.detach(rl);
+        }
+    }
+}
+
+impl Drop for DapServer {
+    fn drop(&mut self) {
+        /* The pause callback holds the session, so let go of the
+         * debugger it holds in turn. */
+        if let Ok(mut session) = self.session.try_borrow_mut() {
+            session.debugger = None;
+        }
+    }
+}
@@ -0,0 +1,162 @@
+/* A script debugger with breakpoints and stepping, on the debug hook */
+use ::{RumLua, Value, HookTriggers, HookEvent, DebugInfo};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+/// How a paused script should carry on; returned by the pause callback
+/// given to `Debugger::attach`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StepMode {
+    /// Run until the next breakpoint.
+    Continue,
+    /// Pause at the next line, including in called functions.
+    StepInto,
+    /// Pause at the next line of this function or its callers.
+    StepOver,
+    /// Pause once this function has returned.
+    StepOut,
+}
+
+/// Why a script paused.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PauseReason {
+    Breakpoint,
+    Step,
+}
+
+/// The state of a paused script, passed to the pause callback.
+#[derive(Debug)]
+pub struct Pause {
+    pub reason: PauseReason,
+    /// The chunk name as Lua shows it, such as `config/ai.lua`.
+    pub source: String,
+    pub line: u32,
+    /// The name the paused function was called by, if Lua could tell.
+    pub function: Option<String>,
+    /// The paused function's local variables in scope, in order.
+    pub locals: Vec<(String, Value)>,
+    /// The paused function's upvalues.
+    pub upvalues: Vec<(String, Value)>,
+}
+
+/// Called when a script pauses, to inspect it and choose how to go on.
+pub type PauseCallback = Box<dyn FnMut(&mut RumLua, &Pause) -> StepMode>;
+
+struct DebugState {
+    breakpoints: HashSet<(String, u32)>,
+    mode: StepMode,
+    /* Call depth now and when the last step started, relative to when
+     * the debugger was attached */
+    depth: isize,
+    step_depth: isize,
+    on_pause: Option<PauseCallback>,
+}
+
+impl DebugState {
+    /* Return why the script should pause at this line, if it should. */
+    fn should_pause(&self, info: &DebugInfo, line: u32) -> Option<PauseReason> {
+        let stepped = match self.mode {
+            StepMode::Continue => false,
+            StepMode::StepInto => true,
+            StepMode::StepOver => self.depth <= self.step_depth,
+            StepMode::StepOut => self.depth < self.step_depth,
+        };
+        if stepped {
+            Some(PauseReason::Step)
+        } else if self.breakpoints.contains(&(info.source.clone(), line)) {
+            Some(PauseReason::Breakpoint)
+        } else {
+            None
+        }
+    }
+}
+
+/// Pauses scripts at breakpoints and after steps, handing control to a
+/// callback which can inspect the paused function.  Calls and returns in
+/// coroutines are counted as if they ran on one stack.
+pub struct Debugger {
+    state: Rc<RefCell<DebugState>>,
+}
+
+impl Debugger {
+    /// Start debugging the scripts run by `rl`, calling `on_pause`
+    /// whenever one pauses.  This replaces any hook set with
+    /// `RumLua::set_hook`.
+    pub fn attach(rl: &mut RumLua, on_pause: PauseCallback) -> Debugger {
+        let state = Rc::new(RefCell::new(DebugState {
+            breakpoints: HashSet::new(),
+            mode: StepMode::Continue,
+            depth: 0,
+            step_depth: 0,
+            on_pause: Some(on_pause),
+        }));
+        let hook_state = state.clone();
+        rl.set_hook(HookTriggers{ on_call: true, on_return: true, every_line: true,
+                                  every_nth_instruction: None },
+                    Box::new(move |rl, info| hook_event(rl, &hook_state, info)));
+        Debugger{ state: state }
+    }
+
+    /// Stop debugging, removing the hook.
+    pub fn detach(&self, rl: &mut RumLua) {
+        rl.remove_hook();
+    }
+
+    /// Pause before running `line` of the chunk `chunk`, named as Lua
+    /// shows it.
+    pub fn set_breakpoint(&self, chunk: &str, line: u32) {
+        self.state.borrow_mut().breakpoints.insert((chunk.to_string(), line));
+    }
+
+    /// Remove a breakpoint set with `set_breakpoint`.
+    pub fn clear_breakpoint(&self, chunk: &str, line: u32) {
+        self.state.borrow_mut().breakpoints.remove(&(chunk.to_string(), line));
+    }
+
+    /// Set how the script carries on, such as `StepInto` to pause at the
+    /// first line run.
+    pub fn set_mode(&self, mode: StepMode) {
+        let mut state = self.state.borrow_mut();
+        state.mode = mode;
+        state.step_depth = state.depth;
+    }
+}
+
+fn hook_event(rl: &mut RumLua, state: &Rc<RefCell<DebugState>>, info: DebugInfo) {
+    let line = {
+        let mut state = state.borrow_mut();
+        match info.event {
+            HookEvent::Call => { state.depth += 1; return; },
+            HookEvent::Return => { state.depth -= 1; return; },
+            HookEvent::Line => {},
+            HookEvent::TailCall | HookEvent::Count => return,
+        }
+        match info.current_line {
+            Some(line) => line,
+            None => return,
+        }
+    };
+    let reason = match state.borrow().should_pause(&info, line) {
+        Some(reason) => reason,
+        None => return,
+    };
+    let pause = Pause {
+        reason: reason,
+        source: info.source,
+        line: line,
+        function: info.name,
+        locals: rl.locals(0).unwrap_or_else(|_| Vec::new()),
+        upvalues: rl.stack_function(0).and_then(|f| rl.upvalues(&f)).unwrap_or_else(|_| Vec::new()),
+    };
+    /* The callback may use the debugger, so it isn't borrowed meanwhile. */
+    let mut on_pause = match state.borrow_mut().on_pause.take() {
+        Some(f) => f,
+        None => return,
+    };
+    let mode = on_pause(rl, &pause);
+    let mut state = state.borrow_mut();
+    state.on_pause = Some(on_pause);
+    state.mode = mode;
+    state.step_depth = state.depth;
+}
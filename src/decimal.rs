@@ -0,0 +1,183 @@
+/* Decimal numbers as userdata, with the "decimal" feature */
+use ::{RumLua, LuaError, LuaRet, LuaPtr, ToLua, FromLua, TypeBuilder, MetaMethod, CallbackFn, lfail};
+use convert::conversion_error;
+use types;
+use lua;
+use lua::Index;
+use rust_decimal::Decimal;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/* Pushed as a userdata, so that arithmetic in Lua stays decimal. */
+impl ToLua for Decimal {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        if !rl.is_registered::<Decimal>() {
+            register(rl)?;
+        }
+        rl.push(&LuaPtr::new(*self))
+    }
+}
+
+/* Decimals also convert from integers and strings, but not from floats,
+ * which would bring their rounding with them. */
+impl FromLua for Decimal {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<Decimal, LuaError> {
+        match rl.state.type_of(index) {
+            Some(lua::Type::Number) => match rl.state.to_integerx(index) {
+                Some(i) if rl.state.is_integer(index) => Ok(Decimal::from(i)),
+                _ => Err(LuaError::ConversionError("decimal expected, got float (use a string)".to_string())),
+            },
+            Some(lua::Type::String) => {
+                let s = String::from_lua(rl, index)?;
+                Decimal::from_str(s.trim())
+                    .map_err(|e| LuaError::ConversionError(format!("invalid decimal '{}': {}", s, e)))
+            },
+            Some(lua::Type::Userdata) if rl.is_registered::<Decimal>() => {
+                match rl.with_ref(index, |d: &Decimal| *d) {
+                    Ok(d) => Ok(d),
+                    Err(_) => conversion_error(rl, index, "decimal"),
+                }
+            },
+            _ => conversion_error(rl, index, "decimal"),
+        }
+    }
+}
+
+fn operands(rl: &mut RumLua) -> Result<(Decimal, Decimal), LuaError> {
+    Ok((rl.check_arg(1)?, rl.check_arg(2)?))
+}
+
+/* Nothing but division can fail with a zero operand. */
+fn arith<F>(rl: &mut RumLua, op: F) -> LuaRet
+                  where F: Fn(Decimal, Decimal) -> Option<Decimal>
+{
+    let (a, b) = operands(rl)?;
+    match op(a, b) {
+        Some(result) => {
+            result.to_lua(rl)?;
+            Ok(1)
+        },
+        None if b.is_zero() => lfail("decimal division by zero"),
+        None => lfail("decimal overflow"),
+    }
+}
+
+fn decimal_add(rl: &mut RumLua) -> LuaRet {
+    arith(rl, Decimal::checked_add)
+}
+
+fn decimal_sub(rl: &mut RumLua) -> LuaRet {
+    arith(rl, Decimal::checked_sub)
+}
+
+fn decimal_mul(rl: &mut RumLua) -> LuaRet {
+    arith(rl, Decimal::checked_mul)
+}
+
+fn decimal_div(rl: &mut RumLua) -> LuaRet {
+    arith(rl, Decimal::checked_div)
+}
+
+fn decimal_mod(rl: &mut RumLua) -> LuaRet {
+    arith(rl, Decimal::checked_rem)
+}
+
+fn decimal_unm(rl: &mut RumLua) -> LuaRet {
+    let a: Decimal = rl.check_arg(1)?;
+    (-a).to_lua(rl)?;
+    Ok(1)
+}
+
+fn compare(rl: &mut RumLua) -> Result<Ordering, LuaError> {
+    let (a, b) = operands(rl)?;
+    Ok(a.cmp(&b))
+}
+
+fn decimal_eq(rl: &mut RumLua) -> LuaRet {
+    let eq = compare(rl)? == Ordering::Equal;
+    rl.state.push_bool(eq);
+    Ok(1)
+}
+
+fn decimal_lt(rl: &mut RumLua) -> LuaRet {
+    let lt = compare(rl)? == Ordering::Less;
+    rl.state.push_bool(lt);
+    Ok(1)
+}
+
+fn decimal_le(rl: &mut RumLua) -> LuaRet {
+    let le = compare(rl)? != Ordering::Greater;
+    rl.state.push_bool(le);
+    Ok(1)
+}
+
+fn decimal_tostring(rl: &mut RumLua) -> LuaRet {
+    let a: Decimal = rl.check_arg(1)?;
+    a.to_string().to_lua(rl)?;
+    Ok(1)
+}
+
+fn decimal_concat(rl: &mut RumLua) -> LuaRet {
+    let mut parts = String::new();
+    for n in 1..3 {
+        if rl.state.is_userdata(n) {
+            let d: Decimal = rl.check_arg(n)?;
+            parts.push_str(&d.to_string());
+        } else {
+            let s: String = rl.check_arg(n)?;
+            parts.push_str(&s);
+        }
+    }
+    parts.to_lua(rl)?;
+    Ok(1)
+}
+
+/* d:round(places): rounded to `places` decimal places, with ties going
+ * to even. */
+fn decimal_round(rl: &mut RumLua) -> LuaRet {
+    let a: Decimal = rl.check_arg(1)?;
+    let places: u32 = rl.opt_arg(2, 0)?;
+    a.round_dp(places).to_lua(rl)?;
+    Ok(1)
+}
+
+/* d:tonumber(): the nearest float */
+fn decimal_tonumber(rl: &mut RumLua) -> LuaRet {
+    let a: Decimal = rl.check_arg(1)?;
+    match a.to_string().parse::<f64>() {
+        Ok(f) => rl.state.push_number(f),
+        Err(_) => return lfail("decimal can't be converted to a number"),
+    }
+    Ok(1)
+}
+
+/* rum.decimal(value): a Decimal from an integer or string */
+fn rum_decimal(rl: &mut RumLua) -> LuaRet {
+    let d: Decimal = rl.check_arg(1)?;
+    d.to_lua(rl)?;
+    Ok(1)
+}
+
+/* Registered when the first Decimal is pushed. */
+fn register(rl: &mut RumLua) -> Result<(), LuaError> {
+    types::register(rl, TypeBuilder::<Decimal>::new("Decimal")
+        .method("round", decimal_round)
+        .method("tonumber", decimal_tonumber)
+        .metamethod(MetaMethod::Add, decimal_add)
+        .metamethod(MetaMethod::Sub, decimal_sub)
+        .metamethod(MetaMethod::Mul, decimal_mul)
+        .metamethod(MetaMethod::Div, decimal_div)
+        .metamethod(MetaMethod::Mod, decimal_mod)
+        .metamethod(MetaMethod::Unm, decimal_unm)
+        .metamethod(MetaMethod::Eq, decimal_eq)
+        .metamethod(MetaMethod::Lt, decimal_lt)
+        .metamethod(MetaMethod::Le, decimal_le)
+        .metamethod(MetaMethod::ToString, decimal_tostring)
+        .metamethod(MetaMethod::Concat, decimal_concat))
+}
+
+/* Add rum.decimal to the rum table on top of the stack. */
+pub fn open(rl: &mut RumLua) {
+    rl.push_registered_closure(CallbackFn::Plain(rum_decimal), "rum.decimal");
+    rl.state.set_field(-2, "decimal");
+}
@@ -0,0 +1,93 @@
+/* Rust enums exposed to Lua as tables of constants */
+use ::{RumLua, LuaError, ToLua};
+use convert::type_name;
+use lua;
+use lua::Index;
+use std::any::{Any, TypeId};
+
+/* A registered enum, whose variants are numbered from 1 in Lua. */
+pub struct EnumInfo {
+    name: String,
+    names: Vec<String>,
+    /* A Vec<E> of the variants, in the same order */
+    values: Box<dyn Any>,
+}
+
+/* Publish the global table `name` of the variants' numbers. */
+pub fn register<E: Any + Clone>(rl: &mut RumLua, name: &str, variants: &[(&str, E)]) -> Result<(), LuaError> {
+    if let Some(info) = rl.shared.enums.get(&TypeId::of::<E>()) {
+        return Err(LuaError::TypeError(format!("Enum is already registered as '{}'", info.name)));
+    }
+    let table = rl.create_table();
+    for (i, &(variant, _)) in variants.iter().enumerate() {
+        table.raw_set(rl, variant, (i + 1) as lua::Integer)?;
+    }
+    table.set_readonly(rl, true)?;
+    table.to_lua(rl)?;
+    rl.state.set_global(name);
+
+    let values: Vec<E> = variants.iter().map(|&(_, ref value)| value.clone()).collect();
+    rl.shared.enums.insert(TypeId::of::<E>(), EnumInfo {
+        name: name.to_string(),
+        names: variants.iter().map(|&(variant, _)| variant.to_string()).collect(),
+        values: Box::new(values),
+    });
+    Ok(())
+}
+
+fn unregistered() -> LuaError {
+    LuaError::TypeError("Attempt to convert a value of an unregistered enum".to_string())
+}
+
+/* Push the number of `value`'s variant. */
+pub fn push<E: Any + PartialEq>(rl: &mut RumLua, value: &E) -> Result<(), LuaError> {
+    let position = match rl.shared.enums.get(&TypeId::of::<E>()) {
+        Some(info) => {
+            let values = info.values.downcast_ref::<Vec<E>>().unwrap();
+            match values.iter().position(|v| v == value) {
+                Some(i) => i,
+                None => return Err(LuaError::ConversionError(format!("value is not a variant of {}", info.name))),
+            }
+        },
+        None => return Err(unregistered()),
+    };
+    rl.state.push_integer((position + 1) as lua::Integer);
+    Ok(())
+}
+
+/* How a variant was given from Lua */
+enum Key {
+    Number(lua::Integer),
+    Name(String),
+}
+
+/* Convert a variant's number or name at `index`. */
+pub fn get<E: Any + Clone>(rl: &mut RumLua, index: Index) -> Result<E, LuaError> {
+    let key = match rl.state.type_of(index) {
+        Some(lua::Type::Number) if rl.state.is_integer(index) => Key::Number(rl.state.to_integer(index)),
+        Some(lua::Type::String) => Key::Name(rl.state.to_str(index).unwrap_or("").to_string()),
+        _ => {
+            let got = type_name(rl, index);
+            return match rl.shared.enums.get(&TypeId::of::<E>()) {
+                Some(info) => Err(LuaError::ConversionError(format!("{} expected, got {}", info.name, got))),
+                None => Err(unregistered()),
+            };
+        },
+    };
+    let info = match rl.shared.enums.get(&TypeId::of::<E>()) {
+        Some(info) => info,
+        None => return Err(unregistered()),
+    };
+    let position = match key {
+        Key::Number(n) if n >= 1 && n as usize <= info.names.len() => (n - 1) as usize,
+        Key::Number(n) => {
+            return Err(LuaError::ConversionError(format!("{} has no variant {}", info.name, n)));
+        },
+        Key::Name(name) => match info.names.iter().position(|n| *n == name) {
+            Some(i) => i,
+            None => return Err(LuaError::ConversionError(format!("{} has no variant '{}'", info.name, name))),
+        },
+    };
+    let values = info.values.downcast_ref::<Vec<E>>().unwrap();
+    Ok(values[position].clone())
+}
@@ -0,0 +1,206 @@
+/* Error type for Lua operations */
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// Errors from loading or running Lua code, or from moving values between
+/// Rust and Lua.
+#[derive(Debug)]
+pub enum LuaError {
+    /// A chunk could not be compiled.
+    SyntaxError(String),
+    /// An error was raised while running Lua code.  The traceback is
+    /// available when the error was caught by this crate's message handler.
+    RuntimeError { message: String, traceback: Option<String> },
+    /// A Lua value could not be converted to the requested Rust type.
+    ConversionError(String),
+    /// A userdata type was unregistered, or did not match the expected type.
+    TypeError(String),
+    /// A Rust callback failed with an application error.
+    CallbackError { cause: Box<dyn Error + Send + Sync> },
+    /// A value shared with Lua was already borrowed.
+    BorrowError(String),
+    /// Lua failed to allocate memory.
+    MemoryError(String),
+    /// Lua tried to allocate more than the limit set with
+    /// `RumLua::set_memory_limit`.
+    MemoryLimit(String),
+    /// A file could not be opened or read.
+    FileError(String),
+    /// A script ran for longer than allowed by
+    /// `RumLua::set_instruction_limit` or `RumLua::set_timeout`.
+    Timeout(String),
+    /// A script was stopped through an `Interrupt` handle.
+    Interrupted(String),
+    /// The Lua stack could not grow to hold the values being pushed.
+    StackOverflow(String),
+}
+
+impl LuaError {
+    /// Wrap an application error returned from a callback.
+    pub fn callback<E>(cause: E) -> LuaError where E: Error + Send + Sync + 'static {
+        LuaError::CallbackError{ cause: Box::new(cause) }
+    }
+
+    /// The frames of a `RuntimeError`'s traceback, innermost first, or
+    /// nothing if there is no traceback.
+    pub fn frames(&self) -> Vec<Frame> {
+        match *self {
+            LuaError::RuntimeError{ traceback: Some(ref traceback), .. } => parse_traceback(traceback),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// One level of a Lua traceback; see `LuaError::frames`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// The chunk name as Lua shows it, or `[C]` for a C or Rust function.
+    pub source: String,
+    /// The line being run, if known.
+    pub line: Option<u32>,
+    /// The name the function was called by, if Lua could tell.
+    pub name: Option<String>,
+}
+
+/* Parse the output of luaL_traceback, skipping the header and the lines
+ * standing in for tail calls and skipped levels. */
+fn parse_traceback(traceback: &str) -> Vec<Frame> {
+    traceback.lines()
+             .map(|line| line.trim())
+             .filter(|line| *line != "stack traceback:" && !line.starts_with("(") &&
+                            !line.starts_with("..."))
+             .filter_map(parse_frame)
+             .collect()
+}
+
+/* Parse a line like "config/ai.lua:12: in local 'think'". */
+fn parse_frame(line: &str) -> Option<Frame> {
+    let pos = match line.find(": in ") {
+        Some(pos) => pos,
+        None => return None,
+    };
+    let (location, what) = (&line[..pos], &line[pos + 5..]);
+    let (source, line) = match location.rfind(':') {
+        Some(colon) => match location[colon + 1..].parse() {
+            Ok(n) => (&location[..colon], Some(n)),
+            Err(_) => (location, None),
+        },
+        None => (location, None),
+    };
+    /* Named functions look like "function 'name'" or "local 'name'";
+     * others are "main chunk", "?" or "function <source:line>". */
+    let name = match (what.find('\''), what.rfind('\'')) {
+        (Some(start), Some(end)) if end > start => Some(what[start + 1..end].to_string()),
+        _ => None,
+    };
+    Some(Frame {
+        source: source.to_string(),
+        line: line,
+        name: name,
+    })
+}
+
+impl LuaError {
+    /* The message of any error but a `CallbackError`, which is shown
+     * through its cause's `Display`. */
+    fn message(&self) -> Option<&str> {
+        match *self {
+            LuaError::SyntaxError(ref message) |
+            LuaError::ConversionError(ref message) |
+            LuaError::TypeError(ref message) |
+            LuaError::BorrowError(ref message) |
+            LuaError::MemoryError(ref message) |
+            LuaError::MemoryLimit(ref message) |
+            LuaError::FileError(ref message) |
+            LuaError::Timeout(ref message) |
+            LuaError::Interrupted(ref message) |
+            LuaError::StackOverflow(ref message) => Some(message),
+            LuaError::RuntimeError{ ref message, .. } => Some(message),
+            LuaError::CallbackError{ .. } => None,
+        }
+    }
+}
+
+impl Error for LuaError {
+    fn description(&self) -> &str {
+        match *self {
+            #[allow(deprecated)]
+            LuaError::CallbackError{ ref cause } => cause.description(),
+            _ => self.message().unwrap_or(""),
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            LuaError::CallbackError{ ref cause } => Some(&**cause),
+            _ => None,
+        }
+    }
+}
+
+impl Display for LuaError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            LuaError::CallbackError{ ref cause } => write!(f, "{}", cause),
+            _ => write!(f, "{}", self.message().unwrap_or("")),
+        }
+    }
+}
+
+/// A chunk which failed to compile; see `RumLua::check_syntax`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    /// The chunk name as Lua shows it, such as `config/ai.lua` for a chunk
+    /// named "@config/ai.lua".
+    pub chunk: String,
+    /// The line of the error, if Lua reported one.
+    pub line: Option<u32>,
+    /// The error without the chunk name and line.
+    pub message: String,
+}
+
+impl SyntaxError {
+    /// Split an error message in Lua's "chunk:line: message" form.
+    pub fn parse(msg: &str) -> SyntaxError {
+        let bytes = msg.as_bytes();
+        for (i, _) in msg.match_indices(':') {
+            let digits = bytes[i + 1..].iter().take_while(|b| b.is_ascii_digit()).count();
+            let end = i + 1 + digits;
+            if digits > 0 && msg[end..].starts_with(": ") {
+                if let Ok(line) = msg[i + 1..end].parse() {
+                    return SyntaxError {
+                        chunk: msg[..i].to_string(),
+                        line: Some(line),
+                        message: msg[end + 2..].to_string(),
+                    };
+                }
+            }
+        }
+        SyntaxError {
+            chunk: String::new(),
+            line: None,
+            message: msg.to_string(),
+        }
+    }
+}
+
+impl Error for SyntaxError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.chunk, line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl From<SyntaxError> for LuaError {
+    fn from(e: SyntaxError) -> LuaError {
+        LuaError::SyntaxError(format!("Syntax error loading chunk: {}", e))
+    }
+}
@@ -0,0 +1,166 @@
+/* Named events between Rust and Lua: rum.on, rum.emit and friends */
+use ::{RumLua, LuaError, LuaRet, LuaFunction, MultiValue, CallbackFn, FromLuaMulti, lfail};
+use thread::convert_values;
+use lua;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Identifies an event handler, for removing it with `RumLua::off`.
+/// Handlers added by `rum.on` are identified by an integer instead.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct HandlerId(u64);
+
+type RustHandler = Rc<RefCell<Box<dyn FnMut(&mut RumLua, MultiValue) -> Result<(), LuaError>>>>;
+
+#[derive(Clone)]
+enum Callback {
+    Lua(LuaFunction),
+    Rust(RustHandler),
+}
+
+struct Handler {
+    id: u64,
+    once: bool,
+    callback: Callback,
+}
+
+/* The handlers of a state, by event name, in the order they were added. */
+#[derive(Default)]
+pub struct Events {
+    handlers: HashMap<String, Vec<Handler>>,
+    next_id: u64,
+}
+
+impl Events {
+    fn add(&mut self, event: &str, once: bool, callback: Callback) -> HandlerId {
+        self.next_id += 1;
+        self.handlers.entry(event.to_string()).or_insert_with(Vec::new).push(Handler {
+            id: self.next_id,
+            once: once,
+            callback: callback,
+        });
+        HandlerId(self.next_id)
+    }
+
+    fn remove(&mut self, id: HandlerId) -> bool {
+        for handlers in self.handlers.values_mut() {
+            if let Some(pos) = handlers.iter().position(|h| h.id == id.0) {
+                handlers.remove(pos);
+                return true;
+            }
+        }
+        false
+    }
+
+    /* The callback of a handler about to be called, if it hasn't been
+     * removed.  Once-handlers are removed now, so that they can't be
+     * called again by emits from within. */
+    fn take(&mut self, event: &str, id: u64) -> Option<Callback> {
+        let handlers = self.handlers.get_mut(event)?;
+        let pos = handlers.iter().position(|h| h.id == id)?;
+        if handlers[pos].once {
+            Some(handlers.remove(pos).callback)
+        } else {
+            Some(handlers[pos].callback.clone())
+        }
+    }
+}
+
+pub fn on<A, F>(rl: &mut RumLua, event: &str, once: bool, mut f: F) -> HandlerId
+              where A: FromLuaMulti, F: FnMut(&mut RumLua, A) -> Result<(), LuaError> + 'static
+{
+    let handler: Box<dyn FnMut(&mut RumLua, MultiValue) -> Result<(), LuaError>> = Box::new(move |rl, values| {
+        let args = convert_values(rl, values)?;
+        f(rl, args)
+    });
+    rl.shared.events.add(event, once, Callback::Rust(Rc::new(RefCell::new(handler))))
+}
+
+pub fn off(rl: &mut RumLua, id: HandlerId) -> bool {
+    rl.shared.events.remove(id)
+}
+
+/* Call each handler of `event` with `values`, even if earlier ones fail,
+ * returning how many were called or the first error. */
+pub fn emit(rl: &mut RumLua, event: &str, values: MultiValue) -> Result<usize, LuaError> {
+    let ids: Vec<u64> = match rl.shared.events.handlers.get(event) {
+        Some(handlers) => handlers.iter().map(|h| h.id).collect(),
+        None => return Ok(0),
+    };
+    let mut called = 0;
+    let mut first_error = None;
+    for id in ids {
+        let callback = match rl.shared.events.take(event, id) {
+            Some(callback) => callback,
+            None => continue,
+        };
+        called += 1;
+        let result = match callback {
+            Callback::Lua(f) => f.call::<_, ()>(rl, values.clone()),
+            Callback::Rust(f) => match f.try_borrow_mut() {
+                Ok(mut f) => (&mut *f)(rl, values.clone()),
+                Err(_) => lfail(&format!("handler for event '{}' called recursively", event)),
+            },
+        };
+        if let Err(e) = result {
+            if first_error.is_none() {
+                first_error = Some(e);
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(called),
+    }
+}
+
+fn add_lua_handler(rl: &mut RumLua, once: bool) -> LuaRet {
+    let event: String = rl.check_arg(1)?;
+    let f: LuaFunction = rl.check_arg(2)?;
+    let id = rl.shared.events.add(&event, once, Callback::Lua(f));
+    rl.state.push_integer(id.0 as lua::Integer);
+    Ok(1)
+}
+
+/* rum.on(event, f): the handler's id */
+fn rum_on(rl: &mut RumLua) -> LuaRet {
+    add_lua_handler(rl, false)
+}
+
+/* rum.once(event, f): as rum.on, but removed when first called */
+fn rum_once(rl: &mut RumLua) -> LuaRet {
+    add_lua_handler(rl, true)
+}
+
+/* rum.off(id): whether the handler was still there */
+fn rum_off(rl: &mut RumLua) -> LuaRet {
+    let id: u64 = rl.check_arg(1)?;
+    let removed = off(rl, HandlerId(id));
+    rl.state.push_bool(removed);
+    Ok(1)
+}
+
+/* rum.emit(event, ...): how many handlers were called */
+fn rum_emit(rl: &mut RumLua) -> LuaRet {
+    let event: String = rl.check_arg(1)?;
+    let nargs = rl.state.get_top();
+    let values = MultiValue::from_lua_multi(rl, nargs - 1)?;
+    let called = emit(rl, &event, values)?;
+    rl.state.push_integer(called as lua::Integer);
+    Ok(1)
+}
+
+/* Add the event functions to the rum table on top of the stack. */
+pub fn open(rl: &mut RumLua) {
+    let fns: [(&str, fn(&mut RumLua) -> LuaRet); 4] = [
+        ("on", rum_on),
+        ("once", rum_once),
+        ("off", rum_off),
+        ("emit", rum_emit),
+    ];
+    for &(name, f) in fns.iter() {
+        rl.push_registered_closure(CallbackFn::Plain(f), &format!("rum.{}", name));
+        rl.state.set_field(-2, name);
+    }
+}
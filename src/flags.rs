@@ -0,0 +1,139 @@
+/* Rust bit flag types exposed to Lua as integers */
+use ::{RumLua, LuaError, LuaRet, CallbackFn, ToLua};
+use convert::type_name;
+use lua;
+use lua::Index;
+use std::any::{Any, TypeId};
+use std::rc::Rc;
+use std::cell::RefCell;
+
+/// A set of bit flags, such as a type declared with `bitflags!`, which
+/// can be registered with `register_flags`.  `lua_flags!` implements this
+/// along with `ToLua` and `FromLua`.
+pub trait LuaFlags: Any + Copy {
+    /// The flags as an integer.
+    fn to_bits(&self) -> i64;
+    /// The flags from an integer, or `None` if it has unknown bits set.
+    fn from_bits(bits: i64) -> Option<Self>;
+}
+
+/* A registered flags type and the names of its flags. */
+pub struct FlagsInfo {
+    name: String,
+    flags: Vec<(String, i64)>,
+}
+
+impl FlagsInfo {
+    /* The bits given at `index` as an integer, a flag's name or an array
+     * of either. */
+    fn parse(&self, rl: &mut RumLua, index: Index) -> Result<i64, String> {
+        match rl.state.type_of(index) {
+            Some(lua::Type::Table) => {
+                let index = rl.state.abs_index(index);
+                let mut bits = 0;
+                let mut i = 1;
+                while rl.state.raw_geti(index, i) != lua::Type::Nil {
+                    let flag = self.parse_flag(rl, -1);
+                    rl.state.pop(1);
+                    bits |= flag?;
+                    i += 1;
+                }
+                rl.state.pop(1);
+                Ok(bits)
+            },
+            _ => self.parse_flag(rl, index),
+        }
+    }
+
+    fn parse_flag(&self, rl: &mut RumLua, index: Index) -> Result<i64, String> {
+        match rl.state.type_of(index) {
+            Some(lua::Type::Number) if rl.state.is_integer(index) => Ok(rl.state.to_integer(index)),
+            Some(lua::Type::String) => {
+                let name = rl.state.to_str(index).unwrap_or("").to_string();
+                match self.flags.iter().find(|&&(ref n, _)| *n == name) {
+                    Some(&(_, bits)) => Ok(bits),
+                    None => Err(format!("{} has no flag '{}'", self.name, name)),
+                }
+            },
+            _ => Err(format!("{} expected, got {}", self.name, type_name(rl, index))),
+        }
+    }
+
+    /* The two arguments of has, set and clear */
+    fn args(&self, rl: &mut RumLua) -> Result<(i64, i64), LuaError> {
+        let value = match self.parse(rl, 1) {
+            Ok(bits) => bits,
+            Err(msg) => return Err(rl.arg_error(1, &msg)),
+        };
+        match self.parse(rl, 2) {
+            Ok(bits) => Ok((value, bits)),
+            Err(msg) => Err(rl.arg_error(2, &msg)),
+        }
+    }
+}
+
+/* Publish the global table `name` of the flags' values, with the helpers
+ * has, set and clear. */
+pub fn register<F: LuaFlags>(rl: &mut RumLua, name: &str, flags: &[(&str, F)]) -> Result<(), LuaError> {
+    if let Some(info) = rl.shared.flags.get(&TypeId::of::<F>()) {
+        return Err(LuaError::TypeError(format!("Flags are already registered as '{}'", info.name)));
+    }
+    let info = Rc::new(FlagsInfo {
+        name: name.to_string(),
+        flags: flags.iter().map(|&(flag, ref value)| (flag.to_string(), value.to_bits())).collect(),
+    });
+    let table = rl.create_table();
+    for &(ref flag, bits) in info.flags.iter() {
+        table.raw_set(rl, flag.as_str(), bits)?;
+    }
+    table.to_lua(rl)?;
+    let helpers: [(&str, fn(&mut RumLua, i64, i64)); 3] = [
+        ("has", |rl, value, bits| rl.state.push_bool(value & bits == bits)),
+        ("set", |rl, value, bits| rl.state.push_integer(value | bits)),
+        ("clear", |rl, value, bits| rl.state.push_integer(value & !bits)),
+    ];
+    for &(helper, op) in helpers.iter() {
+        let info = info.clone();
+        let f = move |rl: &mut RumLua| -> LuaRet {
+            let (value, bits) = info.args(rl)?;
+            op(rl, value, bits);
+            Ok(1)
+        };
+        rl.push_registered_closure(CallbackFn::Boxed(RefCell::new(Box::new(f))),
+                                   &format!("{}.{}", name, helper));
+        rl.state.set_field(-2, helper);
+    }
+    rl.state.pop(1);
+    table.set_readonly(rl, true)?;
+    table.to_lua(rl)?;
+    rl.state.set_global(name);
+
+    rl.shared.flags.insert(TypeId::of::<F>(), info);
+    Ok(())
+}
+
+fn unregistered() -> LuaError {
+    LuaError::TypeError("Attempt to convert unregistered flags".to_string())
+}
+
+/* Convert flags given as for the helpers. */
+pub fn get<F: LuaFlags>(rl: &mut RumLua, index: Index) -> Result<F, LuaError> {
+    let info = match rl.shared.flags.get(&TypeId::of::<F>()) {
+        Some(info) => info.clone(),
+        None => return Err(unregistered()),
+    };
+    let bits = info.parse(rl, index).map_err(LuaError::ConversionError)?;
+    match F::from_bits(bits) {
+        Some(flags) => Ok(flags),
+        None => Err(LuaError::ConversionError(format!("{} has no flags 0x{:x}", info.name, bits))),
+    }
+}
+
+/* Push flags as their integer. */
+pub fn push<F: LuaFlags>(rl: &mut RumLua, flags: &F) -> Result<(), LuaError> {
+    if !rl.shared.flags.contains_key(&TypeId::of::<F>()) {
+        return Err(unregistered());
+    }
+    rl.state.push_integer(flags.to_bits());
+    Ok(())
+}
@@ -0,0 +1,39 @@
+/* Handle type for Lua functions */
+use ::{RumLua, LuaError, ToLua, FromLua, ToLuaMulti, FromLuaMulti};
+use convert::conversion_error;
+use reference::LuaRef;
+use lua::Index;
+
+/// A handle to a Lua function (or Rust function exposed to Lua), which
+/// can be kept and called later from Rust.
+#[derive(Clone)]
+pub struct LuaFunction {
+    reference: LuaRef,
+}
+
+impl LuaFunction {
+    /// Call the function with `args`, converting its results to `R`.
+    pub fn call<A, R>(&self, rl: &mut RumLua, args: A) -> Result<R, LuaError>
+                  where A: ToLuaMulti, R: FromLuaMulti
+    {
+        let base = rl.state.get_top();
+        self.reference.push(rl)?;
+        rl.call_pushed(base, args)
+    }
+}
+
+impl ToLua for LuaFunction {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        self.reference.push(rl)
+    }
+}
+
+impl FromLua for LuaFunction {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<LuaFunction, LuaError> {
+        if rl.state.is_fn(index) {
+            Ok(LuaFunction{ reference: LuaRef::new(rl, index) })
+        } else {
+            conversion_error(rl, index, "function")
+        }
+    }
+}
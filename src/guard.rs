@@ -0,0 +1,51 @@
+/* Keeping the Lua stack balanced */
+use ::RumLua;
+use lua::Index;
+use std::ops::{Deref, DerefMut};
+
+/// Access to a `RumLua` which, when dropped, removes anything left on the
+/// stack above where it was when the guard was made.
+///
+/// ```ignore
+/// let mut rl = StackGuard::new(rl);
+/// rl.state.get_global("config");
+/// // The table is popped when `rl` goes out of scope.
+/// ```
+pub struct StackGuard<'r, 'a: 'r> {
+    rl: &'r mut RumLua<'a>,
+    top: Index,
+}
+
+impl<'r, 'a> StackGuard<'r, 'a> {
+    pub fn new(rl: &'r mut RumLua<'a>) -> StackGuard<'r, 'a> {
+        let top = rl.state.get_top();
+        StackGuard { rl: rl, top: top }
+    }
+
+    /// The top of the stack when the guard was made.
+    pub fn top(&self) -> Index {
+        self.top
+    }
+}
+
+impl<'r, 'a> Deref for StackGuard<'r, 'a> {
+    type Target = RumLua<'a>;
+    fn deref(&self) -> &RumLua<'a> {
+        self.rl
+    }
+}
+
+impl<'r, 'a> DerefMut for StackGuard<'r, 'a> {
+    fn deref_mut(&mut self) -> &mut RumLua<'a> {
+        self.rl
+    }
+}
+
+impl<'r, 'a> Drop for StackGuard<'r, 'a> {
+    fn drop(&mut self) {
+        /* Values below the top may have been popped on purpose. */
+        if self.rl.state.get_top() > self.top {
+            self.rl.state.set_top(self.top);
+        }
+    }
+}
@@ -0,0 +1,321 @@
+/* Lua hook enforcing limits on how long scripts may run, and calling
+ * the application's debug hook */
+use ::{RumLua, Shared, panic_message};
+use lua;
+use libc::{c_int, c_void};
+use std::ffi::CStr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/* How many instructions run between checks, at most. */
+const HOOK_INTERVAL: u64 = 1000;
+
+/* Registry key (by address) of the pointer to the HookState. */
+static HOOK_KEY: u8 = 0;
+
+/// A handle which can stop the Lua scripts running in a `RumLua`, from
+/// any thread; see `RumLua::interrupt_handle`.
+#[derive(Clone)]
+pub struct Interrupt {
+    flag: Arc<AtomicBool>,
+}
+
+impl Interrupt {
+    /// Ask for the running script to be stopped with
+    /// `LuaError::Interrupted`.  If no script is running, the next one
+    /// to run is stopped.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Which events are reported to the hook set with `RumLua::set_hook`.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct HookTriggers {
+    /// When a function is called.
+    pub on_call: bool,
+    /// When a function returns.
+    pub on_return: bool,
+    /// When Lua code starts a new line.
+    pub every_line: bool,
+    /// After every `n` instructions.
+    pub every_nth_instruction: Option<u32>,
+}
+
+/// The event being reported to a debug hook.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HookEvent {
+    Call,
+    /// A call which replaced the calling function, as with `return f()`.
+    TailCall,
+    Return,
+    Line,
+    Count,
+}
+
+/// Information about the running function, passed to a debug hook.
+#[derive(Clone, Debug)]
+pub struct DebugInfo {
+    pub event: HookEvent,
+    /// The name the function was called by, if Lua could tell.
+    pub name: Option<String>,
+    /// The chunk name as Lua shows it, such as `config/ai.lua`.
+    pub source: String,
+    /// "Lua", "C" or "main".
+    pub what: String,
+    /// The line being run, if known.
+    pub current_line: Option<u32>,
+    /// The line where the function was defined, if known.
+    pub line_defined: Option<u32>,
+}
+
+/// A debug hook; see `RumLua::set_hook`.
+pub type HookCallback = Box<dyn FnMut(&mut RumLua, DebugInfo)>;
+
+struct UserHook {
+    triggers: HookTriggers,
+    callback: HookCallback,
+    /* The Shared of the RumLua which set the hook */
+    shared: *mut c_void,
+}
+
+/* Why the hook stopped a script. */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stop {
+    Timeout,
+    Interrupted,
+}
+
+pub struct HookState {
+    pub instruction_limit: Option<u64>,
+    pub timeout: Option<Duration>,
+    interrupt: Option<Interrupt>,
+    /* Nesting of calls into Lua; the budget covers the outermost. */
+    pub run_depth: u32,
+    instructions: u64,
+    deadline: Option<Instant>,
+    /* Set when a script is stopped, so the error can be recognised. */
+    pub triggered: Option<Stop>,
+    user: Option<UserHook>,
+    /* Changed whenever the user hook is, so a hook which replaces itself
+     * isn't put back after it returns. */
+    user_generation: u64,
+}
+
+impl HookState {
+    pub fn new() -> HookState {
+        HookState {
+            instruction_limit: None,
+            timeout: None,
+            interrupt: None,
+            run_depth: 0,
+            instructions: 0,
+            deadline: None,
+            triggered: None,
+            user: None,
+            user_generation: 0,
+        }
+    }
+
+    /* Whether the limits need checking from the count hook. */
+    fn limited(&self) -> bool {
+        self.instruction_limit.is_some() || self.timeout.is_some() || self.interrupt.is_some()
+    }
+
+    /* The hook's instruction count, or None if no count hook is needed.
+     * A count asked for by the user hook is used for the limits too. */
+    fn interval(&self) -> Option<c_int> {
+        if let Some(n) = self.user.as_ref().and_then(|u| u.triggers.every_nth_instruction) {
+            return Some(n.max(1) as c_int);
+        }
+        if !self.limited() {
+            return None;
+        }
+        let limit = self.instruction_limit.unwrap_or(HOOK_INTERVAL);
+        Some(limit.min(HOOK_INTERVAL).max(1) as c_int)
+    }
+
+    fn mask(&self) -> c_int {
+        let mut mask = 0;
+        if self.interval().is_some() {
+            mask |= lua::ffi::LUA_MASKCOUNT;
+        }
+        if let Some(ref user) = self.user {
+            if user.triggers.on_call {
+                mask |= lua::ffi::LUA_MASKCALL;
+            }
+            if user.triggers.on_return {
+                mask |= lua::ffi::LUA_MASKRET;
+            }
+            if user.triggers.every_line {
+                mask |= lua::ffi::LUA_MASKLINE;
+            }
+        }
+        mask
+    }
+
+    /* Set or (with None) remove the user hook, which is called with a
+     * RumLua using `shared`. */
+    pub fn set_user(&mut self, hook: Option<(HookTriggers, HookCallback)>, shared: *mut c_void) {
+        self.user = hook.map(|(triggers, callback)| UserHook {
+            triggers: triggers,
+            callback: callback,
+            shared: shared,
+        });
+        self.user_generation += 1;
+    }
+
+    /* Start the budget for a new outermost call into Lua. */
+    pub fn enter(&mut self) {
+        if self.run_depth == 0 {
+            self.instructions = 0;
+            self.deadline = self.timeout.map(|t| Instant::now() + t);
+            self.triggered = None;
+        }
+        self.run_depth += 1;
+    }
+
+    pub fn leave(&mut self) {
+        self.run_depth -= 1;
+    }
+
+    /* The interrupt handle, created on first use. */
+    pub fn interrupt_handle(&mut self) -> Interrupt {
+        if self.interrupt.is_none() {
+            self.interrupt = Some(Interrupt{ flag: Arc::new(AtomicBool::new(false)) });
+        }
+        self.interrupt.as_ref().unwrap().clone()
+    }
+
+    /* Called from the hook; returns why and an error message if the
+     * script should be stopped. */
+    fn check(&mut self, interval: u64) -> Option<(Stop, &'static str)> {
+        if let Some(ref interrupt) = self.interrupt {
+            if interrupt.flag.swap(false, Ordering::SeqCst) {
+                return Some((Stop::Interrupted, "script interrupted"));
+            }
+        }
+        self.instructions += interval;
+        if let Some(limit) = self.instruction_limit {
+            if self.instructions > limit {
+                return Some((Stop::Timeout, "script exceeded its instruction limit"));
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Some((Stop::Timeout, "script exceeded its time limit"));
+            }
+        }
+        None
+    }
+}
+
+/* Make `hooks` reachable from the hook function. */
+pub fn register(state: &mut lua::State, hooks: &mut HookState) {
+    unsafe {
+        let l = state.as_ptr();
+        lua::ffi::lua_pushlightuserdata(l, hooks as *mut HookState as *mut c_void);
+        lua::ffi::lua_rawsetp(l, lua::REGISTRYINDEX, key());
+    }
+}
+
+/* Set or clear the hook on the thread `l`, according to `hooks`. */
+pub fn apply(l: *mut lua::ffi::lua_State, hooks: &HookState) {
+    unsafe {
+        match hooks.mask() {
+            0 => lua::ffi::lua_sethook(l, None, 0, 0),
+            mask => lua::ffi::lua_sethook(l, Some(hook), mask, hooks.interval().unwrap_or(0)),
+        }
+    }
+}
+
+fn key() -> *const c_void {
+    &HOOK_KEY as *const u8 as *const c_void
+}
+
+unsafe extern "C" fn hook(l: *mut lua::ffi::lua_State, ar: *mut lua::ffi::lua_Debug) {
+    lua::ffi::lua_rawgetp(l, lua::REGISTRYINDEX, key());
+    let hooks = &mut *(lua::ffi::lua_touserdata(l, -1) as *mut HookState);
+    lua::ffi::lua_settop(l, -2);
+    let event = match (*ar).event {
+        lua::ffi::LUA_HOOKCALL => HookEvent::Call,
+        lua::ffi::LUA_HOOKTAILCALL => HookEvent::TailCall,
+        lua::ffi::LUA_HOOKRET => HookEvent::Return,
+        lua::ffi::LUA_HOOKLINE => HookEvent::Line,
+        _ => HookEvent::Count,
+    };
+    if event == HookEvent::Count && hooks.limited() {
+        let interval = hooks.interval().unwrap_or(1) as u64;
+        if let Some((stop, msg)) = hooks.check(interval) {
+            hooks.triggered = Some(stop);
+            raise(l, msg.to_string());
+        }
+    }
+    let wanted = match hooks.user {
+        Some(ref user) => event != HookEvent::Count || user.triggers.every_nth_instruction.is_some(),
+        None => false,
+    };
+    if wanted {
+        if let Some(msg) = call_user(l, ar, hooks, event) {
+            raise(l, msg);
+        }
+    }
+}
+
+/* Run the user hook, returning an error to raise if it panicked. */
+unsafe fn call_user(l: *mut lua::ffi::lua_State, ar: *mut lua::ffi::lua_Debug,
+                    hooks: &mut HookState, event: HookEvent) -> Option<String> {
+    lua::ffi::lua_getinfo(l, b"nSl\0".as_ptr() as *const _, ar);
+    let ar = &*ar;
+    let line = |n: c_int| if n > 0 { Some(n as u32) } else { None };
+    let info = DebugInfo {
+        event: event,
+        name: c_string(ar.name),
+        source: CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy().into_owned(),
+        what: c_string(ar.what).unwrap_or_else(String::new),
+        current_line: line(ar.currentline),
+        line_defined: line(ar.linedefined),
+    };
+    /* Take the hook out while it runs, as it may replace itself. */
+    let generation = hooks.user_generation;
+    let mut user = match hooks.user.take() {
+        Some(user) => user,
+        None => return None,
+    };
+    /* As for callbacks, the hook may be running in a coroutine. */
+    let mut rl = RumLua::borrowed(l, user.shared as *mut Shared);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        (user.callback)(&mut rl, info)
+    }));
+    if rl.shared.hooks.user_generation == generation {
+        rl.shared.hooks.user = Some(user);
+    }
+    match result {
+        Ok(()) => None,
+        Err(payload) => {
+            let msg = format!("Rust hook panicked: {}", panic_message(&payload));
+            if rl.shared.resume_panics {
+                rl.shared.pending_panic = Some(payload);
+            }
+            Some(msg)
+        },
+    }
+}
+
+unsafe fn c_string(p: *const ::libc::c_char) -> Option<String> {
+    if p.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(p).to_string_lossy().into_owned())
+    }
+}
+
+/* Raise `msg` as a Lua error.  This doesn't return, so nothing in the
+ * caller may need dropping. */
+unsafe fn raise(l: *mut lua::ffi::lua_State, msg: String) {
+    lua::ffi::lua_pushlstring(l, msg.as_ptr() as *const _, msg.len());
+    drop(msg);
+    lua::ffi::lua_error(l);
+}
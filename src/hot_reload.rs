@@ -0,0 +1,138 @@
+/* Reloading script files while keeping chosen state */
+use ::{RumLua, LuaError, LuaTable, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Called after a script is reloaded, with its chunk name and its old and
+/// new environments, to carry state over.
+pub type MigrationHook = Box<dyn FnMut(&mut RumLua, &str, &LuaTable, &LuaTable) -> Result<(), LuaError>>;
+
+struct Script {
+    name: String,
+    path: PathBuf,
+    /* When the file was last modified, and its length */
+    stamp: Option<(SystemTime, u64)>,
+    env: LuaTable,
+}
+
+/// Runs script files, each in its own environment whose unknown names
+/// fall back to the globals, and reruns them in fresh environments when
+/// they change.  The globals named by `preserve` are copied from the old
+/// environment into the new one before the script reruns, so its top-level
+/// code sees them and can keep them with `count = count or 0`, or replace
+/// them.  Then the migration hooks run, before the new environment
+/// replaces the old one.  If reloading fails, the old one is kept.
+pub struct HotReload {
+    scripts: Vec<Script>,
+    preserved: Vec<String>,
+    hooks: Vec<MigrationHook>,
+}
+
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/* Run the file at `path` in a new environment, holding `seed`. */
+fn run(rl: &mut RumLua, name: &str, path: &Path, seed: Vec<(&str, Value)>) -> Result<LuaTable, LuaError> {
+    let source = fs::read(path).map_err(|e| LuaError::FileError(format!("{}: {}", name, e)))?;
+    let env = rl.create_env();
+    for (global, value) in seed {
+        env.raw_set(rl, global, value)?;
+    }
+    let f = rl.load_with_env(&source, &format!("@{}", name), &env)?;
+    f.call::<_, ()>(rl, ())?;
+    Ok(env)
+}
+
+impl HotReload {
+    pub fn new() -> HotReload {
+        HotReload {
+            scripts: Vec::new(),
+            preserved: Vec::new(),
+            hooks: Vec::new(),
+        }
+    }
+
+    /// Run each of `paths` and watch it for changes.  Its chunk name is
+    /// the path as given.
+    pub fn watch<P: AsRef<Path>>(&mut self, rl: &mut RumLua, paths: &[P]) -> Result<(), LuaError> {
+        for path in paths {
+            let path = path.as_ref().to_path_buf();
+            let name = path.display().to_string();
+            let stamp = stamp(&path);
+            let env = run(rl, &name, &path, Vec::new())?;
+            self.scripts.retain(|script| script.name != name);
+            self.scripts.push(Script{ name: name, path: path, stamp: stamp, env: env });
+        }
+        Ok(())
+    }
+
+    /// Carry the global `name` over to a reloaded script's new
+    /// environment, if it was set in the old one.  The script can still
+    /// assign to it as it reruns.
+    pub fn preserve(&mut self, name: &str) {
+        self.preserved.push(name.to_string());
+    }
+
+    /// Add a hook to run after each reload.
+    pub fn on_reload<F>(&mut self, hook: F)
+                  where F: FnMut(&mut RumLua, &str, &LuaTable, &LuaTable) -> Result<(), LuaError> + 'static
+    {
+        self.hooks.push(Box::new(hook));
+    }
+
+    /// Rerun the watched script `chunk_name` now, whether or not it has
+    /// changed.
+    pub fn reload(&mut self, rl: &mut RumLua, chunk_name: &str) -> Result<(), LuaError> {
+        let pos = match self.scripts.iter().position(|script| script.name == chunk_name) {
+            Some(pos) => pos,
+            None => return Err(LuaError::FileError(format!("{} is not being watched", chunk_name))),
+        };
+        let path = self.scripts[pos].path.clone();
+        self.scripts[pos].stamp = stamp(&path);
+        let old = self.scripts[pos].env.clone();
+        let mut seed = Vec::new();
+        for name in self.preserved.iter() {
+            let value: Value = old.raw_get(rl, name.as_str())?;
+            if !value.is_nil() {
+                seed.push((name.as_str(), value));
+            }
+        }
+        let new = run(rl, chunk_name, &path, seed)?;
+        for hook in self.hooks.iter_mut() {
+            hook(rl, chunk_name, &old, &new)?;
+        }
+        self.scripts[pos].env = new;
+        Ok(())
+    }
+
+    /// Reload the watched scripts whose files have changed since they
+    /// were last run, returning their chunk names.  If any fail, the
+    /// others are still reloaded, and the first error is returned.
+    pub fn poll(&mut self, rl: &mut RumLua) -> Result<Vec<String>, LuaError> {
+        let changed: Vec<String> = self.scripts.iter()
+            .filter(|script| stamp(&script.path) != script.stamp)
+            .map(|script| script.name.clone())
+            .collect();
+        let mut first_error = None;
+        for name in changed.iter() {
+            if let Err(e) = self.reload(rl, name) {
+                if first_error.is_none() {
+                    first_error = Some(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(changed),
+        }
+    }
+
+    /// The current environment of the watched script `chunk_name`, holding
+    /// the globals it defined.
+    pub fn env(&self, chunk_name: &str) -> Option<LuaTable> {
+        self.scripts.iter().find(|script| script.name == chunk_name).map(|script| script.env.clone())
+    }
+}
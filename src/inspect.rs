@@ -0,0 +1,135 @@
+/* Reading and changing the local variables and upvalues of running
+ * functions, as with the debug library */
+use ::{RumLua, LuaError, LuaFunction, Value, FromLua, ToLua, lerror};
+use lua;
+use libc::c_char;
+use std::ffi::CStr;
+use std::mem;
+
+/* Find the function running at stack level `level`. */
+fn stack_level(rl: &mut RumLua, level: i32) -> Result<lua::ffi::lua_Debug, LuaError> {
+    unsafe {
+        let mut ar: lua::ffi::lua_Debug = mem::zeroed();
+        if level < 0 || lua::ffi::lua_getstack(rl.state.as_ptr(), level, &mut ar) == 0 {
+            return Err(lerror(&format!("no function at stack level {}", level)));
+        }
+        Ok(ar)
+    }
+}
+
+unsafe fn name_string(name: *const c_char) -> String {
+    CStr::from_ptr(name).to_string_lossy().into_owned()
+}
+
+/* Call `f` with the index and name of each local at `ar`, with its value
+ * on top of the stack, until it returns false. */
+fn each_local<F>(rl: &mut RumLua, ar: &lua::ffi::lua_Debug, mut f: F) -> Result<(), LuaError>
+           where F: FnMut(&mut RumLua, i32, String) -> Result<bool, LuaError>
+{
+    let mut n = 1;
+    loop {
+        let name = unsafe { lua::ffi::lua_getlocal(rl.state.as_ptr(), ar, n) };
+        if name.is_null() {
+            return Ok(());
+        }
+        let name = unsafe { name_string(name) };
+        let result = f(rl, n, name);
+        rl.state.pop(1);
+        if !result? {
+            return Ok(());
+        }
+        n += 1;
+    }
+}
+
+pub fn locals(rl: &mut RumLua, level: i32) -> Result<Vec<(String, Value)>, LuaError> {
+    let ar = stack_level(rl, level)?;
+    let mut locals = Vec::new();
+    each_local(rl, &ar, |rl, _, name| {
+        /* Names like "(for index)" are Lua's own temporaries. */
+        if !name.starts_with('(') {
+            locals.push((name, Value::from_lua(rl, -1)?));
+        }
+        Ok(true)
+    })?;
+    Ok(locals)
+}
+
+pub fn set_local<V: ToLua>(rl: &mut RumLua, level: i32, name: &str, value: V) -> Result<(), LuaError> {
+    let ar = stack_level(rl, level)?;
+    /* The last local of that name is the one in scope. */
+    let mut found = None;
+    each_local(rl, &ar, |_, n, local| {
+        if local == name {
+            found = Some(n);
+        }
+        Ok(true)
+    })?;
+    let n = match found {
+        Some(n) => n,
+        None => return Err(lerror(&format!("no local variable '{}' at stack level {}", name, level))),
+    };
+    value.to_lua(rl)?;
+    unsafe { lua::ffi::lua_setlocal(rl.state.as_ptr(), &ar, n) };
+    Ok(())
+}
+
+pub fn stack_function(rl: &mut RumLua, level: i32) -> Result<LuaFunction, LuaError> {
+    let mut ar = stack_level(rl, level)?;
+    unsafe { lua::ffi::lua_getinfo(rl.state.as_ptr(), b"f\0".as_ptr() as *const _, &mut ar) };
+    let f = LuaFunction::from_lua(rl, -1);
+    rl.state.pop(1);
+    f
+}
+
+/* As each_local, for the upvalues of the function on top of the stack. */
+fn each_upvalue<F>(rl: &mut RumLua, mut f: F) -> Result<(), LuaError>
+           where F: FnMut(&mut RumLua, i32, String) -> Result<bool, LuaError>
+{
+    let mut n = 1;
+    loop {
+        let name = unsafe { lua::ffi::lua_getupvalue(rl.state.as_ptr(), -1, n) };
+        if name.is_null() {
+            return Ok(());
+        }
+        let name = unsafe { name_string(name) };
+        let result = f(rl, n, name);
+        rl.state.pop(1);
+        if !result? {
+            return Ok(());
+        }
+        n += 1;
+    }
+}
+
+pub fn upvalues(rl: &mut RumLua, f: &LuaFunction) -> Result<Vec<(String, Value)>, LuaError> {
+    let base = rl.state.get_top();
+    f.to_lua(rl)?;
+    let mut upvalues = Vec::new();
+    let result = each_upvalue(rl, |rl, _, name| {
+        upvalues.push((name, Value::from_lua(rl, -1)?));
+        Ok(true)
+    });
+    rl.state.set_top(base);
+    result.map(|()| upvalues)
+}
+
+pub fn set_upvalue<V: ToLua>(rl: &mut RumLua, f: &LuaFunction, name: &str, value: V) -> Result<(), LuaError> {
+    let base = rl.state.get_top();
+    f.to_lua(rl)?;
+    let mut found = None;
+    let searched = each_upvalue(rl, |_, n, upvalue| {
+        if upvalue == name {
+            found = Some(n);
+        }
+        Ok(found.is_none())
+    });
+    let result = searched.and_then(|()| match found {
+        Some(n) => value.to_lua(rl).map(|()| {
+            unsafe { lua::ffi::lua_setupvalue(rl.state.as_ptr(), -2, n) };
+        }),
+        None => Err(lerror(&format!("no upvalue '{}'", name))),
+    });
+    rl.state.set_top(base);
+    result
+}
@@ -0,0 +1,32 @@
+/* JSON encoding and decoding, from Rust and as rum.json */
+use ::{RumLua, LuaError, LuaRet, CallbackFn, ToLua};
+use serde_json;
+
+fn json_error(e: serde_json::Error) -> LuaError {
+    LuaError::ConversionError(format!("JSON error: {}", e))
+}
+
+fn json_encode(rl: &mut RumLua) -> LuaRet {
+    rl.check_arg_count(1, Some(1))?;
+    let value = rl.to_json(1)?;
+    let s = serde_json::to_string(&value).map_err(json_error)?;
+    s.to_lua(rl)?;
+    Ok(1)
+}
+
+fn json_decode(rl: &mut RumLua) -> LuaRet {
+    let s: String = rl.check_arg(1)?;
+    let value: serde_json::Value = serde_json::from_str(&s).map_err(json_error)?;
+    rl.push_json(&value)?;
+    Ok(1)
+}
+
+/* Add the json table to the rum table on top of the stack. */
+pub fn open(rl: &mut RumLua) {
+    rl.state.new_table();
+    rl.push_registered_closure(CallbackFn::Plain(json_encode), "json.encode");
+    rl.state.set_field(-2, "encode");
+    rl.push_registered_closure(CallbackFn::Plain(json_decode), "json.decode");
+    rl.state.set_field(-2, "decode");
+    rl.state.set_field(-2, "json");
+}
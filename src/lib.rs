@@ -1,24 +1,139 @@
-#![deny(warnings)]
-#![feature(plugin)]
-//#![plugin(clippy)]
 //#![warn(missing_docs)]
 
 #[macro_use]
 extern crate lua;
 extern crate libc;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "json")]
+#[macro_use]
+extern crate serde_json;
+#[cfg(feature = "log")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "decimal")]
+extern crate rust_decimal;
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+
+#[cfg(any(feature = "lua51", feature = "lua52", feature = "lua54"))]
+compile_error!("Only Lua 5.3 is supported; build with the default `lua53` feature.");
+#[cfg(not(feature = "lua53"))]
+compile_error!("No Lua version was selected; enable the `lua53` feature.");
 
 pub use self::libc::{c_int,c_void};
 use lua::{ThreadStatus, Index};
-use std::rc::Rc;
-use std::cell::{RefCell};
+use std::rc::{Rc, Weak};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::SyncSender;
+use std::cell::{Cell, RefCell};
 use std::cell;
 use std::ptr;
+use std::mem;
+use std::ops::{Deref, DerefMut};
 use std::marker::PhantomData;
 use std::clone::Clone;
 use std::collections::hash_map::HashMap;
+use std::collections::HashSet;
 use std::any::{Any, TypeId};
+use std::time::{Duration, Instant};
+#[cfg(feature = "async")]
 use std::error::Error;
-use std::fmt::{Display,Formatter};
+use std::io::{Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+#[macro_use]
+mod macros;
+mod error;
+pub use error::{LuaError, SyntaxError, Frame};
+mod builder;
+pub use builder::{RumLuaBuilder, StdLib};
+mod memory;
+pub use memory::MemoryStats;
+mod hook;
+pub use hook::{Interrupt, HookTriggers, HookEvent, DebugInfo, HookCallback};
+mod convert;
+pub use convert::{ToLua, FromLua, ToLuaMulti, FromLuaMulti, CallbackResult, MethodArg, Variadic};
+mod reference;
+pub use reference::LuaRef;
+mod string;
+pub use string::LuaString;
+mod guard;
+pub use guard::StackGuard;
+mod loader;
+pub use loader::ScriptSource;
+mod output;
+mod sourcemap;
+pub use sourcemap::SourceMap;
+mod table;
+pub use table::{LuaTable, Pairs, SequenceValues};
+mod function;
+pub use function::LuaFunction;
+mod scope;
+pub use scope::Scope;
+use scope::ScopedRef;
+mod types;
+pub use types::{TypeBuilder, MetaMethod, Inherits};
+mod thread;
+pub use thread::{LuaThread, Resume, Yields};
+mod value;
+pub use value::{Value, MultiValue};
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(feature = "serde")]
+pub use serialize::{to_value, from_value};
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+mod inspect;
+mod class;
+mod enums;
+mod flags;
+pub use flags::LuaFlags;
+mod time;
+mod bigint;
+pub use bigint::BigInt;
+#[cfg(feature = "decimal")]
+mod decimal;
+#[cfg(feature = "async")]
+mod tasks;
+mod scheduler;
+pub use scheduler::{Scheduler, TaskId};
+mod events;
+pub use events::HandlerId;
+mod channel;
+mod pool;
+pub use pool::StatePool;
+mod sync;
+pub use sync::RumLuaSync;
+mod snapshot;
+mod hot_reload;
+pub use hot_reload::{HotReload, MigrationHook};
+mod replay;
+mod repl;
+pub use repl::Repl;
+pub use time::{DurationFormat, SleepHandler};
+mod metrics;
+pub use metrics::CallbackStats;
+mod coverage;
+pub use coverage::Coverage;
+mod debugger;
+pub use debugger::{Debugger, StepMode, PauseReason, Pause, PauseCallback};
+#[cfg(feature = "dap")]
+mod dap;
+#[cfg(feature = "dap")]
+pub use dap::DapServer;
+#[cfg(feature = "profiler")]
+mod profiler;
+#[cfg(feature = "profiler")]
+pub use profiler::{Profiler, FunctionStats, LineStats};
 
 /* Smart wrapper for types shared with Lua */
 pub struct LuaPtr<T> {
@@ -40,61 +155,237 @@ impl<T> LuaPtr<T> {
     pub fn borrow_mut<'a>(&'a mut self) -> cell::RefMut<'a, T> where T:'a {
         (*self.obj).borrow_mut()
     }
-    pub fn borrow(&self) -> cell::Ref<T> {
+    pub fn borrow(&self) -> cell::Ref<'_, T> {
         (*self.obj).borrow()
     }
+    /// Create a weak pointer, which doesn't keep the value alive.
+    pub fn downgrade(&self) -> LuaWeak<T> {
+        LuaWeak{ obj: Rc::downgrade(&self.obj) }
+    }
+    /// Return the value if this is the only pointer to it, or else this
+    /// pointer back.
+    pub fn try_unwrap(self) -> Result<T, LuaPtr<T>> {
+        match Rc::try_unwrap(self.obj) {
+            Ok(cell) => Ok(cell.into_inner()),
+            Err(obj) => Err(LuaPtr{ obj: obj }),
+        }
+    }
+    /// As `borrow`, but returns a `LuaError::BorrowError` instead of
+    /// panicking if the value is mutably borrowed.
+    pub fn try_borrow(&self) -> Result<cell::Ref<'_, T>, LuaError> {
+        self.obj.try_borrow().map_err(|_| {
+            LuaError::BorrowError("Userdata is already mutably borrowed".to_string())
+        })
+    }
+    /// As `borrow_mut`, but returns a `LuaError::BorrowError` instead of
+    /// panicking if the value is already borrowed.
+    pub fn try_borrow_mut(&self) -> Result<cell::RefMut<'_, T>, LuaError> {
+        self.obj.try_borrow_mut().map_err(|_| {
+            LuaError::BorrowError("Userdata is already borrowed".to_string())
+        })
+    }
+}
+
+/// A weak version of `LuaPtr`.  It can also be pushed to Lua, as a
+/// userdata whose methods fail once the value has been dropped.
+pub struct LuaWeak<T> {
+    obj: Weak<RefCell<T>>,
+}
+
+impl<T> Clone for LuaWeak<T> {
+    fn clone(&self) -> Self {
+        LuaWeak{obj: self.obj.clone()}
+    }
+}
+
+impl<T> LuaWeak<T> {
+    /// Get a `LuaPtr` to the value, if it's still alive.
+    pub fn upgrade(&self) -> Option<LuaPtr<T>> {
+        self.obj.upgrade().map(|obj| LuaPtr{ obj: obj })
+    }
+}
+
+/// Thread-safe wrapper for types shared with Lua, which can also be
+/// used from other threads.  It's pushed and converted like `LuaPtr`,
+/// using the same registered type.
+pub struct ArcPtr<T> {
+    obj: Arc<Mutex<T>>,
+}
+
+impl<T> Clone for ArcPtr<T> {
+    fn clone(&self) -> Self {
+        ArcPtr{obj: self.obj.clone()}
+    }
+}
+
+impl<T> ArcPtr<T> {
+    pub fn new(obj: T) -> ArcPtr<T> {
+        ArcPtr{
+            obj: Arc::new(Mutex::new(obj)),
+        }
+    }
+    /// Lock the value, waiting for any other thread holding it.  As with
+    /// `Mutex`, locking it again from the same thread deadlocks.
+    pub fn lock(&self) -> Result<MutexGuard<'_, T>, LuaError> {
+        self.obj.lock().map_err(|_| {
+            LuaError::BorrowError("Userdata's lock was poisoned by a panic".to_string())
+        })
+    }
 }
 
-/// Lua function which helps translate from Rust's Result<>
-/// to Lua-style error.
+/// Lua chunk returning a function which wraps a Rust function, to help
+/// translate from Rust's Result<> to Lua-style error.  The base functions
+/// it needs are passed in, as the base library may not be open.
 const LUA_FUNC_SHIM: &'static str = r#"
-    local rust_f, fname = ...
-    local function check(ok, ...)
-        if ok then
-            return ...
-        else
-            local msg = ...
-            error("Calling "..tostring(fname)..":\n"..msg, 2)
+    local error, tostring = ...
+    return function(rust_f, fname)
+        local function check(ok, ...)
+            if ok then
+                return ...
+            else
+                local msg = ...
+                error("Calling "..tostring(fname)..":\n"..msg, 2)
+            end
+        end
+        return function(...)
+            return check(rust_f(...))
         end
     end
-    function f(...)
-        return check(rust_f(...))
-    end
-    return f
 "#;
 
 /* Lua interface */
 pub struct RumLua<'a> {
     pub state: lua::State,
+    /* Declared after the state, as closing the state runs callbacks. */
+    shared: SharedRef,
+    yield_requested: bool,
+    /* Whether a callback is running, so its name can be found */
+    in_callback: bool,
+    marker: PhantomData<&'a ()>,
+}
+
+/* Everything besides the state which callbacks and hooks need, at a
+ * fixed address so that the RumLua can be moved. */
+struct Shared {
+    /* Only used by the allocator, so must outlive the state. */
+    memory: Box<memory::MemoryState>,
+    /* Reached by the count hook through the registry. */
+    hooks: Box<hook::HookState>,
     types_str_to_id: HashMap<String, TypeId>,
     types_id_to_str: HashMap<TypeId, String>,
+    /* How many userdata of each registered type have been pushed and not
+     * collected; shared with the type's __gc. */
+    live_userdata: HashMap<TypeId, Rc<Cell<usize>>>,
+    /* For each type, the derived types and how to upcast them. */
+    upcasts: HashMap<TypeId, Vec<(String, types::Upcast)>>,
     lua_func_shim: lua::Reference,
-    marker: PhantomData<&'a ()>,
+    unref_queue: Rc<RefCell<Vec<c_int>>>,
+    callback_errors: Vec<(String, LuaError)>,
+    resume_panics: bool,
+    pending_panic: Option<Box<dyn Any + Send>>,
+    /* By the chunk name Lua shows in messages */
+    source_maps: HashMap<String, Box<dyn SourceMap>>,
+    /* Only kept while enabled, as finding callback names isn't free */
+    metrics: Option<metrics::Metrics>,
+    /* Callbacks which last as long as the state, called by index so
+     * their closures need no userdata of their own. */
+    registered: Vec<Rc<CallbackFn>>,
+    /* How many callbacks are running, nested through calls into Lua */
+    callback_depth: u32,
+    recursion_limit: u32,
+    enums: HashMap<TypeId, enums::EnumInfo>,
+    flags: HashMap<TypeId, Rc<flags::FlagsInfo>>,
+    duration_format: DurationFormat,
+    /* When rum.time.monotonic() was 0 */
+    clock_start: Instant,
+    sleep_handler: Option<SleepHandler>,
+    events: events::Events,
+    /* Makes the recv methods of channels, once one is created */
+    channel_shim: Option<lua::Reference>,
+    /* The globals of a new state, which snapshots leave out */
+    base_globals: HashSet<String>,
+    snapshot_codecs: Vec<Rc<snapshot::Codec>>,
+    call_log: Option<replay::CallLog>,
+    /* Application values for callbacks, one of each type */
+    app_data: HashMap<TypeId, RefCell<Box<dyn Any>>>,
+    #[cfg(feature = "async")]
+    tasks: tasks::Tasks,
 }
 
-pub type LuaError = Box<Error>;
+/* The Shared of a state, owned by the RumLua which created the state
+ * and borrowed by those made to run callbacks and hooks. */
+struct SharedRef {
+    ptr: *mut Shared,
+    owned: bool,
+}
 
-#[derive(Debug)]
-pub struct LError {
-    message: String,
+impl Drop for SharedRef {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe { drop(Box::from_raw(self.ptr)) };
+        }
+    }
 }
 
-impl Error for LError {
-    fn description(&self) -> &str {
-        &self.message
+impl Deref for SharedRef {
+    type Target = Shared;
+    fn deref(&self) -> &Shared {
+        unsafe { &*self.ptr }
     }
-    fn cause(&self) -> Option<&Error> { None }
 }
 
-impl Display for LError {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
-        write!(f, "Error: {}", self.message).unwrap();
-        Ok(())
+impl DerefMut for SharedRef {
+    fn deref_mut(&mut self) -> &mut Shared {
+        unsafe { &mut *self.ptr }
     }
 }
 
 pub type LuaRet = Result<isize, LuaError>;
 pub type Callback = fn(&mut RumLua) -> LuaRet;
+/// A Rust closure which can be called from Lua.
+pub type BoxedCallback = Box<dyn FnMut(&mut RumLua) -> LuaRet>;
+/// A function written against the Lua C API, as a `lua_CFunction`.
+pub type CFunction = unsafe extern "C" fn(*mut lua::ffi::lua_State) -> c_int;
+
+/// An entry for `register_mixed_func_table`.
+pub enum TableFunction {
+    Rust(Callback),
+    C(CFunction),
+}
+
+/* The Rust side of a function exposed to Lua. */
+enum CallbackFn {
+    Plain(Callback),
+    /* Closures can't be re-entered, so are borrowed during each call. */
+    Boxed(RefCell<BoxedCallback>),
+}
+
+impl CallbackFn {
+    fn call(&self, rl: &mut RumLua) -> LuaRet {
+        match *self {
+            CallbackFn::Plain(f) => f(rl),
+            CallbackFn::Boxed(ref f) => match f.try_borrow_mut() {
+                Ok(mut f) => (&mut **f)(rl),
+                Err(_) => lfail("Rust closure called recursively"),
+            },
+        }
+    }
+}
+
+/* How many callback errors to remember while they propagate through Lua. */
+const MAX_CALLBACK_ERRORS: usize = 16;
+
+/* How deeply callbacks may nest by default; each level uses some of the
+ * native stack as well as Lua's. */
+const DEFAULT_RECURSION_LIMIT: u32 = 100;
+
+/* Registry name of the metatable for CallbackFn userdata. */
+const CALLBACK_MT: &'static str = "rum.callback";
+
+/* Registry names of the table of values kept by `store_named`, and of the
+ * metatable for their userdata. */
+const NAMED_VALUES: &'static str = "rum.named";
+const NAMED_MT: &'static str = "rum.named_value";
 
 // Return a LuaRet with an error string.
 pub fn lfail<T>(message: &str) -> Result<T, LuaError> {
@@ -102,7 +393,7 @@ pub fn lfail<T>(message: &str) -> Result<T, LuaError> {
 }
 // Return a LuaError (not wrapped in Result<>)
 pub fn lerror(message: &str) -> LuaError {
-    Box::new(LError{message: message.to_string()})
+    LuaError::RuntimeError{ message: message.to_string(), traceback: None }
 }
 
 pub struct LuaType {
@@ -112,34 +403,552 @@ pub struct LuaType {
 impl<'a> RumLua<'a> {
 //    #[allow(new_without_default)]
     pub fn new() -> RumLua<'a> {
+        RumLuaBuilder::new().build()
+    }
+
+    fn new_with_libs(libs: StdLib) -> RumLua<'a> {
         let mut state = lua::State::new();
-        state.open_libs();
+        let memory = memory::install(&mut state);
+        let mut hooks = Box::new(hook::HookState::new());
+        hook::register(&mut state, &mut hooks);
+
+        state.new_metatable(CALLBACK_MT);
+        state.push_closure(lua_func!(::RumLua::callback_gc), 0);
+        state.set_field(-2, "__gc");
+        state.pop(1);
 
+        state.new_metatable(NAMED_MT);
+        state.push_closure(lua_func!(::RumLua::named_gc), 0);
+        state.set_field(-2, "__gc");
+        state.pop(1);
+
+        /* The shim needs some base functions even if Lua code can't
+         * have them. */
+        state.requiref("_G", Some(lua::ffi::luaopen_base), true);
+        state.pop(1);
         state.load_string(LUA_FUNC_SHIM);
+        state.get_global("error");
+        state.get_global("tostring");
+        state.call(2, 1);
         let lua_func_shim = state.reference(lua::REGISTRYINDEX);
-        let mut result = RumLua{
-            state: state,
+        #[cfg(feature = "async")]
+        let async_shim = {
+            state.load_string(tasks::ASYNC_SHIM);
+            state.get_global("error");
+            state.call(1, 1);
+            state.reference(lua::REGISTRYINDEX)
+        };
+        if !libs.contains(StdLib::BASE) {
+            /* Start again with empty globals */
+            state.new_table();
+            state.raw_seti(lua::REGISTRYINDEX, lua::ffi::LUA_RIDX_GLOBALS);
+            state.get_field(lua::REGISTRYINDEX, "_LOADED");
+            state.push_nil();
+            state.set_field(-2, "_G");
+            state.pop(1);
+        }
+        for &(lib, name, open) in builder::LIBS {
+            if libs.contains(lib) {
+                state.requiref(name, Some(open), true);
+                state.pop(1);
+            }
+        }
+
+        let shared = Box::new(Shared{
+            memory: memory,
+            hooks: hooks,
             types_id_to_str: HashMap::new(),
             types_str_to_id: HashMap::new(),
+            live_userdata: HashMap::new(),
+            upcasts: HashMap::new(),
             lua_func_shim: lua_func_shim,
+            unref_queue: Rc::new(RefCell::new(Vec::new())),
+            callback_errors: Vec::new(),
+            resume_panics: false,
+            pending_panic: None,
+            source_maps: HashMap::new(),
+            metrics: None,
+            registered: Vec::new(),
+            callback_depth: 0,
+            recursion_limit: DEFAULT_RECURSION_LIMIT,
+            enums: HashMap::new(),
+            flags: HashMap::new(),
+            duration_format: DurationFormat::Seconds,
+            clock_start: Instant::now(),
+            sleep_handler: None,
+            events: events::Events::default(),
+            channel_shim: None,
+            base_globals: HashSet::new(),
+            snapshot_codecs: Vec::new(),
+            call_log: None,
+            app_data: HashMap::new(),
+            #[cfg(feature = "async")]
+            tasks: tasks::Tasks::new(async_shim),
+        });
+        let mut result = RumLua{
+            state: state,
+            shared: SharedRef{ ptr: Box::into_raw(shared), owned: true },
+            yield_requested: false,
+            in_callback: false,
             marker: PhantomData,
         };
         result.add_rum_libs();
+        result.shared.base_globals = snapshot::base_globals(&mut result);
+        result
+    }
+
+    /// Choose what happens when a Rust callback panics.  By default the
+    /// panic is converted into a Lua error; if `resume` is true, the panic
+    /// is instead resumed once Lua has unwound back to the Rust code which
+    /// called into it.
+    pub fn set_resume_panics(&mut self, resume: bool) {
+        self.shared.resume_panics = resume;
+    }
+
+    /// From within a callback, ask for the callback's results to be
+    /// yielded from the running coroutine instead of returned.  When the
+    /// coroutine is resumed, the values passed to resume become the
+    /// results of the call.
+    pub fn request_yield(&mut self) {
+        self.yield_requested = true;
+    }
+
+    /// Limit the memory Lua may allocate to `bytes`, or remove the limit
+    /// with `None`.  Allocations beyond the limit fail, raising an error
+    /// which Lua code can catch, or which is returned to Rust as
+    /// `LuaError::MemoryLimit`.
+    pub fn set_memory_limit(&mut self, bytes: Option<usize>) {
+        self.shared.memory.limit = bytes;
+        self.shared.memory.limit_hit = false;
+    }
+
+    /// The number of bytes currently allocated by Lua.
+    pub fn memory_used(&self) -> usize {
+        self.shared.memory.used
+    }
+
+    /// Report the memory in use, allocation counts and the number of
+    /// live userdata of each registered type, to help find leaks.
+    pub fn memory_stats(&self) -> MemoryStats {
+        let userdata = self.shared.types_id_to_str.iter().map(|(id, name)| {
+            (name.clone(), self.shared.live_userdata.get(id).map(|live| live.get()).unwrap_or(0))
+        }).collect();
+        MemoryStats {
+            bytes_used: self.shared.memory.used,
+            allocations: self.shared.memory.allocations,
+            frees: self.shared.memory.frees,
+            userdata: userdata,
+        }
+    }
+
+    /// Limit how deeply Rust callbacks may nest, by calling into Lua which
+    /// calls them again.  A callback beyond the limit fails with an error
+    /// instead of running, before the native stack can overflow.  The
+    /// default is 100.
+    pub fn set_recursion_limit(&mut self, depth: u32) {
+        self.shared.recursion_limit = depth;
+    }
+
+    /// Choose how `Duration` and `SystemTime` values are pushed to Lua.
+    /// The default is `DurationFormat::Seconds`.
+    pub fn set_duration_format(&mut self, format: DurationFormat) {
+        self.shared.duration_format = format;
+    }
+
+    /// The time on the state's monotonic clock, which `rum.time.monotonic()`
+    /// returns in seconds.  It starts at zero when the state is created.
+    pub fn monotonic(&self) -> Duration {
+        self.shared.clock_start.elapsed()
+    }
+
+    /// The version of the Lua runtime linked into the program, which the
+    /// state runs on, such as `(5, 3)`.  This is reported by the library
+    /// itself, so shows what was actually linked.
+    pub fn lua_version(&self) -> (u32, u32) {
+        let version = unsafe { *lua::ffi::lua_version(self.state.as_ptr()) } as u32;
+        (version / 100, version % 100)
+    }
+
+    /// Handle `rum.time.sleep_until(t)`, which is called with the `Instant`
+    /// at which `rum.time.monotonic()` reaches `t`, instead of blocking the
+    /// thread.  The handler might run an event loop, or request a yield.
+    pub fn set_sleep_handler<F>(&mut self, handler: F)
+                  where F: FnMut(&mut RumLua, Instant) -> Result<(), LuaError> + 'static
+    {
+        self.shared.sleep_handler = Some(Box::new(handler));
+    }
+
+    /// Keep `value` with the state, replacing any earlier value of its
+    /// type, so that callbacks can reach it with `app_data`.
+    pub fn set_app_data<T: Any>(&mut self, value: T) {
+        self.shared.app_data.insert(TypeId::of::<T>(), RefCell::new(Box::new(value)));
+    }
+
+    /// Borrow the value of type `T` kept with `set_app_data`, if there is
+    /// one and it isn't already borrowed, as by a callback further out.
+    pub fn app_data<T: Any>(&self) -> Option<cell::RefMut<'_, T>> {
+        self.shared.app_data.get(&TypeId::of::<T>())
+            .and_then(|data| data.try_borrow_mut().ok())
+            .map(|data| cell::RefMut::map(data, |data| data.downcast_mut::<T>().unwrap()))
+    }
+
+    /// Take back the value of type `T` kept with `set_app_data`.
+    pub fn remove_app_data<T: Any>(&mut self) -> Option<T> {
+        self.shared.app_data.remove(&TypeId::of::<T>())
+            .and_then(|data| data.into_inner().downcast::<T>().ok())
+            .map(|data| *data)
+    }
+
+    /// Keep `value` in the registry under `name`, out of sight of scripts,
+    /// replacing any value kept under that name.  Returns a handle to it.
+    pub fn store_named<T: Any>(&mut self, name: &str, value: T) -> LuaPtr<T> {
+        let obj = LuaPtr::new(value);
+        self.push_named_table();
+        unsafe {
+            let p: *mut Box<dyn Any> = self.state.new_userdata_typed();
+            ptr::write(p, Box::new(obj.clone()));
+        }
+        self.state.set_metatable_from_registry(NAMED_MT);
+        self.state.set_field(-2, name);
+        self.state.pop(1);
+        obj
+    }
+
+    /// Get a handle to the value kept under `name` by `store_named`, if
+    /// there is one of type `T`.
+    pub fn fetch_named<T: Any>(&mut self, name: &str) -> Option<LuaPtr<T>> {
+        self.push_named_table();
+        self.state.get_field(-1, name);
+        let obj = unsafe { self.state.test_userdata_typed::<Box<dyn Any>>(-1, NAMED_MT) }
+            .and_then(|p| p.downcast_ref::<LuaPtr<T>>())
+            .cloned();
+        self.state.pop(2);
+        obj
+    }
+
+    /// Forget the value kept under `name` by `store_named`.
+    pub fn remove_named(&mut self, name: &str) {
+        self.push_named_table();
+        self.state.push_nil();
+        self.state.set_field(-2, name);
+        self.state.pop(1);
+    }
+
+    /* Push the table of values kept by store_named, creating it the
+     * first time. */
+    fn push_named_table(&mut self) {
+        if self.state.get_field(lua::REGISTRYINDEX, NAMED_VALUES) != lua::Type::Table {
+            self.state.pop(1);
+            self.state.new_table();
+            self.state.push_value(-1);
+            self.state.set_field(lua::REGISTRYINDEX, NAMED_VALUES);
+        }
+    }
+
+    /// Limit how many Lua instructions each call into Lua (such as
+    /// `do_string`) may run, or remove the limit with `None`.  A script
+    /// which runs too long is stopped with `LuaError::Timeout`.  The
+    /// limit is checked every thousand instructions or so.
+    pub fn set_instruction_limit(&mut self, count: Option<u64>) {
+        self.shared.hooks.instruction_limit = count;
+        self.update_hooks();
+    }
+
+    /// Limit how long each call into Lua may run for, or remove the limit
+    /// with `None`.  A script which runs too long is stopped with
+    /// `LuaError::Timeout`.  Time spent in Rust callbacks counts, but
+    /// they are not interrupted.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.shared.hooks.timeout = timeout;
+        self.update_hooks();
+    }
+
+    /// Return a handle which can be used, from any thread, to stop the
+    /// script running in this `RumLua` with `LuaError::Interrupted`.
+    pub fn interrupt_handle(&mut self) -> Interrupt {
+        let handle = self.shared.hooks.interrupt_handle();
+        self.update_hooks();
+        handle
+    }
+
+    /// Log each call a script makes to a Rust callback to `out`, with the
+    /// callback's name, arguments and results or error, for
+    /// `replay_callbacks`.  Values are encoded as by `snapshot`, with those
+    /// it can't save logged as nil.  Calls made from within another
+    /// callback are part of that call, so aren't logged.
+    pub fn record_callbacks<W: Write + 'static>(&mut self, out: W) {
+        self.shared.call_log = Some(replay::CallLog::Record(Box::new(out)));
+    }
+
+    /// Replay a log written by `record_callbacks`: rather than running,
+    /// each callback returns the results or error logged for the next
+    /// call, so that a script's run can be reproduced without the host.
+    /// A call to a different callback than was logged, or beyond the end
+    /// of the log, fails.
+    pub fn replay_callbacks<R: Read + 'static>(&mut self, input: R) {
+        self.shared.call_log = Some(replay::CallLog::Replay(Box::new(input)));
+    }
+
+    /// Stop recording or replaying callbacks.
+    pub fn stop_call_log(&mut self) {
+        self.shared.call_log = None;
+    }
+
+    /// Start or stop counting the calls to and time spent in each Rust
+    /// callback, including the methods of registered types, by the name
+    /// it was registered with.  Starting again clears the counts.
+    pub fn enable_callback_metrics(&mut self, enabled: bool) {
+        self.shared.metrics = if enabled { Some(metrics::Metrics::default()) } else { None };
+    }
+
+    /// The counts kept since `enable_callback_metrics`, by decreasing
+    /// total time.
+    pub fn callback_stats(&self) -> Vec<CallbackStats> {
+        self.shared.metrics.as_ref().map(|m| m.stats()).unwrap_or_else(Vec::new)
+    }
+
+    /// Call `callback` on the events chosen by `triggers` while Lua code
+    /// runs, for building profilers, tracers and debuggers.  This replaces
+    /// any hook set before.  While an instruction count is asked for, the
+    /// instruction limit and timeout are checked at that interval.
+    pub fn set_hook(&mut self, triggers: HookTriggers, callback: HookCallback) {
+        let shared = self.shared.ptr as *mut c_void;
+        self.shared.hooks.set_user(Some((triggers, callback)), shared);
+        self.update_hooks();
+    }
+
+    /// The local variables in scope in the function at stack level
+    /// `level`, in order, as with `debug.getlocal`.  Level 0 is the
+    /// running function; in a callback that is the callback itself, and
+    /// the Lua function calling it is at level 2.
+    pub fn locals(&mut self, level: i32) -> Result<Vec<(String, Value)>, LuaError> {
+        inspect::locals(self, level)
+    }
+
+    /// Set the local variable `name` in scope in the function at stack
+    /// level `level`.
+    pub fn set_local<V: ToLua>(&mut self, level: i32, name: &str, value: V) -> Result<(), LuaError> {
+        inspect::set_local(self, level, name, value)
+    }
+
+    /// The function running at stack level `level`.
+    pub fn stack_function(&mut self, level: i32) -> Result<LuaFunction, LuaError> {
+        inspect::stack_function(self, level)
+    }
+
+    /// The upvalues of `f`, in order, as with `debug.getupvalue`.  Those
+    /// of Rust closures have empty names.
+    pub fn upvalues(&mut self, f: &LuaFunction) -> Result<Vec<(String, Value)>, LuaError> {
+        inspect::upvalues(self, f)
+    }
+
+    /// Set the upvalue `name` of `f`, which is shared with any other
+    /// closures using the same variable.
+    pub fn set_upvalue<V: ToLua>(&mut self, f: &LuaFunction, name: &str, value: V) -> Result<(), LuaError> {
+        inspect::set_upvalue(self, f, name, value)
+    }
+
+    /// Remove the hook set with `set_hook`.
+    pub fn remove_hook(&mut self) {
+        self.shared.hooks.set_user(None, ptr::null_mut());
+        self.update_hooks();
+    }
+
+    /* Set up the hook on the current and main threads.  Threads
+     * created later inherit it. */
+    fn update_hooks(&mut self) {
+        hook::apply(self.state.as_ptr(), &self.shared.hooks);
+        self.state.raw_geti(lua::REGISTRYINDEX, lua::ffi::LUA_RIDX_MAINTHREAD);
+        let main = unsafe { lua::ffi::lua_tothread(self.state.as_ptr(), -1) };
+        self.state.pop(1);
+        hook::apply(main, &self.shared.hooks);
+    }
+
+    /// Create a new empty Lua table.
+    pub fn create_table(&mut self) -> LuaTable {
+        self.state.new_table();
+        let table = LuaTable::from_lua(self, -1);
+        self.state.pop(1);
+        table.unwrap()
+    }
+
+    /// Create a coroutine which will run `f` when first resumed.
+    pub fn create_thread(&mut self, f: &LuaFunction) -> Result<LuaThread, LuaError> {
+        LuaThread::new(self, f)
+    }
+
+    /// The table of global variables.
+    pub fn globals(&mut self) -> LuaTable {
+        self.state.push_global_table();
+        let table = LuaTable::from_lua(self, -1);
+        self.state.pop(1);
+        table.unwrap()
+    }
+
+    /// Create an environment table for `load_with_env`, which reads
+    /// through to the real globals but keeps its own assignments.  Scripts
+    /// run in it can still modify tables reached through the globals.
+    pub fn create_env(&mut self) -> LuaTable {
+        self.state.new_table();
+        self.state.new_table();
+        self.state.push_global_table();
+        self.state.set_field(-2, "__index");
+        self.state.set_metatable(-2);
+        let env = LuaTable::from_lua(self, -1);
+        self.state.pop(1);
+        env.unwrap()
+    }
+
+    /// Make the globals and the `rum` table read-only, as with
+    /// `LuaTable::set_readonly`, so that scripts can't replace the
+    /// functions they have been given.  Other tables reachable from the
+    /// globals, such as `string`, are left writable.
+    pub fn freeze_globals(&mut self) -> Result<(), LuaError> {
+        let globals = self.globals();
+        if let Ok(rum) = globals.raw_get::<_, LuaTable>(self, "rum") {
+            rum.set_readonly(self, true)?;
+        }
+        globals.set_readonly(self, true)
+    }
+
+    /// Replace the global `print` with a function which passes each line
+    /// it would have written, without the newline, to `handler`.  The
+    /// arguments are converted with `tostring` as by the standard `print`.
+    pub fn set_print_handler(&mut self, handler: Box<dyn FnMut(&str)>) {
+        output::install_print(self, handler);
+    }
+
+    /// Replace `io.write` with a function which passes the text it would
+    /// have written to `handler`.  Fails if the io library is not open.
+    /// Writing through `io.stdout` directly is not affected.
+    pub fn set_write_handler(&mut self, handler: Box<dyn FnMut(&str)>) -> Result<(), LuaError> {
+        output::install_write(self, handler)
+    }
+
+    /// Save the globals defined since the state was created, such as by
+    /// scripts, to a blob for `restore`.  Booleans, numbers, strings and
+    /// tables are saved, keeping tables which are shared or cyclic so, and
+    /// so are userdata of types given to `register_snapshot_type`.  Table
+    /// metatables are not saved, and nor are functions, threads and other
+    /// userdata, or the table entries holding them.
+    pub fn snapshot(&mut self) -> Result<Vec<u8>, LuaError> {
+        let base = mem::replace(&mut self.shared.base_globals, HashSet::new());
+        let codecs = self.shared.snapshot_codecs.clone();
+        let result = snapshot::save(self, &base, codecs);
+        self.shared.base_globals = base;
+        result
+    }
+
+    /// Set the globals saved by `snapshot`, in this state or another set
+    /// up the same way.  A global which is already a table is updated in
+    /// place, keeping any functions it holds.  If `blob` is corrupt, an
+    /// error is returned and some globals may have been set already.
+    pub fn restore(&mut self, blob: &[u8]) -> Result<(), LuaError> {
+        let codecs = self.shared.snapshot_codecs.clone();
+        snapshot::restore(self, blob, codecs)
+    }
+
+    /// Save userdata of the registered type `T` in snapshots as the bytes
+    /// returned by `save`, to be restored by `load`.  `name` identifies
+    /// the type in the blob.
+    pub fn register_snapshot_type<T, S, L>(&mut self, name: &str, save: S, load: L)
+                  where T: Any, S: Fn(&T) -> Vec<u8> + 'static, L: Fn(&[u8]) -> Result<T, LuaError> + 'static
+    {
+        self.shared.snapshot_codecs.push(Rc::new(snapshot::codec(name, save, load)));
+    }
+
+    /// Set the global variable `name` to `value`.
+    pub fn set_global_value<T: ToLua>(&mut self, name: &str, value: T) -> Result<(), LuaError> {
+        value.to_lua(self)?;
+        self.state.set_global(name);
+        Ok(())
+    }
+
+    /// Get the value of the global variable `name`.
+    pub fn get_global_value<T: FromLua>(&mut self, name: &str) -> Result<T, LuaError> {
+        self.state.get_global(name);
+        let result = T::from_lua(self, -1);
+        self.state.pop(1);
         result
     }
 
+    /// Push `value`, converted as by `to_value`.
+    #[cfg(feature = "serde")]
+    pub fn push_serialize<T: serde::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        let value = to_value(self, value)?;
+        value.to_lua(self)
+    }
+
+    /// Convert the value at `index`, as by `from_value`.
+    #[cfg(feature = "serde")]
+    pub fn get_deserialize<T: serde::de::DeserializeOwned>(&mut self, index: Index) -> Result<T, LuaError> {
+        let value = Value::from_lua(self, index)?;
+        from_value(self, value)
+    }
+
+    /// Push a JSON value.  Arrays become sequence tables, objects become
+    /// tables, and null becomes nil.
+    #[cfg(feature = "json")]
+    pub fn push_json(&mut self, value: &serde_json::Value) -> Result<(), LuaError> {
+        self.push_serialize(value)
+    }
+
+    /// Convert the value at `index` to JSON.  Empty tables become empty
+    /// objects.
+    #[cfg(feature = "json")]
+    pub fn to_json(&mut self, index: Index) -> Result<serde_json::Value, LuaError> {
+        self.get_deserialize(index)
+    }
+
+    /// Encode the value at `index` as MessagePack, which can be passed to
+    /// `push_msgpack` on another `RumLua`.  Functions, userdata, threads
+    /// and cyclic tables can't be encoded.
+    #[cfg(feature = "msgpack")]
+    pub fn to_msgpack(&mut self, index: Index) -> Result<Vec<u8>, LuaError> {
+        msgpack::encode(self, index)
+    }
+
+    /// Decode MessagePack data and push the value.
+    #[cfg(feature = "msgpack")]
+    pub fn push_msgpack(&mut self, data: &[u8]) -> Result<(), LuaError> {
+        msgpack::decode(self, data)
+    }
+
+    /// Anchor the value at `index` in the registry, so that it stays alive
+    /// (and can be pushed again) for as long as the `LuaRef` exists.
+    pub fn create_ref(&mut self, index: Index) -> LuaRef {
+        LuaRef::new(self, index)
+    }
+
+    /// Push the value held by `r` onto the stack.
+    pub fn push_ref(&mut self, r: &LuaRef) -> Result<(), LuaError> {
+        r.push(self)
+    }
+
+    /* Release registry slots of dropped LuaRefs. */
+    fn release_refs(&mut self) {
+        let keys: Vec<c_int> = self.shared.unref_queue.borrow_mut().drain(..).collect();
+        for key in keys {
+            unsafe { lua::ffi::luaL_unref(self.state.as_ptr(), lua::REGISTRYINDEX, key) };
+        }
+    }
+
     /* Run the Lua function at the top of the stack, with the
      * error catching.
      */
     pub fn run_loaded_lua(&mut self, num_args: i32, num_results: i32)
                           -> Result<(), LuaError> {
-        self.state.get_global("debug");
-        self.state.get_field(-1, "traceback");
-        self.state.remove(-2);
+        self.state.push_fn(lua_func!(::RumLua::traceback_handler));
         let msgh_pos = self.state.get_top() - 1 - num_args;
         // Swap with chunk to execute
         self.state.rotate(-2-num_args, 1);
+        self.shared.hooks.enter();
         let status = self.state.pcall(num_args, num_results, msgh_pos);
+        self.shared.hooks.leave();
+        if let Some(payload) = self.shared.pending_panic.take() {
+            /* Lua has unwound past the panicking callback. */
+            panic::resume_unwind(payload);
+        }
         // Remove message handler
         match status {
             ThreadStatus::Ok => {
@@ -147,87 +956,487 @@ impl<'a> RumLua<'a> {
                 Ok(())
             },
             _ => {
-                self.state.remove(-3); // message handler below err,msg
-                let err_msg = self.state.to_str(-1);
-                match err_msg {
-                    Some(msg) => lfail(&format!("Error running Lua: {}", msg)),
-                    _ => lfail("Error loading string"),
+                self.state.remove(msgh_pos); // message handler below err msg
+                let err_msg = self.state.to_str(-1).map(|s| s.to_string());
+                Err(self.error_from_lua(status, err_msg))
+            },
+        }
+    }
+
+    /* Build the LuaError for a failed call, given the status and the
+     * error message (with any traceback appended).
+     */
+    fn error_from_lua(&mut self, status: ThreadStatus, err_msg: Option<String>) -> LuaError {
+        match (status, err_msg) {
+            (ThreadStatus::MemoryError, msg) => {
+                let msg = msg.unwrap_or("Out of memory".to_string());
+                if mem::replace(&mut self.shared.memory.limit_hit, false) {
+                    LuaError::MemoryLimit(msg)
+                } else {
+                    LuaError::MemoryError(msg)
+                }
+            },
+            (_, Some(msg)) => {
+                let msg = sourcemap::apply(&self.shared.source_maps, &msg);
+                /* Split off the traceback added by the message handler */
+                let (message, traceback) = match msg.find("\nstack traceback:") {
+                    Some(pos) => (msg[..pos].to_string(), Some(msg[pos+1..].to_string())),
+                    None => (msg.clone(), None),
+                };
+                match self.shared.hooks.triggered.take() {
+                    Some(hook::Stop::Timeout) => return LuaError::Timeout(message),
+                    Some(hook::Stop::Interrupted) => return LuaError::Interrupted(message),
+                    None => {},
                 }
+                if let Some(e) = self.take_callback_error(&message) {
+                    return e;
+                }
+                LuaError::RuntimeError{
+                    message: format!("Error running Lua: {}", message),
+                    traceback: traceback,
+                }
+            },
+            (_, None) => lerror("Error running Lua: (error object is not a string)"),
+        }
+    }
+
+    /// Convert the value at `index` to a `Value`, whatever its type.
+    pub fn to_value(&mut self, index: Index) -> Result<Value, LuaError> {
+        Value::from_lua(self, index)
+    }
+
+    /// Push any `Value` onto the stack.
+    pub fn push_any(&mut self, value: &Value) -> Result<(), LuaError> {
+        value.to_lua(self)
+    }
+
+    /// Call the global Lua function `name` with `args`, converting its
+    /// results to `R`.
+    pub fn call_global<A, R>(&mut self, name: &str, args: A) -> Result<R, LuaError>
+                  where A: ToLuaMulti, R: FromLuaMulti
+    {
+        let base = self.state.get_top();
+        if self.state.get_global(name) != lua::Type::Function {
+            self.state.set_top(base);
+            return Err(LuaError::TypeError(format!("Global '{}' is not a function", name)));
+        }
+        self.call_pushed(base, args)
+    }
+
+    /* Call the function just above `base` on the stack with `args`, and
+     * convert its results.  The stack is restored to `base`.
+     */
+    fn call_pushed<A, R>(&mut self, base: Index, args: A) -> Result<R, LuaError>
+                  where A: ToLuaMulti, R: FromLuaMulti
+    {
+        let result = match args.to_lua_multi(self) {
+            Ok(nargs) => self.run_loaded_lua(nargs, lua::MULTRET),
+            Err(e) => Err(e),
+        };
+        let result = match result {
+            Ok(()) => {
+                let nresults = self.state.get_top() - base;
+                R::from_lua_multi(self, nresults)
             },
+            Err(e) => Err(e),
+        };
+        self.state.set_top(base);
+        result
+    }
+
+    /// Make sure there is room on the stack to push `n` more values, which
+    /// Lua only promises for a few at a time.
+    pub fn ensure_stack(&mut self, n: usize) -> Result<(), LuaError> {
+        if n <= c_int::max_value() as usize && self.state.check_stack(n as c_int) {
+            Ok(())
+        } else {
+            Err(LuaError::StackOverflow(format!("stack overflow (no room for {} more values)", n)))
         }
     }
 
-    pub fn dump_stack(&mut self, message: &str) {
+    /// Describe the values on the stack, one per line from the bottom,
+    /// for debugging.  The stack is left untouched.
+    pub fn stack_dump(&mut self) -> String {
         let top = self.state.get_top();
-        println!("Lua stack dump ({} items); {}", top, message);
-        for i in 1..top+1 {
-            match self.state.to_str(i) {
-                Some(s) => println!("  {}: {}", i, s),
-                _ => println!("  {}: ???", i),
-            }
-            self.state.pop(1);
+        let mut dump = format!("Lua stack ({} items)\n", top);
+        for i in 1..(top + 1) {
+            let desc = self.describe_value(i);
+            dump.push_str(&format!("  {} ({}): {}\n", i, i - top - 1, desc));
+        }
+        dump
+    }
+
+    /* A one-line description of the value at `index`, for stack_dump. */
+    fn describe_value(&mut self, index: Index) -> String {
+        let ptr = self.state.to_pointer(index);
+        match self.state.type_of(index) {
+            Some(lua::Type::Nil) => "nil".to_string(),
+            Some(lua::Type::Boolean) => self.state.to_bool(index).to_string(),
+            Some(lua::Type::Number) => {
+                let kind = if self.state.is_integer(index) { "integer" } else { "float" };
+                /* Convert a copy, so the value itself stays a number. */
+                self.state.push_value(index);
+                let s = self.state.to_str(-1).unwrap_or("?").to_string();
+                self.state.pop(1);
+                format!("{} {}", kind, s)
+            },
+            Some(lua::Type::String) => {
+                let s = LuaString::from_lua(self, index).map(|s| s.into_bytes()).unwrap_or_else(|_| Vec::new());
+                let text = String::from_utf8_lossy(&s[..s.len().min(60)]).into_owned();
+                let more = if s.len() > 60 { "..." } else { "" };
+                format!("string {:?}{} (len {})", text, more, s.len())
+            },
+            Some(lua::Type::Table) => {
+                format!("table {:p} (len {})", ptr, self.state.raw_len(index))
+            },
+            Some(lua::Type::Function) => {
+                let kind = if self.state.is_native_fn(index) { "C function" } else { "function" };
+                format!("{} {:p}", kind, ptr)
+            },
+            Some(lua::Type::Userdata) => {
+                let mut desc = format!("userdata {:p}", ptr);
+                if self.state.get_metatable(index) {
+                    self.state.push("__name");
+                    if self.state.raw_get(-2) == lua::Type::String {
+                        desc = format!("{} ({})", desc, self.state.to_str(-1).unwrap_or("?"));
+                    }
+                    self.state.pop(2);
+                }
+                desc
+            },
+            Some(lua::Type::LightUserdata) => format!("light userdata {:p}", ptr),
+            Some(lua::Type::Thread) => format!("thread {:p}", ptr),
+            None | Some(lua::Type::None) => "no value".to_string(),
         }
     }
 
     #[allow(dead_code)]
     pub fn do_string(&mut self, s: &str) -> Result<(), LuaError> {
-        let status = self.state.load_string(s);
+        let mut rl = StackGuard::new(self);
+        let status = rl.state.load_string(s);
         let result = match status {
             ThreadStatus::Ok => {
-                    self.run_loaded_lua(0, 0)
+                    rl.run_loaded_lua(0, 0)
                 },
             _ => {
-                let err_msg = self.state.to_str(-1);
+                let err_msg = rl.state.to_str(-1);
                 match err_msg {
-                    Some(msg) => lfail(&format!("Syntax error loading string: {}", msg)),
-                    _ => lfail("Error loading string"),
+                    Some(msg) => Err(LuaError::SyntaxError(format!("Syntax error loading string: {}", msg))),
+                    _ => Err(LuaError::SyntaxError("Error loading string".to_string())),
                 }
             }
         };
-        /* Clear the stack... */
-        // TODO: only pop what we put there
-        let size = self.state.get_top();
-        self.state.pop(size);
-
+        if let Err(ref e) = result {
+            rum_log!(debug, "do_string failed: {}", e);
+        }
         result
     }
 
-    pub fn do_file(&mut self, path: &str) -> Result<(),LuaError> {
-        let status = self.state.load_file(path);
-        match status {
-            ThreadStatus::Ok => {
-                    try!(self.run_loaded_lua(0, 0))
-                },
+    /// Compile `bytes` as a Lua chunk without running it.  `chunk_name`
+    /// is used in error messages and tracebacks; as in Lua, a name like
+    /// "@config/ai.lua" is shown as a file name and "=name" as is.
+    pub fn load_bytes(&mut self, bytes: &[u8], chunk_name: &str) -> Result<LuaFunction, LuaError> {
+        self.load_buffer(bytes, chunk_name, "bt")?;
+        let f = LuaFunction::from_lua(self, -1);
+        self.state.pop(1);
+        f
+    }
+
+    /// As `load_bytes`, reading the chunk from `reader`.
+    pub fn load_reader<R: Read>(&mut self, mut reader: R, chunk_name: &str) -> Result<LuaFunction, LuaError> {
+        let mut bytes = Vec::new();
+        if let Err(e) = reader.read_to_end(&mut bytes) {
+            return Err(LuaError::FileError(format!("Error reading {}: {}", chunk_name, e)));
+        }
+        self.load_bytes(&bytes, chunk_name)
+    }
+
+    /// Compile `source` without running it, to check it for syntax
+    /// errors.  `chunk_name` is as for `load_bytes`.
+    pub fn check_syntax(&mut self, source: &str, chunk_name: &str) -> Result<(), SyntaxError> {
+        let status = self.state.load_bufferx(source.as_bytes(), chunk_name, "t");
+        let result = match status {
+            ThreadStatus::Ok => Ok(()),
             _ => {
-                let err_msg = self.state.to_str(-1);
-                return match err_msg {
-                    Some(err_msg) => lfail(&format!("Syntax error loading file: {}", err_msg)),
-                    _ => lfail("Error loading file"),
-                }
-            }
+                let msg = self.state.to_str(-1).unwrap_or("unknown error").to_string();
+                Err(SyntaxError::parse(&sourcemap::apply(&self.shared.source_maps, &msg)))
+            },
+        };
+        self.state.pop(1);
+        result
+    }
+
+    /// As `load_bytes`, but the chunk sees `env` as its global
+    /// environment (`_ENV`) instead of the real globals.  Only source
+    /// text is accepted: Lua doesn't check precompiled chunks, so they
+    /// could break out of the environment.
+    pub fn load_with_env(&mut self, source: &[u8], chunk_name: &str, env: &LuaTable) -> Result<LuaFunction, LuaError> {
+        self.load_buffer(source, chunk_name, "t")?;
+        let base = self.state.get_top();
+        if let Err(e) = env.to_lua(self) {
+            self.state.set_top(base - 1);
+            return Err(e);
         }
-        /* Clear the stack... */
-        // TODO: only pop what we put there
-        let size = self.state.get_top();
-        self.state.pop(size);
-        Ok(())
+        /* A main chunk's only upvalue is _ENV. */
+        unsafe { lua::ffi::lua_setupvalue(self.state.as_ptr(), -2, 1) };
+        let f = LuaFunction::from_lua(self, -1);
+        self.state.pop(1);
+        f
     }
 
-    fn add_rum_libs(&mut self) {
-        self.state.new_table();
-        self.state.set_global("rum");
+    /// As `do_string`, but naming the chunk `chunk_name` in errors and
+    /// tracebacks, as for `load_bytes`.
+    pub fn do_string_named(&mut self, code: &str, chunk_name: &str) -> Result<(), LuaError> {
+        let f = self.load_bytes(code.as_bytes(), chunk_name)?;
+        f.call(self, ())
     }
 
-    fn lua_func_wrapper(state: &mut lua::State) -> c_int {
-        let rl_obj: &mut RumLua = unsafe {
-            let rl_ptr = state.to_userdata(lua::ffi::lua_upvalueindex(1));
-            &mut *(rl_ptr as *mut RumLua)
+    /// Report positions in the chunk `chunk_name` through `map` in the
+    /// errors and tracebacks of chunks loaded with that name.  Only names
+    /// starting with "@" or "=" can be mapped; others are shown by Lua as
+    /// part of the source.
+    pub fn set_source_map<M: SourceMap + 'static>(&mut self, chunk_name: &str, map: M) -> Result<(), LuaError> {
+        match sourcemap::display_name(chunk_name) {
+            Some(name) => {
+                self.shared.source_maps.insert(name.to_string(), Box::new(map));
+                Ok(())
+            },
+            None => Err(lerror("Source maps need a chunk name starting with '@' or '='")),
+        }
+    }
+
+    /// Run `code` with `env` as its global environment; see
+    /// `load_with_env`.
+    pub fn do_string_in_env(&mut self, code: &str, env: &LuaTable) -> Result<(), LuaError> {
+        let f = self.load_with_env(code.as_bytes(), code, env)?;
+        f.call(self, ())
+    }
+
+    /* Compile a chunk, pushing the function. */
+    fn load_buffer(&mut self, bytes: &[u8], chunk_name: &str, mode: &str) -> Result<(), LuaError> {
+        match self.state.load_bufferx(bytes, chunk_name, mode) {
+            ThreadStatus::Ok => Ok(()),
+            _ => {
+                let msg = self.state.to_str(-1).unwrap_or("unknown error").to_string();
+                self.state.pop(1);
+                let msg = sourcemap::apply(&self.shared.source_maps, &msg);
+                Err(LuaError::SyntaxError(format!("Syntax error loading chunk: {}", msg)))
+            },
+        }
+    }
+
+    /// Run `s` as a Lua chunk, converting the values it returns to `R`.
+    pub fn eval<R: FromLuaMulti>(&mut self, s: &str) -> Result<R, LuaError> {
+        let base = self.state.get_top();
+        match self.state.load_string(s) {
+            ThreadStatus::Ok => self.call_pushed(base, ()),
+            _ => {
+                let result = match self.state.to_str(-1) {
+                    Some(msg) => Err(LuaError::SyntaxError(format!("Syntax error loading string: {}", msg))),
+                    _ => Err(LuaError::SyntaxError("Error loading string".to_string())),
+                };
+                self.state.set_top(base);
+                result
+            },
+        }
+    }
+
+    /// Evaluate the Lua expression `expr`, such as "cfg.width * 2", and
+    /// convert its value to `T`.  A malformed expression gives a
+    /// `SyntaxError`, one which raises an error a `RuntimeError`, and a
+    /// value of the wrong type a `ConversionError`.
+    pub fn eval_expr<T: FromLua>(&mut self, expr: &str) -> Result<T, LuaError> {
+        let base = self.state.get_top();
+        /* The newline ends any trailing comment before the parenthesis. */
+        let source = format!("return ({}\n)", expr);
+        self.load_buffer(source.as_bytes(), &format!("={}", expr), "t")?;
+        match self.call_pushed(base, ()) {
+            Err(LuaError::ConversionError(msg)) => Err(LuaError::ConversionError(format!("{}: {}", expr, msg))),
+            result => result,
+        }
+    }
+
+    pub fn do_file(&mut self, path: &str) -> Result<(),LuaError> {
+        let mut rl = StackGuard::new(self);
+        rl.push_file_chunk(path)?;
+        rl.run_loaded_lua(0, 0)
+    }
+
+    /// Run the file at `path` as a standalone script, as the `lua`
+    /// interpreter would: with `args` as the chunk's varargs and in the
+    /// global table `arg`, whose element 0 is `path`.  Returns the chunk's
+    /// results, which a tool might use for its exit status.
+    pub fn do_file_with_args(&mut self, path: &str, args: &[Value]) -> Result<Vec<Value>, LuaError> {
+        let base = self.state.get_top();
+        self.push_file_chunk(path)?;
+        if let Err(e) = self.set_script_args(path, args) {
+            self.state.set_top(base);
+            return Err(e);
+        }
+        self.call_pushed::<_, MultiValue>(base, MultiValue(args.to_vec())).map(|results| results.0)
+    }
+
+    /* Set the global `arg` for do_file_with_args. */
+    fn set_script_args(&mut self, path: &str, args: &[Value]) -> Result<(), LuaError> {
+        let arg = self.create_table();
+        arg.raw_set(self, 0, path)?;
+        for (i, v) in args.iter().enumerate() {
+            arg.raw_set(self, (i + 1) as lua::Integer, v.clone())?;
+        }
+        self.globals().raw_set(self, "arg", arg)
+    }
+
+    /* Push the chunk in the file at `path`, or return why it can't be
+     * loaded. */
+    fn push_file_chunk(&mut self, path: &str) -> Result<(), LuaError> {
+        let result = match self.state.load_file(path) {
+            ThreadStatus::Ok => return Ok(()),
+            ThreadStatus::FileError => {
+                let err_msg = self.state.to_str(-1).unwrap_or("Error loading file").to_string();
+                Err(LuaError::FileError(err_msg))
+            },
+            _ => {
+                match self.state.to_str(-1) {
+                    Some(err_msg) => Err(LuaError::SyntaxError(format!("Syntax error loading file: {}", err_msg))),
+                    _ => Err(LuaError::SyntaxError("Error loading file".to_string())),
+                }
+            }
         };
-        let f: &mut Box<Callback> = unsafe {
-            let f_ptr: *mut Box<Callback> = state.to_userdata(lua::ffi::lua_upvalueindex(2)) as *mut Box<Callback>;
-            &mut *f_ptr
+        self.state.pop(1);
+        result
+    }
+
+    fn add_rum_libs(&mut self) {
+        self.state.new_table();
+        self.push_registered_closure(CallbackFn::Plain(rum_types), "rum.types");
+        self.state.set_field(-2, "types");
+        class::open(self);
+        time::open(self);
+        scheduler::open(self);
+        events::open(self);
+        #[cfg(feature = "json")]
+        json::open(self);
+        #[cfg(feature = "decimal")]
+        decimal::open(self);
+        self.state.set_global("rum");
+    }
+
+    /* A RumLua for running a callback or hook on the thread `l`, which
+     * shares everything else with the RumLua owning the state. */
+    unsafe fn borrowed(l: *mut lua::ffi::lua_State, shared: *mut Shared) -> RumLua<'a> {
+        RumLua{
+            state: lua::State::from_ptr(l),
+            shared: SharedRef{ ptr: shared, owned: false },
+            yield_requested: false,
+            in_callback: false,
+            marker: PhantomData,
+        }
+    }
+
+    /* The Shared and CallbackFn in the upvalues of a callback closure.
+     * The CallbackFn is held for the call, in case the closure is
+     * replaced while it runs. */
+    fn callback_upvalues(state: &mut lua::State) -> (*mut Shared, Rc<CallbackFn>) {
+        unsafe {
+            let shared = state.to_userdata(lua::ffi::lua_upvalueindex(1)) as *mut Shared;
+            let f = if state.is_integer(lua::ffi::lua_upvalueindex(2)) {
+                let id = state.to_integer(lua::ffi::lua_upvalueindex(2)) as usize;
+                let registered = &(*shared).registered;
+                registered[id].clone()
+            } else {
+                let f_ptr = state.to_userdata(lua::ffi::lua_upvalueindex(2)) as *const Rc<CallbackFn>;
+                (*f_ptr).clone()
+            };
+            (shared, f)
+        }
+    }
+
+    /* Run the callback in the upvalues of the running closure on the
+     * thread `state`, turning panics into errors.  Returns whether the
+     * callback asked to yield, and its error message if it failed. */
+    fn run_callback(state: &mut lua::State) -> (Result<isize, String>, bool) {
+        let (shared, f) = RumLua::callback_upvalues(state);
+        let mut rl = unsafe { RumLua::borrowed(state.as_ptr(), shared) };
+        rl.in_callback = true;
+        if rl.shared.callback_depth >= rl.shared.recursion_limit {
+            let msg = format!("Rust callbacks nested too deeply (limit {})", rl.shared.recursion_limit);
+            return (Err(msg), false);
+        }
+        /* Calls made within another callback are part of that call, so
+         * aren't logged. */
+        let logged = rl.shared.call_log.is_some() && rl.shared.callback_depth == 0;
+        if logged {
+            if let Some(outcome) = replay::replay(&mut rl) {
+                return outcome;
+            }
+        }
+        let record = if logged { replay::record_args(&mut rl) } else { None };
+        rl.shared.callback_depth += 1;
+        rum_log!(trace, "calling callback '{}'", rl.callback_name());
+        let started = Instant::now();
+        /* Unwinding into Lua's C frames is undefined behaviour, so
+         * panics are turned into Lua errors here. */
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            f.call(&mut rl)
+        }));
+        rl.shared.callback_depth -= 1;
+        match result {
+            Ok(Ok(_)) => rum_log!(trace, "callback '{}' returned after {:?}",
+                                  rl.callback_name(), started.elapsed()),
+            Ok(Err(ref e)) => rum_log!(debug, "callback '{}' failed after {:?}: {}",
+                                       rl.callback_name(), started.elapsed(), e),
+            Err(_) => rum_log!(warn, "callback '{}' panicked", rl.callback_name()),
+        }
+        if rl.shared.metrics.is_some() {
+            let name = rl.callback_name();
+            let failed = match result { Ok(Ok(_)) => false, _ => true };
+            if let Some(ref mut metrics) = rl.shared.metrics {
+                metrics.record(name, started.elapsed(), failed);
+            }
+        }
+        let result = match result {
+            Ok(result) => result,
+            Err(payload) => {
+                let msg = panic_message(&payload);
+                if rl.shared.resume_panics {
+                    rl.shared.pending_panic = Some(payload);
+                }
+                lfail(&format!("Rust callback panicked: {}", msg))
+            },
+        };
+        if let Some(record) = record {
+            let yielded = rl.yield_requested;
+            replay::record(&mut rl, record, &result, yielded);
+        }
+        match result {
+            Ok(num_results) => (Ok(num_results), rl.yield_requested),
+            Err(e) => {
+                let msg = e.to_string();
+                rl.stash_callback_error(e);
+                (Err(msg), rl.yield_requested)
+            },
+        }
+    }
+
+    fn lua_func_wrapper(state: &mut lua::State) -> c_int {
+        let (result, yield_requested) = RumLua::run_callback(state);
+        let result = match result {
+            Ok(_) if yield_requested && unsafe { lua::ffi::lua_isyieldable(state.as_ptr()) } == 0 => {
+                Err("attempt to yield from outside a coroutine".to_string())
+            },
+            result => result,
         };
-        match f(rl_obj) {
+        match result {
+            Ok(num_results) if yield_requested => {
+                /* Yield the results.  This doesn't return, so nothing
+                 * with a destructor may be live here. */
+                let ctx = state.get_top() - num_results as c_int;
+                unsafe {
+                    lua::ffi::lua_yieldk(state.as_ptr(), num_results as c_int,
+                                         ctx as lua::ffi::lua_KContext,
+                                         Some(yield_continuation))
+                }
+            },
             Ok(num_results) => {
                 /* The results are on the top of the stask.  We need to
                  * push a "true" underneath.
@@ -236,52 +1445,483 @@ impl<'a> RumLua<'a> {
                 state.rotate(-(num_results as i32)-1, 1);
                 (num_results+1) as c_int
             },
-            Err(s) => {
+            Err(msg) => {
                 /* Just push 'false' and the error string */
                 state.push_bool(false);
-                state.push_string(s.description());
+                state.push_string(&msg);
                 2
             },
         }
     }
 
-    fn _push_closure(&mut self, f: fn(&mut RumLua)->LuaRet, name: &str) {
+    /* As lua_func_wrapper, for closures pushed without the shim: returns
+     * the number of results, or -1 with an error message pushed for
+     * raw_callback to raise. */
+    fn raw_func_wrapper(state: &mut lua::State) -> c_int {
+        let (result, yield_requested) = RumLua::run_callback(state);
+        let result = match result {
+            Ok(_) if yield_requested => Err("attempt to yield from a raw callback".to_string()),
+            result => result,
+        };
+        match result {
+            Ok(num_results) => num_results as c_int,
+            Err(msg) => {
+                /* The same message as the shim gives, so that the error
+                 * can be recognised by take_callback_error. */
+                unsafe { lua::ffi::luaL_where(state.as_ptr(), 1) };
+                let name = state.to_str(lua::ffi::lua_upvalueindex(3)).unwrap_or("?").to_string();
+                state.push_string(&format!("Calling {}:\n{}", name, msg));
+                state.concat(2);
+                -1
+            },
+        }
+    }
+
+    /* Lua only sees a callback's error as a string; keep the original so
+     * it can be returned if the error propagates back out to Rust.
+     * Plain runtime errors are better reported with Lua's traceback.
+     */
+    fn stash_callback_error(&mut self, e: LuaError) {
+        if let LuaError::RuntimeError{..} = e {
+            return;
+        }
+        let message = e.to_string();
+        if message.is_empty() {
+            return;
+        }
+        if self.shared.callback_errors.len() >= MAX_CALLBACK_ERRORS {
+            self.shared.callback_errors.remove(0);
+        }
+        self.shared.callback_errors.push((message, e));
+    }
+
+    /* Find the most recent callback error which the Lua error `msg` was
+     * raised from, i.e. which ends "Calling <name>:\n<message>". */
+    fn take_callback_error(&mut self, msg: &str) -> Option<LuaError> {
+        let pos = self.shared.callback_errors.iter().rposition(|&(ref m, _)| {
+            msg.ends_with(&m[..]) && msg[..msg.len()-m.len()].ends_with(":\n")
+        });
+        pos.map(|pos| self.shared.callback_errors.remove(pos).1)
+    }
+
+    /* Message handler adding a traceback to errors */
+    fn traceback_handler(state: &mut lua::State) -> c_int {
+        /* Leave non-string error objects alone */
+        if state.is_string(1) {
+            unsafe {
+                let msg = lua::ffi::lua_tolstring(state.as_ptr(), 1, ptr::null_mut());
+                lua::ffi::luaL_traceback(state.as_ptr(), state.as_ptr(), msg, 1);
+            }
+        }
+        1
+    }
+
+    /* __gc for the userdata holding a CallbackFn */
+    fn callback_gc(state: &mut lua::State) -> c_int {
+        unsafe {
+            let f_ptr = state.to_userdata(1) as *mut Rc<CallbackFn>;
+            ptr::drop_in_place(f_ptr);
+        }
+        0
+    }
+
+    /* __gc for the userdata holding a value kept by store_named */
+    fn named_gc(state: &mut lua::State) -> c_int {
+        unsafe {
+            let p = state.to_userdata(1) as *mut Box<dyn Any>;
+            ptr::drop_in_place(p);
+        }
+        0
+    }
+
+    fn _push_closure(&mut self, f: CallbackFn, name: &str) {
+        self.push_callback_fn(f);
+        self.wrap_callback_fn(name);
+    }
+
+    /* As `_push_closure`, for callbacks which are kept until the state
+     * is closed, such as those registered as methods. */
+    fn push_registered_closure(&mut self, f: CallbackFn, name: &str) {
+        self.push_registered_fn(f);
+        self.wrap_callback_fn(name);
+    }
+
+    /* Push a userdata holding `f`. */
+    fn push_callback_fn(&mut self, f: CallbackFn) {
         unsafe {
-            let stolen = self as *mut RumLua as usize;
-            self.state.push_light_userdata(stolen as *mut c_void);
-            let fp: *mut Box<Callback> = self.state.new_userdata_typed();
-            ptr::write(fp, Box::new(f));
+            let fp: *mut Rc<CallbackFn> = self.state.new_userdata_typed();
+            ptr::write(fp, Rc::new(f));
         };
+        self.state.set_metatable_from_registry(CALLBACK_MT);
+    }
+
+    /* Keep `f` with the state, and push its index in `registered`. */
+    fn push_registered_fn(&mut self, f: CallbackFn) {
+        self.shared.registered.push(Rc::new(f));
+        let id = self.shared.registered.len() - 1;
+        self.state.push_integer(id as lua::Integer);
+    }
+
+    /* Replace the callback on top of the stack, either a CallbackFn
+     * userdata or the index of a registered one, with a Lua function
+     * which calls it. */
+    fn wrap_callback_fn(&mut self, name: &str) {
+        self.state.push_light_userdata(self.shared.ptr as *mut c_void);
+        self.state.rotate(-2, 1);
+        self.state.push_string(name);
         /* Load the shim generator */
-        self.state.push_closure(lua_func!(::RumLua::lua_func_wrapper), 2);
-        self.state.raw_geti(lua::REGISTRYINDEX, self.lua_func_shim.value() as lua::Integer);
+        self.state.push_closure(lua_func!(::RumLua::lua_func_wrapper), 3);
+        self.state.raw_geti(lua::REGISTRYINDEX, self.shared.lua_func_shim.value() as lua::Integer);
         self.state.rotate(-2, 1);
         self.state.push(name);
         self.state.pcall(2, 1, 0);
     }
+
+    /* Push `f` as a C closure which raises its errors itself, without
+     * the shim's extra Lua calls. */
+    fn push_raw_closure(&mut self, f: CallbackFn, name: &str) {
+        self.state.push_light_userdata(self.shared.ptr as *mut c_void);
+        self.push_registered_fn(f);
+        self.state.push_string(name);
+        unsafe { lua::ffi::lua_pushcclosure(self.state.as_ptr(), Some(raw_callback), 3) };
+    }
+
+    /// Set the global `name` to `f`, called directly rather than through
+    /// the Lua shim which other callbacks use, which makes calls cheaper.
+    /// Errors are reported in the same way, but `f` cannot yield.
+    pub fn register_raw(&mut self, name: &str, f: Callback) {
+        self.push_raw_closure(CallbackFn::Plain(f), name);
+        self.state.set_global(name);
+    }
+
+    /// Push a Rust closure onto the stack as a Lua function.  `name` is
+    /// used in error messages.
+    pub fn push_closure<F>(&mut self, name: &str, f: F)
+                  where F: FnMut(&mut RumLua) -> LuaRet + 'static
+    {
+        self._push_closure(CallbackFn::Boxed(RefCell::new(Box::new(f))), name);
+    }
+
+    /// Return the values on the stack from `from_index` to the top, such
+    /// as the trailing arguments of a callback.
+    pub fn varargs(&mut self, from_index: Index) -> Result<Vec<Value>, LuaError> {
+        let top = self.state.get_top();
+        let mut values = Vec::new();
+        for index in from_index..(top + 1) {
+            values.push(Value::from_lua(self, index)?);
+        }
+        Ok(values)
+    }
+
+    /// Convert argument `n` of the running callback, with an error like
+    /// "bad argument #2 to 'set' (string expected, got nil)" if it
+    /// doesn't convert.
+    pub fn check_arg<T: FromLua>(&mut self, n: Index) -> Result<T, LuaError> {
+        match T::from_lua(self, n) {
+            Err(LuaError::ConversionError(msg)) => Err(self.arg_error(n, &msg)),
+            result => result,
+        }
+    }
+
+    /// As `check_arg`, but return `default` if argument `n` is nil or
+    /// missing.
+    pub fn opt_arg<T: FromLua>(&mut self, n: Index, default: T) -> Result<T, LuaError> {
+        if self.state.is_none_or_nil(n) {
+            Ok(default)
+        } else {
+            self.check_arg(n)
+        }
+    }
+
+    /// Whether the running callback was passed argument `n`, even if it
+    /// is nil.  Missing arguments convert as nil, such as to `None`.
+    pub fn has_arg(&mut self, n: Index) -> bool {
+        n >= 1 && n <= self.state.get_top()
+    }
+
+    /// Check that the running callback has at least `min` arguments, and
+    /// at most `max` if given.
+    pub fn check_arg_count(&mut self, min: usize, max: Option<usize>) -> Result<(), LuaError> {
+        let count = self.state.get_top() as usize;
+        if count < min {
+            return Err(self.arg_error((count + 1) as Index, "value expected"));
+        }
+        match max {
+            Some(max) if count > max => {
+                let name = self.callback_name();
+                Err(LuaError::ConversionError(format!("wrong number of arguments to '{}' (at most {} expected, got {})",
+                                                      name, max, count)))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn arg_error(&mut self, n: Index, msg: &str) -> LuaError {
+        let name = self.callback_name();
+        LuaError::ConversionError(format!("bad argument #{} to '{}' ({})", n, name, msg))
+    }
+
+    /* The name given to the running callback, from its closure. */
+    fn callback_name(&mut self) -> String {
+        if !self.in_callback {
+            return "?".to_string();
+        }
+        match self.state.to_str(lua::ffi::lua_upvalueindex(3)) {
+            Some(name) => name.to_string(),
+            None => "?".to_string(),
+        }
+    }
+
+    /// Push a Rust closure onto the stack as a Lua function, converting
+    /// its arguments from Lua and its results back.
+    pub fn push_function<A, R, F>(&mut self, name: &str, mut f: F)
+                  where A: FromLuaMulti, R: ToLuaMulti,
+                        F: FnMut(&mut RumLua, A) -> Result<R, LuaError> + 'static
+    {
+        self.push_closure(name, move |rl| {
+            let nargs = rl.state.get_top();
+            let args = A::from_lua_multi(rl, nargs)?;
+            let results = f(rl, args)?;
+            let nresults = results.to_lua_multi(rl)?;
+            Ok(nresults as isize)
+        });
+    }
+
+    /// Push a Lua function returning the items of `iter` in turn and then
+    /// nil, for use in a generic `for` loop.  The iterator is advanced only
+    /// as the loop runs, and dropped once it is exhausted.
+    pub fn push_iterator<I>(&mut self, iter: I)
+                  where I: Iterator + 'static, I::Item: ToLuaMulti
+    {
+        let mut iter = Some(iter);
+        self.push_closure("iterator", move |rl| {
+            let item = match iter.as_mut() {
+                Some(iter) => iter.next(),
+                None => None,
+            };
+            match item {
+                Some(item) => Ok(item.to_lua_multi(rl)? as isize),
+                None => {
+                    iter = None;
+                    rl.state.push_nil();
+                    Ok(1)
+                },
+            }
+        });
+    }
+
+    /// Call `f` with the converted payload whenever `event` is emitted,
+    /// by `emit` or by `rum.emit(event, ...)` in Lua.  Handlers added by
+    /// `rum.on` in Lua are called alongside, in the order all were added.
+    pub fn on<A, F>(&mut self, event: &str, f: F) -> HandlerId
+                  where A: FromLuaMulti, F: FnMut(&mut RumLua, A) -> Result<(), LuaError> + 'static
+    {
+        events::on(self, event, false, f)
+    }
+
+    /// As `on`, but the handler is removed when it is first called.
+    pub fn once<A, F>(&mut self, event: &str, f: F) -> HandlerId
+                  where A: FromLuaMulti, F: FnMut(&mut RumLua, A) -> Result<(), LuaError> + 'static
+    {
+        events::on(self, event, true, f)
+    }
+
+    /// Remove a handler, returning false if it was already gone.
+    pub fn off(&mut self, id: HandlerId) -> bool {
+        events::off(self, id)
+    }
+
+    /// Call every handler of `event` with `payload`, and return how many
+    /// there were.  A handler's error doesn't stop the others being
+    /// called, but the first is returned afterwards.
+    pub fn emit<A: ToLuaMulti>(&mut self, event: &str, payload: A) -> Result<usize, LuaError> {
+        let values = value::to_values(self, payload)?;
+        events::emit(self, event, values)
+    }
+
+    /// Create a channel holding up to `capacity` values, returning the
+    /// sending half for feeding values from other threads, and pushing the
+    /// receiving half onto the stack.  That is a table with methods
+    /// `recv()`, which returns the next value, or nil once every sender has
+    /// been dropped, and `try_recv()`, which returns nil rather than wait.
+    /// Inside a coroutine `recv` yields while the channel is empty, to be
+    /// resumed later, and elsewhere it blocks.
+    pub fn create_channel<T: ToLua + Send + 'static>(&mut self, capacity: usize) -> SyncSender<T> {
+        channel::create(self, capacity)
+    }
+
+    /// Set the tokio runtime which runs the futures of async functions.
+    /// While a task waits for one, the thread must not block the runtime,
+    /// so a multi-threaded runtime is needed for `run_async`.
+    #[cfg(feature = "async")]
+    pub fn set_async_handle(&mut self, handle: tokio::runtime::Handle) {
+        self.shared.tasks.set_handle(handle);
+    }
+
+    /// Push an async Rust function onto the stack as a Lua function.  When
+    /// called from a task started by `spawn_async` or `run_async`, `f` is
+    /// given the converted arguments and returns a future, which is spawned
+    /// on the runtime while the task yields.  The task is resumed with the
+    /// future's results, or raises its error, once it completes.
+    #[cfg(feature = "async")]
+    pub fn push_async_function<A, R, E, F, Fut>(&mut self, name: &str, f: F)
+                  where A: FromLuaMulti, R: ToLuaMulti + Send + 'static, E: Error + Send + Sync + 'static,
+                        F: FnMut(&mut RumLua, A) -> Fut + 'static,
+                        Fut: std::future::Future<Output = Result<R, E>> + Send + 'static
+    {
+        tasks::push_function(self, name, f)
+    }
+
+    /// Start a task running `f` in a new coroutine, which runs until it
+    /// first waits for an async function.  Its results are discarded.
+    #[cfg(feature = "async")]
+    pub fn spawn_async<A: ToLuaMulti>(&mut self, f: &LuaFunction, args: A) -> Result<(), LuaError> {
+        tasks::spawn(self, f, args)
+    }
+
+    /// Resume the tasks whose async calls have completed, without waiting
+    /// for any, and return how many tasks are unfinished.  A task's error
+    /// is returned, and ends that task.
+    #[cfg(feature = "async")]
+    pub fn poll_async(&mut self) -> Result<usize, LuaError> {
+        tasks::poll(self)
+    }
+
+    /// Run `f` as a task, blocking until it finishes and returning its
+    /// results.  Other tasks are resumed meanwhile, and their errors are
+    /// returned too.
+    #[cfg(feature = "async")]
+    pub fn run_async<A, R>(&mut self, f: &LuaFunction, args: A) -> Result<R, LuaError>
+                  where A: ToLuaMulti, R: FromLuaMulti
+    {
+        tasks::run(self, f, args)
+    }
+
+    /// Push a Rust closure which can only be called once; later calls
+    /// raise a Lua error.
+    pub fn push_closure_once<F>(&mut self, name: &str, f: F)
+                  where F: FnOnce(&mut RumLua) -> LuaRet + 'static
+    {
+        let mut f = Some(f);
+        self.push_closure(name, move |rl| {
+            match f.take() {
+                Some(f) => f(rl),
+                None => lfail("Function can only be called once"),
+            }
+        });
+    }
+
+    /// Register the type `T` with methods from `typeinfo`.  Fails if `T`
+    /// or `mt_name` is already registered.
     pub fn register_type<T>(&mut self,
                             mt_name: String,
-                            typeinfo: &'static LuaType)
+                            typeinfo: &'static LuaType) -> Result<(), LuaError>
                   where T: Any
     {
-        if self.types_str_to_id.contains_key(&mt_name) {
-            panic!("Illegally re-registered type. {}", mt_name);
-        }
+        self.register_type_builder(TypeBuilder::<T>::new(&mt_name).methods(typeinfo))
+    }
 
-        /* Create the metatable */
-        self.state.new_metatable(&mt_name);
-        self._push_closure(generic_gc::<T>, "__gc");
-        self.state.set_field(-2, "__gc");
+    /// Register the type `T` with methods from `typeinfo`, and from the
+    /// already registered type `B`.  Userdata of type `T` can also be
+    /// used where a `B` is expected.
+    pub fn register_type_with_base<T, B>(&mut self,
+                                         mt_name: String,
+                                         typeinfo: &'static LuaType) -> Result<(), LuaError>
+                  where T: Any + Inherits<B>, B: Any
+    {
+        self.register_type_builder(TypeBuilder::<T>::new(&mt_name).methods(typeinfo).base::<B>())
+    }
 
-        for &(name, f) in typeinfo.methods {
-            self._push_closure(f, name);
-            self.state.set_field(-2, name);
-        }
-        // And set the metatable as its own __index
-        self.state.set_field(-1, "__index");
+    /// Register the type `T` as described by `builder`.  Fails if `T` or
+    /// its name is already registered, or if its base type isn't.
+    pub fn register_type_builder<T>(&mut self, builder: TypeBuilder<T>) -> Result<(), LuaError>
+                  where T: Any
+    {
+        types::register(self, builder)
+    }
+
+    /// Register `T` as described by `builder`, but named `name`, failing
+    /// if either is already registered.  This suits generic types, whose
+    /// builder can be written once with generic methods and registered
+    /// under a different name for each instantiation:
+    ///
+    /// ```ignore
+    /// rl.register_type_as::<Grid<Tile>>("Grid<Tile>", grid_type::<Tile>())?;
+    /// rl.register_type_as::<Grid<Item>>("Grid<Item>", grid_type::<Item>())?;
+    /// ```
+    pub fn register_type_as<T: Any>(&mut self, name: &str, builder: TypeBuilder<T>) -> Result<(), LuaError> {
+        types::register_as(self, name, builder)
+    }
+
+    /// Forget the registered type `T`, so that it can be registered again.
+    /// Existing userdata of the type lose their methods and can no longer
+    /// be converted to `T`, but are still dropped when collected.
+    pub fn unregister_type<T: Any>(&mut self) -> Result<(), LuaError> {
+        types::unregister::<T>(self)
+    }
+
+    /// As `register_type_builder`, but replacing `T` if it is already
+    /// registered, as by `unregister_type`.  Fails if another type was
+    /// registered with the same name.
+    pub fn register_type_or_replace<T: Any>(&mut self, builder: TypeBuilder<T>) -> Result<(), LuaError> {
+        types::register_or_replace(self, builder)
+    }
+
+    /// The names and Rust types of the registered userdata types, sorted
+    /// by name.
+    pub fn registered_types(&self) -> Vec<(String, TypeId)> {
+        let mut types: Vec<(String, TypeId)> = self.shared.types_str_to_id.iter()
+            .map(|(name, id)| (name.clone(), *id))
+            .collect();
+        types.sort_by(|a, b| a.0.cmp(&b.0));
+        types
+    }
 
-        self.types_str_to_id.insert(mt_name.clone(), TypeId::of::<T>());
-        self.types_id_to_str.insert(TypeId::of::<T>(), mt_name);
+    /// Whether `T` has been registered as a userdata type.
+    pub fn is_registered<T: Any>(&self) -> bool {
+        self.shared.types_id_to_str.contains_key(&TypeId::of::<T>())
+    }
+
+    /// The name `T` was registered with, which is also the name of its
+    /// metatable in the registry.
+    pub fn metatable_name<T: Any>(&self) -> Option<String> {
+        self.shared.types_id_to_str.get(&TypeId::of::<T>()).cloned()
+    }
+
+    /// Publish the Rust enum `E` to Lua as the global table `name`, of
+    /// read-only constants numbering the variants from 1 (so
+    /// `Color.RED == 1`).  Use `lua_enum!` to convert `E` itself.
+    pub fn register_enum<E: Any + Clone>(&mut self, name: &str, variants: &[(&str, E)]) -> Result<(), LuaError> {
+        enums::register(self, name, variants)
+    }
+
+    /// Push the number of a variant of a registered enum.
+    pub fn push_enum<E: Any + PartialEq>(&mut self, value: &E) -> Result<(), LuaError> {
+        enums::push(self, value)
+    }
+
+    /// Convert a variant of a registered enum from its number or name.
+    pub fn get_enum<E: Any + Clone>(&mut self, index: c_int) -> Result<E, LuaError> {
+        enums::get(self, index)
+    }
+
+    /// Publish the bit flags type `F` to Lua as the global table `name`, of
+    /// read-only constants for the flags and the functions `has(value,
+    /// flags)`, `set(value, flags)` and `clear(value, flags)`.  Flags
+    /// convert from an integer, a flag's name or an array of names, and
+    /// to an integer.  Use `lua_flags!` to implement `LuaFlags`.
+    pub fn register_flags<F: LuaFlags>(&mut self, name: &str, flags: &[(&str, F)]) -> Result<(), LuaError> {
+        flags::register(self, name, flags)
+    }
+
+    /// Push registered flags as an integer.
+    pub fn push_flags<F: LuaFlags>(&mut self, flags: &F) -> Result<(), LuaError> {
+        flags::push(self, flags)
+    }
+
+    /// Convert registered flags from an integer, a name or an array of names.
+    pub fn get_flags<F: LuaFlags>(&mut self, index: c_int) -> Result<F, LuaError> {
+        flags::get(self, index)
     }
 
     pub fn register_func_table(&mut self,
@@ -290,58 +1930,429 @@ impl<'a> RumLua<'a> {
         self.state.new_table();
 
         for (name, f) in funcs {
-            self._push_closure(f, name);
+            self.push_registered_closure(CallbackFn::Plain(f), name);
+            self.state.set_field(-2, &name);
+        }
+        // And save the table to a global
+        self.state.set_global(table_name);
+    }
+
+    /// As `register_func_table`, with C functions among the callbacks, as
+    /// when porting a C module a function at a time.
+    pub fn register_mixed_func_table(&mut self,
+                                     table_name: &str,
+                                     funcs: Vec<(&str, TableFunction)>) {
+        self.state.new_table();
+
+        for (name, f) in funcs {
+            match f {
+                TableFunction::Rust(f) => self.push_registered_closure(CallbackFn::Plain(f), name),
+                TableFunction::C(f) => self.state.push_fn(Some(f)),
+            }
+            self.state.set_field(-2, &name);
+        }
+        self.state.set_global(table_name);
+    }
+
+    /// Set the global `name` to the C function `f`, which Lua calls
+    /// directly, so that it works as it did in a C module: errors it
+    /// raises unwind with `longjmp`, and none of this crate's callback
+    /// handling applies.
+    pub fn register_c_function(&mut self, name: &str, f: CFunction) {
+        self.state.push_fn(Some(f));
+        self.state.set_global(name);
+    }
+
+    /// As `register_func_table`, but with closures which may capture
+    /// state.
+    pub fn register_closure_table(&mut self,
+                                  table_name: &str,
+                                  funcs: Vec<(&str, BoxedCallback)>) {
+        self.state.new_table();
+
+        for (name, f) in funcs {
+            self.push_registered_closure(CallbackFn::Boxed(RefCell::new(f)), name);
             self.state.set_field(-2, &name);
         }
         // And save the table to a global
         self.state.set_global(table_name);
     }
 
-    pub fn push<'b, T>(&mut self, objp: &LuaPtr<T>) where T:Any, T:'b {
+    /// Make `require(name)` call `loader` the first time the module is
+    /// required, with the module name as its argument.  The value it
+    /// returns becomes the module.
+    pub fn preload_module(&mut self, name: &str, loader: Callback) {
+        self.push_preload_table();
+        self.push_registered_closure(CallbackFn::Plain(loader), name);
+        self.state.set_field(-2, name);
+        self.state.pop(1);
+    }
+
+    /// Make `require(name)` run the Lua chunk `source` the first time the
+    /// module is required, such as a file embedded with `include_str!`.
+    /// The chunk is compiled straight away, so syntax errors are found
+    /// here.
+    pub fn preload_lua_module(&mut self, name: &str, source: &str) -> Result<(), LuaError> {
+        self.load_buffer(source.as_bytes(), &format!("={}", name), "t")?;
+        self.push_preload_table();
+        self.state.rotate(-2, 1);
+        self.state.set_field(-2, name);
+        self.state.pop(1);
+        Ok(())
+    }
+
+    /// Load scripts for `require` and `loadfile` from `source` instead of
+    /// the filesystem.  `require` still finds preloaded modules first, and
+    /// then tries `source` before the usual searchers.  Fails if the
+    /// package library isn't open.
+    pub fn set_script_source<S: ScriptSource + 'static>(&mut self, source: S) -> Result<(), LuaError> {
+        loader::install(self, Rc::new(source))
+    }
+
+    /* Push package.preload, which the package library keeps in the
+     * registry; create it if the library isn't open. */
+    fn push_preload_table(&mut self) {
+        if self.state.get_field(lua::REGISTRYINDEX, "_PRELOAD") != lua::Type::Table {
+            self.state.pop(1);
+            self.state.new_table();
+            self.state.push_value(-1);
+            self.state.set_field(lua::REGISTRYINDEX, "_PRELOAD");
+        }
+    }
+
+    pub fn push<'b, T>(&mut self, objp: &LuaPtr<T>) -> Result<(), LuaError> where T:Any, T:'b {
+        self.push_payload::<T>(Box::new((*objp).clone()))
+    }
+
+    /// Push a `LuaWeak` as a userdata of its registered type.  Getting it
+    /// back as a `LuaPtr` fails once the value has been dropped.
+    pub fn push_weak<T: Any>(&mut self, objp: &LuaWeak<T>) -> Result<(), LuaError> {
+        self.push_payload::<T>(Box::new(objp.clone()))
+    }
+
+    /// Push an `ArcPtr` as a userdata of its registered type.
+    pub fn push_arc<T: Any>(&mut self, objp: &ArcPtr<T>) -> Result<(), LuaError> {
+        self.push_payload::<T>(Box::new(objp.clone()))
+    }
+
+    /// Get the `ArcPtr` from the userdata at `index`, which must have been
+    /// pushed by `push_arc`.
+    pub fn get_arc<T: Any>(&mut self, index: Index) -> Result<ArcPtr<T>, LuaError> {
+        let (obj, upcast) = self.userdata_payload::<T>(index)?;
+        let obj = unsafe { &*obj };
+        match (obj, upcast) {
+            (&None, _) => Err(LuaError::TypeError("Called method on GCed object".to_string())),
+            (&Some(ref bx), None) => match bx.downcast_ref::<ArcPtr<T>>() {
+                Some(p) => Ok(p.clone()),
+                None => Err(LuaError::TypeError("Userdata does not contain the expected type".to_string())),
+            },
+            (_, Some(_)) => Err(LuaError::TypeError("Userdata does not contain the expected type".to_string())),
+        }
+    }
+
+    /* Push a userdata of registered type `T`, holding `payload`. */
+    fn push_payload<T: Any>(&mut self, payload: Box<dyn Any>) -> Result<(), LuaError> {
         let id = TypeId::of::<T>();
-        let p: *mut Option<Box<Any>> = self.state.new_userdata_typed();
-        let r = Some(Box::new((*objp).clone()) as Box<Any>);
-        unsafe { ptr::write(p, r) };
-        self.state.set_metatable_from_registry(&self.types_id_to_str[&id]);
+        if !self.shared.types_id_to_str.contains_key(&id) {
+            return Err(LuaError::TypeError("Attempt to push a value of an unregistered type".to_string()));
+        }
+        let p: *mut Option<Box<dyn Any>> = self.state.new_userdata_typed();
+        unsafe { ptr::write(p, Some(payload)) };
+        self.state.set_metatable_from_registry(&self.shared.types_id_to_str[&id]);
+        if let Some(live) = self.shared.live_userdata.get(&id) {
+            live.set(live.get() + 1);
+        }
+        Ok(())
+    }
+
+    /* Find the payload of the userdata of type `T` at `index`.  It stays
+     * valid while the userdata is on the stack.  If the userdata is of a
+     * type derived from `T`, the upcast to apply is also returned.  An
+     * instance of a Lua class derived from `T` gives its base object's
+     * payload, which stays valid while the instance is on the stack. */
+    fn userdata_payload<T: Any>(&mut self, index: Index)
+                  -> Result<(*mut Option<Box<dyn Any>>, Option<types::Upcast>), LuaError> {
+        if class::push_base(self, index) {
+            let result = self.userdata_payload::<T>(-1);
+            self.state.pop(1);
+            return result;
+        }
+        let id = TypeId::of::<T>();
+        if !self.shared.types_id_to_str.contains_key(&id) {
+            return Err(LuaError::TypeError("Attempt to get a value of an unregistered type".to_string()));
+        }
+        let obj: Option<&mut Option<Box<dyn Any>>> = unsafe { self.state.test_userdata_typed::<Option<Box<dyn Any>>>(index, &self.shared.types_id_to_str[&id]) };
+        if let Some(p) = obj {
+            return Ok((p as *mut Option<Box<dyn Any>>, None));
+        }
+        let derived = self.shared.upcasts.get(&id).cloned().unwrap_or(Vec::new());
+        for (name, upcast) in derived {
+            let obj: Option<&mut Option<Box<dyn Any>>> = unsafe { self.state.test_userdata_typed::<Option<Box<dyn Any>>>(index, &name) };
+            if let Some(p) = obj {
+                return Ok((p as *mut Option<Box<dyn Any>>, Some(upcast)));
+            }
+        }
+        Err(LuaError::TypeError("Error getting object from stack".to_string()))
     }
+
     pub fn get<'ret, 'rl, T: Any>(&'rl mut self, index: Index) -> Result<LuaPtr<T>, LuaError>
                    where 'rl: 'ret, T: 'ret
     {
-        let id = TypeId::of::<T>();
-        if !self.types_id_to_str.contains_key(&id) {
-            panic!("Unknown type!");
-        }
-        let obj: Option<&mut Option<Box<Any>>> = unsafe { self.state.test_userdata_typed::<Option<Box<Any>>>(index, &self.types_id_to_str[&id]) };
-        println!("get(): obj={:?}, &obj={:p}", obj, &obj);
-        match obj {
-            Some(&mut None) => {
-                Err(Box::new(LError{message: "Called method on GCed object".to_string()}))
+        let (obj, upcast) = self.userdata_payload::<T>(index)?;
+        let obj = unsafe { &*obj };
+        match *obj {
+            None => {
+                Err(LuaError::TypeError("Called method on GCed object".to_string()))
+            },
+            Some(ref bx) => {
+                let base;
+                let bx = match upcast {
+                    Some(upcast) => { base = upcast(&**bx)?; &base },
+                    None => bx,
+                };
+                match strong_ptr::<T>(bx) {
+                    Some(rxf) => rxf,
+                    _ => Err(LuaError::TypeError("Userdata does not contain the expected type".to_string())),
+                }
+            },
+        }
+    }
+
+    /// Remove the value from the userdata of type `T` at `index`, and
+    /// return it if Lua held the only `LuaPtr` to it.  The userdata can
+    /// no longer be used from Lua.
+    pub fn take<T: Any>(&mut self, index: Index) -> Result<T, LuaError> {
+        let (obj, upcast) = self.userdata_payload::<T>(index)?;
+        if upcast.is_some() {
+            return Err(LuaError::TypeError("Userdata does not contain the expected type".to_string()));
+        }
+        let obj = unsafe { &mut *obj };
+        let ptr = match obj.take() {
+            None => return Err(LuaError::TypeError("Called method on GCed object".to_string())),
+            Some(bx) => match bx.downcast::<LuaPtr<T>>() {
+                Ok(ptr) => *ptr,
+                Err(bx) => {
+                    *obj = Some(bx);
+                    return Err(LuaError::TypeError("Userdata does not contain the expected type".to_string()));
+                },
+            },
+        };
+        match ptr.try_unwrap() {
+            Ok(value) => Ok(value),
+            Err(ptr) => {
+                *obj = Some(Box::new(ptr));
+                Err(LuaError::BorrowError("Userdata is shared with Rust, so can't be taken".to_string()))
+            },
+        }
+    }
+
+    /// Call `f` with a reference to the userdata of type `T` at `index`,
+    /// which may be a `LuaPtr<T>` or a reference exposed with a `Scope`.
+    pub fn with_ref<T, R, F>(&mut self, index: Index, f: F) -> Result<R, LuaError>
+                  where T: Any, F: FnOnce(&T) -> R
+    {
+        let (obj, upcast) = self.userdata_payload::<T>(index)?;
+        let obj = unsafe { &*obj };
+        match *obj {
+            None => Err(LuaError::TypeError("Userdata used after it was collected or its scope ended".to_string())),
+            Some(ref bx) => {
+                let base;
+                let bx = match upcast {
+                    Some(upcast) => { base = upcast(&**bx)?; &base },
+                    None => bx,
+                };
+                if let Some(p) = strong_ptr::<T>(bx) {
+                    /* Keep the object alive even if Lua drops it meanwhile */
+                    let p = p?;
+                    let result = p.try_borrow().map(|obj| f(&*obj));
+                    result
+                } else if let Some(p) = bx.downcast_ref::<ArcPtr<T>>() {
+                    let p = p.clone();
+                    let result = p.lock().map(|obj| f(&*obj));
+                    result
+                } else if let Some(r) = bx.downcast_ref::<ScopedRef<T>>() {
+                    r.with_ref(f)
+                } else {
+                    Err(LuaError::TypeError("Userdata does not contain the expected type".to_string()))
+                }
             },
-            Some(&mut Some(ref bx)) => {
-                match bx.downcast_ref::<LuaPtr<T>>() {
-                    Some(rxf) => Ok(rxf.clone()),
-                    _ => panic!("downcast error"),//None,
+        }
+    }
+
+    /// As `with_ref`, but with a mutable reference.
+    pub fn with_mut<T, R, F>(&mut self, index: Index, f: F) -> Result<R, LuaError>
+                  where T: Any, F: FnOnce(&mut T) -> R
+    {
+        let (obj, upcast) = self.userdata_payload::<T>(index)?;
+        let obj = unsafe { &*obj };
+        match *obj {
+            None => Err(LuaError::TypeError("Userdata used after it was collected or its scope ended".to_string())),
+            Some(ref bx) => {
+                let base;
+                let bx = match upcast {
+                    Some(upcast) => { base = upcast(&**bx)?; &base },
+                    None => bx,
+                };
+                if let Some(p) = strong_ptr::<T>(bx) {
+                    let p = p?;
+                    let result = p.try_borrow_mut().map(|mut obj| f(&mut *obj));
+                    result
+                } else if let Some(p) = bx.downcast_ref::<ArcPtr<T>>() {
+                    let p = p.clone();
+                    let result = p.lock().map(|mut obj| f(&mut *obj));
+                    result
+                } else if let Some(r) = bx.downcast_ref::<ScopedRef<T>>() {
+                    r.with_mut(f)
+                } else {
+                    Err(LuaError::TypeError("Userdata does not contain the expected type".to_string()))
                 }
             },
-            _ => Err(Box::new(LError{message: "Error getting object from stack".to_string()})),
         }
     }
+
+    /// Attach `value` to the userdata at `index`, such as a table of
+    /// Lua-side data for the object.  It is collected with the userdata.
+    pub fn set_uservalue<V: ToLua>(&mut self, index: Index, value: V) -> Result<(), LuaError> {
+        let index = self.state.abs_index(index);
+        if self.state.type_of(index) != Some(lua::Type::Userdata) {
+            return convert::conversion_error(self, index, "userdata");
+        }
+        value.to_lua(self)?;
+        self.state.set_uservalue(index);
+        Ok(())
+    }
+
+    /// Get the value attached to the userdata at `index` with
+    /// `set_uservalue`, which is nil if none has been set.
+    pub fn get_uservalue<V: FromLua>(&mut self, index: Index) -> Result<V, LuaError> {
+        if self.state.type_of(index) != Some(lua::Type::Userdata) {
+            return convert::conversion_error(self, index, "userdata");
+        }
+        self.state.get_uservalue(index);
+        let result = V::from_lua(self, -1);
+        self.state.pop(1);
+        result
+    }
+
+    /// Push `p` as a light userdata: a bare pointer, which Lua can store
+    /// and compare but never frees or dereferences, as C libraries use to
+    /// pass opaque handles.
+    pub fn push_light<T>(&mut self, p: *mut T) {
+        self.state.push_light_userdata(p as *mut c_void);
+    }
+
+    /// Get the pointer from the light userdata at `index`.  Lua keeps no
+    /// type with light userdata, so this can't check that the pointer is
+    /// to a `T`, nor that it is still valid.
+    pub fn to_light<T>(&mut self, index: Index) -> Result<*mut T, LuaError> {
+        if !self.state.is_light_userdata(index) {
+            return convert::conversion_error(self, index, "light userdata");
+        }
+        Ok(self.state.to_userdata(index) as *mut T)
+    }
+
+    /// Call `f` with a reference to the value the light userdata at
+    /// `index` points to.  A null pointer gives an error.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be to a live, aligned `T`, which nothing else uses
+    /// until `f` returns.  Nothing checks this: Lua code can pass in any
+    /// light userdata, including ones pushed for a different type.
+    pub unsafe fn with_light<T, R, F>(&mut self, index: Index, f: F) -> Result<R, LuaError>
+                  where F: FnOnce(&mut T) -> R
+    {
+        let p = self.to_light::<T>(index)?;
+        if p.is_null() {
+            return Err(LuaError::TypeError("light userdata is a null pointer".to_string()));
+        }
+        Ok(f(&mut *p))
+    }
+
+    /// Run `f` with a `Scope`, through which Rust values which don't live
+    /// for `'static` can be exposed to Lua.  When `f` returns, anything
+    /// exposed through the scope is invalidated, so Lua can no longer
+    /// reach it.
+    pub fn scope<'scope, F, R>(&mut self, f: F) -> R
+                  where F: FnOnce(&mut RumLua<'a>, &Scope<'scope>) -> R
+    {
+        let scope = scope::new_scope();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(self, &scope)));
+        scope::invalidate(&scope, self);
+        match result {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+/* The LuaPtr held by a userdata payload, directly or by a LuaWeak. */
+fn strong_ptr<T: Any>(payload: &Box<dyn Any>) -> Option<Result<LuaPtr<T>, LuaError>> {
+    if let Some(p) = payload.downcast_ref::<LuaPtr<T>>() {
+        Some(Ok(p.clone()))
+    } else if let Some(w) = payload.downcast_ref::<LuaWeak<T>>() {
+        Some(w.upgrade().ok_or_else(|| {
+            LuaError::TypeError("Userdata refers to a value which has been dropped".to_string())
+        }))
+    } else {
+        None
+    }
 }
 
+/* The C function for raw callbacks.  lua_error doesn't return, so it is
+ * only called once nothing with a destructor is left. */
+unsafe extern "C" fn raw_callback(l: *mut lua::ffi::lua_State) -> c_int {
+    let mut state = lua::State::from_ptr(l);
+    let num_results = RumLua::raw_func_wrapper(&mut state);
+    drop(state);
+    if num_results < 0 {
+        lua::ffi::lua_error(l)
+    } else {
+        num_results
+    }
+}
+
+/* Continuation for yielding callbacks: returns the values the coroutine
+ * was resumed with, as the successful results of the call.  `ctx` is
+ * the height of the callback's own stack.
+ */
+unsafe extern "C" fn yield_continuation(l: *mut lua::ffi::lua_State, _status: c_int,
+                                        ctx: lua::ffi::lua_KContext) -> c_int {
+    let nargs = lua::ffi::lua_gettop(l) - ctx as c_int;
+    lua::ffi::lua_pushboolean(l, 1);
+    lua::ffi::lua_rotate(l, -nargs-1, 1);
+    nargs + 1
+}
+
+/* rum.types(): the names of the registered userdata types. */
+fn rum_types(rl: &mut RumLua) -> LuaRet {
+    let names: Vec<String> = rl.registered_types().into_iter().map(|(name, _)| name).collect();
+    names.to_lua(rl)?;
+    Ok(1)
+}
+
+/* Describe a panic payload for an error message. */
+fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "(unknown panic payload)".to_string()
+    }
+}
 
-fn generic_gc<T: Any>(rl: &mut RumLua) -> LuaRet {
-    let id = TypeId::of::<T>();
-    let typename = &rl.types_id_to_str[&id];
-    let obj : Option<&mut Option<Box<Any>>> = unsafe { rl.state.test_userdata_typed::<Option<Box<Any>>>(1, typename) };
-    match obj {
-        None => {
-            println!("Error in generic_gc: failed to match item");
-        },
-        Some(p_ref) => {
-            let mut tmp = None;
-            unsafe { ptr::swap(p_ref, &mut tmp as *mut Option<Box<Any>>) };
-        },
+fn generic_gc(rl: &mut RumLua, live: &Cell<usize>) -> LuaRet {
+    /* Not checked by name, as the type may have been unregistered or
+     * replaced since the userdata was made. */
+    if !types::has_payload(rl, 1) {
+        rum_log!(error, "generic_gc: userdata has no payload");
+        return Ok(0);
     }
+    let p = rl.state.to_userdata(1) as *mut Option<Box<dyn Any>>;
+    let old = unsafe { (*p).take() };
+    drop(old);
+    live.set(live.get().saturating_sub(1));
     Ok(0)
 }
 
@@ -0,0 +1,84 @@
+/* Loading scripts from an application-provided source */
+use ::{RumLua, LuaError, ToLua, lerror};
+use lua;
+use std::ptr;
+use std::rc::Rc;
+
+/// Somewhere scripts can be loaded from by `require` and `loadfile`,
+/// such as an archive or a database; see `RumLua::set_script_source`.
+pub trait ScriptSource {
+    /// Return the contents of the script `name`, or `None` if there is no
+    /// such script.
+    fn load(&self, name: &str) -> Option<Vec<u8>>;
+}
+
+impl<F: Fn(&str) -> Option<Vec<u8>>> ScriptSource for F {
+    fn load(&self, name: &str) -> Option<Vec<u8>> {
+        self(name)
+    }
+}
+
+/* Add a searcher for `source` to package.searchers, after the preload
+ * searcher, and replace loadfile with one which uses it. */
+pub fn install(rl: &mut RumLua, source: Rc<dyn ScriptSource>) -> Result<(), LuaError> {
+    let base = rl.state.get_top();
+    let found = rl.state.get_field(lua::REGISTRYINDEX, "_LOADED") == lua::Type::Table &&
+                rl.state.get_field(-1, "package") == lua::Type::Table &&
+                rl.state.get_field(-1, "searchers") == lua::Type::Table;
+    if !found {
+        rl.state.set_top(base);
+        return Err(lerror("The package library is not open"));
+    }
+    let len = rl.state.raw_len(-1) as lua::Integer;
+    for i in (2..(len + 1)).rev() {
+        rl.state.raw_geti(-1, i);
+        rl.state.raw_seti(-2, i + 1);
+    }
+    let searcher_source = source.clone();
+    rl.push_closure("searcher", move |rl| {
+        let name: String = rl.check_arg(1)?;
+        match searcher_source.load(&name) {
+            Some(bytes) => {
+                rl.load_buffer(&bytes, &format!("@{}", name), "bt")?;
+                name.to_lua(rl)?;
+                Ok(2)
+            },
+            None => {
+                format!("\n\tno script '{}' in the script source", name).to_lua(rl)?;
+                Ok(1)
+            },
+        }
+    });
+    rl.state.raw_seti(-2, 2);
+    rl.state.set_top(base);
+
+    rl.push_closure("loadfile", move |rl| {
+        let name: String = rl.check_arg(1)?;
+        let mode: String = rl.opt_arg(2, "bt".to_string())?;
+        let bytes = match source.load(&name) {
+            Some(bytes) => bytes,
+            None => {
+                rl.state.push_nil();
+                format!("cannot open {}", name).to_lua(rl)?;
+                return Ok(2);
+            },
+        };
+        if let Err(e) = rl.load_buffer(&bytes, &format!("@{}", name), &mode) {
+            rl.state.push_nil();
+            e.to_string().to_lua(rl)?;
+            return Ok(2);
+        }
+        if !rl.state.is_none(3) {
+            /* As with the standard loadfile, the third argument becomes
+             * the chunk's _ENV. */
+            rl.state.push_value(3);
+            let name = unsafe { lua::ffi::lua_setupvalue(rl.state.as_ptr(), -2, 1) };
+            if name == ptr::null() {
+                rl.state.pop(1);
+            }
+        }
+        Ok(1)
+    });
+    rl.state.set_global("loadfile");
+    Ok(())
+}
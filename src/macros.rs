@@ -0,0 +1,172 @@
+/* Macros for exposing Rust types to Lua */
+
+/// Declare a `LuaType` whose methods call methods of a Rust type, with
+/// the arguments and results converted automatically:
+///
+/// ```ignore
+/// rum_methods! {
+///     static POINT_METHODS for Point {
+///         fn norm(&self) -> f64;
+///         fn scale(&mut self, by: f64);
+///         fn describe(&self, prefix: &str) -> String;
+///     }
+/// }
+/// ...
+/// rl.register_type::<Point>("Point".to_string(), &POINT_METHODS)?;
+/// ```
+///
+/// Arguments can be any `FromLua` type or `&str`, and results any
+/// `CallbackResult` type, so a method returning `Err` raises a Lua error.  The generated callbacks go in a module with the
+/// same name as the static.
+#[macro_export]
+macro_rules! rum_methods {
+    (static $name:ident for $t:ty { $($methods:tt)* }) => {
+        rum_methods!(@module [] $name, $t; $($methods)*);
+        rum_methods!(@table [] $name; []; $($methods)*);
+    };
+    (pub static $name:ident for $t:ty { $($methods:tt)* }) => {
+        rum_methods!(@module [pub] $name, $t; $($methods)*);
+        rum_methods!(@table [pub] $name; []; $($methods)*);
+    };
+
+    (@module [$($vis:tt)*] $name:ident, $t:ty; $($methods:tt)*) => {
+        #[allow(non_snake_case)]
+        $($vis)* mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+            rum_methods!(@fns $t; $($methods)*);
+        }
+    };
+
+    (@fns $t:ty; fn $m:ident(&self $(, $arg:ident : $aty:ty)*) $(-> $ret:ty)*; $($rest:tt)*) => {
+        pub fn $m(rl: &mut $crate::RumLua) -> $crate::LuaRet {
+            rum_methods!(@call rl, $t, with_ref, $m, $($arg: $aty),*)
+        }
+        rum_methods!(@fns $t; $($rest)*);
+    };
+    (@fns $t:ty; fn $m:ident(&mut self $(, $arg:ident : $aty:ty)*) $(-> $ret:ty)*; $($rest:tt)*) => {
+        pub fn $m(rl: &mut $crate::RumLua) -> $crate::LuaRet {
+            rum_methods!(@call rl, $t, with_mut, $m, $($arg: $aty),*)
+        }
+        rum_methods!(@fns $t; $($rest)*);
+    };
+    (@fns $t:ty;) => {};
+
+    (@call $rl:ident, $t:ty, $with:ident, $m:ident, $($arg:ident: $aty:ty),*) => {{
+        /* The object is argument 1 */
+        #[allow(unused_mut, unused_variables)]
+        let mut index = 1;
+        $(
+            index += 1;
+            let mut $arg = (<$aty as $crate::MethodArg>::get_arg($rl, index))?;
+        )*
+        let result = $rl.$with::<$t, _, _>(1, |obj| {
+            obj.$m($(<$aty as $crate::MethodArg>::pass_arg(&mut $arg)),*)
+        })?;
+        let count = $crate::CallbackResult::push_results(result, $rl)?;
+        Ok(count as isize)
+    }};
+
+    (@table [$($vis:tt)*] $name:ident; [$($acc:tt)*]; fn $m:ident($($args:tt)*) $(-> $ret:ty)*; $($rest:tt)*) => {
+        rum_methods!(@table [$($vis)*] $name; [$($acc)* (stringify!($m), $name::$m as $crate::Callback),]; $($rest)*);
+    };
+    (@table [$($vis:tt)*] $name:ident; [$($acc:tt)*];) => {
+        $($vis)* static $name: $crate::LuaType = $crate::LuaType {
+            methods: &[$($acc)*],
+        };
+    };
+}
+
+/// Implement `ToLua` and `FromLua` for an enum registered with
+/// `register_enum`, converting from either a variant's number or its name.
+///
+/// ```ignore
+/// lua_enum!(Color);
+/// rl.register_enum("Color", &[("RED", Color::Red), ("GREEN", Color::Green)])?;
+/// ```
+#[macro_export]
+macro_rules! lua_enum {
+    ($t:ty) => {
+        impl $crate::ToLua for $t {
+            fn to_lua(&self, rl: &mut $crate::RumLua) -> Result<(), $crate::LuaError> {
+                rl.push_enum(self)
+            }
+        }
+
+        impl $crate::FromLua for $t {
+            fn from_lua(rl: &mut $crate::RumLua, index: $crate::c_int) -> Result<Self, $crate::LuaError> {
+                rl.get_enum(index)
+            }
+        }
+    };
+}
+
+/// Implement `LuaFlags`, `ToLua` and `FromLua` for a type declared with
+/// `bitflags!`, to be registered with `register_flags`.
+///
+/// ```ignore
+/// lua_flags!(Perms);
+/// rl.register_flags("Perms", &[("READ", Perms::READ), ("WRITE", Perms::WRITE)])?;
+/// ```
+#[macro_export]
+macro_rules! lua_flags {
+    ($t:ty) => {
+        impl $crate::LuaFlags for $t {
+            fn to_bits(&self) -> i64 {
+                self.bits() as i64
+            }
+            fn from_bits(bits: i64) -> Option<Self> {
+                <$t>::from_bits(bits as _)
+            }
+        }
+
+        impl $crate::ToLua for $t {
+            fn to_lua(&self, rl: &mut $crate::RumLua) -> Result<(), $crate::LuaError> {
+                rl.push_flags(self)
+            }
+        }
+
+        impl $crate::FromLua for $t {
+            fn from_lua(rl: &mut $crate::RumLua, index: $crate::c_int) -> Result<Self, $crate::LuaError> {
+                rl.get_flags(index)
+            }
+        }
+    };
+}
+
+/// Embed Lua modules in the binary and preload them, so that `require`
+/// finds them without touching the filesystem.  Paths are relative to the
+/// file using the macro, as with `include_str!`.
+///
+/// ```ignore
+/// bundle!(rl, "util" => "lua/util.lua", "app.config" => "lua/config.lua")?;
+/// ```
+#[macro_export]
+macro_rules! bundle {
+    ($rl:expr, $($name:expr => $path:expr),* $(,)*) => {{
+        let rl: &mut $crate::RumLua = $rl;
+        let mut result: Result<(), $crate::LuaError> = Ok(());
+        $(
+            if result.is_ok() {
+                result = rl.preload_lua_module($name, include_str!($path));
+            }
+        )*
+        result
+    }};
+}
+
+/* Internal diagnostics, sent to the log crate with the "log" feature and
+ * otherwise compiled away (though the arguments are still checked). */
+#[cfg(feature = "log")]
+macro_rules! rum_log {
+    ($level:ident, $($arg:tt)*) => { $level!($($arg)*) };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! rum_log {
+    ($level:ident, $($arg:tt)*) => {
+        if false {
+            let _ = format!($($arg)*);
+        }
+    };
+}
@@ -0,0 +1,82 @@
+/* Lua allocator which can enforce a memory limit */
+use lua;
+use libc;
+use libc::{c_void, size_t};
+use std::collections::HashMap;
+use std::ptr;
+
+/// A snapshot of Lua's memory use; see `RumLua::memory_stats`.
+#[derive(Debug, Clone)]
+pub struct MemoryStats {
+    /// The number of bytes currently allocated by Lua.
+    pub bytes_used: usize,
+    /// How many blocks Lua has allocated, since the allocator was
+    /// installed.
+    pub allocations: u64,
+    /// How many blocks Lua has freed.
+    pub frees: u64,
+    /// The number of live userdata of each registered type, by name.
+    pub userdata: HashMap<String, usize>,
+}
+
+/* Allocator state, passed to the allocator as its userdata. */
+pub struct MemoryState {
+    pub used: usize,
+    pub limit: Option<usize>,
+    /* Set when an allocation is refused because of the limit, so the
+     * resulting error can be told apart from running out of memory. */
+    pub limit_hit: bool,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+/* Install the allocator on `state`, which must have been created with
+ * Lua's default (realloc based) allocator, as blocks are shared. */
+pub fn install(state: &mut lua::State) -> Box<MemoryState> {
+    let mut memory = Box::new(MemoryState {
+        used: 0,
+        limit: None,
+        limit_hit: false,
+        allocations: 0,
+        frees: 0,
+    });
+    unsafe {
+        let l = state.as_ptr();
+        let kbytes = lua::ffi::lua_gc(l, lua::ffi::LUA_GCCOUNT, 0) as usize;
+        let bytes = lua::ffi::lua_gc(l, lua::ffi::LUA_GCCOUNTB, 0) as usize;
+        memory.used = kbytes * 1024 + bytes;
+        let ud = &mut *memory as *mut MemoryState as *mut c_void;
+        lua::ffi::lua_setallocf(l, Some(limited_alloc), ud);
+    }
+    memory
+}
+
+unsafe extern "C" fn limited_alloc(ud: *mut c_void, p: *mut c_void,
+                                   osize: size_t, nsize: size_t) -> *mut c_void {
+    let memory = &mut *(ud as *mut MemoryState);
+    /* For new blocks, osize is the type of object instead. */
+    let old = if p.is_null() { 0 } else { osize as usize };
+    if nsize == 0 {
+        if !p.is_null() {
+            memory.frees += 1;
+        }
+        libc::free(p);
+        memory.used = memory.used.saturating_sub(old);
+        return ptr::null_mut();
+    }
+    let nsize = nsize as usize;
+    if let Some(limit) = memory.limit {
+        if nsize > old && memory.used.saturating_sub(old) + nsize > limit {
+            memory.limit_hit = true;
+            return ptr::null_mut();
+        }
+    }
+    let newp = libc::realloc(p, nsize as size_t);
+    if !newp.is_null() {
+        memory.used = memory.used.saturating_sub(old) + nsize;
+        if p.is_null() {
+            memory.allocations += 1;
+        }
+    }
+    newp
+}
@@ -0,0 +1,43 @@
+/* Counting calls to Rust callbacks */
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// How much one callback has been used; see `RumLua::callback_stats`.
+#[derive(Clone, Debug)]
+pub struct CallbackStats {
+    /// The name the callback was registered with, such as a method name.
+    pub name: String,
+    pub calls: u64,
+    /// How many calls returned an error or panicked.
+    pub errors: u64,
+    /// The wall time spent in the callback, including any Lua it called.
+    pub total: Duration,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    stats: HashMap<String, CallbackStats>,
+}
+
+impl Metrics {
+    pub fn record(&mut self, name: String, elapsed: Duration, failed: bool) {
+        let stats = self.stats.entry(name.clone()).or_insert_with(|| CallbackStats {
+            name: name,
+            calls: 0,
+            errors: 0,
+            total: Duration::new(0, 0),
+        });
+        stats.calls += 1;
+        if failed {
+            stats.errors += 1;
+        }
+        stats.total += elapsed;
+    }
+
+    /* The stats by decreasing total time. */
+    pub fn stats(&self) -> Vec<CallbackStats> {
+        let mut stats: Vec<CallbackStats> = self.stats.values().cloned().collect();
+        stats.sort_by(|a, b| (b.total, &a.name).cmp(&(a.total, &b.name)));
+        stats
+    }
+}
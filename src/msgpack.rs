@@ -0,0 +1,330 @@
+/* MessagePack encoding of Lua values, for moving them between states */
+use ::{RumLua, LuaError, FromLua};
+use convert::type_name;
+use string::{LuaString, push_bytes};
+use lua;
+use lua::Index;
+use libc::{c_int, c_void};
+use std::str;
+
+/* How deeply tables may be nested, when encoding or decoding. */
+const MAX_DEPTH: usize = 200;
+
+fn encode_error<T>(message: &str) -> Result<T, LuaError> {
+    Err(LuaError::ConversionError(format!("Error encoding MessagePack: {}", message)))
+}
+
+fn decode_error<T>(message: &str) -> Result<T, LuaError> {
+    Err(LuaError::ConversionError(format!("Error decoding MessagePack: {}", message)))
+}
+
+/* Append the low `len` bytes of `value`, most significant first. */
+fn write_be(out: &mut Vec<u8>, value: u64, len: usize) {
+    for i in (0..len).rev() {
+        out.push((value >> (i * 8)) as u8);
+    }
+}
+
+/* Write a length with the smallest of the given markers: the fixed
+ * marker for lengths below its limit, then the 8 (if any), 16 and 32 bit
+ * length markers. */
+fn write_len(out: &mut Vec<u8>, len: usize, fix: Option<(u8, usize)>,
+             markers: (Option<u8>, u8, u8)) -> Result<(), LuaError> {
+    match (fix, markers.0) {
+        (Some((fix, limit)), _) if len < limit => out.push(fix | len as u8),
+        (_, Some(m8)) if len <= 0xff => {
+            out.push(m8);
+            write_be(out, len as u64, 1);
+        },
+        _ if len <= 0xffff => {
+            out.push(markers.1);
+            write_be(out, len as u64, 2);
+        },
+        _ if len <= 0xffff_ffff => {
+            out.push(markers.2);
+            write_be(out, len as u64, 4);
+        },
+        _ => return encode_error("value is too long"),
+    }
+    Ok(())
+}
+
+fn write_int(out: &mut Vec<u8>, i: lua::Integer) {
+    if i >= 0 {
+        let u = i as u64;
+        if u < 0x80 {
+            out.push(u as u8);
+        } else if u <= 0xff {
+            out.push(0xcc);
+            write_be(out, u, 1);
+        } else if u <= 0xffff {
+            out.push(0xcd);
+            write_be(out, u, 2);
+        } else if u <= 0xffff_ffff {
+            out.push(0xce);
+            write_be(out, u, 4);
+        } else {
+            out.push(0xcf);
+            write_be(out, u, 8);
+        }
+    } else if i >= -32 {
+        out.push(i as u8);
+    } else if i >= -0x80 {
+        out.push(0xd0);
+        write_be(out, i as u64, 1);
+    } else if i >= -0x8000 {
+        out.push(0xd1);
+        write_be(out, i as u64, 2);
+    } else if i >= -0x8000_0000 {
+        out.push(0xd2);
+        write_be(out, i as u64, 4);
+    } else {
+        out.push(0xd3);
+        write_be(out, i as u64, 8);
+    }
+}
+
+/// Encode the value at `index` as MessagePack.  Tables whose keys are
+/// exactly 1 to n become arrays, and other tables become maps; strings
+/// which aren't valid UTF-8 become binary data.  Functions, userdata,
+/// threads and cyclic tables can't be encoded.
+pub fn encode(rl: &mut RumLua, index: Index) -> Result<Vec<u8>, LuaError> {
+    let index = rl.state.abs_index(index);
+    let base = rl.state.get_top();
+    let mut out = Vec::new();
+    let mut path = Vec::new();
+    let result = encode_value(rl, index, &mut out, &mut path);
+    rl.state.set_top(base);
+    result.map(|()| out)
+}
+
+fn encode_value(rl: &mut RumLua, index: Index, out: &mut Vec<u8>,
+                path: &mut Vec<*const c_void>) -> Result<(), LuaError> {
+    match rl.state.type_of(index) {
+        None | Some(lua::Type::None) | Some(lua::Type::Nil) => out.push(0xc0),
+        Some(lua::Type::Boolean) => out.push(if rl.state.to_bool(index) { 0xc3 } else { 0xc2 }),
+        Some(lua::Type::Number) => {
+            if rl.state.is_integer(index) {
+                write_int(out, rl.state.to_integer(index));
+            } else {
+                out.push(0xcb);
+                write_be(out, rl.state.to_number(index).to_bits(), 8);
+            }
+        },
+        Some(lua::Type::String) => {
+            let s = LuaString::from_lua(rl, index)?;
+            let bytes = s.as_bytes();
+            if str::from_utf8(bytes).is_ok() {
+                write_len(out, bytes.len(), Some((0xa0, 32)), (Some(0xd9), 0xda, 0xdb))?;
+            } else {
+                write_len(out, bytes.len(), None, (Some(0xc4), 0xc5, 0xc6))?;
+            }
+            out.extend_from_slice(bytes);
+        },
+        Some(lua::Type::Table) => encode_table(rl, index, out, path)?,
+        _ => {
+            let msg = format!("cannot encode a {}", type_name(rl, index));
+            return encode_error(&msg);
+        },
+    }
+    Ok(())
+}
+
+fn encode_table(rl: &mut RumLua, index: Index, out: &mut Vec<u8>,
+                path: &mut Vec<*const c_void>) -> Result<(), LuaError> {
+    let ptr = unsafe { lua::ffi::lua_topointer(rl.state.as_ptr(), index) };
+    if path.contains(&ptr) {
+        return encode_error("cannot encode a cyclic table");
+    }
+    if path.len() >= MAX_DEPTH || !rl.state.check_stack(4) {
+        return encode_error("tables are nested too deeply");
+    }
+    path.push(ptr);
+
+    /* Count the entries, and see whether they form a sequence. */
+    let mut count: usize = 0;
+    let mut max_key: lua::Integer = 0;
+    let mut sequence = true;
+    rl.state.push_nil();
+    while rl.state.next(index) {
+        count += 1;
+        if sequence && rl.state.is_integer(-2) && rl.state.to_integer(-2) >= 1 {
+            max_key = ::std::cmp::max(max_key, rl.state.to_integer(-2));
+        } else {
+            sequence = false;
+        }
+        rl.state.pop(1);
+    }
+    sequence = sequence && count > 0 && max_key as usize == count;
+
+    if sequence {
+        write_len(out, count, Some((0x90, 16)), (None, 0xdc, 0xdd))?;
+        for i in 1..(count + 1) {
+            rl.state.raw_geti(index, i as lua::Integer);
+            let top = rl.state.get_top();
+            encode_value(rl, top, out, path)?;
+            rl.state.pop(1);
+        }
+    } else {
+        write_len(out, count, Some((0x80, 16)), (None, 0xde, 0xdf))?;
+        rl.state.push_nil();
+        while rl.state.next(index) {
+            let top = rl.state.get_top();
+            encode_value(rl, top - 1, out, path)?;
+            encode_value(rl, top, out, path)?;
+            rl.state.pop(1);
+        }
+    }
+    path.pop();
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], LuaError> {
+        if self.data.len() - self.pos < len {
+            return decode_error("unexpected end of data");
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, LuaError> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn uint(&mut self, len: usize) -> Result<u64, LuaError> {
+        let bytes = self.bytes(len)?;
+        Ok(bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
+
+    /* Read a length, checking that at least that many more bytes remain
+     * so that a corrupt length can't cause a huge allocation. */
+    fn len(&mut self, size: usize) -> Result<usize, LuaError> {
+        let len = self.uint(size)? as usize;
+        if len > self.data.len() - self.pos {
+            return decode_error("unexpected end of data");
+        }
+        Ok(len)
+    }
+}
+
+/// Decode MessagePack data produced by `encode` (or anything else using
+/// the types it produces), and push the value.  Integers too large for
+/// Lua become floats.
+pub fn decode(rl: &mut RumLua, data: &[u8]) -> Result<(), LuaError> {
+    let base = rl.state.get_top();
+    let mut reader = Reader { data: data, pos: 0 };
+    let result = decode_value(rl, &mut reader, 0).and_then(|()| {
+        if reader.pos == data.len() {
+            Ok(())
+        } else {
+            decode_error("unexpected data after the value")
+        }
+    });
+    if result.is_err() {
+        rl.state.set_top(base);
+    }
+    result
+}
+
+fn decode_value(rl: &mut RumLua, r: &mut Reader, depth: usize) -> Result<(), LuaError> {
+    if depth >= MAX_DEPTH || !rl.state.check_stack(3) {
+        return decode_error("tables are nested too deeply");
+    }
+    let marker = r.byte()?;
+    match marker {
+        0x00..=0x7f => rl.state.push_integer(marker as lua::Integer),
+        0x80..=0x8f => decode_map(rl, r, (marker & 0x0f) as usize, depth)?,
+        0x90..=0x9f => decode_array(rl, r, (marker & 0x0f) as usize, depth)?,
+        0xa0..=0xbf => {
+            let bytes = r.bytes((marker & 0x1f) as usize)?;
+            push_bytes(rl, bytes);
+        },
+        0xc0 => rl.state.push_nil(),
+        0xc2 => rl.state.push_bool(false),
+        0xc3 => rl.state.push_bool(true),
+        0xc4 | 0xd9 => {
+            let len = r.len(1)?;
+            push_bytes(rl, r.bytes(len)?);
+        },
+        0xc5 | 0xda => {
+            let len = r.len(2)?;
+            push_bytes(rl, r.bytes(len)?);
+        },
+        0xc6 | 0xdb => {
+            let len = r.len(4)?;
+            push_bytes(rl, r.bytes(len)?);
+        },
+        0xca => {
+            let bits = r.uint(4)? as u32;
+            rl.state.push_number(f32::from_bits(bits) as lua::Number);
+        },
+        0xcb => {
+            let bits = r.uint(8)?;
+            rl.state.push_number(f64::from_bits(bits));
+        },
+        0xcc => rl.state.push_integer(r.uint(1)? as lua::Integer),
+        0xcd => rl.state.push_integer(r.uint(2)? as lua::Integer),
+        0xce => rl.state.push_integer(r.uint(4)? as lua::Integer),
+        0xcf => {
+            let u = r.uint(8)?;
+            if u > lua::Integer::max_value() as u64 {
+                rl.state.push_number(u as lua::Number);
+            } else {
+                rl.state.push_integer(u as lua::Integer);
+            }
+        },
+        0xd0 => rl.state.push_integer(r.uint(1)? as i8 as lua::Integer),
+        0xd1 => rl.state.push_integer(r.uint(2)? as i16 as lua::Integer),
+        0xd2 => rl.state.push_integer(r.uint(4)? as i32 as lua::Integer),
+        0xd3 => rl.state.push_integer(r.uint(8)? as i64 as lua::Integer),
+        0xdc => {
+            let len = r.len(2)?;
+            decode_array(rl, r, len, depth)?;
+        },
+        0xdd => {
+            let len = r.len(4)?;
+            decode_array(rl, r, len, depth)?;
+        },
+        0xde => {
+            let len = r.len(2)?;
+            decode_map(rl, r, len, depth)?;
+        },
+        0xdf => {
+            let len = r.len(4)?;
+            decode_map(rl, r, len, depth)?;
+        },
+        0xe0..=0xff => rl.state.push_integer(marker as i8 as lua::Integer),
+        _ => return decode_error(&format!("unsupported type 0x{:02x}", marker)),
+    }
+    Ok(())
+}
+
+fn decode_array(rl: &mut RumLua, r: &mut Reader, len: usize, depth: usize) -> Result<(), LuaError> {
+    rl.state.create_table(len as c_int, 0);
+    for i in 1..(len + 1) {
+        decode_value(rl, r, depth + 1)?;
+        rl.state.raw_seti(-2, i as lua::Integer);
+    }
+    Ok(())
+}
+
+fn decode_map(rl: &mut RumLua, r: &mut Reader, len: usize, depth: usize) -> Result<(), LuaError> {
+    rl.state.create_table(0, len as c_int);
+    for _ in 0..len {
+        decode_value(rl, r, depth + 1)?;
+        /* Lua raises an error for these keys, which can't happen here. */
+        if rl.state.is_nil(-1) || (rl.state.type_of(-1) == Some(lua::Type::Number) && rl.state.to_number(-1).is_nan()) {
+            return decode_error("map key is nil or NaN");
+        }
+        decode_value(rl, r, depth + 1)?;
+        rl.state.raw_set(-3);
+    }
+    Ok(())
+}
@@ -0,0 +1,85 @@
+/* Sending print and io.write output to the host */
+use ::{RumLua, LuaError, LuaFunction, FromLua, ToLua, Value, lerror};
+use convert::type_name;
+use string::LuaString;
+use lua;
+use lua::Index;
+
+/* Replace the global print with one which passes each line, without the
+ * newline, to `handler`. */
+pub fn install_print(rl: &mut RumLua, mut handler: Box<dyn FnMut(&str)>) {
+    rl.push_closure("print", move |rl| {
+        let line = print_line(rl)?;
+        handler(&line);
+        Ok(0)
+    });
+    rl.state.set_global("print");
+}
+
+/* Replace io.write with one which passes its text to `handler`. */
+pub fn install_write(rl: &mut RumLua, mut handler: Box<dyn FnMut(&str)>) -> Result<(), LuaError> {
+    let io = match rl.get_global_value::<Value>("io") {
+        Ok(Value::Table(io)) => io,
+        _ => return Err(lerror("The io library is not open")),
+    };
+    /* Returned as the standard io.write does, for chaining. */
+    let stdout: Value = io.get(rl, "stdout")?;
+    rl.push_closure("io.write", move |rl| {
+        let mut text = String::new();
+        for i in 1..(rl.state.get_top() + 1) {
+            text.push_str(&write_arg(rl, i)?);
+        }
+        handler(&text);
+        stdout.to_lua(rl)?;
+        Ok(1)
+    });
+    let write = LuaFunction::from_lua(rl, -1)?;
+    rl.state.pop(1);
+    io.set(rl, "write", write)
+}
+
+/* The arguments of the running callback converted with the global
+ * tostring and separated by tabs, as the standard print writes them. */
+fn print_line(rl: &mut RumLua) -> Result<String, LuaError> {
+    let count = rl.state.get_top();
+    let tostring: LuaFunction = match rl.get_global_value("tostring") {
+        Ok(f) => f,
+        Err(_) => return Err(lerror("'tostring' must be a function")),
+    };
+    let mut line = String::new();
+    for i in 1..(count + 1) {
+        let value = Value::from_lua(rl, i)?;
+        let s: LuaString = match tostring.call(rl, value) {
+            Err(LuaError::ConversionError(_)) => {
+                return Err(lerror("'tostring' must return a string to 'print'"));
+            },
+            result => result?,
+        };
+        if i > 1 {
+            line.push('\t');
+        }
+        line.push_str(&String::from_utf8_lossy(s.as_bytes()));
+    }
+    Ok(line)
+}
+
+/* Argument `i` of io.write, which must be a string or a number. */
+fn write_arg(rl: &mut RumLua, i: Index) -> Result<String, LuaError> {
+    match rl.state.type_of(i) {
+        Some(lua::Type::String) => {
+            let s = LuaString::from_lua(rl, i)?;
+            Ok(String::from_utf8_lossy(s.as_bytes()).into_owned())
+        },
+        Some(lua::Type::Number) => {
+            /* Convert a copy, leaving the argument a number. */
+            rl.state.push_value(i);
+            let s = rl.state.to_str(-1).unwrap_or("").to_string();
+            rl.state.pop(1);
+            Ok(s)
+        },
+        _ => {
+            let msg = format!("string expected, got {}", type_name(rl, i));
+            Err(rl.arg_error(i, &msg))
+        },
+    }
+}
@@ -0,0 +1,112 @@
+/* A pool of states on worker threads, for running scripts in parallel */
+use ::{RumLua, LuaError, ToLuaMulti, FromLuaMulti, lfail};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce(&mut RumLua) + Send>;
+
+/// A fixed number of independent states, each on its own thread and
+/// prepared by the same setup function, which take jobs from a shared
+/// queue.  A `RumLua` can't be used from more than one thread, but a pool
+/// can be.  Dropping the pool waits for the queued jobs to finish.
+pub struct StatePool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl StatePool {
+    /// Start `size` workers, each creating a state and passing it to
+    /// `setup`, and wait until they are ready.  If setup fails for any of
+    /// them, the first error is returned.
+    pub fn new<F>(size: usize, setup: F) -> Result<StatePool, LuaError>
+                  where F: Fn(&mut RumLua) -> Result<(), LuaError> + Send + Sync + 'static
+    {
+        let setup = Arc::new(setup);
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let (ready_sender, ready) = channel();
+        let mut workers = Vec::with_capacity(size);
+        for _ in 0..size {
+            let setup = setup.clone();
+            let receiver = receiver.clone();
+            let ready_sender = ready_sender.clone();
+            workers.push(thread::spawn(move || {
+                let mut rl = RumLua::new();
+                let result = setup(&mut rl);
+                let ok = result.is_ok();
+                let _ = ready_sender.send(result);
+                if !ok {
+                    return;
+                }
+                loop {
+                    /* The queue is locked only while waiting for a job. */
+                    let job = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    let job = match job {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    if panic::catch_unwind(AssertUnwindSafe(|| job(&mut rl))).is_err() {
+                        /* The state may be inconsistent, so start afresh. */
+                        rl = RumLua::new();
+                        if setup(&mut rl).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }));
+        }
+        drop(ready_sender);
+        /* If setup fails, dropping the pool stops the other workers. */
+        let pool = StatePool {
+            sender: Some(sender),
+            workers: workers,
+        };
+        for _ in 0..size {
+            match ready.recv() {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return lfail("worker stopped during setup"),
+            }
+        }
+        Ok(pool)
+    }
+
+    /// Queue a job which loads `source` as a chunk and calls it with
+    /// `args`, on whichever state is free first.  Its results, or error,
+    /// arrive on the returned receiver, which is instead disconnected if
+    /// the job panics.
+    pub fn execute<A, R>(&self, source: &str, args: A) -> Receiver<Result<R, LuaError>>
+                  where A: ToLuaMulti + Send + 'static, R: FromLuaMulti + Send + 'static
+    {
+        let (reply, results) = channel();
+        let source = source.to_string();
+        let job: Job = Box::new(move |rl: &mut RumLua| {
+            let result = rl.load_bytes(source.as_bytes(), "job").and_then(|f| f.call(rl, args));
+            let _ = reply.send(result);
+        });
+        if let Some(ref sender) = self.sender {
+            let _ = sender.send(job);
+        }
+        results
+    }
+
+    /// The number of workers.
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+}
+
+impl Drop for StatePool {
+    fn drop(&mut self) {
+        /* The workers stop once the queue is empty and closed. */
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,204 @@
+/* Profiling scripts with the debug hook */
+use ::{RumLua, HookTriggers, HookEvent, DebugInfo};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Time spent in one function; see `Profiler::functions`.
+#[derive(Clone, Debug)]
+pub struct FunctionStats {
+    /// The function's name and where it was defined, such as
+    /// `update (game/ai.lua:12)`.
+    pub name: String,
+    pub calls: u64,
+    /// Time from calls to returns, including other functions called.
+    pub total: Duration,
+    /// Time not spent in other functions.
+    pub self_time: Duration,
+}
+
+/// Time spent on one line; see `Profiler::lines`.
+#[derive(Clone, Debug)]
+pub struct LineStats {
+    pub source: String,
+    pub line: u32,
+    /// How many times the line was started.
+    pub hits: u64,
+    /// Time from starting the line to starting the next one, including
+    /// any functions it called which have no lines of their own.
+    pub time: Duration,
+}
+
+struct Frame {
+    name: String,
+    started: Instant,
+    child_time: Duration,
+}
+
+#[derive(Default)]
+struct ProfileData {
+    stack: Vec<Frame>,
+    functions: HashMap<String, FunctionStats>,
+    lines: HashMap<(String, u32), LineStats>,
+    /* Self time by stack, as "outer;inner" */
+    stacks: HashMap<String, Duration>,
+    last_line: Option<((String, u32), Instant)>,
+}
+
+impl ProfileData {
+    fn event(&mut self, info: DebugInfo) {
+        let now = Instant::now();
+        match info.event {
+            HookEvent::Call => self.call(&info, now),
+            HookEvent::TailCall => {
+                /* The caller is replaced, and gets no return event. */
+                self.ret(now);
+                self.call(&info, now);
+            },
+            HookEvent::Return => self.ret(now),
+            HookEvent::Line => self.line(info, now),
+            HookEvent::Count => {},
+        }
+    }
+
+    fn call(&mut self, info: &DebugInfo, now: Instant) {
+        let name = frame_name(info);
+        self.functions.entry(name.clone()).or_insert_with(|| FunctionStats {
+            name: name.clone(),
+            calls: 0,
+            total: Duration::new(0, 0),
+            self_time: Duration::new(0, 0),
+        }).calls += 1;
+        self.stack.push(Frame {
+            name: name,
+            started: now,
+            child_time: Duration::new(0, 0),
+        });
+    }
+
+    fn ret(&mut self, now: Instant) {
+        /* Functions already running when profiling started aren't on
+         * the stack. */
+        let path = self.stack.iter().map(|f| &f.name[..]).collect::<Vec<_>>().join(";");
+        let frame = match self.stack.pop() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let total = now.duration_since(frame.started);
+        let self_time = if total > frame.child_time { total - frame.child_time } else { Duration::new(0, 0) };
+        if let Some(stats) = self.functions.get_mut(&frame.name) {
+            stats.total += total;
+            stats.self_time += self_time;
+        }
+        *self.stacks.entry(path).or_insert(Duration::new(0, 0)) += self_time;
+        if let Some(parent) = self.stack.last_mut() {
+            parent.child_time += total;
+        }
+    }
+
+    fn line(&mut self, info: DebugInfo, now: Instant) {
+        self.finish_line(now);
+        if let Some(line) = info.current_line {
+            let key = (info.source, line);
+            self.lines.entry(key.clone()).or_insert_with(|| LineStats {
+                source: key.0.clone(),
+                line: line,
+                hits: 0,
+                time: Duration::new(0, 0),
+            }).hits += 1;
+            self.last_line = Some((key, now));
+        }
+    }
+
+    fn finish_line(&mut self, now: Instant) {
+        if let Some((key, started)) = self.last_line.take() {
+            if let Some(stats) = self.lines.get_mut(&key) {
+                stats.time += now.duration_since(started);
+            }
+        }
+    }
+}
+
+fn frame_name(info: &DebugInfo) -> String {
+    let name = info.name.as_ref().map(|s| &s[..]).unwrap_or("?");
+    match &info.what[..] {
+        "main" => format!("main chunk ({})", info.source),
+        "C" => format!("{} [C]", name),
+        _ => format!("{} ({}:{})", name, info.source, info.line_defined.unwrap_or(0)),
+    }
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1000000 + (d.subsec_nanos() / 1000) as u64
+}
+
+/// Records the time spent in each function and on each line of the
+/// scripts run while it is installed, using the debug hook.  Calls and
+/// returns in coroutines are counted as if they ran on one stack.
+pub struct Profiler {
+    data: Rc<RefCell<ProfileData>>,
+}
+
+impl Profiler {
+    /// Start profiling the scripts run by `rl`.  This replaces any hook
+    /// set with `RumLua::set_hook`.
+    pub fn start(rl: &mut RumLua) -> Profiler {
+        let data = Rc::new(RefCell::new(ProfileData::default()));
+        let hook_data = data.clone();
+        rl.set_hook(HookTriggers{ on_call: true, on_return: true, every_line: true,
+                                  every_nth_instruction: None },
+                    Box::new(move |_rl, info| hook_data.borrow_mut().event(info)));
+        Profiler{ data: data }
+    }
+
+    /// Stop profiling, removing the hook.  Functions which are still
+    /// running are counted up to now.
+    pub fn stop(&self, rl: &mut RumLua) {
+        rl.remove_hook();
+        let mut data = self.data.borrow_mut();
+        let now = Instant::now();
+        data.finish_line(now);
+        while !data.stack.is_empty() {
+            data.ret(now);
+        }
+    }
+
+    /// The functions called, by decreasing self time.
+    pub fn functions(&self) -> Vec<FunctionStats> {
+        let mut functions: Vec<FunctionStats> = self.data.borrow().functions.values().cloned().collect();
+        functions.sort_by(|a, b| (b.self_time, &a.name).cmp(&(a.self_time, &b.name)));
+        functions
+    }
+
+    /// The lines run, by decreasing time.
+    pub fn lines(&self) -> Vec<LineStats> {
+        let mut lines: Vec<LineStats> = self.data.borrow().lines.values().cloned().collect();
+        lines.sort_by(|a, b| (b.time, &a.source, a.line).cmp(&(a.time, &b.source, b.line)));
+        lines
+    }
+
+    /// A table of the functions, by decreasing self time, with times in
+    /// microseconds.
+    pub fn flat_report(&self) -> String {
+        let mut report = format!("{:>10} {:>10} {:>8}  {}\n", "self us", "total us", "calls", "function");
+        for f in self.functions() {
+            report.push_str(&format!("{:>10} {:>10} {:>8}  {}\n",
+                                     micros(f.self_time), micros(f.total), f.calls, f.name));
+        }
+        report
+    }
+
+    /// The self time of each call stack in microseconds, in the "folded
+    /// stacks" format read by flame graph tools: `outer;inner 1234`.
+    pub fn folded_stacks(&self) -> String {
+        let data = self.data.borrow();
+        let mut stacks: Vec<(&String, &Duration)> = data.stacks.iter().collect();
+        stacks.sort();
+        let mut folded = String::new();
+        for (stack, time) in stacks {
+            folded.push_str(&format!("{} {}\n", stack, micros(*time)));
+        }
+        folded
+    }
+}
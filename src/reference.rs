@@ -0,0 +1,65 @@
+/* Values anchored in the Lua registry */
+use ::{RumLua, LuaError, ToLua, FromLua, lfail};
+use lua;
+use lua::Index;
+use libc::c_int;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+/* Registry slot, released when the last handle is dropped. */
+struct RefInner {
+    key: c_int,
+    unref_queue: Rc<RefCell<Vec<c_int>>>,
+}
+
+impl Drop for RefInner {
+    fn drop(&mut self) {
+        /* We can't touch the Lua state from here, so leave the slot for
+         * RumLua to release next time it creates a reference. */
+        self.unref_queue.borrow_mut().push(self.key);
+    }
+}
+
+/// A handle to a Lua value held in the registry, keeping it alive for as
+/// long as the handle (or any clone of it) exists.
+#[derive(Clone)]
+pub struct LuaRef {
+    inner: Rc<RefInner>,
+}
+
+impl LuaRef {
+    /// Anchor the value at `index` (leaving the stack unchanged).
+    pub fn new(rl: &mut RumLua, index: Index) -> LuaRef {
+        rl.release_refs();
+        rl.state.push_value(index);
+        let reference = rl.state.reference(lua::REGISTRYINDEX);
+        LuaRef {
+            inner: Rc::new(RefInner{
+                key: reference.value(),
+                unref_queue: rl.shared.unref_queue.clone(),
+            }),
+        }
+    }
+
+    /// Push the referenced value onto the stack.
+    pub fn push(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        if !Rc::ptr_eq(&self.inner.unref_queue, &rl.shared.unref_queue) {
+            return lfail("Lua value used with a different Lua state");
+        }
+        rl.state.raw_geti(lua::REGISTRYINDEX, self.inner.key as lua::Integer);
+        Ok(())
+    }
+}
+
+impl ToLua for LuaRef {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        self.push(rl)
+    }
+}
+
+impl FromLua for LuaRef {
+    /* Any value can be referenced */
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<LuaRef, LuaError> {
+        Ok(LuaRef::new(rl, index))
+    }
+}
@@ -0,0 +1,112 @@
+/* An interactive console, running Lua a line at a time */
+use ::{RumLua, LuaError, LuaFunction, MultiValue};
+use string::LuaString;
+use std::io::{self, BufRead, Write};
+
+/// A read-eval-print loop over a state, as the standalone `lua`
+/// interpreter gives.  Lines are gathered until they make a complete
+/// chunk, which is run as an expression if it is one, and otherwise as
+/// statements; a line starting with "=" is always an expression.
+pub struct Repl<'r, 'a: 'r> {
+    rl: &'r mut RumLua<'a>,
+    pending: Option<String>,
+    history: Vec<String>,
+}
+
+/* Whether a syntax error only means that the chunk is unfinished */
+fn incomplete(e: &LuaError) -> bool {
+    match *e {
+        LuaError::SyntaxError(ref msg) => msg.ends_with("<eof>"),
+        _ => false,
+    }
+}
+
+impl<'r, 'a> Repl<'r, 'a> {
+    pub fn new(rl: &'r mut RumLua<'a>) -> Repl<'r, 'a> {
+        Repl {
+            rl: rl,
+            pending: None,
+            history: Vec::new(),
+        }
+    }
+
+    /// Add `line` to the chunk being entered.  If that completes it, run
+    /// it and return its results converted with `tostring`; if more lines
+    /// are needed, return `None`.
+    pub fn feed(&mut self, line: &str) -> Result<Option<Vec<String>>, LuaError> {
+        let chunk = match self.pending.take() {
+            Some(pending) => pending + "\n" + line,
+            None => line.to_string(),
+        };
+        let f = match self.load(&chunk) {
+            Err(ref e) if incomplete(e) => {
+                self.pending = Some(chunk);
+                return Ok(None);
+            },
+            result => {
+                if !chunk.trim().is_empty() {
+                    self.history.push(chunk);
+                }
+                result?
+            },
+        };
+        let results: MultiValue = f.call(self.rl, ())?;
+        let tostring: LuaFunction = self.rl.get_global_value("tostring")?;
+        let mut shown = Vec::with_capacity(results.0.len());
+        for value in results.0 {
+            let s: LuaString = tostring.call(self.rl, value)?;
+            shown.push(String::from_utf8_lossy(s.as_bytes()).into_owned());
+        }
+        Ok(Some(shown))
+    }
+
+    /* Compile `chunk` as an expression to print if it is one, and
+     * otherwise as statements. */
+    fn load(&mut self, chunk: &str) -> Result<LuaFunction, LuaError> {
+        if chunk.starts_with('=') {
+            return self.rl.load_bytes(format!("return {}", &chunk[1..]).as_bytes(), "=stdin");
+        }
+        match self.rl.load_bytes(format!("return {}", chunk).as_bytes(), "=stdin") {
+            Ok(f) => Ok(f),
+            Err(_) => self.rl.load_bytes(chunk.as_bytes(), "=stdin"),
+        }
+    }
+
+    /// Whether the chunk being entered needs more lines.
+    pub fn is_incomplete(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Drop the lines of an unfinished chunk.
+    pub fn cancel(&mut self) {
+        self.pending = None;
+    }
+
+    /// The prompt to show for the next line.
+    pub fn prompt(&self) -> &'static str {
+        if self.is_incomplete() { ">> " } else { "> " }
+    }
+
+    /// The chunks entered so far, with the lines of each joined by
+    /// newlines, for recalling them.
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Prompt on `output` for lines from `input` and run them, writing
+    /// results separated by tabs, and errors, until `input` runs out.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+        output.write_all(self.prompt().as_bytes())?;
+        output.flush()?;
+        for line in input.lines() {
+            match self.feed(&line?) {
+                Ok(Some(ref results)) if !results.is_empty() => writeln!(output, "{}", results.join("\t"))?,
+                Err(e) => writeln!(output, "{}", e)?,
+                _ => {},
+            }
+            output.write_all(self.prompt().as_bytes())?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+}
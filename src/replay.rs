@@ -0,0 +1,142 @@
+/* Recording the calls scripts make to Rust callbacks, and replaying them */
+use ::{RumLua, LuaError, lfail, lerror};
+use snapshot::{Writer, Reader};
+use libc::c_int;
+use std::io::{Read, Write};
+
+/* How a call ended */
+const RETURNED: u8 = 0;
+const YIELDED: u8 = 1;
+const FAILED: u8 = 2;
+
+/* Each call is logged as a frame: its length, then the callback's name,
+ * the arguments (also length-prefixed, so they can be skipped), and how
+ * it ended followed by its results or error message.  Values are encoded
+ * as in snapshots. */
+pub enum CallLog {
+    Record(Box<dyn Write>),
+    Replay(Box<dyn Read>),
+}
+
+/* Start a frame with the running callback's name and arguments, if
+ * recording. */
+pub fn record_args(rl: &mut RumLua) -> Option<Writer> {
+    match rl.shared.call_log {
+        Some(CallLog::Record(_)) => {},
+        _ => return None,
+    }
+    let codecs = rl.shared.snapshot_codecs.clone();
+    let mut frame = Writer::new(codecs.clone());
+    frame.bytes(rl.callback_name().as_bytes());
+    let mut args = Writer::new(codecs);
+    let nargs = rl.state.get_top();
+    args.u32(nargs as u32);
+    for index in 1..(nargs + 1) {
+        if let Err(e) = args.value(rl, index) {
+            rum_log!(warn, "can't record call to '{}': {}", rl.callback_name(), e);
+            return None;
+        }
+    }
+    frame.bytes(&args.out);
+    Some(frame)
+}
+
+/* Finish the frame with the outcome, whose results are on top of the
+ * stack, and write it out. */
+pub fn record(rl: &mut RumLua, mut frame: Writer, result: &Result<isize, LuaError>, yielded: bool) {
+    match *result {
+        Ok(nresults) => {
+            frame.out.push(if yielded { YIELDED } else { RETURNED });
+            frame.u32(nresults as u32);
+            let top = rl.state.get_top();
+            for index in (top - nresults as c_int + 1)..(top + 1) {
+                if let Err(e) = frame.value(rl, index) {
+                    rum_log!(warn, "can't record call to '{}': {}", rl.callback_name(), e);
+                    return;
+                }
+            }
+        },
+        Err(ref e) => {
+            frame.out.push(FAILED);
+            frame.bytes(e.to_string().as_bytes());
+        },
+    }
+    let mut framed = Writer::new(Vec::new());
+    framed.bytes(&frame.out);
+    let failed = match rl.shared.call_log {
+        Some(CallLog::Record(ref mut out)) => out.write_all(&framed.out).is_err(),
+        _ => false,
+    };
+    if failed {
+        rum_log!(warn, "can't write call log, so stopped recording");
+        rl.shared.call_log = None;
+    }
+}
+
+fn read_frame(input: &mut Box<dyn Read>) -> Result<Vec<u8>, LuaError> {
+    let mut len = [0u8; 4];
+    input.read_exact(&mut len).map_err(|_| lerror("no more calls were recorded"))?;
+    let len = len.iter().rev().fold(0, |n, &b| (n << 8) | b as u64);
+    /* Read as much as there is, rather than trusting the length. */
+    let mut frame = Vec::new();
+    input.by_ref().take(len).read_to_end(&mut frame).map_err(|_| lerror("call log is truncated"))?;
+    if frame.len() as u64 != len {
+        return Err(lerror("call log is truncated"));
+    }
+    Ok(frame)
+}
+
+/* Push the recorded results of a call to `name`, leaving the stack as it
+ * was if they can't be read. */
+fn serve(rl: &mut RumLua, name: &str, frame: &[u8]) -> Result<(Result<isize, String>, bool), LuaError> {
+    let top = rl.state.get_top();
+    let result = push_results(rl, name, frame);
+    if result.is_err() {
+        rl.state.set_top(top);
+    }
+    result
+}
+
+fn push_results(rl: &mut RumLua, name: &str, frame: &[u8]) -> Result<(Result<isize, String>, bool), LuaError> {
+    rl.ensure_stack(1)?;
+    rl.state.new_table();
+    let objects = rl.state.get_top();
+    let codecs = rl.shared.snapshot_codecs.clone();
+    let mut reader = Reader::new(frame, objects, codecs);
+    let recorded = reader.bytes()?;
+    if recorded != name.as_bytes() {
+        return lfail(&format!("a call to '{}' was recorded here", String::from_utf8_lossy(recorded)));
+    }
+    /* The arguments aren't checked, as tables may be in any order. */
+    reader.bytes()?;
+    let outcome = match reader.byte()? {
+        FAILED => (Err(String::from_utf8_lossy(reader.bytes()?).into_owned()), false),
+        ended @ RETURNED | ended @ YIELDED => {
+            let nresults = reader.u32()?;
+            for _ in 0..nresults {
+                reader.next_value(rl)?;
+            }
+            (Ok(nresults as isize), ended == YIELDED)
+        },
+        _ => return lfail("call log is corrupt"),
+    };
+    if !reader.done() {
+        return lfail("call log is corrupt");
+    }
+    rl.state.remove(objects);
+    Ok(outcome)
+}
+
+/* If replaying, push the recorded results of the running callback's next
+ * call instead of running it, and return its outcome. */
+pub fn replay(rl: &mut RumLua) -> Option<(Result<isize, String>, bool)> {
+    let frame = match rl.shared.call_log {
+        Some(CallLog::Replay(ref mut input)) => read_frame(input),
+        _ => return None,
+    };
+    let name = rl.callback_name();
+    match frame.and_then(|frame| serve(rl, &name, &frame)) {
+        Ok(outcome) => Some(outcome),
+        Err(e) => Some((Err(format!("Replaying call to '{}': {}", name, e)), false)),
+    }
+}
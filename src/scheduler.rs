@@ -0,0 +1,284 @@
+/* Cooperative multitasking of Lua coroutines */
+use ::{RumLua, LuaError, LuaRet, LuaFunction, LuaThread, Resume, Value, MultiValue, CallbackFn,
+       ToLuaMulti};
+use value::to_values;
+use libc::c_void;
+use std::time::Duration;
+
+/* Marks the values yielded by rum.sleep and friends, by address. */
+static REQUEST_KEY: u8 = 0;
+
+fn request_key() -> *mut c_void {
+    &REQUEST_KEY as *const u8 as *mut c_void
+}
+
+/// Identifies a task of a `Scheduler`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct TaskId(u64);
+
+/* What a task is waiting for */
+enum Wait {
+    /* To be resumed on the next tick */
+    Ready,
+    Until(Duration),
+    Event(String),
+}
+
+struct Task {
+    id: TaskId,
+    thread: LuaThread,
+    wait: Wait,
+    /* What to resume it with */
+    args: Option<MultiValue>,
+}
+
+/* What a task asked for when it yielded */
+enum Request {
+    Yield,
+    Sleep(Duration),
+    WaitFor(String),
+    Signal(String, MultiValue),
+    Spawn(LuaFunction, MultiValue),
+}
+
+fn request(values: MultiValue) -> Request {
+    let mut values = values.0.into_iter();
+    match (values.next(), values.next()) {
+        (Some(Value::LightUserdata(p)), Some(Value::String(kind))) if p == request_key() => {
+            let first = values.next();
+            match (kind.as_str(), first) {
+                ("sleep", Some(Value::Integer(ms))) => Request::Sleep(Duration::from_millis(ms as u64)),
+                ("sleep", Some(Value::Number(ms))) => {
+                    Request::Sleep(Duration::new((ms / 1000.0) as u64, ((ms % 1000.0) * 1e6) as u32))
+                },
+                ("wait", Some(Value::String(event))) => Request::WaitFor(event),
+                ("signal", Some(Value::String(event))) => Request::Signal(event, MultiValue(values.collect())),
+                ("spawn", Some(Value::Function(f))) => Request::Spawn(f, MultiValue(values.collect())),
+                _ => Request::Yield,
+            }
+        },
+        _ => Request::Yield,
+    }
+}
+
+/// Runs Lua functions as tasks, each in its own coroutine, which take
+/// turns as the scheduler is ticked.  Tasks can call `rum.sleep(ms)` to
+/// wait for the scheduler's clock, `rum.wait_for(event)` to wait until the
+/// event is signalled (returning the values passed to `signal`),
+/// `rum.signal(event, ...)` and `rum.spawn(f, ...)`.  A task which calls
+/// `coroutine.yield()` is resumed on the next tick.
+pub struct Scheduler {
+    tasks: Vec<Task>,
+    time: Duration,
+    next_id: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            tasks: Vec::new(),
+            time: Duration::new(0, 0),
+            next_id: 0,
+        }
+    }
+
+    /// Add a task which calls `f` with `args`, starting on the next tick.
+    pub fn spawn<A: ToLuaMulti>(&mut self, rl: &mut RumLua, f: &LuaFunction, args: A) -> Result<TaskId, LuaError> {
+        let args = to_values(rl, args)?;
+        self.add(rl, f, args)
+    }
+
+    fn add(&mut self, rl: &mut RumLua, f: &LuaFunction, args: MultiValue) -> Result<TaskId, LuaError> {
+        let thread = rl.create_thread(f)?;
+        self.next_id += 1;
+        let id = TaskId(self.next_id);
+        self.tasks.push(Task{ id: id, thread: thread, wait: Wait::Ready, args: Some(args) });
+        Ok(id)
+    }
+
+    /// Wake the tasks waiting for `event`, which get `args` from
+    /// `rum.wait_for` on the next tick.  Returns how many were woken.
+    pub fn signal<A: ToLuaMulti>(&mut self, rl: &mut RumLua, event: &str, args: A) -> Result<usize, LuaError> {
+        let args = to_values(rl, args)?;
+        Ok(self.wake(event, args))
+    }
+
+    fn wake(&mut self, event: &str, args: MultiValue) -> usize {
+        let mut woken = 0;
+        for task in self.tasks.iter_mut() {
+            let waiting = match task.wait {
+                Wait::Event(ref e) => e == event,
+                _ => false,
+            };
+            if waiting {
+                task.wait = Wait::Ready;
+                task.args = Some(args.clone());
+                woken += 1;
+            }
+        }
+        woken
+    }
+
+    /// Advance the scheduler's clock by `dt`, and resume each task which
+    /// is ready or whose sleep is over.  A task which fails is removed, and
+    /// the first error is returned once the other tasks have had their
+    /// turn.
+    pub fn tick(&mut self, rl: &mut RumLua, dt: Duration) -> Result<(), LuaError> {
+        self.time += dt;
+        let time = self.time;
+        let due: Vec<TaskId> = self.tasks.iter().filter(|task| {
+            match task.wait {
+                Wait::Ready => true,
+                Wait::Until(t) => t <= time,
+                Wait::Event(_) => false,
+            }
+        }).map(|task| task.id).collect();
+        let mut first_error = None;
+        for id in due {
+            if let Err(e) = self.resume(rl, id) {
+                if first_error.is_none() {
+                    first_error = Some(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /* Resume a task until it waits for something or ends. */
+    fn resume(&mut self, rl: &mut RumLua, id: TaskId) -> Result<(), LuaError> {
+        loop {
+            let pos = match self.tasks.iter().position(|task| task.id == id) {
+                Some(pos) => pos,
+                None => return Ok(()),
+            };
+            let args = self.tasks[pos].args.take().unwrap_or(MultiValue(Vec::new()));
+            let thread = self.tasks[pos].thread.clone();
+            let values = match thread.resume::<_, MultiValue>(rl, args) {
+                Resume::Yielded(values) => values,
+                Resume::Finished(_) => {
+                    self.tasks.remove(pos);
+                    return Ok(());
+                },
+                Resume::Error(e) => {
+                    self.tasks.remove(pos);
+                    return Err(e);
+                },
+            };
+            self.tasks[pos].wait = match request(values) {
+                Request::Yield => Wait::Ready,
+                Request::Sleep(d) => Wait::Until(self.time + d),
+                Request::WaitFor(event) => Wait::Event(event),
+                /* These carry on straight away. */
+                Request::Signal(event, args) => {
+                    self.wake(&event, args);
+                    continue;
+                },
+                Request::Spawn(f, args) => {
+                    self.add(rl, &f, args)?;
+                    continue;
+                },
+            };
+            return Ok(());
+        }
+    }
+
+    /// Tick until every task has finished or is waiting for an event,
+    /// skipping the clock ahead over sleeps rather than waiting for them.
+    pub fn run_until_idle(&mut self, rl: &mut RumLua) -> Result<(), LuaError> {
+        loop {
+            let mut ready = false;
+            let mut next_wake = None;
+            for task in self.tasks.iter() {
+                match task.wait {
+                    Wait::Ready => ready = true,
+                    Wait::Until(t) if next_wake.map_or(true, |next| t < next) => next_wake = Some(t),
+                    _ => {},
+                }
+            }
+            let dt = match next_wake {
+                _ if ready => Duration::new(0, 0),
+                Some(t) if t > self.time => t - self.time,
+                Some(_) => Duration::new(0, 0),
+                None => return Ok(()),
+            };
+            self.tick(rl, dt)?;
+        }
+    }
+
+    /// The time on the scheduler's clock, the sum of the ticks so far.
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    /// The number of unfinished tasks.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Whether a task is still running or waiting.
+    pub fn contains(&self, id: TaskId) -> bool {
+        self.tasks.iter().any(|task| task.id == id)
+    }
+}
+
+/* Yield a request for the scheduler: its kind and the callback's
+ * arguments. */
+fn yield_request(rl: &mut RumLua, kind: &str) -> LuaRet {
+    let nargs = rl.state.get_top();
+    rl.ensure_stack(2)?;
+    rl.state.push_light_userdata(request_key());
+    rl.state.push_string(kind);
+    rl.state.rotate(1, 2);
+    rl.request_yield();
+    Ok((nargs + 2) as isize)
+}
+
+/* rum.sleep(ms) */
+fn rum_sleep(rl: &mut RumLua) -> LuaRet {
+    let ms: f64 = rl.check_arg(1)?;
+    if !(ms >= 0.0) {
+        return Err(rl.arg_error(1, "non-negative number expected"));
+    }
+    rl.state.set_top(1);
+    yield_request(rl, "sleep")
+}
+
+/* rum.wait_for(event): the values the event was signalled with */
+fn rum_wait_for(rl: &mut RumLua) -> LuaRet {
+    let _: String = rl.check_arg(1)?;
+    rl.state.set_top(1);
+    yield_request(rl, "wait")
+}
+
+/* rum.signal(event, ...) */
+fn rum_signal(rl: &mut RumLua) -> LuaRet {
+    let _: String = rl.check_arg(1)?;
+    yield_request(rl, "signal")
+}
+
+/* rum.spawn(f, ...) */
+fn rum_spawn(rl: &mut RumLua) -> LuaRet {
+    let _: LuaFunction = rl.check_arg(1)?;
+    yield_request(rl, "spawn")
+}
+
+/* Add the scheduler's functions to the rum table on top of the stack. */
+pub fn open(rl: &mut RumLua) {
+    let fns: [(&str, fn(&mut RumLua) -> LuaRet); 4] = [
+        ("sleep", rum_sleep),
+        ("wait_for", rum_wait_for),
+        ("signal", rum_signal),
+        ("spawn", rum_spawn),
+    ];
+    for &(name, f) in fns.iter() {
+        rl.push_registered_closure(CallbackFn::Plain(f), &format!("rum.{}", name));
+        rl.state.set_field(-2, name);
+    }
+}
@@ -0,0 +1,134 @@
+/* Exposing non-'static Rust values to Lua for a limited time */
+use ::{RumLua, LuaError, LuaRet, BoxedCallback, CallbackFn, lfail};
+use reference::LuaRef;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+
+/* Something exposed through a scope, to be invalidated when it ends. */
+enum ScopedItem {
+    /* A CallbackFn userdata */
+    Callback(LuaRef),
+    /* A registered type's userdata, holding a ScopedRef */
+    Userdata(LuaRef),
+}
+
+/// Exposes closures and references which live for `'scope` to Lua; see
+/// `RumLua::scope`.
+pub struct Scope<'scope> {
+    items: RefCell<Vec<ScopedItem>>,
+    /* Invariant in 'scope, so it can't be shortened by the caller */
+    marker: PhantomData<Cell<&'scope ()>>,
+}
+
+/* Userdata payload for a reference exposed through a scope. */
+pub struct ScopedRef<T> {
+    ptr: *mut T,
+    mutable: bool,
+    borrow: RefCell<()>,
+}
+
+impl<T> ScopedRef<T> {
+    pub fn with_ref<R, F: FnOnce(&T) -> R>(&self, f: F) -> Result<R, LuaError> {
+        match self.borrow.try_borrow() {
+            Ok(_guard) => Ok(f(unsafe { &*self.ptr })),
+            Err(_) => Err(LuaError::BorrowError("Userdata is already mutably borrowed".to_string())),
+        }
+    }
+
+    pub fn with_mut<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Result<R, LuaError> {
+        if !self.mutable {
+            return Err(LuaError::BorrowError("Userdata is only shared with Lua immutably".to_string()));
+        }
+        match self.borrow.try_borrow_mut() {
+            Ok(_guard) => Ok(f(unsafe { &mut *self.ptr })),
+            Err(_) => Err(LuaError::BorrowError("Userdata is already borrowed".to_string())),
+        }
+    }
+}
+
+fn expired_callback(_: &mut RumLua) -> LuaRet {
+    lfail("Function called after its scope ended")
+}
+
+/* Free functions, so they stay private to the crate. */
+pub fn new_scope<'scope>() -> Scope<'scope> {
+    Scope {
+        items: RefCell::new(Vec::new()),
+        marker: PhantomData,
+    }
+}
+
+/* Cut Lua off from everything exposed through `scope`. */
+pub fn invalidate(scope: &Scope, rl: &mut RumLua) {
+    let items: Vec<ScopedItem> = scope.items.borrow_mut().drain(..).collect();
+    for item in items {
+        match item {
+            ScopedItem::Callback(r) => {
+                if r.push(rl).is_ok() {
+                    let old = unsafe {
+                        let p = rl.state.to_userdata(-1) as *mut Rc<CallbackFn>;
+                        ptr::replace(p, Rc::new(CallbackFn::Plain(expired_callback)))
+                    };
+                    rl.state.pop(1);
+                    drop(old);
+                }
+            },
+            ScopedItem::Userdata(r) => {
+                if r.push(rl).is_ok() {
+                    let old = unsafe {
+                        let p = rl.state.to_userdata(-1) as *mut Option<Box<dyn Any>>;
+                        (*p).take()
+                    };
+                    rl.state.pop(1);
+                    drop(old);
+                }
+            },
+        }
+    }
+}
+
+impl<'scope> Scope<'scope> {
+    /// Push a Rust closure onto the stack as a Lua function, which may
+    /// borrow from the enclosing stack frame.  Calling it after the scope
+    /// has ended raises a Lua error.
+    pub fn push_closure<F>(&self, rl: &mut RumLua, name: &str, f: F)
+                  where F: FnMut(&mut RumLua) -> LuaRet + 'scope
+    {
+        let f: Box<dyn FnMut(&mut RumLua) -> LuaRet + 'scope> = Box::new(f);
+        /* The closure is dropped when the scope ends, before anything it
+         * borrows can go away. */
+        let f: BoxedCallback = unsafe { mem::transmute(f) };
+        rl.push_callback_fn(CallbackFn::Boxed(RefCell::new(f)));
+        let r = LuaRef::new(rl, -1);
+        self.items.borrow_mut().push(ScopedItem::Callback(r));
+        rl.wrap_callback_fn(name);
+    }
+
+    /// Push `obj` as a userdata of its registered type.  Methods can
+    /// reach it with `RumLua::with_ref`.
+    pub fn push_ref<T: Any>(&self, rl: &mut RumLua, obj: &'scope T) -> Result<(), LuaError> {
+        self.push_scoped(rl, obj as *const T as *mut T, false)
+    }
+
+    /// Push `obj` as a userdata of its registered type.  Methods can
+    /// reach it with `RumLua::with_ref` or `RumLua::with_mut`.
+    pub fn push_mut<T: Any>(&self, rl: &mut RumLua, obj: &'scope mut T) -> Result<(), LuaError> {
+        self.push_scoped(rl, obj as *mut T, true)
+    }
+
+    fn push_scoped<T: Any>(&self, rl: &mut RumLua, ptr: *mut T, mutable: bool) -> Result<(), LuaError> {
+        let scoped = ScopedRef {
+            ptr: ptr,
+            mutable: mutable,
+            borrow: RefCell::new(()),
+        };
+        rl.push_payload::<T>(Box::new(scoped))?;
+        let r = LuaRef::new(rl, -1);
+        self.items.borrow_mut().push(ScopedItem::Userdata(r));
+        Ok(())
+    }
+}
@@ -0,0 +1,549 @@
+/* Converting serde types to and from Lua values */
+use ::{RumLua, LuaError, LuaTable, Value};
+use serde::ser::{self, Serialize};
+use serde::de::{self, Deserialize, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use std::fmt::Display;
+use std::vec;
+
+impl ser::Error for LuaError {
+    fn custom<T: Display>(msg: T) -> LuaError {
+        LuaError::ConversionError(msg.to_string())
+    }
+}
+
+impl de::Error for LuaError {
+    fn custom<T: Display>(msg: T) -> LuaError {
+        LuaError::ConversionError(msg.to_string())
+    }
+}
+
+/// Convert `value` to a Lua value.  Structs and maps become tables with
+/// string keys, sequences become sequence tables, and enum variants with
+/// data become a table with the variant name as its only key.
+pub fn to_value<T: Serialize + ?Sized>(rl: &mut RumLua, value: &T) -> Result<Value, LuaError> {
+    value.serialize(Serializer { rl: rl })
+}
+
+/// Convert a Lua value to `T`, the reverse of `to_value`.
+pub fn from_value<T: DeserializeOwned>(rl: &mut RumLua, value: Value) -> Result<T, LuaError> {
+    T::deserialize(Deserializer { rl: rl, value: value })
+}
+
+struct Serializer<'r, 'lua: 'r> {
+    rl: &'r mut RumLua<'lua>,
+}
+
+/* Build a table with one key, as used for enum variants. */
+fn variant_table(rl: &mut RumLua, variant: &'static str, value: Value) -> Result<Value, LuaError> {
+    let table = rl.create_table();
+    table.raw_set(rl, variant, value)?;
+    Ok(Value::Table(table))
+}
+
+impl<'r, 'lua> ser::Serializer for Serializer<'r, 'lua> {
+    type Ok = Value;
+    type Error = LuaError;
+    type SerializeSeq = SerializeTable<'r, 'lua>;
+    type SerializeTuple = SerializeTable<'r, 'lua>;
+    type SerializeTupleStruct = SerializeTable<'r, 'lua>;
+    type SerializeTupleVariant = SerializeTable<'r, 'lua>;
+    type SerializeMap = SerializeTable<'r, 'lua>;
+    type SerializeStruct = SerializeTable<'r, 'lua>;
+    type SerializeStructVariant = SerializeTable<'r, 'lua>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, LuaError> {
+        Ok(Value::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, LuaError> {
+        Ok(Value::Integer(v as i64))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, LuaError> {
+        Ok(Value::Integer(v as i64))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, LuaError> {
+        Ok(Value::Integer(v as i64))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, LuaError> {
+        Ok(Value::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, LuaError> {
+        Ok(Value::Integer(v as i64))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, LuaError> {
+        Ok(Value::Integer(v as i64))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, LuaError> {
+        Ok(Value::Integer(v as i64))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, LuaError> {
+        if v > i64::max_value() as u64 {
+            return Err(LuaError::ConversionError(format!("{} is out of range for a Lua integer", v)));
+        }
+        Ok(Value::Integer(v as i64))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, LuaError> {
+        Ok(Value::Number(v as f64))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, LuaError> {
+        Ok(Value::Number(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, LuaError> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, LuaError> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    /* As a sequence of byte values, since a Value string must be UTF-8. */
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, LuaError> {
+        let table = self.rl.create_table();
+        for (i, b) in v.iter().enumerate() {
+            table.raw_set(self.rl, i + 1, *b as i64)?;
+        }
+        Ok(Value::Table(table))
+    }
+
+    fn serialize_none(self) -> Result<Value, LuaError> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, LuaError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, LuaError> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, LuaError> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32,
+                              variant: &'static str) -> Result<Value, LuaError> {
+        Ok(Value::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str,
+                                                        value: &T) -> Result<Value, LuaError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32,
+                                                         variant: &'static str,
+                                                         value: &T) -> Result<Value, LuaError> {
+        let value = value.serialize(Serializer { rl: &mut *self.rl })?;
+        variant_table(self.rl, variant, value)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SerializeTable<'r, 'lua>, LuaError> {
+        Ok(SerializeTable::new(self.rl, None))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<SerializeTable<'r, 'lua>, LuaError> {
+        Ok(SerializeTable::new(self.rl, None))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str,
+                              _len: usize) -> Result<SerializeTable<'r, 'lua>, LuaError> {
+        Ok(SerializeTable::new(self.rl, None))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                               _len: usize) -> Result<SerializeTable<'r, 'lua>, LuaError> {
+        Ok(SerializeTable::new(self.rl, Some(variant)))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeTable<'r, 'lua>, LuaError> {
+        Ok(SerializeTable::new(self.rl, None))
+    }
+
+    fn serialize_struct(self, _name: &'static str,
+                        _len: usize) -> Result<SerializeTable<'r, 'lua>, LuaError> {
+        Ok(SerializeTable::new(self.rl, None))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                                _len: usize) -> Result<SerializeTable<'r, 'lua>, LuaError> {
+        Ok(SerializeTable::new(self.rl, Some(variant)))
+    }
+}
+
+/* Fills in a table for any of the compound types. */
+struct SerializeTable<'r, 'lua: 'r> {
+    rl: &'r mut RumLua<'lua>,
+    table: LuaTable,
+    /* The next sequence index */
+    index: usize,
+    /* A map key waiting for its value */
+    key: Option<Value>,
+    /* The enum variant to wrap the table in */
+    variant: Option<&'static str>,
+}
+
+impl<'r, 'lua> SerializeTable<'r, 'lua> {
+    fn new(rl: &'r mut RumLua<'lua>, variant: Option<&'static str>) -> SerializeTable<'r, 'lua> {
+        let table = rl.create_table();
+        SerializeTable {
+            rl: rl,
+            table: table,
+            index: 1,
+            key: None,
+            variant: variant,
+        }
+    }
+
+    fn push_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        let value = value.serialize(Serializer { rl: &mut *self.rl })?;
+        self.table.raw_set(self.rl, self.index, value)?;
+        self.index += 1;
+        Ok(())
+    }
+
+    fn set_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), LuaError> {
+        let value = value.serialize(Serializer { rl: &mut *self.rl })?;
+        self.table.raw_set(self.rl, key, value)
+    }
+
+    fn finish(self) -> Result<Value, LuaError> {
+        match self.variant {
+            Some(variant) => variant_table(self.rl, variant, Value::Table(self.table)),
+            None => Ok(Value::Table(self.table)),
+        }
+    }
+}
+
+impl<'r, 'lua> ser::SerializeSeq for SerializeTable<'r, 'lua> {
+    type Ok = Value;
+    type Error = LuaError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<Value, LuaError> {
+        self.finish()
+    }
+}
+
+impl<'r, 'lua> ser::SerializeTuple for SerializeTable<'r, 'lua> {
+    type Ok = Value;
+    type Error = LuaError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<Value, LuaError> {
+        self.finish()
+    }
+}
+
+impl<'r, 'lua> ser::SerializeTupleStruct for SerializeTable<'r, 'lua> {
+    type Ok = Value;
+    type Error = LuaError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<Value, LuaError> {
+        self.finish()
+    }
+}
+
+impl<'r, 'lua> ser::SerializeTupleVariant for SerializeTable<'r, 'lua> {
+    type Ok = Value;
+    type Error = LuaError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<Value, LuaError> {
+        self.finish()
+    }
+}
+
+impl<'r, 'lua> ser::SerializeMap for SerializeTable<'r, 'lua> {
+    type Ok = Value;
+    type Error = LuaError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), LuaError> {
+        self.key = Some(key.serialize(Serializer { rl: &mut *self.rl })?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        let key = match self.key.take() {
+            Some(Value::Nil) => return Err(LuaError::ConversionError("map key is nil".to_string())),
+            Some(key) => key,
+            None => return Err(LuaError::ConversionError("map value without a key".to_string())),
+        };
+        let value = value.serialize(Serializer { rl: &mut *self.rl })?;
+        self.table.raw_set(self.rl, key, value)
+    }
+
+    fn end(self) -> Result<Value, LuaError> {
+        self.finish()
+    }
+}
+
+impl<'r, 'lua> ser::SerializeStruct for SerializeTable<'r, 'lua> {
+    type Ok = Value;
+    type Error = LuaError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str,
+                                               value: &T) -> Result<(), LuaError> {
+        self.set_field(key, value)
+    }
+
+    fn end(self) -> Result<Value, LuaError> {
+        self.finish()
+    }
+}
+
+impl<'r, 'lua> ser::SerializeStructVariant for SerializeTable<'r, 'lua> {
+    type Ok = Value;
+    type Error = LuaError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str,
+                                               value: &T) -> Result<(), LuaError> {
+        self.set_field(key, value)
+    }
+
+    fn end(self) -> Result<Value, LuaError> {
+        self.finish()
+    }
+}
+
+struct Deserializer<'r, 'lua: 'r> {
+    rl: &'r mut RumLua<'lua>,
+    value: Value,
+}
+
+impl<'r, 'lua> Deserializer<'r, 'lua> {
+    /* The entries of a table, and whether they form a sequence. */
+    fn entries(&mut self, table: &LuaTable) -> Result<(Vec<(Value, Value)>, bool), LuaError> {
+        let mut entries = Vec::new();
+        for pair in table.pairs::<Value, Value>(self.rl) {
+            entries.push(pair?);
+        }
+        let len = entries.len() as i64;
+        let sequence = entries.iter().all(|&(ref k, _)| match *k {
+            Value::Integer(i) => i >= 1 && i <= len,
+            _ => false,
+        });
+        if sequence {
+            entries.sort_by_key(|&(ref k, _)| match *k {
+                Value::Integer(i) => i,
+                _ => 0,
+            });
+        }
+        Ok((entries, sequence))
+    }
+
+    fn visit_seq<'de, V: Visitor<'de>>(self, entries: Vec<(Value, Value)>, visitor: V) -> Result<V::Value, LuaError> {
+        let values: Vec<Value> = entries.into_iter().map(|(_, v)| v).collect();
+        visitor.visit_seq(SeqAccess { rl: self.rl, iter: values.into_iter() })
+    }
+
+    fn visit_map<'de, V: Visitor<'de>>(self, entries: Vec<(Value, Value)>, visitor: V) -> Result<V::Value, LuaError> {
+        visitor.visit_map(MapAccess { rl: self.rl, iter: entries.into_iter(), value: None })
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> Result<T, LuaError> {
+        Err(LuaError::ConversionError(format!("{} expected, got {}", expected, self.value.type_name())))
+    }
+}
+
+impl<'de, 'r, 'lua> de::Deserializer<'de> for Deserializer<'r, 'lua> {
+    type Error = LuaError;
+
+    fn deserialize_any<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, LuaError> {
+        match self.value.clone() {
+            Value::Nil => visitor.visit_unit(),
+            Value::Boolean(b) => visitor.visit_bool(b),
+            Value::Integer(i) => visitor.visit_i64(i),
+            Value::Number(n) => visitor.visit_f64(n),
+            Value::String(s) => visitor.visit_string(s),
+            Value::Table(table) => {
+                let (entries, sequence) = self.entries(&table)?;
+                if sequence && !entries.is_empty() {
+                    self.visit_seq(entries, visitor)
+                } else {
+                    self.visit_map(entries, visitor)
+                }
+            },
+            _ => self.unexpected("serializable value"),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        match self.value {
+            Value::Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str,
+                                                   visitor: V) -> Result<V::Value, LuaError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, LuaError> {
+        match self.value.clone() {
+            Value::Table(table) => {
+                let (entries, sequence) = self.entries(&table)?;
+                if !sequence {
+                    return Err(LuaError::ConversionError("sequence table expected".to_string()));
+                }
+                self.visit_seq(entries, visitor)
+            },
+            _ => self.unexpected("sequence table"),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize,
+                                                 visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, LuaError> {
+        match self.value.clone() {
+            Value::Table(table) => {
+                let (entries, _) = self.entries(&table)?;
+                self.visit_map(entries, visitor)
+            },
+            _ => self.unexpected("table"),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, _fields: &'static [&'static str],
+                                           visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(mut self, _name: &'static str, _variants: &'static [&'static str],
+                                         visitor: V) -> Result<V::Value, LuaError> {
+        match self.value.clone() {
+            Value::String(s) => visitor.visit_enum(s.into_deserializer()),
+            Value::Table(table) => {
+                let (mut entries, _) = self.entries(&table)?;
+                if entries.len() != 1 {
+                    return Err(LuaError::ConversionError("enum table must have exactly one key".to_string()));
+                }
+                let (variant, value) = entries.pop().unwrap();
+                visitor.visit_enum(EnumAccess { rl: self.rl, variant: variant, value: value })
+            },
+            _ => self.unexpected("string or table"),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf unit unit_struct identifier ignored_any
+    }
+}
+
+struct SeqAccess<'r, 'lua: 'r> {
+    rl: &'r mut RumLua<'lua>,
+    iter: vec::IntoIter<Value>,
+}
+
+impl<'de, 'r, 'lua> de::SeqAccess<'de> for SeqAccess<'r, 'lua> {
+    type Error = LuaError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, LuaError> {
+        match self.iter.next() {
+            Some(value) => seed.deserialize(Deserializer { rl: &mut *self.rl, value: value }).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct MapAccess<'r, 'lua: 'r> {
+    rl: &'r mut RumLua<'lua>,
+    iter: vec::IntoIter<(Value, Value)>,
+    value: Option<Value>,
+}
+
+impl<'de, 'r, 'lua> de::MapAccess<'de> for MapAccess<'r, 'lua> {
+    type Error = LuaError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, LuaError> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(Deserializer { rl: &mut *self.rl, value: key }).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, LuaError> {
+        let value = self.value.take().unwrap_or(Value::Nil);
+        seed.deserialize(Deserializer { rl: &mut *self.rl, value: value })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct EnumAccess<'r, 'lua: 'r> {
+    rl: &'r mut RumLua<'lua>,
+    variant: Value,
+    value: Value,
+}
+
+impl<'de, 'r, 'lua> de::EnumAccess<'de> for EnumAccess<'r, 'lua> {
+    type Error = LuaError;
+    type Variant = Deserializer<'r, 'lua>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Deserializer<'r, 'lua>), LuaError> {
+        let variant = seed.deserialize(Deserializer { rl: &mut *self.rl, value: self.variant })?;
+        Ok((variant, Deserializer { rl: self.rl, value: self.value }))
+    }
+}
+
+impl<'de, 'r, 'lua> de::VariantAccess<'de> for Deserializer<'r, 'lua> {
+    type Error = LuaError;
+
+    fn unit_variant(self) -> Result<(), LuaError> {
+        Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, LuaError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, LuaError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str],
+                                       visitor: V) -> Result<V::Value, LuaError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
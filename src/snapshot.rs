@@ -0,0 +1,411 @@
+/* Saving the globals of a state to a blob, and restoring them */
+use ::{RumLua, LuaError, LuaPtr, LuaString, FromLua, lfail};
+use string::push_bytes;
+use lua;
+use lua::Index;
+use libc::c_void;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+const MAGIC: &'static [u8] = b"RUMS\x01";
+
+/* Value tags */
+const END: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const INTEGER: u8 = 3;
+const FLOAT: u8 = 4;
+const STRING: u8 = 5;
+const TABLE: u8 = 6;
+/* A table or userdata seen before, by the order they were first written */
+const SEEN: u8 = 7;
+const USERDATA: u8 = 8;
+/* Only in call logs, for values which can't be saved */
+const NIL: u8 = 9;
+
+/* How deeply tables may be nested, when saving or restoring. */
+const MAX_DEPTH: usize = 200;
+
+/* How a registered type's userdata are saved and restored */
+pub struct Codec {
+    name: String,
+    save: Box<dyn Fn(&mut RumLua, Index) -> Option<Vec<u8>>>,
+    load: Box<dyn Fn(&mut RumLua, &[u8]) -> Result<(), LuaError>>,
+}
+
+pub fn codec<T, S, L>(name: &str, save: S, load: L) -> Codec
+              where T: Any, S: Fn(&T) -> Vec<u8> + 'static, L: Fn(&[u8]) -> Result<T, LuaError> + 'static
+{
+    Codec {
+        name: name.to_string(),
+        save: Box::new(move |rl, index| rl.with_ref(index, |obj: &T| save(obj)).ok()),
+        load: Box::new(move |rl, bytes| {
+            let obj = load(bytes)?;
+            rl.push(&LuaPtr::new(obj))
+        }),
+    }
+}
+
+/* The names of the globals a new state has, which aren't saved. */
+pub fn base_globals(rl: &mut RumLua) -> HashSet<String> {
+    let mut names = HashSet::new();
+    rl.state.raw_geti(lua::REGISTRYINDEX, lua::ffi::LUA_RIDX_GLOBALS);
+    rl.state.push_nil();
+    while rl.state.next(-2) {
+        if let Some(lua::Type::String) = rl.state.type_of(-2) {
+            if let Ok(name) = LuaString::from_lua(rl, -2) {
+                names.insert(String::from_utf8_lossy(name.as_bytes()).into_owned());
+            }
+        }
+        rl.state.pop(1);
+    }
+    rl.state.pop(1);
+    names
+}
+
+/* How a value will be written, if it can be */
+enum Prepared {
+    Plain,
+    Userdata(usize, Vec<u8>),
+}
+
+pub struct Writer {
+    pub out: Vec<u8>,
+    seen: HashMap<*const c_void, u32>,
+    codecs: Vec<Rc<Codec>>,
+}
+
+impl Writer {
+    pub fn new(codecs: Vec<Rc<Codec>>) -> Writer {
+        Writer {
+            out: Vec::new(),
+            seen: HashMap::new(),
+            codecs: codecs,
+        }
+    }
+
+    pub fn u32(&mut self, n: u32) {
+        for i in 0..4 {
+            self.out.push((n >> (8 * i)) as u8);
+        }
+    }
+
+    fn u64(&mut self, n: u64) {
+        for i in 0..8 {
+            self.out.push((n >> (8 * i)) as u8);
+        }
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.out.extend_from_slice(bytes);
+    }
+
+    /* Functions, threads and userdata of types without a codec can't be
+     * saved. */
+    fn prepare(&self, rl: &mut RumLua, index: Index) -> Option<Prepared> {
+        match rl.state.type_of(index) {
+            Some(lua::Type::Boolean) | Some(lua::Type::Number) |
+            Some(lua::Type::String) | Some(lua::Type::Table) => Some(Prepared::Plain),
+            Some(lua::Type::Userdata) => {
+                if self.seen.contains_key(&rl.state.to_pointer(index)) {
+                    return Some(Prepared::Plain);
+                }
+                for (i, codec) in self.codecs.iter().enumerate() {
+                    if let Some(bytes) = (codec.save)(rl, index) {
+                        return Some(Prepared::Userdata(i, bytes));
+                    }
+                }
+                None
+            },
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, rl: &mut RumLua, index: Index, prepared: Prepared, depth: usize) -> Result<(), LuaError> {
+        let index = rl.state.abs_index(index);
+        match rl.state.type_of(index) {
+            Some(lua::Type::Boolean) => {
+                let b = rl.state.to_bool(index);
+                self.out.push(if b { TRUE } else { FALSE });
+            },
+            Some(lua::Type::Number) => match rl.state.to_integerx(index) {
+                Some(i) if rl.state.is_integer(index) => {
+                    self.out.push(INTEGER);
+                    self.u64(i as u64);
+                },
+                _ => {
+                    let f = rl.state.to_number(index);
+                    self.out.push(FLOAT);
+                    self.u64(f.to_bits());
+                },
+            },
+            Some(lua::Type::String) => {
+                let s = LuaString::from_lua(rl, index)?;
+                self.out.push(STRING);
+                self.bytes(s.as_bytes());
+            },
+            _ => {
+                let p = rl.state.to_pointer(index);
+                if let Some(&id) = self.seen.get(&p) {
+                    self.out.push(SEEN);
+                    self.u32(id);
+                    return Ok(());
+                }
+                let id = self.seen.len() as u32;
+                self.seen.insert(p, id);
+                match prepared {
+                    Prepared::Userdata(codec, bytes) => {
+                        self.out.push(USERDATA);
+                        let name = self.codecs[codec].name.clone();
+                        self.bytes(name.as_bytes());
+                        self.bytes(&bytes);
+                    },
+                    Prepared::Plain => {
+                        self.out.push(TABLE);
+                        self.table(rl, index, depth + 1)?;
+                    },
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /* Write any value, as nil if it can't be saved. */
+    pub fn value(&mut self, rl: &mut RumLua, index: Index) -> Result<(), LuaError> {
+        match self.prepare(rl, index) {
+            Some(prepared) => self.write(rl, index, prepared, 0),
+            None => {
+                self.out.push(NIL);
+                Ok(())
+            },
+        }
+    }
+
+    /* The saveable entries of the table at `index`, then END */
+    fn table(&mut self, rl: &mut RumLua, index: Index, depth: usize) -> Result<(), LuaError> {
+        if depth > MAX_DEPTH {
+            return lfail("tables are nested too deeply to snapshot");
+        }
+        rl.ensure_stack(3)?;
+        rl.state.push_nil();
+        while rl.state.next(index) {
+            if let (Some(key), Some(value)) = (self.prepare(rl, -2), self.prepare(rl, -1)) {
+                self.write(rl, -2, key, depth)?;
+                self.write(rl, -1, value, depth)?;
+            }
+            rl.state.pop(1);
+        }
+        self.out.push(END);
+        Ok(())
+    }
+}
+
+/* The globals which aren't in `base`, as: name, value, ... END */
+pub fn save(rl: &mut RumLua, base: &HashSet<String>, codecs: Vec<Rc<Codec>>) -> Result<Vec<u8>, LuaError> {
+    let mut writer = Writer::new(codecs);
+    writer.out.extend_from_slice(MAGIC);
+    let top = rl.state.get_top();
+    let result = save_globals(rl, base, &mut writer);
+    rl.state.set_top(top);
+    result?;
+    writer.out.push(END);
+    Ok(writer.out)
+}
+
+fn save_globals(rl: &mut RumLua, base: &HashSet<String>, writer: &mut Writer) -> Result<(), LuaError> {
+    rl.ensure_stack(3)?;
+    rl.state.raw_geti(lua::REGISTRYINDEX, lua::ffi::LUA_RIDX_GLOBALS);
+    let globals = rl.state.get_top();
+    rl.state.push_nil();
+    while rl.state.next(globals) {
+        if let Some(lua::Type::String) = rl.state.type_of(-2) {
+            let name = LuaString::from_lua(rl, -2)?;
+            let is_base = match ::std::str::from_utf8(name.as_bytes()) {
+                Ok(name) => base.contains(name),
+                Err(_) => false,
+            };
+            if !is_base {
+                if let Some(value) = writer.prepare(rl, -1) {
+                    writer.out.push(STRING);
+                    writer.bytes(name.as_bytes());
+                    writer.write(rl, -1, value, 0)?;
+                }
+            }
+        }
+        rl.state.pop(1);
+    }
+    Ok(())
+}
+
+pub struct Reader<'b> {
+    data: &'b [u8],
+    pos: usize,
+    /* Index of the table of the objects read, by id */
+    objects: Index,
+    next_id: u32,
+    codecs: Vec<Rc<Codec>>,
+}
+
+impl<'b> Reader<'b> {
+    /* `objects` is the index of an empty table to keep track of tables
+     * and userdata. */
+    pub fn new(data: &'b [u8], objects: Index, codecs: Vec<Rc<Codec>>) -> Reader<'b> {
+        Reader {
+            data: data,
+            pos: 0,
+            objects: objects,
+            next_id: 0,
+            codecs: codecs,
+        }
+    }
+
+    /* Whether everything has been read */
+    pub fn done(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'b [u8], LuaError> {
+        if self.data.len() - self.pos < n {
+            return lfail("snapshot is truncated");
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub fn byte(&mut self) -> Result<u8, LuaError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, LuaError> {
+        let bytes = self.take(4)?;
+        Ok(bytes.iter().rev().fold(0, |n, &b| (n << 8) | b as u32))
+    }
+
+    fn u64(&mut self) -> Result<u64, LuaError> {
+        let bytes = self.take(8)?;
+        Ok(bytes.iter().rev().fold(0, |n, &b| (n << 8) | b as u64))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'b [u8], LuaError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /* Remember the object on top of the stack. */
+    fn add_object(&mut self, rl: &mut RumLua) {
+        self.next_id += 1;
+        rl.state.push_value(-1);
+        rl.state.raw_seti(self.objects, self.next_id as lua::Integer);
+    }
+
+    /* Push the next value. */
+    pub fn next_value(&mut self, rl: &mut RumLua) -> Result<(), LuaError> {
+        let tag = self.byte()?;
+        self.value(rl, tag, None, 0)
+    }
+
+    /* Push the value with tag `tag`, within `depth` tables.  A table is
+     * read into the table at `into`, if given, rather than a new one. */
+    fn value(&mut self, rl: &mut RumLua, tag: u8, into: Option<Index>, depth: usize) -> Result<(), LuaError> {
+        rl.ensure_stack(3)?;
+        match tag {
+            NIL => rl.state.push_nil(),
+            FALSE => rl.state.push_bool(false),
+            TRUE => rl.state.push_bool(true),
+            INTEGER => {
+                let i = self.u64()?;
+                rl.state.push_integer(i as lua::Integer);
+            },
+            FLOAT => {
+                let f = f64::from_bits(self.u64()?);
+                rl.state.push_number(f);
+            },
+            STRING => {
+                let s = self.bytes()?;
+                push_bytes(rl, s);
+            },
+            TABLE => {
+                if depth >= MAX_DEPTH {
+                    return lfail("snapshot nested too deeply");
+                }
+                match into {
+                    Some(index) => rl.state.push_value(index),
+                    None => rl.state.new_table(),
+                }
+                self.add_object(rl);
+                loop {
+                    let tag = self.byte()?;
+                    if tag == END {
+                        break;
+                    }
+                    self.value(rl, tag, None, depth + 1)?;
+                    let tag = self.byte()?;
+                    self.value(rl, tag, None, depth + 1)?;
+                    rl.state.raw_set(-3);
+                }
+            },
+            SEEN => {
+                let id = self.u32()?;
+                if id >= self.next_id {
+                    return lfail("snapshot refers to a missing object");
+                }
+                rl.state.raw_geti(self.objects, (id + 1) as lua::Integer);
+            },
+            USERDATA => {
+                let name = self.bytes()?;
+                let bytes = self.bytes()?;
+                let codec = match self.codecs.iter().find(|codec| codec.name.as_bytes() == name) {
+                    Some(codec) => codec.clone(),
+                    None => return lfail(&format!("snapshot has userdata of unknown type '{}'",
+                                                  String::from_utf8_lossy(name))),
+                };
+                (codec.load)(rl, bytes)?;
+                self.add_object(rl);
+            },
+            _ => return lfail("snapshot is corrupt"),
+        }
+        Ok(())
+    }
+}
+
+/* Set the globals saved in `blob`.  Tables already in the globals are
+ * updated in place. */
+pub fn restore(rl: &mut RumLua, blob: &[u8], codecs: Vec<Rc<Codec>>) -> Result<(), LuaError> {
+    if !blob.starts_with(MAGIC) {
+        return lfail("not a snapshot");
+    }
+    let top = rl.state.get_top();
+    let result = restore_globals(rl, blob, codecs);
+    rl.state.set_top(top);
+    result
+}
+
+fn restore_globals(rl: &mut RumLua, blob: &[u8], codecs: Vec<Rc<Codec>>) -> Result<(), LuaError> {
+    rl.ensure_stack(4)?;
+    rl.state.new_table();
+    let mut reader = Reader::new(&blob[MAGIC.len()..], rl.state.get_top(), codecs);
+    rl.state.raw_geti(lua::REGISTRYINDEX, lua::ffi::LUA_RIDX_GLOBALS);
+    let globals = rl.state.get_top();
+    loop {
+        match reader.byte()? {
+            END => return Ok(()),
+            STRING => {},
+            _ => return lfail("snapshot is corrupt"),
+        }
+        let name = reader.bytes()?;
+        push_bytes(rl, name);
+        let tag = reader.byte()?;
+        rl.state.push_value(-1);
+        let into = match rl.state.raw_get(globals) {
+            lua::Type::Table if tag == TABLE => Some(rl.state.get_top()),
+            _ => None,
+        };
+        reader.value(rl, tag, into, 0)?;
+        /* name, old value, new value */
+        rl.state.remove(-2);
+        rl.state.raw_set(globals);
+    }
+}
@@ -0,0 +1,72 @@
+/* Mapping positions in generated chunks back to their original sources */
+use std::collections::HashMap;
+
+/// Maps the lines of a chunk built by the application, such as from a
+/// template or by concatenating files, back to where they came from; see
+/// `RumLua::set_source_map`.
+pub trait SourceMap {
+    /// Return the original file name and line for `line` of the chunk, or
+    /// `None` to report the chunk's own position.
+    fn map_line(&self, line: u32) -> Option<(String, u32)>;
+}
+
+impl<F: Fn(u32) -> Option<(String, u32)>> SourceMap for F {
+    fn map_line(&self, line: u32) -> Option<(String, u32)> {
+        self(line)
+    }
+}
+
+/* The name Lua shows in messages for a chunk loaded as `chunk_name`, or
+ * None if it's a "[string ...]" made from the name. */
+pub fn display_name(chunk_name: &str) -> Option<&str> {
+    if chunk_name.starts_with('@') || chunk_name.starts_with('=') {
+        Some(&chunk_name[1..])
+    } else {
+        None
+    }
+}
+
+/* Rewrite each "chunk:line" in `text` for which there is a source map. */
+pub fn apply(maps: &HashMap<String, Box<dyn SourceMap>>, text: &str) -> String {
+    let mut result = text.to_string();
+    for (chunk, map) in maps {
+        result = apply_one(chunk, &**map, &result);
+    }
+    result
+}
+
+fn apply_one(chunk: &str, map: &dyn SourceMap, text: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(pos) = rest.find(chunk) {
+        let after = &rest[pos + chunk.len()..];
+        /* Don't match the end of a longer name, such as "data.lua" for
+         * "a.lua". */
+        let starts_name = rest[..pos].chars().next_back().map_or(true, |c| {
+            !(c.is_alphanumeric() || "/\\._-".contains(c))
+        });
+        let digits = if after.starts_with(':') {
+            after[1..].chars().take_while(|c| c.is_digit(10)).count()
+        } else {
+            0
+        };
+        let mapped = if starts_name && digits > 0 {
+            after[1..digits + 1].parse().ok().and_then(|line| map.map_line(line))
+        } else {
+            None
+        };
+        match mapped {
+            Some((file, line)) => {
+                result.push_str(&rest[..pos]);
+                result.push_str(&format!("{}:{}", file, line));
+                rest = &after[digits + 1..];
+            },
+            None => {
+                result.push_str(&rest[..pos + chunk.len()]);
+                rest = after;
+            },
+        }
+    }
+    result.push_str(rest);
+    result
+}
@@ -0,0 +1,85 @@
+/* Binary-safe Lua strings */
+use ::{RumLua, LuaError, ToLua, FromLua};
+use convert::conversion_error;
+use lua;
+use lua::Index;
+use libc::{c_char, size_t};
+use std::ptr;
+use std::slice;
+use std::str;
+
+/// A Lua string, which may hold any bytes including NULs.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct LuaString {
+    bytes: Vec<u8>,
+}
+
+impl LuaString {
+    /// Make a `LuaString` holding `bytes`.
+    pub fn new<B: Into<Vec<u8>>>(bytes: B) -> LuaString {
+        LuaString { bytes: bytes.into() }
+    }
+
+    /// The string's contents.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The string's contents, if they are valid UTF-8.
+    pub fn to_str(&self) -> Result<&str, LuaError> {
+        str::from_utf8(&self.bytes).map_err(|_| LuaError::ConversionError("string is not valid UTF-8".to_string()))
+    }
+
+    /// Take the string's contents.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/* Push `bytes` as a Lua string without going through a C string, which
+ * would stop at the first NUL. */
+pub fn push_bytes(rl: &mut RumLua, bytes: &[u8]) {
+    unsafe {
+        lua::ffi::lua_pushlstring(rl.state.as_ptr(), bytes.as_ptr() as *const c_char,
+                                  bytes.len() as size_t);
+    }
+}
+
+impl ToLua for LuaString {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_bytes(rl, &self.bytes);
+        Ok(())
+    }
+}
+
+impl<'a> ToLua for &'a [u8] {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_bytes(rl, self);
+        Ok(())
+    }
+}
+
+impl ToLua for Vec<u8> {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        push_bytes(rl, self);
+        Ok(())
+    }
+}
+
+impl FromLua for LuaString {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<LuaString, LuaError> {
+        match rl.state.type_of(index) {
+            Some(lua::Type::String) => {},
+            _ => return conversion_error(rl, index, "string"),
+        }
+        let mut len: size_t = 0;
+        let bytes = unsafe {
+            let p = lua::ffi::lua_tolstring(rl.state.as_ptr(), index, &mut len);
+            if p == ptr::null() {
+                return conversion_error(rl, index, "string");
+            }
+            slice::from_raw_parts(p as *const u8, len as usize).to_vec()
+        };
+        Ok(LuaString { bytes: bytes })
+    }
+}
@@ -0,0 +1,100 @@
+/* A RumLua which can be moved and shared between threads */
+use ::{RumLua, RumLuaBuilder, LuaError, ToLua, FromLua, ToLuaMulti, FromLuaMulti};
+use std::sync::{Mutex, MutexGuard};
+
+/* Asserted Send, as RumLuaSync only lets Send values into the state, and
+ * none of the state's own Rc handles out. */
+struct SendState(RumLua<'static>);
+
+unsafe impl Send for SendState {}
+
+impl RumLuaBuilder {
+    /// Create a `RumLuaSync` instead of a `RumLua`.
+    pub fn build_sync(self) -> RumLuaSync {
+        RumLuaSync::from_state(self.build())
+    }
+}
+
+/// A `RumLua` behind a mutex, which is `Send` and `Sync` so that it can be
+/// moved into a worker thread or tokio task, or shared between threads.
+/// Its methods only let values which are `Send` into or out of the state:
+/// callbacks must be `Send`, and handles into the state such as
+/// `LuaFunction` can't be returned.  Each call locks the state, and
+/// concurrent calls wait their turn.
+pub struct RumLuaSync {
+    state: Mutex<SendState>,
+}
+
+impl RumLuaSync {
+    /// A state with every standard library, as `RumLua::new`.
+    pub fn new() -> RumLuaSync {
+        RumLuaSync::from_state(RumLua::new())
+    }
+
+    fn from_state(rl: RumLua<'static>) -> RumLuaSync {
+        RumLuaSync {
+            state: Mutex::new(SendState(rl)),
+        }
+    }
+
+    /* A panic while locked leaves the state usable, as RumLua recovers
+     * from panics in callbacks itself. */
+    fn lock(&self) -> MutexGuard<'_, SendState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run `code`, as `RumLua::do_string`.
+    pub fn do_string(&self, code: &str) -> Result<(), LuaError> {
+        self.lock().0.do_string(code)
+    }
+
+    /// Run `code` and convert its results, as `RumLua::eval`.
+    pub fn eval<R: FromLuaMulti + Send>(&self, code: &str) -> Result<R, LuaError> {
+        self.lock().0.eval(code)
+    }
+
+    /// Call the global function `name`, as `RumLua::call_global`.
+    pub fn call_global<A, R>(&self, name: &str, args: A) -> Result<R, LuaError>
+                  where A: ToLuaMulti + Send, R: FromLuaMulti + Send
+    {
+        self.lock().0.call_global(name, args)
+    }
+
+    pub fn set_global_value<T: ToLua + Send>(&self, name: &str, value: T) -> Result<(), LuaError> {
+        self.lock().0.set_global_value(name, value)
+    }
+
+    pub fn get_global_value<T: FromLua + Send>(&self, name: &str) -> Result<T, LuaError> {
+        self.lock().0.get_global_value(name)
+    }
+
+    /// Set the global `name` to a Rust function, as `RumLua::push_function`.
+    pub fn set_global_function<A, R, F>(&self, name: &str, f: F)
+                  where A: FromLuaMulti, R: ToLuaMulti,
+                        F: FnMut(&mut RumLua, A) -> Result<R, LuaError> + Send + 'static
+    {
+        let mut guard = self.lock();
+        let rl = &mut guard.0;
+        rl.push_function(name, f);
+        rl.state.set_global(name);
+    }
+
+    /// Use the state directly.
+    ///
+    /// # Safety
+    ///
+    /// `f` must not leave anything in the state which isn't `Send`, such
+    /// as a callback or userdata holding an `Rc`, nor return anything
+    /// holding state handles.
+    pub unsafe fn with_state<T, F: FnOnce(&mut RumLua) -> T>(&self, f: F) -> T {
+        f(&mut self.lock().0)
+    }
+
+    /// Take the state back out, to use on the current thread.
+    pub fn into_inner(self) -> RumLua<'static> {
+        match self.state.into_inner() {
+            Ok(state) => state.0,
+            Err(e) => e.into_inner().0,
+        }
+    }
+}
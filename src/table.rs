@@ -0,0 +1,398 @@
+/* Handle type for Lua tables */
+use ::{RumLua, LuaError, LuaRet, CallbackFn, ToLua, FromLua, lfail};
+use convert::{conversion_error, type_name};
+use reference::LuaRef;
+use lua;
+use lua::Index;
+use libc::c_void;
+use std::marker::PhantomData;
+
+/* Key (by address) in a read-only table's metatable of the hidden table
+ * holding its contents. */
+static READONLY_KEY: u8 = 0;
+
+/// A handle to a Lua table.
+#[derive(Clone)]
+pub struct LuaTable {
+    reference: LuaRef,
+}
+
+impl LuaTable {
+    /// Return `table[key]`, which may invoke metamethods.
+    pub fn get<K, V>(&self, rl: &mut RumLua, key: K) -> Result<V, LuaError>
+                  where K: ToLua, V: FromLua
+    {
+        let base = rl.state.get_top();
+        let result = self.push_key(rl, &key).and_then(|()| {
+            rl.state.get_table(-2);
+            V::from_lua(rl, -1)
+        });
+        rl.state.set_top(base);
+        result
+    }
+
+    /// Set `table[key] = value`, which may invoke metamethods.
+    pub fn set<K, V>(&self, rl: &mut RumLua, key: K, value: V) -> Result<(), LuaError>
+                  where K: ToLua, V: ToLua
+    {
+        let base = rl.state.get_top();
+        let result = self.push_key(rl, &key).and_then(|()| {
+            value.to_lua(rl)?;
+            rl.state.set_table(-3);
+            Ok(())
+        });
+        rl.state.set_top(base);
+        result
+    }
+
+    /// Return `table[key]` without invoking metamethods.
+    pub fn raw_get<K, V>(&self, rl: &mut RumLua, key: K) -> Result<V, LuaError>
+                  where K: ToLua, V: FromLua
+    {
+        let base = rl.state.get_top();
+        let result = self.push_key(rl, &key).and_then(|()| {
+            rl.state.raw_get(-2);
+            V::from_lua(rl, -1)
+        });
+        rl.state.set_top(base);
+        result
+    }
+
+    /// Set `table[key] = value` without invoking metamethods.
+    pub fn raw_set<K, V>(&self, rl: &mut RumLua, key: K, value: V) -> Result<(), LuaError>
+                  where K: ToLua, V: ToLua
+    {
+        let base = rl.state.get_top();
+        let result = self.push_key(rl, &key).and_then(|()| {
+            value.to_lua(rl)?;
+            rl.state.raw_set(-3);
+            Ok(())
+        });
+        rl.state.set_top(base);
+        result
+    }
+
+    /// Return true if `table[key]` is not nil.
+    pub fn contains_key<K: ToLua>(&self, rl: &mut RumLua, key: K) -> Result<bool, LuaError> {
+        let base = rl.state.get_top();
+        let result = self.push_key(rl, &key).map(|()| {
+            rl.state.get_table(-2);
+            !rl.state.is_nil(-1)
+        });
+        rl.state.set_top(base);
+        result
+    }
+
+    /// Return the length of the table as with Lua's `#` operator, which
+    /// may invoke the `__len` metamethod.
+    pub fn len(&self, rl: &mut RumLua) -> Result<lua::Integer, LuaError> {
+        let base = rl.state.get_top();
+        let result = self.reference.push(rl).and_then(|()| {
+            rl.state.len(-1);
+            match rl.state.to_integerx(-1) {
+                Some(len) => Ok(len),
+                None => conversion_error(rl, -1, "integer length"),
+            }
+        });
+        rl.state.set_top(base);
+        result
+    }
+
+    /// Return the length of the table without invoking metamethods.
+    pub fn raw_len(&self, rl: &mut RumLua) -> Result<usize, LuaError> {
+        self.reference.push(rl)?;
+        let len = rl.state.raw_len(-1);
+        rl.state.pop(1);
+        Ok(len)
+    }
+
+    /// Iterate over the table's entries, as with Lua's `next`, converting
+    /// keys to `K` and values to `V`.
+    pub fn pairs<'rl, 'lua, K, V>(&self, rl: &'rl mut RumLua<'lua>) -> Pairs<'rl, 'lua, K, V>
+                  where K: FromLua, V: FromLua
+    {
+        Pairs {
+            rl: rl,
+            table: self.clone(),
+            key: None,
+            done: false,
+            marker: PhantomData,
+        }
+    }
+
+    /// Iterate over `table[1]`, `table[2]`, ... up to the first nil,
+    /// converting them to `V`.  Metamethods are not invoked.
+    pub fn sequence_values<'rl, 'lua, V>(&self, rl: &'rl mut RumLua<'lua>) -> SequenceValues<'rl, 'lua, V>
+                  where V: FromLua
+    {
+        SequenceValues {
+            rl: rl,
+            table: self.clone(),
+            index: 1,
+            done: false,
+            marker: PhantomData,
+        }
+    }
+
+    /// Make the table read-only, or writable again.  The entries of a
+    /// read-only table are moved to a hidden table behind a protected
+    /// metatable, so that assignments from Lua raise an error while
+    /// indexing, `pairs` and `#` still see them.  Assignments from Rust
+    /// while no Lua code is running still go through, but `raw_get`,
+    /// `raw_set` and `LuaTable::pairs` see only the empty table.
+    pub fn set_readonly(&self, rl: &mut RumLua, readonly: bool) -> Result<(), LuaError> {
+        let base = rl.state.get_top();
+        self.reference.push(rl)?;
+        let t = rl.state.get_top();
+        if readonly {
+            make_readonly(rl, t);
+        } else {
+            make_writable(rl, t);
+        }
+        rl.state.set_top(base);
+        Ok(())
+    }
+
+    /// Return true if the table has been made read-only with
+    /// `set_readonly`.
+    pub fn is_readonly(&self, rl: &mut RumLua) -> Result<bool, LuaError> {
+        let base = rl.state.get_top();
+        self.reference.push(rl)?;
+        let readonly = push_contents(rl, -1);
+        rl.state.set_top(base);
+        Ok(readonly)
+    }
+
+    /* Push the table and then the key. */
+    fn push_key<K: ToLua>(&self, rl: &mut RumLua, key: &K) -> Result<(), LuaError> {
+        self.reference.push(rl)?;
+        key.to_lua(rl)
+    }
+}
+
+fn readonly_key() -> *const c_void {
+    &READONLY_KEY as *const u8 as *const c_void
+}
+
+/* Push the hidden contents of the read-only table at `index`, returning
+ * false (with nothing pushed) if it is not read-only. */
+fn push_contents(rl: &mut RumLua, index: Index) -> bool {
+    if !rl.state.get_metatable(index) {
+        return false;
+    }
+    let found = unsafe {
+        lua::ffi::lua_rawgetp(rl.state.as_ptr(), -1, readonly_key())
+    } == lua::ffi::LUA_TTABLE;
+    if found {
+        rl.state.remove(-2);
+    } else {
+        rl.state.pop(2);
+    }
+    found
+}
+
+/* Move all the entries of the table at `from` to the table at `to`. */
+fn move_entries(rl: &mut RumLua, from: Index, to: Index) {
+    rl.state.push_nil();
+    while rl.state.next(from) {
+        rl.state.push_value(-2);
+        rl.state.push_value(-2);
+        rl.state.raw_set(to);
+        /* Clearing an existing field is allowed during traversal. */
+        rl.state.pop(1);
+        rl.state.push_value(-1);
+        rl.state.push_nil();
+        rl.state.raw_set(from);
+    }
+}
+
+fn make_readonly(rl: &mut RumLua, t: Index) {
+    if push_contents(rl, t) {
+        return;
+    }
+    rl.state.new_table();
+    let contents = rl.state.get_top();
+    move_entries(rl, t, contents);
+    /* Keep any existing metatable working behind the contents. */
+    if rl.state.get_metatable(t) {
+        rl.state.set_metatable(contents);
+    }
+    rl.state.new_table();
+    rl.state.push_value(contents);
+    rl.state.set_field(-2, "__index");
+    rl._push_closure(CallbackFn::Plain(readonly_newindex), "__newindex");
+    rl.state.set_field(-2, "__newindex");
+    rl._push_closure(CallbackFn::Plain(readonly_pairs), "__pairs");
+    rl.state.set_field(-2, "__pairs");
+    rl._push_closure(CallbackFn::Plain(readonly_len), "__len");
+    rl.state.set_field(-2, "__len");
+    rl.state.push_bool(false);
+    rl.state.set_field(-2, "__metatable");
+    rl.state.push_value(contents);
+    unsafe {
+        lua::ffi::lua_rawsetp(rl.state.as_ptr(), -2, readonly_key());
+    }
+    rl.state.set_metatable(t);
+}
+
+fn make_writable(rl: &mut RumLua, t: Index) {
+    if !push_contents(rl, t) {
+        return;
+    }
+    let contents = rl.state.get_top();
+    move_entries(rl, contents, t);
+    if !rl.state.get_metatable(contents) {
+        rl.state.push_nil();
+    }
+    rl.state.set_metatable(t);
+}
+
+fn readonly_newindex(rl: &mut RumLua) -> LuaRet {
+    if rl.shared.hooks.run_depth > 0 {
+        let key = String::from_lua(rl, 2).map(|k| format!("'{}'", k))
+                                          .unwrap_or_else(|_| type_name(rl, 2).to_string());
+        return lfail(&format!("attempt to modify read-only table (key {})", key));
+    }
+    /* Not from Lua, so the host is setting up the table. */
+    push_contents(rl, 1);
+    rl.state.push_value(2);
+    rl.state.push_value(3);
+    rl.state.raw_set(-3);
+    Ok(0)
+}
+
+/* The `next` returned by `__pairs`. */
+fn readonly_next(rl: &mut RumLua) -> LuaRet {
+    rl.state.set_top(2);
+    if !rl.state.is_table(1) {
+        return lfail("table expected");
+    }
+    if !rl.state.is_nil(2) {
+        /* lua_next raises an error for an unknown key. */
+        rl.state.push_value(2);
+        if rl.state.raw_get(1) == lua::Type::Nil {
+            return lfail("invalid key to 'next'");
+        }
+        rl.state.pop(1);
+    }
+    if rl.state.next(1) {
+        Ok(2)
+    } else {
+        rl.state.push_nil();
+        Ok(1)
+    }
+}
+
+fn readonly_pairs(rl: &mut RumLua) -> LuaRet {
+    rl._push_closure(CallbackFn::Plain(readonly_next), "next");
+    push_contents(rl, 1);
+    rl.state.push_nil();
+    Ok(3)
+}
+
+fn readonly_len(rl: &mut RumLua) -> LuaRet {
+    push_contents(rl, 1);
+    let len = rl.state.raw_len(-1) as lua::Integer;
+    rl.state.push_integer(len);
+    Ok(1)
+}
+
+/// Iterator over the entries of a table; see `LuaTable::pairs`.
+pub struct Pairs<'rl, 'lua: 'rl, K, V> {
+    rl: &'rl mut RumLua<'lua>,
+    table: LuaTable,
+    /* The previous key, from which lua_next continues */
+    key: Option<LuaRef>,
+    done: bool,
+    marker: PhantomData<(K, V)>,
+}
+
+impl<'rl, 'lua, K: FromLua, V: FromLua> Iterator for Pairs<'rl, 'lua, K, V> {
+    type Item = Result<(K, V), LuaError>;
+
+    fn next(&mut self) -> Option<Result<(K, V), LuaError>> {
+        if self.done {
+            return None;
+        }
+        let rl = &mut *self.rl;
+        let key = &self.key;
+        let base = rl.state.get_top();
+        let pushed = self.table.to_lua(rl).and_then(|()| {
+            match *key {
+                Some(ref key) => key.push(rl),
+                None => {
+                    rl.state.push_nil();
+                    Ok(())
+                },
+            }
+        });
+        if let Err(e) = pushed {
+            rl.state.set_top(base);
+            self.done = true;
+            return Some(Err(e));
+        }
+        if !rl.state.next(-2) {
+            rl.state.set_top(base);
+            self.done = true;
+            return None;
+        }
+        /* The key is at -2 and the value at -1 */
+        self.key = Some(LuaRef::new(rl, -2));
+        let result = K::from_lua(rl, -2).and_then(|k| {
+            V::from_lua(rl, -1).map(|v| (k, v))
+        });
+        rl.state.set_top(base);
+        Some(result)
+    }
+}
+
+/// Iterator over the sequence part of a table; see
+/// `LuaTable::sequence_values`.
+pub struct SequenceValues<'rl, 'lua: 'rl, V> {
+    rl: &'rl mut RumLua<'lua>,
+    table: LuaTable,
+    index: lua::Integer,
+    done: bool,
+    marker: PhantomData<V>,
+}
+
+impl<'rl, 'lua, V: FromLua> Iterator for SequenceValues<'rl, 'lua, V> {
+    type Item = Result<V, LuaError>;
+
+    fn next(&mut self) -> Option<Result<V, LuaError>> {
+        if self.done {
+            return None;
+        }
+        let rl = &mut *self.rl;
+        let base = rl.state.get_top();
+        if let Err(e) = self.table.to_lua(rl) {
+            self.done = true;
+            return Some(Err(e));
+        }
+        let result = if rl.state.raw_geti(-1, self.index) == lua::Type::Nil {
+            self.done = true;
+            None
+        } else {
+            self.index += 1;
+            Some(V::from_lua(rl, -1))
+        };
+        rl.state.set_top(base);
+        result
+    }
+}
+
+impl ToLua for LuaTable {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        self.reference.push(rl)
+    }
+}
+
+impl FromLua for LuaTable {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<LuaTable, LuaError> {
+        if rl.state.is_table(index) {
+            Ok(LuaTable{ reference: LuaRef::new(rl, index) })
+        } else {
+            conversion_error(rl, index, "table")
+        }
+    }
+}
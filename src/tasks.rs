@@ -0,0 +1,272 @@
+/* Async Rust functions called from Lua tasks, with the "async" feature */
+use ::{RumLua, LuaError, LuaFunction, LuaThread, Resume, MultiValue, ToLua, ToLuaMulti, FromLuaMulti, lfail};
+use convert::callback_error;
+use thread::convert_values;
+use lua;
+use libc::c_int;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::task::{Context, Poll};
+use tokio::runtime::Handle;
+
+/// Lua chunk returning a function which wraps the Rust half of an async
+/// function.  That yields until the call completes, and is resumed with
+/// true and the results, or false and an error to raise.
+pub const ASYNC_SHIM: &'static str = r#"
+    local error = ...
+    return function(start)
+        local function finish(ok, ...)
+            if ok then
+                return ...
+            else
+                error((...), 2)
+            end
+        end
+        return function(...)
+            return finish(start(...))
+        end
+    end
+"#;
+
+/* A completed call, by id */
+type Completed = (u64, Result<Box<dyn ToLuaMulti + Send>, LuaError>);
+
+/* The tasks of a state, and the calls they are waiting for. */
+pub struct Tasks {
+    shim: lua::Reference,
+    handle: Option<Handle>,
+    sender: Sender<Completed>,
+    receiver: Receiver<Completed>,
+    next_id: u64,
+    /* The thread of the task being resumed */
+    running: Option<*mut lua::ffi::lua_State>,
+    /* The call started by the running task before it yielded, and the
+     * function's name */
+    started: Option<(u64, String)>,
+    /* By call: the task waiting for it, its thread and the function's name */
+    waiting: HashMap<u64, (u64, LuaThread, String)>,
+    /* Tasks which yielded for some other reason, to resume next time */
+    ready: Vec<(u64, LuaThread)>,
+}
+
+impl Tasks {
+    pub fn new(shim: lua::Reference) -> Tasks {
+        let (sender, receiver) = channel();
+        Tasks {
+            shim: shim,
+            handle: None,
+            sender: sender,
+            receiver: receiver,
+            next_id: 0,
+            running: None,
+            started: None,
+            waiting: HashMap::new(),
+            ready: Vec::new(),
+        }
+    }
+
+    pub fn set_handle(&mut self, handle: Handle) {
+        self.handle = Some(handle);
+    }
+
+    fn new_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+/* Sends a future's result to the state once it completes. */
+struct Notify<F> {
+    call: u64,
+    future: Pin<Box<F>>,
+    sender: Sender<Completed>,
+}
+
+impl<F, R, E> Future for Notify<F>
+              where F: Future<Output = Result<R, E>>,
+                    R: ToLuaMulti + Send + 'static, E: Error + Send + Sync + 'static
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        match self.future.as_mut().poll(cx) {
+            Poll::Ready(result) => {
+                let result = result.map(|values| Box::new(values) as Box<dyn ToLuaMulti + Send>)
+                                   .map_err(callback_error);
+                /* The state may have been closed. */
+                let _ = self.sender.send((self.call, result));
+                Poll::Ready(())
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/* What a waiting task is resumed with */
+struct Outcome(Result<Box<dyn ToLuaMulti + Send>, String>);
+
+impl ToLuaMulti for Outcome {
+    fn to_lua_multi(&self, rl: &mut RumLua) -> Result<c_int, LuaError> {
+        rl.ensure_stack(2)?;
+        match self.0 {
+            Ok(ref values) => {
+                rl.state.push_bool(true);
+                Ok(values.to_lua_multi(rl)? + 1)
+            },
+            Err(ref msg) => {
+                rl.state.push_bool(false);
+                rl.state.push_string(msg);
+                Ok(2)
+            },
+        }
+    }
+}
+
+pub fn push_function<A, R, E, F, Fut>(rl: &mut RumLua, name: &str, mut f: F)
+              where A: FromLuaMulti, R: ToLuaMulti + Send + 'static, E: Error + Send + Sync + 'static,
+                    F: FnMut(&mut RumLua, A) -> Fut + 'static,
+                    Fut: Future<Output = Result<R, E>> + Send + 'static
+{
+    let fname = name.to_string();
+    rl.state.raw_geti(lua::REGISTRYINDEX, rl.shared.tasks.shim.value() as lua::Integer);
+    rl.push_closure(name, move |rl| {
+        if rl.shared.tasks.running != Some(rl.state.as_ptr()) {
+            return lfail("async functions can only be called from a task, not another coroutine");
+        }
+        let handle = match rl.shared.tasks.handle {
+            Some(ref handle) => handle.clone(),
+            None => return lfail("no async runtime has been set"),
+        };
+        let nargs = rl.state.get_top();
+        let args = A::from_lua_multi(rl, nargs)?;
+        let call = rl.shared.tasks.new_id();
+        handle.spawn(Notify {
+            call: call,
+            future: Box::pin(f(rl, args)),
+            sender: rl.shared.tasks.sender.clone(),
+        });
+        rl.shared.tasks.started = Some((call, fname.clone()));
+        rl.request_yield();
+        Ok(0)
+    });
+    rl.state.call(1, 1);
+}
+
+/* The lua_State of a thread */
+fn thread_ptr(rl: &mut RumLua, thread: &LuaThread) -> Result<*mut lua::ffi::lua_State, LuaError> {
+    thread.to_lua(rl)?;
+    let l = unsafe { lua::ffi::lua_tothread(rl.state.as_ptr(), -1) };
+    rl.state.pop(1);
+    Ok(l)
+}
+
+/* Resume a task, returning its results if it finished. */
+fn resume_task<A: ToLuaMulti>(rl: &mut RumLua, task: u64, thread: LuaThread, args: A)
+                              -> Result<Option<MultiValue>, LuaError> {
+    let l = thread_ptr(rl, &thread)?;
+    let outer = mem::replace(&mut rl.shared.tasks.running, Some(l));
+    let result = thread.resume::<_, MultiValue>(rl, args);
+    rl.shared.tasks.running = outer;
+    let started = rl.shared.tasks.started.take();
+    match result {
+        Resume::Finished(values) => Ok(Some(values)),
+        Resume::Yielded(_) => {
+            match started {
+                Some((call, name)) => { rl.shared.tasks.waiting.insert(call, (task, thread, name)); },
+                None => rl.shared.tasks.ready.push((task, thread)),
+            }
+            Ok(None)
+        },
+        Resume::Error(e) => Err(e),
+    }
+}
+
+/* Resume the task waiting for a completed call, returning the task and
+ * its results if it finished. */
+fn deliver(rl: &mut RumLua, completed: Completed) -> Result<Option<(u64, MultiValue)>, LuaError> {
+    let (call, result) = completed;
+    let (task, thread, name) = match rl.shared.tasks.waiting.remove(&call) {
+        Some(waiting) => waiting,
+        None => return Ok(None),
+    };
+    let outcome = match result {
+        Ok(values) => Outcome(Ok(values)),
+        Err(e) => {
+            /* The same message as the shim gives, so that the error can
+             * be recognised as it propagates out. */
+            let msg = format!("Calling {}:\n{}", name, e);
+            rl.stash_callback_error(e);
+            Outcome(Err(msg))
+        },
+    };
+    Ok(resume_task(rl, task, thread, outcome)?.map(|values| (task, values)))
+}
+
+/* Resume the tasks which are ready, waiting for a call to complete first
+ * if `block` and none are.  Returns the results of `target` if it
+ * finished. */
+fn step(rl: &mut RumLua, target: Option<u64>, block: bool) -> Result<Option<MultiValue>, LuaError> {
+    let mut found = None;
+    let ready = mem::replace(&mut rl.shared.tasks.ready, Vec::new());
+    for (task, thread) in ready {
+        if let Some(values) = resume_task(rl, task, thread, ())? {
+            if Some(task) == target {
+                found = Some(values);
+            }
+        }
+    }
+    if block && found.is_none() && rl.shared.tasks.ready.is_empty() && !rl.shared.tasks.waiting.is_empty() {
+        let completed = match rl.shared.tasks.receiver.recv() {
+            Ok(completed) => completed,
+            Err(_) => return Ok(None),
+        };
+        if let Some((task, values)) = deliver(rl, completed)? {
+            if Some(task) == target {
+                found = Some(values);
+            }
+        }
+    }
+    loop {
+        let completed = match rl.shared.tasks.receiver.try_recv() {
+            Ok(completed) => completed,
+            Err(_) => break,
+        };
+        if let Some((task, values)) = deliver(rl, completed)? {
+            if Some(task) == target {
+                found = Some(values);
+            }
+        }
+    }
+    Ok(found)
+}
+
+pub fn spawn<A: ToLuaMulti>(rl: &mut RumLua, f: &LuaFunction, args: A) -> Result<(), LuaError> {
+    let task = rl.shared.tasks.new_id();
+    let thread = rl.create_thread(f)?;
+    resume_task(rl, task, thread, args).map(|_| ())
+}
+
+/* Returns how many tasks are unfinished. */
+pub fn poll(rl: &mut RumLua) -> Result<usize, LuaError> {
+    step(rl, None, false)?;
+    Ok(rl.shared.tasks.waiting.len() + rl.shared.tasks.ready.len())
+}
+
+pub fn run<A, R>(rl: &mut RumLua, f: &LuaFunction, args: A) -> Result<R, LuaError>
+              where A: ToLuaMulti, R: FromLuaMulti
+{
+    let task = rl.shared.tasks.new_id();
+    let thread = rl.create_thread(f)?;
+    let mut results = resume_task(rl, task, thread, args)?;
+    while results.is_none() {
+        if rl.shared.tasks.waiting.is_empty() && rl.shared.tasks.ready.is_empty() {
+            return lfail("task can't finish, as it isn't waiting for anything");
+        }
+        results = step(rl, Some(task), true)?;
+    }
+    convert_values(rl, results.unwrap())
+}
@@ -1,10 +1,16 @@
-use ::{RumLua, LuaType, LuaRet, LuaPtr};
+use ::{RumLua, ArcPtr, LuaWeak, LuaRef, RumLuaBuilder, StdLib, TypeBuilder, MetaMethod, Inherits, LuaError, SyntaxError, Frame, LuaType, LuaRet, LuaPtr, LuaTable, LuaFunction, Value, MultiValue, ScriptSource, LuaString, Variadic, FromLua, BoxedCallback, Resume, Coverage, Debugger, StepMode, PauseReason, HookTriggers, HookEvent, DebugInfo, StackGuard, LuaFlags, DurationFormat, BigInt, Scheduler, HandlerId, StatePool, RumLuaSync, HotReload, Repl, TableFunction};
 use lua;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::error;
+use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::collections::{HashMap, BTreeMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::Cursor;
+use std::any::{Any, TypeId};
 
 #[derive(Debug)]
 struct TestDrop {
@@ -30,7 +36,7 @@ static EMPTY_METHODS: LuaType = LuaType{ methods: &[], };
 fn lua_register() {
     {
         let mut rlua = RumLua::new();
-        rlua.register_type::<TestDrop>("testdrop".to_string(), &EMPTY_METHODS);
+        rlua.register_type::<TestDrop>("testdrop".to_string(), &EMPTY_METHODS).unwrap();
     }
 }
 #[test]
@@ -38,9 +44,9 @@ fn lua_drop() {
     let dropcount = Rc::new(RefCell::new(0u32));
     {
         let mut rlua = RumLua::new();
-        rlua.register_type::<TestDrop>("TestDrop".to_string(), &EMPTY_METHODS);
+        rlua.register_type::<TestDrop>("TestDrop".to_string(), &EMPTY_METHODS).unwrap();
         let ts = TestDrop{ dropcount: dropcount.clone() };
-        rlua.push(&LuaPtr::new(ts));
+        rlua.push(&LuaPtr::new(ts)).unwrap();
 //        rlua.state.set_metatable_from_registry("TestDrop");
         TestDrop{ dropcount: dropcount.clone() };
     }
@@ -68,23 +74,24 @@ static SOME_METHODS: LuaType = LuaType{
     ], };
 
 fn test_method_get(rl: &mut RumLua) -> LuaRet {
-    let tobj = try!(rl.get::<TestMeth>(1));
+    let tobj = rl.get::<TestMeth>(1)?;
     rl.state.push(tobj.borrow().get());
     Ok(1)
 }
 
 fn test_method_set(rl: &mut RumLua) -> LuaRet {
-    let mut tobj = try!(rl.get::<TestMeth>(1));
-    tobj.borrow_mut().set(rl.state.to_str(2).unwrap());
+    let mut tobj = rl.get::<TestMeth>(1)?;
+    let s: String = rl.check_arg(2)?;
+    tobj.borrow_mut().set(&s);
     Ok(0)
 }
 
 #[test]
 fn lua_meth1() {
     let mut rlua = RumLua::new();
-    rlua.register_type::<TestMeth>("TestMeth".to_string(), &SOME_METHODS);
+    rlua.register_type::<TestMeth>("TestMeth".to_string(), &SOME_METHODS).unwrap();
 
-    rlua.push(&LuaPtr::new(TestMeth{data: "foo".to_string()}));
+    rlua.push(&LuaPtr::new(TestMeth{data: "foo".to_string()})).unwrap();
     rlua.state.set_global("testvar");
     rlua.do_string("
         testvar:set(testvar:get() .. 'bar')
@@ -95,7 +102,7 @@ fn lua_meth1() {
 }
 
 fn test_method_getstr(rl: &mut RumLua) -> LuaRet {
-    let tobj = try!(rl.get::<TestDrop>(1));
+    let tobj = rl.get::<TestDrop>(1)?;
     rl.state.push(format!("asdf {:p}", &tobj));
     Ok(1)
 }
@@ -111,10 +118,10 @@ fn lua_gc_resurrect() {
     let dropcount = Rc::new(RefCell::new(0u32));
     {
         let mut rlua = RumLua::new();
-        rlua.register_type::<TestDrop>("TestDrop".to_string(), &GCTEST_METHODS);
+        rlua.register_type::<TestDrop>("TestDrop".to_string(), &GCTEST_METHODS).unwrap();
         let ts = TestDrop{ dropcount: dropcount.clone() };
 
-        rlua.push(&LuaPtr::new(ts));
+        rlua.push(&LuaPtr::new(ts)).unwrap();
         rlua.state.set_global("global_obj");
 
         rlua.do_string(r#"
@@ -140,7 +147,7 @@ fn lua_gc_resurrect() {
 
         assert_eq!(rlua.state.get_global("global_foo"), lua::Type::Userdata);
         let err = rlua.do_string(r#" global_s = global_foo:getstr() "#).unwrap_err();
-        assert!(err.description().contains("Called method on GCed object"));
+        assert!(err.to_string().contains("Called method on GCed object"));
     }
 }
 
@@ -150,7 +157,7 @@ impl error::Error  for TestError {
     fn description(&self) -> &str {
         &self.0
     }
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         None
     }
 }
@@ -162,8 +169,33 @@ impl Display for TestError {
     }
 }
 
+/* An error type with nothing but Display, as most have now */
+#[derive(Debug)]
+struct DisplayError;
+impl error::Error for DisplayError {}
+
+impl Display for DisplayError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "shown by Display")
+    }
+}
+
+fn display_fail(_: &mut RumLua) -> LuaRet {
+    Err(LuaError::callback(DisplayError))
+}
+
+#[test]
+fn lua_display_only_errors() {
+    let mut rlua = RumLua::new();
+    rlua.register_func_table("funcs", vec![("fail", display_fail)]);
+    let msg = rlua.eval::<String>("local ok, err = pcall(funcs.fail); return tostring(err)").unwrap();
+    assert!(msg.contains("shown by Display"), "{}", msg);
+    let err = rlua.do_string("funcs.fail()").unwrap_err();
+    assert!(err.to_string().contains("shown by Display"));
+}
+
 fn test_fail(_: &mut RumLua) -> LuaRet {
-    Err(Box::new(TestError("foo".to_string())))
+    Err(LuaError::callback(TestError("foo".to_string())))
 }
 fn test_seven(rl: &mut RumLua) -> LuaRet {
     rl.state.push(7);
@@ -186,5 +218,3018 @@ fn lua_errors() {
     assert_eq!(rlua.state.get_global("result1"), lua::Type::String);
     assert_eq!(rlua.state.to_str(-1).unwrap(), "ret7 returned 7");
     assert_eq!(rlua.state.get_global("result2"), lua::Type::String);
-    assert_eq!(rlua.state.to_str(-1).unwrap(), "fail returned [false], [Calling fail:\nfoo]");
+    /* The callback's error is shown with its Display. */
+    assert_eq!(rlua.state.to_str(-1).unwrap(), "fail returned [false], [Calling fail:\nTestError(foo)]");
+}
+
+#[test]
+fn lua_call_global() {
+    let mut rlua = RumLua::new();
+    rlua.do_string(r#"
+        function add(a, b)
+            return a + b
+        end
+        function greet(name)
+            return "hello "..name, #name
+        end
+    "#).unwrap();
+    let sum: i64 = rlua.call_global("add", (3, 4)).unwrap();
+    assert_eq!(sum, 7);
+    let (greeting, len): (String, u32) = rlua.call_global("greet", "world").unwrap();
+    assert_eq!(greeting, "hello world");
+    assert_eq!(len, 5);
+    assert!(rlua.call_global::<_, ()>("no_such_function", ()).is_err());
+    assert_eq!(rlua.state.get_top(), 0);
+}
+
+#[test]
+fn lua_table_handle() {
+    let mut rlua = RumLua::new();
+    rlua.do_string(r#"
+        config = { name = "test", size = 3, 10, 20, 30 }
+    "#).unwrap();
+    rlua.state.get_global("config");
+    let config = LuaTable::from_lua(&mut rlua, -1).unwrap();
+    rlua.state.pop(1);
+    let name: String = config.get(&mut rlua, "name").unwrap();
+    assert_eq!(name, "test");
+    let second: i64 = config.raw_get(&mut rlua, 2).unwrap();
+    assert_eq!(second, 20);
+    assert_eq!(config.len(&mut rlua).unwrap(), 3);
+    assert!(config.contains_key(&mut rlua, "size").unwrap());
+    assert!(!config.contains_key(&mut rlua, "missing").unwrap());
+
+    let table = rlua.create_table();
+    table.set(&mut rlua, "answer", 42).unwrap();
+    table.raw_set(&mut rlua, 1, "first").unwrap();
+    assert_eq!(table.raw_len(&mut rlua).unwrap(), 1);
+    config.set(&mut rlua, "child", table).unwrap();
+    rlua.do_string(r#"
+        assert(config.child.answer == 42)
+        assert(config.child[1] == "first")
+    "#).unwrap();
+    assert_eq!(rlua.state.get_top(), 0);
+}
+
+fn test_apply(rl: &mut RumLua) -> LuaRet {
+    let f = LuaFunction::from_lua(rl, 1)?;
+    let x = i64::from_lua(rl, 2)?;
+    let result: i64 = f.call(rl, x)?;
+    rl.state.push(result * 10);
+    Ok(1)
+}
+
+#[test]
+fn lua_function_handle() {
+    let mut rlua = RumLua::new();
+    rlua.register_func_table("funcs", vec![
+        ("apply", test_apply),
+    ]);
+    rlua.do_string(r#"
+        function double(x) return x * 2 end
+        applied = funcs.apply(function(x) return x + 1 end, 4)
+    "#).unwrap();
+    assert_eq!(rlua.state.get_global("applied"), lua::Type::Number);
+    assert_eq!(rlua.state.to_integer(-1), 50);
+    rlua.state.pop(1);
+
+    rlua.state.get_global("double");
+    let double = LuaFunction::from_lua(&mut rlua, -1).unwrap();
+    rlua.state.pop(1);
+    rlua.do_string("double = nil").unwrap();
+    rlua.state.gc(lua::GcOption::Collect, 0);
+    let r: i64 = double.call(&mut rlua, 21).unwrap();
+    assert_eq!(r, 42);
+}
+
+#[test]
+fn lua_values() {
+    let mut rlua = RumLua::new();
+    rlua.do_string(r#"
+        values = { nil, true, 3, 2.5, "str", {}, print, coroutine.create(print) }
+    "#).unwrap();
+    rlua.state.get_global("values");
+    let values = LuaTable::from_lua(&mut rlua, -1).unwrap();
+    rlua.state.pop(1);
+    let expected = ["nil", "boolean", "number", "number", "string", "table",
+                    "function", "thread"];
+    for (i, name) in expected.iter().enumerate() {
+        let v: Value = values.get(&mut rlua, i + 1).unwrap();
+        assert_eq!(v.type_name(), *name);
+    }
+    match values.get(&mut rlua, 3).unwrap() {
+        Value::Integer(3) => {},
+        v => panic!("Unexpected value {:?}", v),
+    }
+    match values.get(&mut rlua, 4).unwrap() {
+        Value::Number(n) => assert_eq!(n, 2.5),
+        v => panic!("Unexpected value {:?}", v),
+    }
+
+    /* Round-trip everything back into Lua. */
+    let copy = rlua.create_table();
+    for i in 1..9 {
+        let v = values.get::<_, Value>(&mut rlua, i).unwrap();
+        rlua.push_any(&v).unwrap();
+        let v2 = rlua.to_value(-1).unwrap();
+        rlua.state.pop(1);
+        copy.set(&mut rlua, i, v2).unwrap();
+    }
+    rlua.push_any(&Value::Table(copy)).unwrap();
+    rlua.state.set_global("copy");
+    rlua.do_string(r#"
+        for i = 1, 8 do
+            assert(rawequal(values[i], copy[i]), i)
+        end
+    "#).unwrap();
+}
+
+#[test]
+fn lua_closures() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let dropcount = Rc::new(RefCell::new(0u32));
+    {
+        let mut rlua = RumLua::new();
+        let log2 = log.clone();
+        let held = TestDrop{ dropcount: dropcount.clone() };
+        let record: BoxedCallback = Box::new(move |rl| {
+            let _ = &held;
+            let s = String::from_lua(rl, 1)?;
+            log2.borrow_mut().push(s);
+            Ok(0)
+        });
+        let mut count = 0;
+        let counter: BoxedCallback = Box::new(move |rl| {
+            count += 1;
+            rl.state.push(count);
+            Ok(1)
+        });
+        rlua.register_closure_table("host", vec![
+            ("record", record),
+            ("counter", counter),
+        ]);
+        rlua.push_closure_once("once", |rl| {
+            rl.state.push("only once");
+            Ok(1)
+        });
+        rlua.state.set_global("once");
+        rlua.do_string(r#"
+            host.record("a")
+            host.record("b")
+            assert(host.counter() == 1)
+            assert(host.counter() == 2)
+            assert(once() == "only once")
+            assert(not pcall(once))
+        "#).unwrap();
+        assert_eq!(*dropcount.borrow(), 0u32);
+    }
+    assert_eq!(*log.borrow(), vec!["a".to_string(), "b".to_string()]);
+    /* The captured state is dropped with the Lua state. */
+    assert_eq!(*dropcount.borrow(), 1u32);
+}
+
+#[test]
+fn lua_eval() {
+    let mut rlua = RumLua::new();
+    let x: i64 = rlua.eval("return 6 * 7").unwrap();
+    assert_eq!(x, 42);
+    let (a, b, c): (String, bool, Value) = rlua.eval("return 'x', true").unwrap();
+    assert_eq!((a.as_str(), b, c.type_name()), ("x", true, "nil"));
+    let err = rlua.eval::<i64>("return +").unwrap_err();
+    assert!(err.to_string().contains("Syntax error"));
+    assert!(rlua.eval::<()>("error('runtime')").is_err());
+    assert_eq!(rlua.state.get_top(), 0);
+}
+
+#[test]
+fn lua_unregistered_types() {
+    let mut rlua = RumLua::new();
+    let dropcount = Rc::new(RefCell::new(0u32));
+    assert!(rlua.push(&LuaPtr::new(TestDrop{ dropcount: dropcount.clone() })).is_err());
+    assert_eq!(rlua.state.get_top(), 0);
+
+    rlua.register_type::<TestMeth>("TestMeth".to_string(), &SOME_METHODS).unwrap();
+    rlua.push(&LuaPtr::new(TestMeth{data: "foo".to_string()})).unwrap();
+    assert!(rlua.get::<TestDrop>(1).is_err());
+    rlua.register_type::<TestDrop>("TestDrop".to_string(), &EMPTY_METHODS).unwrap();
+    assert!(rlua.get::<TestDrop>(1).is_err());
+    rlua.state.push("not userdata");
+    assert!(rlua.get::<TestMeth>(2).is_err());
+    assert!(rlua.get::<TestMeth>(1).is_ok());
+}
+
+#[test]
+fn lua_error_kinds() {
+    let mut rlua = RumLua::new();
+    match rlua.do_string("x = = 1") {
+        Err(LuaError::SyntaxError(_)) => {},
+        r => panic!("Unexpected result {:?}", r),
+    }
+    match rlua.do_string("error('oops')") {
+        Err(LuaError::RuntimeError{ message, traceback }) => {
+            assert!(message.contains("oops"));
+            assert!(traceback.unwrap().starts_with("stack traceback:"));
+        },
+        r => panic!("Unexpected result {:?}", r),
+    }
+    match rlua.eval::<i64>("return {}") {
+        Err(LuaError::ConversionError(msg)) => assert_eq!(msg, "integer expected, got table"),
+        r => panic!("Unexpected result {:?}", r),
+    }
+    match rlua.do_file("/nonexistent/file.lua") {
+        Err(LuaError::FileError(_)) => {},
+        r => panic!("Unexpected result {:?}", r),
+    }
+    match rlua.call_global::<_, ()>("no_such_function", ()) {
+        Err(LuaError::TypeError(_)) => {},
+        r => panic!("Unexpected result {:?}", r),
+    }
+
+    let err = LuaError::callback(TestError("foo".to_string()));
+    assert_eq!(err.to_string(), "TestError(foo)");
+    assert_eq!(err.source().unwrap().downcast_ref::<TestError>().unwrap().0, "foo");
+}
+
+#[test]
+fn lua_callback_error_preserved() {
+    let mut rlua = RumLua::new();
+    rlua.register_func_table("funcs", vec![
+        ("fail", test_fail),
+    ]);
+    let err = rlua.do_string(r#"
+        local function inner() funcs.fail() end
+        inner()
+    "#).unwrap_err();
+    match err {
+        LuaError::CallbackError{ ref cause } => {
+            assert_eq!(cause.downcast_ref::<TestError>().unwrap().0, "foo");
+        },
+        ref e => panic!("Unexpected error {:?}", e),
+    }
+
+    /* Errors caught in Lua don't leak into later failures. */
+    let err = rlua.do_string(r#"
+        pcall(funcs.fail)
+        error("something else")
+    "#).unwrap_err();
+    assert!(err.to_string().contains("something else"));
+}
+
+fn test_panic(_: &mut RumLua) -> LuaRet {
+    panic!("boom");
+}
+
+#[test]
+fn lua_callback_panic() {
+    let mut rlua = RumLua::new();
+    rlua.register_func_table("funcs", vec![
+        ("panic", test_panic),
+    ]);
+    let msg: String = rlua.eval(r#"
+        local ok, err = pcall(funcs.panic)
+        assert(not ok)
+        return err
+    "#).unwrap();
+    assert!(msg.contains("Rust callback panicked: boom"));
+
+    rlua.set_resume_panics(true);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        rlua.do_string("funcs.panic()")
+    }));
+    let payload = result.unwrap_err();
+    assert_eq!(*payload.downcast_ref::<&str>().unwrap(), "boom");
+}
+
+#[test]
+fn lua_threads() {
+    let mut rlua = RumLua::new();
+    let f: LuaFunction = rlua.eval(r#"
+        return function(a)
+            local b = coroutine.yield(a + 1)
+            local c = coroutine.yield(b * 2)
+            return a + b + c
+        end
+    "#).unwrap();
+    let thread = rlua.create_thread(&f).unwrap();
+    match thread.resume::<_, i64>(&mut rlua, 1) {
+        Resume::Yielded(2) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    match thread.resume::<_, i64>(&mut rlua, 10) {
+        Resume::Yielded(20) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    match thread.resume::<_, i64>(&mut rlua, 100) {
+        Resume::Finished(111) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    match thread.resume::<_, ()>(&mut rlua, ()) {
+        Resume::Error(_) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+
+    let f: LuaFunction = rlua.eval("return function() error('oops') end").unwrap();
+    let thread = rlua.create_thread(&f).unwrap();
+    match thread.resume::<_, ()>(&mut rlua, ()) {
+        Resume::Error(LuaError::RuntimeError{ref message, ref traceback}) => {
+            assert!(message.contains("oops"));
+            assert!(traceback.is_some());
+        },
+        r => panic!("unexpected {:?}", r),
+    }
+}
+
+#[test]
+fn lua_yielding_callback() {
+    let mut rlua = RumLua::new();
+    rlua.push_closure("wait", |rl| {
+        let n = i64::from_lua(rl, 1)?;
+        rl.state.push_integer(n);
+        rl.request_yield();
+        Ok(1)
+    });
+    rlua.state.set_global("wait");
+    let f: LuaFunction = rlua.eval(r#"
+        return function()
+            local x = wait(5)
+            return x * 2
+        end
+    "#).unwrap();
+    let thread = rlua.create_thread(&f).unwrap();
+    match thread.resume::<_, i64>(&mut rlua, ()) {
+        Resume::Yielded(5) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    match thread.resume::<_, i64>(&mut rlua, 21) {
+        Resume::Finished(42) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+
+    /* Outside a coroutine, yielding is an error. */
+    assert!(rlua.do_string("wait(1)").is_err());
+}
+
+struct Counter {
+    count: i64,
+}
+
+static COUNTER_METHODS: LuaType = LuaType{
+    methods: &[
+        ("incr", test_counter_incr),
+    ], };
+
+fn test_counter_incr(rl: &mut RumLua) -> LuaRet {
+    rl.with_mut::<Counter, _, _>(1, |c| c.count += 1)?;
+    Ok(0)
+}
+
+#[test]
+fn lua_scope() {
+    let mut rlua = RumLua::new();
+    rlua.register_type::<Counter>("Counter".to_string(), &COUNTER_METHODS).unwrap();
+    let mut calls = 0;
+    let mut counter = Counter{ count: 0 };
+    rlua.scope(|rl, scope| {
+        scope.push_closure(rl, "called", |_| { calls += 1; Ok(0) });
+        rl.state.set_global("called");
+        scope.push_mut(rl, &mut counter).unwrap();
+        rl.state.set_global("counter");
+        rl.do_string("called() called() counter:incr()").unwrap();
+    });
+    assert_eq!(calls, 2);
+    assert_eq!(counter.count, 1);
+
+    /* Both are unusable once the scope has ended. */
+    assert!(rlua.do_string("called()").is_err());
+    assert!(rlua.do_string("counter:incr()").is_err());
+
+    let readonly = Counter{ count: 0 };
+    rlua.scope(|rl, scope| {
+        scope.push_ref(rl, &readonly).unwrap();
+        rl.state.set_global("counter");
+        assert!(rl.do_string("counter:incr()").is_err());
+    });
+    assert_eq!(readonly.count, 0);
+}
+
+#[test]
+fn lua_builder_libs() {
+    let mut rlua = RumLuaBuilder::new().with_libs(StdLib::SAFE).build();
+    let (io, os, s): (Value, Value, String) = rlua.eval("return io, os, string.upper('x')").unwrap();
+    assert_eq!(io.type_name(), "nil");
+    assert_eq!(os.type_name(), "nil");
+    assert_eq!(s, "X");
+
+    /* Without the base library, callbacks still work. */
+    let mut rlua = RumLuaBuilder::new().with_libs(StdLib::TABLE).build();
+    rlua.register_func_table("funcs", vec![
+        ("fail", test_fail),
+    ]);
+    let (print, tinsert): (Value, Value) = rlua.eval("return print, table.insert").unwrap();
+    assert_eq!(print.type_name(), "nil");
+    assert_eq!(tinsert.type_name(), "function");
+    assert!(rlua.do_string("funcs.fail()").is_err());
+}
+
+#[test]
+fn lua_memory_limit() {
+    let mut rlua = RumLua::new();
+    let limit = rlua.memory_used() + 1024 * 1024;
+    rlua.set_memory_limit(Some(limit));
+    match rlua.do_string("local s = string.rep('x', 10 * 1024 * 1024)") {
+        Err(LuaError::MemoryLimit(_)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    /* Lua code can catch it */
+    let ok: bool = rlua.eval("return (pcall(string.rep, 'x', 10 * 1024 * 1024))").unwrap();
+    assert!(!ok);
+    assert!(rlua.memory_used() <= limit);
+
+    rlua.set_memory_limit(None);
+    rlua.do_string("local s = string.rep('x', 10 * 1024 * 1024)").unwrap();
+}
+
+#[test]
+fn lua_instruction_limit() {
+    let mut rlua = RumLua::new();
+    rlua.set_instruction_limit(Some(100000));
+    match rlua.do_string("while true do end") {
+        Err(LuaError::Timeout(_)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    /* The budget applies afresh to each call. */
+    rlua.do_string("for i=1,1000 do end").unwrap();
+    rlua.do_string("for i=1,1000 do end").unwrap();
+
+    rlua.set_instruction_limit(None);
+    rlua.set_timeout(Some(::std::time::Duration::from_millis(50)));
+    match rlua.do_string("while true do end") {
+        Err(LuaError::Timeout(_)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+}
+
+#[test]
+fn lua_interrupt() {
+    let mut rlua = RumLua::new();
+    let handle = rlua.interrupt_handle();
+    let interrupter = ::std::thread::spawn(move || {
+        ::std::thread::sleep(::std::time::Duration::from_millis(50));
+        handle.interrupt();
+    });
+    match rlua.do_string("while true do end") {
+        Err(LuaError::Interrupted(_)) => {},
+        r => panic!("unexpected {:?}", r),
+    }
+    interrupter.join().unwrap();
+    rlua.do_string("for i=1,10000 do end").unwrap();
+}
+
+struct Point {
+    x: f64,
+    y: f64,
+}
+
+impl Point {
+    fn norm(&self) -> f64 {
+        (self.x * self.x + self.y * self.y).sqrt()
+    }
+    fn scale(&mut self, by: f64) {
+        self.x *= by;
+        self.y *= by;
+    }
+    fn describe(&self, prefix: &str) -> String {
+        format!("{}({}, {})", prefix, self.x, self.y)
+    }
+}
+
+rum_methods! {
+    static POINT_METHODS for Point {
+        fn norm(&self) -> f64;
+        fn scale(&mut self, by: f64);
+        fn describe(&self, prefix: &str) -> String;
+    }
+}
+
+#[test]
+fn lua_methods_macro() {
+    let mut rlua = RumLua::new();
+    rlua.register_type::<Point>("Point".to_string(), &POINT_METHODS).unwrap();
+    rlua.push(&LuaPtr::new(Point{ x: 3.0, y: 4.0 })).unwrap();
+    rlua.state.set_global("p");
+    let (norm, desc): (f64, String) = rlua.eval(r#"
+        local n = p:norm()
+        p:scale(2)
+        return n, p:describe("P")
+    "#).unwrap();
+    assert_eq!(norm, 5.0);
+    assert_eq!(desc, "P(6, 8)");
+    assert!(rlua.do_string("p:scale('x')").is_err());
+}
+
+#[test]
+fn lua_type_builder() {
+    let mut rlua = RumLua::new();
+    let calls = Rc::new(RefCell::new(0));
+    let calls2 = calls.clone();
+    let mut builder = TypeBuilder::<TestMeth>::new("TestMeth")
+                          .methods(&SOME_METHODS)
+                          .closure("count", move |_| {
+                              *calls2.borrow_mut() += 1;
+                              Ok(0)
+                          });
+    for &name in ["get2"].iter() {
+        builder = builder.method(name, test_method_get);
+    }
+    rlua.register_type_builder(builder).unwrap();
+    rlua.push(&LuaPtr::new(TestMeth{data: "foo".to_string()})).unwrap();
+    rlua.state.set_global("testvar");
+    let s: String = rlua.eval(r#"
+        testvar:count()
+        testvar:set(testvar:get2() .. "bar")
+        return testvar:get()
+    "#).unwrap();
+    assert_eq!(s, "foobar");
+    assert_eq!(*calls.borrow(), 1);
+}
+
+fn test_point_add(rl: &mut RumLua) -> LuaRet {
+    let (x, y) = rl.with_ref::<Point, _, _>(1, |p| (p.x, p.y))?;
+    let (x2, y2) = rl.with_ref::<Point, _, _>(2, |p| (p.x, p.y))?;
+    rl.push(&LuaPtr::new(Point{ x: x + x2, y: y + y2 }))?;
+    Ok(1)
+}
+
+fn test_point_eq(rl: &mut RumLua) -> LuaRet {
+    let a = rl.with_ref::<Point, _, _>(1, |p| (p.x, p.y))?;
+    let b = rl.with_ref::<Point, _, _>(2, |p| (p.x, p.y))?;
+    rl.state.push_bool(a == b);
+    Ok(1)
+}
+
+fn test_point_tostring(rl: &mut RumLua) -> LuaRet {
+    let s = rl.with_ref::<Point, _, _>(1, |p| p.describe("Point"))?;
+    rl.state.push_string(&s);
+    Ok(1)
+}
+
+#[test]
+fn lua_metamethods() {
+    let mut rlua = RumLua::new();
+    rlua.register_type_builder(TypeBuilder::<Point>::new("Point")
+                                   .methods(&POINT_METHODS)
+                                   .metamethod(MetaMethod::Add, test_point_add)
+                                   .metamethod(MetaMethod::Eq, test_point_eq)
+                                   .metamethod(MetaMethod::ToString, test_point_tostring)
+                                   .metamethod_closure(MetaMethod::Len, |rl| {
+                                       rl.state.push_integer(2);
+                                       Ok(1)
+                                   })).unwrap();
+    rlua.push(&LuaPtr::new(Point{ x: 1.0, y: 2.0 })).unwrap();
+    rlua.state.set_global("a");
+    rlua.push(&LuaPtr::new(Point{ x: 3.0, y: 4.0 })).unwrap();
+    rlua.state.set_global("b");
+    let (s, eq, len): (String, bool, i64) = rlua.eval(r#"
+        local c = a + b
+        return tostring(c), c == a + b, #c
+    "#).unwrap();
+    assert_eq!(s, "Point(4, 6)");
+    assert!(eq);
+    assert_eq!(len, 2);
+}
+
+fn test_point_get_x(rl: &mut RumLua) -> LuaRet {
+    let x = rl.with_ref::<Point, _, _>(1, |p| p.x)?;
+    rl.state.push_number(x);
+    Ok(1)
+}
+
+fn test_point_set_x(rl: &mut RumLua) -> LuaRet {
+    let x = f64::from_lua(rl, 2)?;
+    rl.with_mut::<Point, _, _>(1, |p| p.x = x)?;
+    Ok(0)
+}
+
+#[test]
+fn lua_fields() {
+    let mut rlua = RumLua::new();
+    rlua.register_type_builder(TypeBuilder::<Point>::new("Point")
+                                   .methods(&POINT_METHODS)
+                                   .getter("x", test_point_get_x)
+                                   .setter("x", test_point_set_x)).unwrap();
+    let p = LuaPtr::new(Point{ x: 3.0, y: 4.0 });
+    rlua.push(&p).unwrap();
+    rlua.state.set_global("p");
+    let (x, norm, missing): (f64, f64, Value) = rlua.eval(r#"
+        local x = p.x
+        p.x = 6
+        return x, p:norm(), p.nothing
+    "#).unwrap();
+    assert_eq!(x, 3.0);
+    assert!((norm - 7.2111).abs() < 0.001);
+    assert_eq!(missing.type_name(), "nil");
+    assert_eq!(p.borrow().x, 6.0);
+    assert!(rlua.do_string("p.y = 1").is_err());
+}
+
+#[test]
+fn lua_debug_tostring() {
+    let mut rlua = RumLua::new();
+    rlua.register_type_builder(TypeBuilder::<TestMeth>::new("TestMeth")
+                                   .methods(&SOME_METHODS)
+                                   .debug_tostring()).unwrap();
+    rlua.push(&LuaPtr::new(TestMeth{data: "foo".to_string()})).unwrap();
+    rlua.state.set_global("testvar");
+    let s: String = rlua.eval("return tostring(testvar)").unwrap();
+    assert_eq!(s, "TestMeth { data: \"foo\" }");
+}
+
+fn test_meth_new(rl: &mut RumLua) -> LuaRet {
+    let data = String::from_lua(rl, 1)?;
+    rl.push(&LuaPtr::new(TestMeth{ data: data }))?;
+    Ok(1)
+}
+
+#[test]
+fn lua_static_functions() {
+    let mut rlua = RumLua::new();
+    rlua.register_type_builder(TypeBuilder::<TestMeth>::new("TestMeth")
+                                   .methods(&SOME_METHODS)
+                                   .static_fn("new", test_meth_new)).unwrap();
+    rlua.register_type_builder(TypeBuilder::<Point>::new("Point")
+                                   .methods(&POINT_METHODS)
+                                   .static_closure("origin", |rl| {
+                                       rl.push(&LuaPtr::new(Point{ x: 0.0, y: 0.0 }))?;
+                                       Ok(1)
+                                   })
+                                   .statics_in_rum()).unwrap();
+    let (s, norm, global): (String, f64, Value) = rlua.eval(r#"
+        local t = TestMeth.new("made in Lua")
+        return t:get(), rum.Point.origin():norm(), Point
+    "#).unwrap();
+    assert_eq!(s, "made in Lua");
+    assert_eq!(norm, 0.0);
+    assert_eq!(global.type_name(), "nil");
+}
+
+struct TestDerived {
+    base: LuaPtr<TestMeth>,
+    extra: i64,
+}
+
+impl Inherits<TestMeth> for TestDerived {
+    fn base(&self) -> &LuaPtr<TestMeth> {
+        &self.base
+    }
+}
+
+static DERIVED_METHODS: LuaType = LuaType{
+    methods: &[
+        ("extra", test_derived_extra),
+    ], };
+
+fn test_derived_extra(rl: &mut RumLua) -> LuaRet {
+    let extra = rl.with_ref::<TestDerived, _, _>(1, |d| d.extra)?;
+    rl.state.push_integer(extra);
+    Ok(1)
+}
+
+#[test]
+fn lua_inheritance() {
+    let mut rlua = RumLua::new();
+    rlua.register_type::<TestMeth>("TestMeth".to_string(), &SOME_METHODS).unwrap();
+    rlua.register_type_with_base::<TestDerived, TestMeth>("TestDerived".to_string(), &DERIVED_METHODS).unwrap();
+    let base = LuaPtr::new(TestMeth{ data: "foo".to_string() });
+    rlua.push(&LuaPtr::new(TestDerived{ base: base.clone(), extra: 7 })).unwrap();
+    rlua.state.set_global("d");
+    let (s, extra): (String, i64) = rlua.eval(r#"
+        d:set(d:get() .. "bar")
+        return d:get(), d:extra()
+    "#).unwrap();
+    assert_eq!(s, "foobar");
+    assert_eq!(extra, 7);
+    assert_eq!(base.borrow().data, "foobar");
+
+    rlua.state.get_global("d");
+    let as_base = rlua.get::<TestMeth>(-1).unwrap();
+    assert_eq!(as_base.borrow().data, "foobar");
+    rlua.state.pop(1);
+
+    /* But not the other way round */
+    rlua.push(&base).unwrap();
+    assert!(rlua.get::<TestDerived>(-1).is_err());
+}
+
+#[test]
+fn lua_borrow_conflict() {
+    let mut rlua = RumLua::new();
+    rlua.register_type::<Point>("Point".to_string(), &POINT_METHODS).unwrap();
+    let p = LuaPtr::new(Point{ x: 3.0, y: 4.0 });
+    rlua.push(&p).unwrap();
+    rlua.state.set_global("p");
+    rlua.push_closure("reenter", move |rl| {
+        let _guard = p.try_borrow_mut()?;
+        match rl.do_string("p:norm()") {
+            Err(LuaError::BorrowError(_)) => Ok(0),
+            r => panic!("unexpected {:?}", r),
+        }
+    });
+    rlua.state.set_global("reenter");
+    rlua.do_string("reenter()").unwrap();
+}
+
+#[test]
+fn lua_take() {
+    let mut rlua = RumLua::new();
+    rlua.register_type::<TestMeth>("TestMeth".to_string(), &SOME_METHODS).unwrap();
+
+    let shared = LuaPtr::new(TestMeth{data: "shared".to_string()});
+    rlua.push(&shared).unwrap();
+    assert!(rlua.take::<TestMeth>(-1).is_err());
+    let shared = match shared.try_unwrap() {
+        Ok(_) => panic!("still shared with Lua"),
+        Err(p) => p,
+    };
+    drop(shared);
+
+    rlua.push(&LuaPtr::new(TestMeth{data: "foo".to_string()})).unwrap();
+    rlua.state.set_global("testvar");
+    rlua.state.get_global("testvar");
+    let obj = rlua.take::<TestMeth>(-1).unwrap();
+    rlua.state.pop(1);
+    assert_eq!(obj.data, "foo");
+    assert!(rlua.do_string("testvar:get()").is_err());
+}
+
+#[test]
+fn lua_arcptr() {
+    let mut rlua = RumLua::new();
+    rlua.register_type::<Point>("Point".to_string(), &POINT_METHODS).unwrap();
+    let p = ArcPtr::new(Point{ x: 3.0, y: 4.0 });
+    let p2 = p.clone();
+    ::std::thread::spawn(move || {
+        p2.lock().unwrap().scale(2.0);
+    }).join().unwrap();
+    rlua.push_arc(&p).unwrap();
+    rlua.state.set_global("p");
+    let norm: f64 = rlua.eval("p:scale(0.5) return p:norm()").unwrap();
+    assert_eq!(norm, 5.0);
+    let back: ArcPtr<Point> = rlua.eval("return p").unwrap();
+    assert_eq!(back.lock().unwrap().x, 3.0);
+    assert!(rlua.eval::<LuaPtr<Point>>("return p").is_err());
+}
+
+#[test]
+fn lua_weak() {
+    let mut rlua = RumLua::new();
+    rlua.register_type::<TestMeth>("TestMeth".to_string(), &SOME_METHODS).unwrap();
+
+    /* Rust observes an object owned by Lua */
+    let weak: LuaWeak<TestMeth> = {
+        let p = LuaPtr::new(TestMeth{data: "foo".to_string()});
+        rlua.push(&p).unwrap();
+        rlua.state.set_global("testvar");
+        p.downgrade()
+    };
+    assert_eq!(weak.upgrade().unwrap().borrow().data, "foo");
+    rlua.do_string("testvar = nil; collectgarbage()").unwrap();
+    assert!(weak.upgrade().is_none());
+
+    /* Lua observes an object owned by Rust */
+    let p = LuaPtr::new(TestMeth{data: "bar".to_string()});
+    rlua.push_weak(&p.downgrade()).unwrap();
+    rlua.state.set_global("weakvar");
+    let s: String = rlua.eval("return weakvar:get()").unwrap();
+    assert_eq!(s, "bar");
+    drop(p);
+    assert!(rlua.do_string("weakvar:get()").is_err());
+}
+
+#[test]
+fn lua_refs() {
+    let mut rlua = RumLua::new();
+    let saved: Rc<RefCell<Option<LuaRef>>> = Rc::new(RefCell::new(None));
+    let saved2 = saved.clone();
+    rlua.push_closure("save", move |rl| {
+        *saved2.borrow_mut() = Some(rl.create_ref(1));
+        Ok(0)
+    });
+    rlua.state.set_global("save");
+    rlua.do_string("save({answer = 42})").unwrap();
+    rlua.do_string("collectgarbage()").unwrap();
+
+    let r = saved.borrow_mut().take().unwrap();
+    rlua.push_ref(&r).unwrap();
+    let t = LuaTable::from_lua(&mut rlua, -1).unwrap();
+    rlua.state.pop(1);
+    let answer: i64 = t.get(&mut rlua, "answer").unwrap();
+    assert_eq!(answer, 42);
+    drop(r);
+}
+
+#[test]
+fn lua_globals() {
+    let mut rlua = RumLua::new();
+    rlua.register_type::<TestMeth>("TestMeth".to_string(), &SOME_METHODS).unwrap();
+    rlua.set_global_value("testvar", LuaPtr::new(TestMeth{data: "foo".to_string()})).unwrap();
+    rlua.set_global_value("suffix", "bar").unwrap();
+    rlua.do_string("testvar:set(testvar:get() .. suffix)").unwrap();
+    let tvar: LuaPtr<TestMeth> = rlua.get_global_value("testvar").unwrap();
+    assert_eq!(tvar.borrow().data, "foobar");
+
+    let globals = rlua.globals();
+    globals.set(&mut rlua, "answer", 42).unwrap();
+    let answer: i64 = rlua.eval("return answer").unwrap();
+    assert_eq!(answer, 42);
+    assert!(rlua.get_global_value::<i64>("suffix").is_err());
+}
+
+#[test]
+fn lua_table_iteration() {
+    let mut rlua = RumLua::new();
+    let t: LuaTable = rlua.eval(r#"
+        return { "a", "b", "c", x = 1, y = 2 }
+    "#).unwrap();
+    let mut named: Vec<(String, i64)> = Vec::new();
+    let mut count = 0;
+    for pair in t.pairs::<Value, Value>(&mut rlua) {
+        let (k, v) = pair.unwrap();
+        count += 1;
+        if let (Value::String(k), Value::Integer(v)) = (k, v) {
+            named.push((k, v));
+        }
+    }
+    named.sort();
+    assert_eq!(count, 5);
+    assert_eq!(named, vec![("x".to_string(), 1), ("y".to_string(), 2)]);
+
+    let seq: Vec<String> = t.sequence_values::<String>(&mut rlua).map(|v| v.unwrap()).collect();
+    assert_eq!(seq, vec!["a", "b", "c"]);
+    assert!(t.pairs::<String, i64>(&mut rlua).any(|pair| pair.is_err()));
+}
+
+#[test]
+fn lua_collections() {
+    let mut rlua = RumLua::new();
+    rlua.set_global_value("list", vec![1, 2, 3]).unwrap();
+    let mut map = HashMap::new();
+    map.insert("a".to_string(), 1.5);
+    rlua.set_global_value("map", map).unwrap();
+    let mut set = HashSet::new();
+    set.insert(7);
+    rlua.set_global_value("set", set).unwrap();
+    let ok: bool = rlua.eval("return #list == 3 and list[3] == 3 and map.a == 1.5 and set[7]").unwrap();
+    assert!(ok);
+
+    let v: Vec<String> = rlua.eval(r#"return {"x", "y"}"#).unwrap();
+    assert_eq!(v, vec!["x", "y"]);
+    let m: BTreeMap<String, i64> = rlua.eval("return { b = 2, a = 1 }").unwrap();
+    assert_eq!(m.into_iter().collect::<Vec<_>>(), vec![("a".to_string(), 1), ("b".to_string(), 2)]);
+    let s: HashSet<i64> = rlua.eval("return { [4] = true, [5] = true }").unwrap();
+    assert!(s.contains(&4) && s.contains(&5));
+
+    match rlua.eval::<Vec<i64>>("return { 1, 2, x = 3 }") {
+        Err(LuaError::ConversionError(msg)) => assert!(msg.contains("sequence")),
+        _ => panic!("expected a conversion error"),
+    }
+    match rlua.eval::<Vec<i64>>(r#"return { 1, "two" }"#) {
+        Err(LuaError::ConversionError(msg)) => assert!(msg.contains("index 2")),
+        _ => panic!("expected a conversion error"),
+    }
+}
+
+#[test]
+fn lua_multi_values() {
+    let mut rlua = RumLua::new();
+    rlua.push_function("divmod", |_, (a, b): (i64, i64)| {
+        if b == 0 {
+            return Err(LuaError::RuntimeError{ message: "division by zero".to_string(), traceback: None });
+        }
+        Ok((a / b, a % b))
+    });
+    rlua.state.set_global("divmod");
+    let (q, r): (i64, i64) = rlua.call_global("divmod", (17, 5)).unwrap();
+    assert_eq!((q, r), (3, 2));
+    assert!(rlua.call_global::<_, (i64, i64)>("divmod", (1, 0)).is_err());
+
+    rlua.push_function("count", |_, args: MultiValue| Ok(args.0.len()));
+    rlua.state.set_global("count");
+    let n: usize = rlua.eval("return count(1, nil, 'x', {})").unwrap();
+    assert_eq!(n, 4);
+
+    let many: (i64, i64, i64, i64, i64, i64, i64, i64, i64, i64, i64, i64) =
+        rlua.eval("return 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12").unwrap();
+    assert_eq!(many.11, 12);
+    let MultiValue(values) = rlua.eval("return 1, 'two'").unwrap();
+    assert_eq!(values.len(), 2);
+}
+
+#[test]
+fn lua_varargs() {
+    let mut rlua = RumLua::new();
+    rlua.push_closure("describe", |rl| {
+        let rest = rl.varargs(2)?;
+        let desc = rest.iter().map(|v| format!("{:?}", v)).collect::<Vec<_>>().join(",");
+        rl.state.push_string(&desc);
+        Ok(1)
+    });
+    rlua.state.set_global("describe");
+    let desc: String = rlua.eval("return describe('skipped', 1, 'a', true)").unwrap();
+    assert_eq!(desc, "1,\"a\",true");
+
+    rlua.push_function("sum", |_, Variadic(xs): Variadic<i64>| Ok(xs.iter().sum::<i64>()));
+    rlua.state.set_global("sum");
+    let total: i64 = rlua.eval("return sum(1, 2, 3, 4)").unwrap();
+    assert_eq!(total, 10);
+    assert!(rlua.do_string("sum(1, 'x')").is_err());
+    let Variadic(back): Variadic<String> = rlua.call_global("select", (2, "a", "b", "c")).unwrap();
+    assert_eq!(back, vec!["b", "c"]);
+}
+
+#[test]
+fn lua_arg_checks() {
+    let mut rlua = RumLua::new();
+    rlua.push_closure("repeat_str", |rl| {
+        rl.check_arg_count(1, Some(2))?;
+        let s: String = rl.check_arg(1)?;
+        let n: usize = rl.opt_arg(2, 2)?;
+        rl.state.push_string(&s.repeat(n));
+        Ok(1)
+    });
+    rlua.state.set_global("repeat_str");
+    let s: String = rlua.eval("return repeat_str('ab')").unwrap();
+    assert_eq!(s, "abab");
+    let s: String = rlua.eval("return repeat_str('ab', 3)").unwrap();
+    assert_eq!(s, "ababab");
+
+    match rlua.do_string("repeat_str('ab', {})") {
+        Err(LuaError::ConversionError(msg)) => {
+            assert_eq!(msg, "bad argument #2 to 'repeat_str' (integer expected, got table)")
+        },
+        other => panic!("unexpected result {:?}", other),
+    }
+    match rlua.do_string("repeat_str()") {
+        Err(LuaError::ConversionError(msg)) => {
+            assert_eq!(msg, "bad argument #1 to 'repeat_str' (value expected)")
+        },
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(rlua.do_string("repeat_str('a', 1, 2)").is_err());
+
+    rlua.register_type::<TestMeth>("TestMeth".to_string(), &SOME_METHODS).unwrap();
+    rlua.push(&LuaPtr::new(TestMeth{data: "foo".to_string()})).unwrap();
+    rlua.state.set_global("testvar");
+    match rlua.do_string("testvar:set(nil)") {
+        Err(LuaError::ConversionError(msg)) => {
+            assert_eq!(msg, "bad argument #2 to 'set' (string expected, got nil)")
+        },
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn lua_binary_strings() {
+    let mut rlua = RumLua::new();
+    let blob: Vec<u8> = vec![0x89, b'P', 0, b'N', 0xff];
+    rlua.set_global_value("blob", blob.clone()).unwrap();
+    let len: i64 = rlua.eval("return #blob").unwrap();
+    assert_eq!(len, 5);
+    let back: LuaString = rlua.eval("return blob").unwrap();
+    assert_eq!(back.as_bytes(), &blob[..]);
+    assert!(back.to_str().is_err());
+
+    let s: LuaString = rlua.eval(r#"return "a\0b""#).unwrap();
+    assert_eq!(s.to_str().unwrap(), "a\0b");
+    let s: String = rlua.eval(r#"return "a\0b""#).unwrap();
+    assert_eq!(s, "a\0b");
+    rlua.set_global_value("nul", "x\0y").unwrap();
+    let len: i64 = rlua.eval("return #nul").unwrap();
+    assert_eq!(len, 3);
+}
+
+#[test]
+fn lua_integer_conversions() {
+    let mut rlua = RumLua::new();
+    assert_eq!(rlua.eval::<i64>("return 2.0").unwrap(), 2);
+    assert!(rlua.eval::<i64>("return 2.5").is_err());
+    assert!(rlua.eval::<i8>("return 300").is_err());
+    assert!(rlua.eval::<u32>("return -1").is_err());
+    assert_eq!(rlua.eval::<usize>("return 7").unwrap(), 7);
+    assert_eq!(rlua.eval::<f64>("return 1").unwrap(), 1.0);
+
+    rlua.set_global_value("i", 3usize).unwrap();
+    rlua.set_global_value("f", 3.0f64).unwrap();
+    let types: (String, String) = rlua.eval("return math.type(i), math.type(f)").unwrap();
+    assert_eq!(types, ("integer".to_string(), "float".to_string()));
+    /* Integers too big for Lua fall back to BigInt. */
+    rlua.set_global_value("big", u64::max_value()).unwrap();
+    assert_eq!(rlua.get_global_value::<u64>("big").unwrap(), u64::max_value());
+    assert!(rlua.get_global_value::<i64>("big").is_err());
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+enum Shape {
+    Dot,
+    Circle(f64),
+    Rect { w: i64, h: i64 },
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Config {
+    name: String,
+    level: Option<u32>,
+    tags: Vec<String>,
+    shapes: Vec<Shape>,
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn lua_serde() {
+    use ::{to_value, from_value};
+    let mut rlua = RumLua::new();
+    let config = Config {
+        name: "test".to_string(),
+        level: None,
+        tags: vec!["a".to_string(), "b".to_string()],
+        shapes: vec![Shape::Dot, Shape::Circle(1.5), Shape::Rect { w: 2, h: 3 }],
+    };
+    rlua.push_serialize(&config).unwrap();
+    rlua.state.set_global("config");
+    let ok: bool = rlua.eval(r#"
+        return config.name == "test" and config.level == nil and #config.tags == 2
+           and config.shapes[1] == "Dot" and config.shapes[2].Circle == 1.5
+           and config.shapes[3].Rect.h == 3
+    "#).unwrap();
+    assert!(ok);
+    rlua.state.get_global("config");
+    let back: Config = rlua.get_deserialize(-1).unwrap();
+    rlua.state.pop(1);
+    assert_eq!(back, config);
+
+    let value: Value = rlua.eval(r#"return { name = "lua", level = 2, tags = {}, shapes = { { Rect = { w = 1, h = 1 } } } }"#).unwrap();
+    let from_lua: Config = from_value(&mut rlua, value).unwrap();
+    assert_eq!(from_lua.level, Some(2));
+    assert_eq!(from_lua.shapes, vec![Shape::Rect { w: 1, h: 1 }]);
+    let value = to_value(&mut rlua, &vec![1, 2, 3]).unwrap();
+    let nums: Vec<i64> = from_value(&mut rlua, value).unwrap();
+    assert_eq!(nums, vec![1, 2, 3]);
+
+    let bad: Value = rlua.eval("return { name = 3 }").unwrap();
+    assert!(from_value::<Config>(&mut rlua, bad).is_err());
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn lua_json() {
+    use serde_json;
+    let mut rlua = RumLua::new();
+    let s: String = rlua.eval(r#"return rum.json.encode({ name = "x", list = { 1, 2.5, true } })"#).unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&s).unwrap();
+    assert_eq!(parsed["name"], "x");
+    assert_eq!(parsed["list"][2], true);
+
+    let ok: bool = rlua.eval(r#"
+        local v = rum.json.decode('{"a": [1, 2, {"b": "c"}], "n": 1.5}')
+        return v.a[3].b == "c" and v.n == 1.5
+    "#).unwrap();
+    assert!(ok);
+    assert!(rlua.do_string("rum.json.decode('{')").is_err());
+
+    let value: serde_json::Value = serde_json::from_str(r#"{"k": [3]}"#).unwrap();
+    rlua.push_json(&value).unwrap();
+    let back = rlua.to_json(-1).unwrap();
+    rlua.state.pop(1);
+    assert_eq!(back, value);
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn lua_msgpack() {
+    let mut from = RumLua::new();
+    let mut to = RumLua::new();
+    from.state.get_global("math");
+    assert!(from.to_msgpack(-1).is_err());
+    from.state.pop(1);
+
+    let _: () = from.eval(r#"
+        data = { 1, -200, 2^40, 1.5, "str", "\0\255", true, { x = { y = false } } }
+        cyclic = {}
+        cyclic.self = cyclic
+    "#).unwrap();
+    from.state.get_global("data");
+    let bytes = from.to_msgpack(-1).unwrap();
+    from.state.pop(1);
+    to.push_msgpack(&bytes).unwrap();
+    to.state.set_global("data");
+    let ok: bool = to.eval(r#"
+        return #data == 8 and data[1] == 1 and data[2] == -200 and data[3] == 2^40
+           and data[4] == 1.5 and data[5] == "str" and data[6] == "\0\255"
+           and data[7] == true and data[8].x.y == false
+    "#).unwrap();
+    assert!(ok);
+
+    from.state.get_global("cyclic");
+    assert!(from.to_msgpack(-1).is_err());
+    from.state.pop(1);
+    assert!(to.push_msgpack(&bytes[..bytes.len() - 1]).is_err());
+    assert_eq!(to.state.get_top(), 0);
+}
+
+static GREET_LOADS: AtomicUsize = AtomicUsize::new(0);
+
+fn greet_hello(rl: &mut RumLua) -> LuaRet {
+    let name: String = rl.check_arg(1)?;
+    rl.state.push_string(&format!("hello {}", name));
+    Ok(1)
+}
+
+fn open_greet(rl: &mut RumLua) -> LuaRet {
+    GREET_LOADS.fetch_add(1, Ordering::SeqCst);
+    rl.state.new_table();
+    rl.push_closure("hello", greet_hello);
+    rl.state.set_field(-2, "hello");
+    Ok(1)
+}
+
+#[test]
+fn lua_preload_module() {
+    let mut rlua = RumLua::new();
+    rlua.preload_module("greet", open_greet);
+    assert_eq!(GREET_LOADS.load(Ordering::SeqCst), 0);
+    let s: String = rlua.eval(r#"
+        local greet = require "greet"
+        assert(require "greet" == greet)
+        return greet.hello("world")
+    "#).unwrap();
+    assert_eq!(s, "hello world");
+    assert_eq!(GREET_LOADS.load(Ordering::SeqCst), 1);
+    assert!(rlua.do_string("greet.hello('x')").is_err());
+}
+
+#[test]
+fn lua_bundled_modules() {
+    let mut rlua = RumLua::new();
+    rlua.preload_lua_module("counter", r#"
+        local n = 0
+        return function() n = n + 1; return n end
+    "#).unwrap();
+    bundle!(&mut rlua, "shapes" => "../tests/lua/shapes.lua").unwrap();
+    let r: (i64, i64, i64) = rlua.eval(r#"
+        local count = require "counter"
+        count()
+        return require("counter")(), require("shapes").area(2, 3), package.loaded.counter and 1 or 0
+    "#).unwrap();
+    assert_eq!(r, (2, 6, 1));
+    match rlua.preload_lua_module("broken", "return (") {
+        Err(LuaError::SyntaxError(msg)) => assert!(msg.contains("broken")),
+        _ => panic!("expected a syntax error"),
+    }
+}
+
+struct MemorySource {
+    files: HashMap<String, &'static str>,
+}
+
+impl ScriptSource for MemorySource {
+    fn load(&self, name: &str) -> Option<Vec<u8>> {
+        self.files.get(name).map(|s| s.as_bytes().to_vec())
+    }
+}
+
+#[test]
+fn lua_script_source() {
+    let mut files = HashMap::new();
+    files.insert("lib.util".to_string(), "return { twice = function(x) return 2 * x end }");
+    files.insert("config".to_string(), "value = (value or 0) + 1; return value");
+    files.insert("broken".to_string(), "return (");
+    let mut rlua = RumLua::new();
+    rlua.set_script_source(MemorySource { files: files }).unwrap();
+
+    let r: (i64, i64, i64) = rlua.eval(r#"
+        local util = require "lib.util"
+        local env = { }
+        local chunk = loadfile("config", "t", env)
+        chunk()
+        return util.twice(21), env.value, loadfile("config")()
+    "#).unwrap();
+    assert_eq!(r, (42, 1, 1));
+    let (f, msg): (Value, String) = rlua.eval(r#"return loadfile("missing")"#).unwrap();
+    assert!(match f { Value::Nil => true, _ => false });
+    assert!(msg.contains("missing"));
+    assert!(rlua.do_string(r#"require "broken""#).is_err());
+
+    let mut bare = RumLuaBuilder::new().with_libs(StdLib::BASE).build();
+    assert!(bare.set_script_source(|_: &str| None).is_err());
+}
+
+#[test]
+fn lua_load_named_chunks() {
+    let mut rlua = RumLua::new();
+    let f = rlua.load_bytes(b"return 1 + ...", "@config/ai.lua").unwrap();
+    let r: i64 = f.call(&mut rlua, 2).unwrap();
+    assert_eq!(r, 3);
+
+    let f = rlua.load_reader(Cursor::new("error('bad config')"), "@config/ai.lua").unwrap();
+    match f.call::<_, ()>(&mut rlua, ()) {
+        Err(LuaError::RuntimeError{ message, .. }) => assert!(message.contains("config/ai.lua:1: bad config")),
+        _ => panic!("expected a runtime error"),
+    }
+    match rlua.load_bytes(b"x = = 1", "=settings") {
+        Err(LuaError::SyntaxError(msg)) => assert!(msg.contains("settings:1:")),
+        _ => panic!("expected a syntax error"),
+    }
+}
+
+#[test]
+fn lua_check_syntax() {
+    let mut rlua = RumLua::new();
+    assert_eq!(rlua.check_syntax("x = 1", "@ok.lua"), Ok(()));
+    let err = rlua.check_syntax("local a = 1\nlocal b = = 2\n", "@scripts/bad.lua").unwrap_err();
+    assert_eq!(err.chunk, "scripts/bad.lua");
+    assert_eq!(err.line, Some(2));
+    assert!(err.message.contains("unexpected symbol"));
+    assert_eq!(format!("{}", err), format!("scripts/bad.lua:2: {}", err.message));
+    let _: () = rlua.eval("assert(x == nil)").unwrap();
+    assert_eq!(rlua.state.get_top(), 0);
+
+    let parsed = SyntaxError::parse("[string \"a:b\"]:3: '=' expected near 'c'");
+    assert_eq!(parsed.chunk, "[string \"a:b\"]");
+    assert_eq!(parsed.line, Some(3));
+    assert_eq!(parsed.message, "'=' expected near 'c'");
+}
+
+#[test]
+fn lua_chunk_environments() {
+    let mut rlua = RumLua::new();
+    rlua.do_string("shared = 'real'").unwrap();
+    let env1 = rlua.create_env();
+    let env2 = rlua.create_env();
+    rlua.do_string_in_env("shared = 'one'; mine = string.upper(shared)", &env1).unwrap();
+    rlua.do_string_in_env("shared = 'two'", &env2).unwrap();
+    assert_eq!(env1.get::<_, String>(&mut rlua, "mine").unwrap(), "ONE");
+    assert_eq!(env2.get::<_, String>(&mut rlua, "shared").unwrap(), "two");
+    assert_eq!(rlua.get_global_value::<String>("shared").unwrap(), "real");
+    assert!(rlua.get_global_value::<Value>("mine").map(|v| match v { Value::Nil => true, _ => false }).unwrap());
+
+    let bare = rlua.create_table();
+    bare.set(&mut rlua, "x", 5).unwrap();
+    let f = rlua.load_with_env(b"return x, print", "=sandboxed", &bare).unwrap();
+    let (x, p): (i64, Value) = f.call(&mut rlua, ()).unwrap();
+    assert_eq!(x, 5);
+    assert!(match p { Value::Nil => true, _ => false });
+
+    /* Bytecode isn't checked by Lua, so sandboxed chunks must be source. */
+    let blob: LuaString = rlua.eval("return string.dump(function() return 1 end)").unwrap();
+    assert!(rlua.load_with_env(blob.as_bytes(), "=dumped", &bare).is_err());
+    assert!(rlua.load_bytes(blob.as_bytes(), "=dumped").is_ok());
+}
+
+#[test]
+fn lua_readonly_tables() {
+    let mut rlua = RumLua::new();
+    let rum: LuaTable = rlua.get_global_value("rum").unwrap();
+    rum.set(&mut rlua, "version", 3).unwrap();
+    rlua.set_global_value("limit", 10).unwrap();
+    rlua.freeze_globals().unwrap();
+    assert!(rlua.globals().is_readonly(&mut rlua).unwrap());
+
+    assert!(rlua.do_string("limit = 11").is_err());
+    assert!(rlua.do_string("rum.version = 4").is_err());
+    assert!(rlua.do_string("newglobal = 1").is_err());
+    assert!(rlua.do_string("setmetatable(rum, nil)").is_err());
+    assert_eq!(rlua.eval::<i64>("return limit + rum.version").unwrap(), 13);
+    let expected = if cfg!(feature = "json") { 2 } else { 1 };
+    assert_eq!(rlua.eval::<i64>("local n = 0; for k, v in pairs(rum) do n = n + 1 end; return n").unwrap(),
+               expected);
+
+    /* The host can still change things between scripts. */
+    rlua.set_global_value("limit", 20).unwrap();
+    assert_eq!(rlua.eval::<i64>("return limit").unwrap(), 20);
+
+    let t = rlua.create_table();
+    t.set(&mut rlua, 1, "a").unwrap();
+    t.set(&mut rlua, 2, "b").unwrap();
+    t.set_readonly(&mut rlua, true).unwrap();
+    rlua.set_global_value("t", t.clone()).unwrap();
+    assert_eq!(rlua.eval::<i64>("return #t").unwrap(), 2);
+    assert!(rlua.do_string("t[1] = 'z'").is_err());
+    t.set_readonly(&mut rlua, false).unwrap();
+    rlua.do_string("t[1] = 'z'").unwrap();
+    assert_eq!(t.raw_get::<_, String>(&mut rlua, 1).unwrap(), "z");
+}
+
+#[test]
+fn lua_print_handler() {
+    let mut rlua = RumLua::new();
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let lines = output.clone();
+    rlua.set_print_handler(Box::new(move |line| lines.borrow_mut().push(line.to_string())));
+    let written = output.clone();
+    rlua.set_write_handler(Box::new(move |text| written.borrow_mut().push(text.to_string()))).unwrap();
+    rlua.do_string(r#"
+        print("hello", 42, nil, true)
+        print(setmetatable({}, { __tostring = function() return "custom" end }))
+        print()
+        io.write("a", 1, 2.5)
+        io.write("b")
+    "#).unwrap();
+    assert_eq!(*output.borrow(), vec!["hello\t42\tnil\ttrue".to_string(), "custom".to_string(),
+                                      "".to_string(), "a12.5".to_string(), "b".to_string()]);
+    assert!(rlua.do_string("io.write({})").is_err());
+}
+
+#[test]
+fn lua_stack_dump() {
+    let mut rlua = RumLua::new();
+    rlua.register_type::<TestDrop>("TestDrop".to_string(), &EMPTY_METHODS).unwrap();
+    rlua.do_string("t = {1, 2, 3}").unwrap();
+    let dropcount = Rc::new(RefCell::new(0u32));
+    rlua.push(&LuaPtr::new(TestDrop{ dropcount: dropcount })).unwrap();
+    rlua.state.push_integer(42);
+    rlua.state.push_number(1.5);
+    rlua.state.push_string("hi");
+    rlua.state.get_global("t");
+    rlua.state.push_nil();
+
+    let dump = rlua.stack_dump();
+    let lines: Vec<&str> = dump.lines().collect();
+    assert_eq!(lines[0], "Lua stack (6 items)");
+    assert!(lines[1].starts_with("  1 (-6): userdata ") && lines[1].ends_with("(TestDrop)"));
+    assert_eq!(lines[2], "  2 (-5): integer 42");
+    assert_eq!(lines[3], "  3 (-4): float 1.5");
+    assert_eq!(lines[4], "  4 (-3): string \"hi\" (len 2)");
+    assert!(lines[5].starts_with("  5 (-2): table ") && lines[5].ends_with("(len 3)"));
+    assert_eq!(lines[6], "  6 (-1): nil");
+
+    /* The stack is unchanged, and its numbers are still numbers. */
+    assert_eq!(rlua.state.get_top(), 6);
+    assert!(rlua.state.type_of(2) == Some(lua::Type::Number));
+    assert!(rlua.state.is_integer(2));
+}
+
+#[test]
+fn lua_traceback_frames() {
+    let mut rlua = RumLua::new();
+    let f = rlua.load_bytes(b"local function inner()
+    error('broken')
+end
+local function outer()
+    inner()
+end
+outer()", "@scripts/a.lua").unwrap();
+    let e = f.call::<_, ()>(&mut rlua, ()).unwrap_err();
+    let frames = e.frames();
+    assert_eq!(frames[0], Frame{ source: "[C]".to_string(), line: None, name: Some("error".to_string()) });
+    assert_eq!(frames[1], Frame{ source: "scripts/a.lua".to_string(), line: Some(2), name: Some("inner".to_string()) });
+    assert_eq!(frames[2], Frame{ source: "scripts/a.lua".to_string(), line: Some(5), name: Some("outer".to_string()) });
+    assert_eq!(frames[3], Frame{ source: "scripts/a.lua".to_string(), line: Some(7), name: None });
+    assert!(LuaError::ConversionError("x".to_string()).frames().is_empty());
+}
+
+#[test]
+fn lua_source_maps() {
+    let mut rlua = RumLua::new();
+    /* A template expanded with two lines of prelude. */
+    let chunk = "local page = {}\nlocal title = 'x'\nlocal n = 1\nerror('bad template')\n";
+    rlua.set_source_map("@page.lua", |line: u32| {
+        if line > 2 { Some(("page.tmpl".to_string(), line - 2)) } else { None }
+    }).unwrap();
+    assert!(rlua.set_source_map("return 1", |_: u32| None).is_err());
+    let e = rlua.do_string_named(chunk, "@page.lua").unwrap_err();
+    assert!(e.to_string().contains("page.tmpl:2: bad template"));
+    assert!(e.frames().iter().any(|f| f.source == "page.tmpl" && f.line == Some(2)));
+
+    let e = rlua.do_string_named("local x = 1\nlocal y = 2\nlocal z = = 3", "@page.lua").unwrap_err();
+    assert!(e.to_string().contains("page.tmpl:1:"));
+    let e = rlua.check_syntax("x = 1\n\n\n)", "@page.lua").unwrap_err();
+    assert_eq!((e.chunk.as_str(), e.line), ("page.tmpl", Some(2)));
+
+    /* Other chunks are not affected. */
+    let e = rlua.do_string_named("\n\n\nerror('elsewhere')", "@mypage.lua").unwrap_err();
+    assert!(e.to_string().contains("mypage.lua:4: elsewhere"));
+}
+
+#[test]
+fn lua_memory_stats() {
+    let mut rlua = RumLua::new();
+    rlua.register_type::<TestDrop>("TestDrop".to_string(), &EMPTY_METHODS).unwrap();
+    let dropcount = Rc::new(RefCell::new(0u32));
+    for _ in 0..3 {
+        rlua.push(&LuaPtr::new(TestDrop{ dropcount: dropcount.clone() })).unwrap();
+    }
+    let stats = rlua.memory_stats();
+    assert_eq!(stats.userdata["TestDrop"], 3);
+    assert_eq!(stats.bytes_used, rlua.memory_used());
+    assert!(stats.allocations > stats.frees);
+
+    rlua.state.set_top(0);
+    rlua.do_string("collectgarbage()").unwrap();
+    let after = rlua.memory_stats();
+    assert_eq!(after.userdata["TestDrop"], 0);
+    assert_eq!(*dropcount.borrow(), 3);
+    assert!(after.frees > stats.frees);
+}
+
+#[test]
+fn lua_on_collect() {
+    let collected = Rc::new(RefCell::new(Vec::new()));
+    let seen = collected.clone();
+    let mut rlua = RumLua::new();
+    rlua.register_type_builder(TypeBuilder::<TestMeth>::new("TestMeth")
+        .on_collect(move |obj| {
+            seen.borrow_mut().push(obj.data.clone());
+            obj.data = "closed".to_string();
+        })).unwrap();
+    let kept = LuaPtr::new(TestMeth{ data: "kept".to_string() });
+    rlua.push(&kept).unwrap();
+    rlua.push(&LuaPtr::new(TestMeth{ data: "temp".to_string() })).unwrap();
+    rlua.state.set_top(0);
+    rlua.do_string("collectgarbage()").unwrap();
+    let mut names = collected.borrow().clone();
+    names.sort();
+    assert_eq!(names, vec!["kept".to_string(), "temp".to_string()]);
+    /* The hook ran on the shared value, which Rust still holds. */
+    assert_eq!(kept.borrow().data, "closed");
+}
+
+#[test]
+fn lua_uservalues() {
+    let mut rlua = RumLua::new();
+    rlua.register_type::<TestMeth>("TestMeth".to_string(), &EMPTY_METHODS).unwrap();
+    rlua.push(&LuaPtr::new(TestMeth{ data: "x".to_string() })).unwrap();
+    assert!(match rlua.get_uservalue::<Value>(-1).unwrap() { Value::Nil => true, _ => false });
+    let extra = rlua.create_table();
+    extra.set(&mut rlua, "cache", 42).unwrap();
+    rlua.set_uservalue(-1, extra).unwrap();
+    let got: LuaTable = rlua.get_uservalue(-1).unwrap();
+    assert_eq!(got.get::<_, i64>(&mut rlua, "cache").unwrap(), 42);
+    assert_eq!(rlua.state.get_top(), 1);
+
+    rlua.state.push_integer(1);
+    assert!(rlua.set_uservalue(-1, 2).is_err());
+    assert!(rlua.get_uservalue::<Value>(-1).is_err());
+}
+
+#[test]
+fn lua_debug_hooks() {
+    let mut rlua = RumLua::new();
+    let events: Rc<RefCell<Vec<DebugInfo>>> = Rc::new(RefCell::new(Vec::new()));
+    let seen = events.clone();
+    rlua.set_hook(HookTriggers{ on_call: true, every_line: true, ..Default::default() },
+                  Box::new(move |_rl, info| seen.borrow_mut().push(info)));
+    let f = rlua.load_bytes(b"local function helper()
+    return 1
+end
+return helper()", "@traced.lua").unwrap();
+    f.call::<_, i64>(&mut rlua, ()).unwrap();
+    rlua.remove_hook();
+    rlua.do_string("local x = 1").unwrap();
+
+    let events = events.borrow();
+    let lines: Vec<u32> = events.iter().filter(|e| e.event == HookEvent::Line)
+                                .filter_map(|e| e.current_line).collect();
+    assert_eq!(lines, vec![3, 4, 2]);
+    assert!(events.iter().any(|e| e.event == HookEvent::TailCall && e.line_defined == Some(1)));
+    assert!(events.iter().all(|e| e.source == "traced.lua" || e.what == "C"));
+
+    /* A count hook can stop a script by interrupting it. */
+    let mut rlua = RumLua::new();
+    let handle = rlua.interrupt_handle();
+    let count = Rc::new(RefCell::new(0));
+    let counter = count.clone();
+    rlua.set_hook(HookTriggers{ every_nth_instruction: Some(100), ..Default::default() },
+                  Box::new(move |_rl, _info| {
+                      *counter.borrow_mut() += 1;
+                      if *counter.borrow() == 5 {
+                          handle.interrupt();
+                      }
+                  }));
+    match rlua.do_string("while true do end") {
+        Err(LuaError::Interrupted(_)) => {},
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(*count.borrow() >= 5);
+}
+
+#[cfg(feature = "profiler")]
+#[test]
+fn lua_profiler() {
+    use ::Profiler;
+    let mut rlua = RumLua::new();
+    let f = rlua.load_bytes(b"local function work(n)
+    local total = 0
+    for i = 1, n do total = total + i end
+    return total
+end
+local sum = 0
+for i = 1, 3 do sum = sum + work(10) end
+return sum", "@prof.lua").unwrap();
+    let profiler = Profiler::start(&mut rlua);
+    assert_eq!(f.call::<_, i64>(&mut rlua, ()).unwrap(), 165);
+    profiler.stop(&mut rlua);
+
+    let functions = profiler.functions();
+    let work = functions.iter().find(|s| s.name == "work (prof.lua:1)").unwrap();
+    assert_eq!(work.calls, 3);
+    assert!(work.total >= work.self_time);
+    let line4 = profiler.lines().into_iter().find(|l| l.source == "prof.lua" && l.line == 4).unwrap();
+    assert_eq!(line4.hits, 3);
+    assert!(profiler.flat_report().contains("work (prof.lua:1)"));
+    assert!(profiler.folded_stacks().lines().any(|l| l.starts_with("main chunk (prof.lua);work (prof.lua:1) ")));
+}
+
+#[test]
+fn lua_coverage() {
+    let mut rlua = RumLua::new();
+    let f = rlua.load_bytes(b"local function check(x)
+    if x > 0 then
+        return 'positive'
+    end
+    return 'other'
+end
+check(1)
+check(2)", "@cov.lua").unwrap();
+    let coverage = Coverage::start(&mut rlua);
+    f.call::<_, ()>(&mut rlua, ()).unwrap();
+    coverage.stop(&mut rlua);
+
+    let hits = coverage.hits();
+    let lines = &hits["cov.lua"];
+    assert_eq!(lines[&2], 2);
+    assert_eq!(lines[&3], 2);
+    assert_eq!(lines[&5], 0);
+    assert_eq!(lines[&7], 1);
+    assert!(!coverage.executed()["cov.lua"].contains(&5));
+    let lcov = coverage.lcov();
+    assert!(lcov.starts_with("TN:\nSF:cov.lua\n"));
+    assert!(lcov.contains("DA:5,0\n"));
+    assert!(lcov.ends_with("end_of_record\n"));
+}
+
+#[test]
+fn lua_debugger() {
+    let mut rlua = RumLua::new();
+    let f = rlua.load_bytes(b"local scale = 10
+local function area(w, h)
+    local a = w * h
+    return a * scale
+end
+local total = area(2, 3)
+total = total + 1
+return total", "@dbg.lua").unwrap();
+    let pauses = Rc::new(RefCell::new(Vec::new()));
+    let seen = pauses.clone();
+    let debugger = Debugger::attach(&mut rlua, Box::new(move |_rl, pause| {
+        let mut seen = seen.borrow_mut();
+        seen.push((pause.reason, pause.line));
+        if pause.line == 3 {
+            let locals: Vec<&str> = pause.locals.iter().map(|&(ref name, _)| &name[..]).collect();
+            assert_eq!(locals, vec!["w", "h"]);
+            assert!(pause.upvalues.iter().any(|&(ref name, ref v)| name == "scale" && match *v { Value::Integer(10) => true, _ => false }));
+            assert_eq!(pause.function, Some("area".to_string()));
+        }
+        match seen.len() {
+            1 => StepMode::StepOver,
+            2 => StepMode::StepOut,
+            _ => StepMode::Continue,
+        }
+    }));
+    debugger.set_breakpoint("dbg.lua", 3);
+    assert_eq!(f.call::<_, i64>(&mut rlua, ()).unwrap(), 61);
+    debugger.detach(&mut rlua);
+    assert_eq!(*pauses.borrow(), vec![(PauseReason::Breakpoint, 3), (PauseReason::Step, 4),
+                                      (PauseReason::Step, 7)]);
+}
+
+#[cfg(feature = "dap")]
+#[test]
+fn lua_dap_server() {
+    use ::DapServer;
+    use serde_json::{self, Value as Json};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn send(stream: &mut TcpStream, seq: u64, command: &str, arguments: Json) {
+        let body = serde_json::to_vec(&json!({ "seq": seq, "type": "request", "command": command,
+                                               "arguments": arguments })).unwrap();
+        write!(stream, "Content-Length: {}\r\n\r\n", body.len()).unwrap();
+        stream.write_all(&body).unwrap();
+    }
+    /* Read messages until the response or event called `name`. */
+    fn expect(input: &mut BufReader<TcpStream>, name: &str) -> Json {
+        loop {
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                input.read_line(&mut line).unwrap();
+                let line = line.trim();
+                if line.is_empty() {
+                    break;
+                }
+                len = line["Content-Length:".len()..].trim().parse().unwrap();
+            }
+            let mut body = vec![0; len];
+            input.read_exact(&mut body).unwrap();
+            let message: Json = serde_json::from_slice(&body).unwrap();
+            if message["command"] == name || message["event"] == name {
+                return message;
+            }
+        }
+    }
+
+    let mut rlua = RumLua::new();
+    let f = rlua.load_bytes(b"local scale = 10
+local function area(w, h)
+    local a = { w = w, h = h }
+    return a.w * a.h * scale
+end
+return area(2, 3)", "@dbg.lua").unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let editor = thread::spawn(move || {
+        let mut output = TcpStream::connect(addr).unwrap();
+        let mut input = BufReader::new(output.try_clone().unwrap());
+        send(&mut output, 1, "initialize", json!({ "adapterID": "rum" }));
+        expect(&mut input, "initialized");
+        send(&mut output, 2, "setBreakpoints", json!({ "source": { "path": "dbg.lua" },
+                                                       "breakpoints": [{ "line": 4 }] }));
+        assert_eq!(expect(&mut input, "setBreakpoints")["body"]["breakpoints"][0]["verified"], true);
+        send(&mut output, 3, "configurationDone", json!({}));
+        expect(&mut input, "configurationDone");
+
+        assert_eq!(expect(&mut input, "stopped")["body"]["reason"], "breakpoint");
+        send(&mut output, 4, "stackTrace", json!({ "threadId": 1 }));
+        let trace = expect(&mut input, "stackTrace");
+        let frames = trace["body"]["stackFrames"].as_array().unwrap();
+        assert_eq!((&frames[0]["line"], &frames[0]["name"]), (&json!(4), &json!("area")));
+        assert_eq!(frames[1]["line"], 6);
+
+        /* The caller's scopes are its own. */
+        send(&mut output, 5, "scopes", json!({ "frameId": frames[1]["id"] }));
+        let scopes = expect(&mut input, "scopes");
+        send(&mut output, 6, "variables", json!({ "variablesReference": scopes["body"]["scopes"][0]["variablesReference"] }));
+        let locals = expect(&mut input, "variables");
+        let names: Vec<&str> = locals["body"]["variables"].as_array().unwrap().iter()
+                                   .map(|v| v["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["scale", "area"]);
+
+        send(&mut output, 7, "scopes", json!({ "frameId": frames[0]["id"] }));
+        let scopes = expect(&mut input, "scopes");
+        send(&mut output, 8, "variables", json!({ "variablesReference": scopes["body"]["scopes"][0]["variablesReference"] }));
+        let locals = expect(&mut input, "variables");
+        let locals = locals["body"]["variables"].as_array().unwrap();
+        let names: Vec<&str> = locals.iter().map(|v| v["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["w", "h", "a"]);
+        assert_eq!(locals[0]["value"], "2");
+        let table = locals[2]["variablesReference"].as_u64().unwrap();
+        send(&mut output, 9, "variables", json!({ "variablesReference": table }));
+        let fields = expect(&mut input, "variables");
+        assert_eq!(fields["body"]["variables"].as_array().unwrap().len(), 2);
+        send(&mut output, 10, "bogus", json!({}));
+        assert_eq!(expect(&mut input, "bogus")["success"], false);
+        send(&mut output, 11, "continue", json!({ "threadId": 1 }));
+        expect(&mut input, "continue");
+        expect(&mut input, "terminated");
+    });
+    let (stream, _) = listener.accept().unwrap();
+    let server = DapServer::attach(&mut rlua, stream).unwrap();
+    assert_eq!(f.call::<_, i64>(&mut rlua, ()).unwrap(), 60);
+    assert!(server.is_connected());
+    server.detach(&mut rlua);
+    assert!(!server.is_connected());
+    editor.join().unwrap();
+}
+
+#[test]
+fn lua_locals_and_upvalues() {
+    let mut rlua = RumLua::new();
+    rlua.push_closure("inspect", |rl| {
+        /* Level 2 is the Lua function calling this callback. */
+        let locals = rl.locals(2)?;
+        let names: Vec<&str> = locals.iter().map(|&(ref name, _)| &name[..]).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        rl.set_local(2, "b", "patched")?;
+        Ok(0)
+    });
+    rlua.state.set_global("inspect");
+    assert_eq!(rlua.eval::<String>("local a = 1; local b = 'x'; inspect(); return b").unwrap(), "patched");
+    assert!(rlua.locals(50).is_err());
+
+    let f: LuaFunction = rlua.eval("local count = 0; return function() count = count + 1; return count end").unwrap();
+    f.call::<_, i64>(&mut rlua, ()).unwrap();
+    let upvalues = rlua.upvalues(&f).unwrap();
+    assert_eq!(upvalues.len(), 1);
+    assert_eq!(upvalues[0].0, "count");
+    assert!(match upvalues[0].1 { Value::Integer(1) => true, _ => false });
+    rlua.set_upvalue(&f, "count", 41).unwrap();
+    assert_eq!(f.call::<_, i64>(&mut rlua, ()).unwrap(), 42);
+    assert!(rlua.set_upvalue(&f, "missing", 0).is_err());
+}
+
+#[test]
+fn lua_callback_metrics() {
+    let mut rlua = RumLua::new();
+    rlua.push_function("double", |_rl, x: i64| Ok(x * 2));
+    rlua.state.set_global("double");
+    rlua.push_function("fail", |_rl, ()| -> Result<(), LuaError> { Err(LuaError::ConversionError("no".to_string())) });
+    rlua.state.set_global("fail");
+    rlua.do_string("double(1)").unwrap();
+    assert!(rlua.callback_stats().is_empty());
+
+    rlua.enable_callback_metrics(true);
+    rlua.do_string("for i = 1, 5 do double(i) end; pcall(fail)").unwrap();
+    let stats = rlua.callback_stats();
+    let double = stats.iter().find(|s| s.name == "double").unwrap();
+    assert_eq!((double.calls, double.errors), (5, 0));
+    let fail = stats.iter().find(|s| s.name == "fail").unwrap();
+    assert_eq!((fail.calls, fail.errors), (1, 1));
+
+    rlua.enable_callback_metrics(false);
+    assert!(rlua.callback_stats().is_empty());
+}
+
+fn raw_add(rl: &mut RumLua) -> LuaRet {
+    let a: i64 = rl.check_arg(1)?;
+    let b: i64 = rl.check_arg(2)?;
+    rl.state.push_integer(a + b);
+    Ok(1)
+}
+
+#[test]
+fn lua_raw_callbacks() {
+    let mut rlua = RumLua::new();
+    rlua.register_raw("add", raw_add);
+    assert_eq!(rlua.eval::<i64>("local t = 0; for i = 1, 10 do t = add(t, i) end; return t").unwrap(), 55);
+    let (ok, msg): (bool, String) = rlua.eval("return pcall(add, 1, 'x')").unwrap();
+    assert!(!ok);
+    assert!(msg.contains("Calling add:\nbad argument #2 to 'add'"));
+    /* The original error comes back out to Rust. */
+    match rlua.do_string("add(1)") {
+        Err(LuaError::ConversionError(msg)) => assert!(msg.contains("bad argument #2")),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    rlua.register_type_builder(TypeBuilder::<TestMeth>::new("TestMeth")
+        .raw_method("get", test_method_get)
+        .method("set", test_method_set)).unwrap();
+    rlua.push(&LuaPtr::new(TestMeth{data: "raw".to_string()})).unwrap();
+    rlua.state.set_global("obj");
+    assert_eq!(rlua.eval::<String>("obj:set(obj:get() .. '!'); return obj:get()").unwrap(), "raw!");
+}
+
+#[test]
+fn lua_registered_callbacks() {
+    let mut rlua = RumLua::new();
+    rlua.register_func_table("t", vec![("add", raw_add)]);
+    /* Registered functions find their callback by index, so there is
+     * no userdata upvalue to collect. */
+    assert_eq!(rlua.eval::<String>(r#"
+        local rust_f
+        for i = 1, 10 do
+            local name, value = debug.getupvalue(t.add, i)
+            if name == "rust_f" then rust_f = value end
+        end
+        local _, id = debug.getupvalue(rust_f, 2)
+        return math.type(id)
+    "#).unwrap(), "integer");
+    rlua.do_string("collectgarbage()").unwrap();
+    assert_eq!(rlua.eval::<i64>("return t.add(2, 3)").unwrap(), 5);
+}
+
+#[test]
+fn lua_movable() {
+    let mut rlua = RumLua::new();
+    rlua.register_func_table("t", vec![("add", raw_add)]);
+    rlua.push_closure("double", |rl| {
+        let x: i64 = rl.check_arg(1)?;
+        rl.state.push_integer(x * 2);
+        Ok(1)
+    });
+    rlua.state.set_global("double");
+    let lines = Rc::new(RefCell::new(0));
+    let counter = lines.clone();
+    rlua.set_hook(HookTriggers{ every_line: true, ..Default::default() },
+                  Box::new(move |_rl, _info| *counter.borrow_mut() += 1));
+
+    /* Callbacks and hooks still work once the RumLua has moved. */
+    let mut moved = vec![rlua];
+    let mut rlua = moved.pop().unwrap();
+    assert_eq!(rlua.eval::<i64>("return double(t.add(1, 2))").unwrap(), 6);
+    assert!(*lines.borrow() > 0);
+}
+
+#[test]
+fn lua_stack_guard() {
+    let mut rlua = RumLua::new();
+    rlua.state.push_integer(1);
+    /* Running code leaves what was already pushed alone. */
+    rlua.do_string("x = 2").unwrap();
+    assert!(rlua.do_string("error('oops')").is_err());
+    assert_eq!(rlua.state.get_top(), 1);
+    {
+        let mut rl = StackGuard::new(&mut rlua);
+        assert_eq!(rl.top(), 1);
+        rl.state.get_global("x");
+        rl.state.push_nil();
+    }
+    assert_eq!(rlua.state.get_top(), 1);
+    assert_eq!(rlua.state.to_integer(1), 1);
+}
+
+#[test]
+fn lua_stack_overflow() {
+    let mut rlua = RumLua::new();
+    assert!(rlua.ensure_stack(100).is_ok());
+    match rlua.ensure_stack(10_000_000) {
+        Err(LuaError::StackOverflow(_)) => {},
+        other => panic!("unexpected result {:?}", other),
+    }
+    /* Too many arguments fail cleanly rather than aborting. */
+    let args = Variadic(vec![0i64; 1_100_000]);
+    match rlua.call_global::<_, ()>("select", args) {
+        Err(LuaError::StackOverflow(_)) => {},
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(rlua.state.get_top(), 0);
+}
+
+#[test]
+fn lua_recursion_limit() {
+    let mut rlua = RumLua::new();
+    rlua.set_recursion_limit(10);
+    rlua.push_closure("descend", |rl| {
+        let n: i64 = rl.check_arg(1)?;
+        if n > 0 {
+            rl.call_global::<_, ()>("descend", n - 1)?;
+        }
+        Ok(0)
+    });
+    rlua.state.set_global("descend");
+    rlua.do_string("descend(5)").unwrap();
+    let err = rlua.do_string("descend(50)").unwrap_err();
+    assert!(err.to_string().contains("nested too deeply (limit 10)"));
+    /* The depth is counted back down after the failure. */
+    rlua.do_string("descend(9)").unwrap();
+}
+
+#[test]
+fn lua_registered_types() {
+    let mut rlua = RumLua::new();
+    assert!(!rlua.is_registered::<TestMeth>());
+    rlua.register_type::<TestMeth>("TestMeth".to_string(), &EMPTY_METHODS).unwrap();
+    rlua.register_type::<TestDrop>("TestDrop".to_string(), &EMPTY_METHODS).unwrap();
+    assert!(rlua.is_registered::<TestMeth>());
+    assert_eq!(rlua.metatable_name::<TestDrop>(), Some("TestDrop".to_string()));
+    assert_eq!(rlua.metatable_name::<String>(), None);
+    let types = rlua.registered_types();
+    assert_eq!(types, vec![("TestDrop".to_string(), TypeId::of::<TestDrop>()),
+                           ("TestMeth".to_string(), TypeId::of::<TestMeth>())]);
+    assert_eq!(rlua.eval::<String>("return table.concat(rum.types(), ',')").unwrap(), "TestDrop,TestMeth");
+}
+
+#[test]
+fn lua_unregister_types() {
+    let mut rlua = RumLua::new();
+    rlua.register_type_builder(TypeBuilder::<TestMeth>::new("TestMeth").method("get", test_method_get)).unwrap();
+    rlua.push(&LuaPtr::new(TestMeth{ data: "old".to_string() })).unwrap();
+    rlua.state.set_global("old");
+    assert!(rlua.unregister_type::<TestMeth>().is_ok());
+    assert!(rlua.unregister_type::<TestMeth>().is_err());
+    assert!(!rlua.is_registered::<TestMeth>());
+    assert!(rlua.do_string("return old:get()").is_err());
+
+    rlua.register_type_or_replace(TypeBuilder::<TestMeth>::new("TestMeth")
+        .method("get", test_method_get)
+        .method("set", test_method_set)).unwrap();
+    rlua.register_type_or_replace(TypeBuilder::<TestMeth>::new("TestMeth")
+        .method("get", test_method_get)).unwrap();
+    rlua.push(&LuaPtr::new(TestMeth{ data: "new".to_string() })).unwrap();
+    rlua.state.set_global("new");
+    assert_eq!(rlua.eval::<String>("return new:get()").unwrap(), "new");
+    assert!(rlua.do_string("new:set('x')").is_err());
+    /* Userdata made before the type was replaced don't become the new type. */
+    rlua.state.get_global("old");
+    assert!(rlua.get::<TestMeth>(-1).is_err());
+    rlua.state.pop(1);
+
+    /* Another type can't take the name. */
+    assert!(rlua.register_type_or_replace(TypeBuilder::<TestDrop>::new("TestMeth")).is_err());
+    /* Registering again fails rather than panicking. */
+    assert!(rlua.register_type::<TestMeth>("Other".to_string(), &SOME_METHODS).is_err());
+    assert!(rlua.register_type_builder(TypeBuilder::<TestDrop>::new("TestMeth")).is_err());
+
+    /* Userdata of an unregistered type are still dropped. */
+    let dropcount = Rc::new(RefCell::new(0u32));
+    rlua.register_type::<TestDrop>("TestDrop".to_string(), &EMPTY_METHODS).unwrap();
+    rlua.push(&LuaPtr::new(TestDrop{ dropcount: dropcount.clone() })).unwrap();
+    rlua.state.pop(1);
+    rlua.unregister_type::<TestDrop>().unwrap();
+    rlua.do_string("collectgarbage()").unwrap();
+    assert_eq!(*dropcount.borrow(), 1);
+}
+
+#[test]
+fn lua_replace_type_counts() {
+    let mut rlua = RumLua::new();
+    let dropcount = Rc::new(RefCell::new(0u32));
+    rlua.register_type::<TestDrop>("TestDrop".to_string(), &EMPTY_METHODS).unwrap();
+    for _ in 0..2 {
+        rlua.push(&LuaPtr::new(TestDrop{ dropcount: dropcount.clone() })).unwrap();
+    }
+    rlua.register_type_or_replace(TypeBuilder::<TestDrop>::new("TestDrop")).unwrap();
+    assert_eq!(rlua.memory_stats().userdata["TestDrop"], 0);
+    rlua.push(&LuaPtr::new(TestDrop{ dropcount: dropcount.clone() })).unwrap();
+    rlua.state.set_global("kept");
+    rlua.state.set_top(0);
+    /* Dropping the old instances doesn't count against the new type. */
+    rlua.do_string("collectgarbage()").unwrap();
+    assert_eq!(*dropcount.borrow(), 2);
+    assert_eq!(rlua.memory_stats().userdata["TestDrop"], 1);
+}
+
+#[test]
+fn lua_replace_without_base() {
+    let mut rlua = RumLua::new();
+    /* The base must be registered first. */
+    match rlua.register_type_with_base::<TestDerived, TestMeth>("TestDerived".to_string(), &DERIVED_METHODS) {
+        Err(LuaError::TypeError(_)) => {},
+        _ => panic!("expected a type error"),
+    }
+    assert!(!rlua.is_registered::<TestDerived>());
+    rlua.register_type::<TestMeth>("TestMeth".to_string(), &SOME_METHODS).unwrap();
+    rlua.register_type_or_replace(TypeBuilder::<TestDerived>::new("TestDerived").base::<TestMeth>()).unwrap();
+
+    /* Replacing after the base has gone fails, and leaves the type as it was. */
+    rlua.unregister_type::<TestMeth>().unwrap();
+    match rlua.register_type_or_replace(TypeBuilder::<TestDerived>::new("TestDerived").base::<TestMeth>()) {
+        Err(LuaError::TypeError(_)) => {},
+        _ => panic!("expected a type error"),
+    }
+    assert_eq!(rlua.metatable_name::<TestDerived>(), Some("TestDerived".to_string()));
+}
+
+struct Grid<T> {
+    cells: Vec<T>,
+}
+
+fn grid_len<T: Any>(rl: &mut RumLua) -> LuaRet {
+    let len = rl.with_ref::<Grid<T>, _, _>(1, |g| g.cells.len())?;
+    rl.state.push_integer(len as i64);
+    Ok(1)
+}
+
+fn grid_type<T: Any>() -> TypeBuilder<Grid<T>> {
+    TypeBuilder::new("Grid").method("len", grid_len::<T>)
+}
+
+#[test]
+fn lua_generic_types() {
+    let mut rlua = RumLua::new();
+    rlua.register_type_as::<Grid<i64>>("Grid<int>", grid_type::<i64>()).unwrap();
+    rlua.register_type_as::<Grid<String>>("Grid<str>", grid_type::<String>()).unwrap();
+    assert_eq!(rlua.metatable_name::<Grid<String>>(), Some("Grid<str>".to_string()));
+    rlua.push(&LuaPtr::new(Grid{ cells: vec![1i64, 2, 3] })).unwrap();
+    rlua.state.set_global("ints");
+    rlua.push(&LuaPtr::new(Grid{ cells: vec!["a".to_string()] })).unwrap();
+    rlua.state.set_global("strs");
+    assert_eq!(rlua.eval::<(i64, i64)>("return ints:len(), strs:len()").unwrap(), (3, 1));
+    rlua.state.get_global("strs");
+    assert!(rlua.get::<Grid<i64>>(-1).is_err());
+    rlua.state.pop(1);
+
+    /* Each instantiation is registered once, and names aren't shared. */
+    assert!(rlua.register_type_as::<Grid<i64>>("Grid<i64>", grid_type::<i64>()).is_err());
+    assert!(rlua.register_type_as::<Grid<bool>>("Grid<int>", grid_type::<bool>()).is_err());
+    /* A missing base type is an error too. */
+    assert!(rlua.register_type_as::<TestDerived>("Derived", TypeBuilder::new("").base::<TestMeth>()).is_err());
+    assert!(!rlua.is_registered::<TestDerived>());
+}
+
+#[test]
+fn lua_instance_fields() {
+    let mut rlua = RumLua::new();
+    rlua.register_type_builder(TypeBuilder::<TestMeth>::new("TestMeth")
+        .method("get", test_method_get)
+        .instance_fields()).unwrap();
+    rlua.push(&LuaPtr::new(TestMeth{ data: "a".to_string() })).unwrap();
+    rlua.state.set_global("a");
+    rlua.push(&LuaPtr::new(TestMeth{ data: "b".to_string() })).unwrap();
+    rlua.state.set_global("b");
+    let (extra, shout, other, over, kept): (i64, String, Value, String, String) = rlua.eval(r#"
+        a.extra = 5
+        function a:shout() return self:get():upper() end
+        b.get = function() return "over" end
+        return a.extra, a:shout(), b.extra, b:get(), a:get()
+    "#).unwrap();
+    assert_eq!((extra, shout.as_str(), other.type_name()), (5, "A", "nil"));
+    assert_eq!((over.as_str(), kept.as_str()), ("over", "a"));
+
+    /* The fields are the uservalue table, which Rust can see too. */
+    rlua.state.get_global("a");
+    let fields: LuaTable = rlua.get_uservalue(-1).unwrap();
+    assert_eq!(fields.get::<_, i64>(&mut rlua, "extra").unwrap(), 5);
+    rlua.state.pop(1);
+}
+
+#[test]
+fn lua_classes() {
+    let mut rlua = RumLua::new();
+    rlua.register_type_builder(TypeBuilder::<TestMeth>::new("TestMeth")
+        .method("get", test_method_get)
+        .method("set", test_method_set)).unwrap();
+    rlua.push(&LuaPtr::new(TestMeth{ data: "a".to_string() })).unwrap();
+    rlua.state.set_global("obj");
+    let (got, title): (String, String) = rlua.eval(r#"
+        local Hero = rum.class("TestMeth")
+        function Hero:get() return "hero " .. Hero.super.get(self) end
+        function Hero:title() return self.name end
+        hero = Hero(obj)
+        hero:set("bob")
+        hero.name = "Sir"
+        return hero:get(), hero:title()
+    "#).unwrap();
+    assert_eq!((got.as_str(), title.as_str()), ("hero bob", "Sir"));
+
+    /* Instances convert to the Rust base type. */
+    rlua.state.get_global("hero");
+    assert_eq!(rlua.get::<TestMeth>(-1).unwrap().borrow().get(), "bob");
+    rlua.state.pop(1);
+
+    assert!(rlua.do_string("rum.class('TestMeth')({})").is_err());
+    assert!(rlua.do_string("rum.class('NoSuchType')").is_err());
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Color {
+    Red,
+    Green,
+    Blue,
+}
+lua_enum!(Color);
+
+#[test]
+fn lua_enums() {
+    let mut rlua = RumLua::new();
+    rlua.register_enum("Color", &[("RED", Color::Red), ("GREEN", Color::Green), ("BLUE", Color::Blue)]).unwrap();
+    let (red, blue): (i64, i64) = rlua.eval("return Color.RED, Color.BLUE").unwrap();
+    assert_eq!((red, blue), (1, 3));
+    assert!(rlua.do_string("Color.RED = 5").is_err());
+
+    rlua.do_string("function same(c) return c end").unwrap();
+    let back: Color = rlua.call_global("same", Color::Blue).unwrap();
+    assert_eq!(back, Color::Blue);
+    rlua.set_global_value("c", Color::Green).unwrap();
+    assert_eq!(rlua.eval::<bool>("return c == Color.GREEN").unwrap(), true);
+
+    /* Either the number or the name converts. */
+    assert_eq!(rlua.eval::<Color>("return 'GREEN'").unwrap(), Color::Green);
+    assert_eq!(rlua.eval::<Color>("return 2").unwrap(), Color::Green);
+
+    match rlua.eval::<Color>("return 'PURPLE'") {
+        Err(LuaError::ConversionError(msg)) => assert!(msg.contains("no variant 'PURPLE'")),
+        e => panic!("{:?}", e),
+    }
+    match rlua.eval::<Color>("return 7") {
+        Err(LuaError::ConversionError(msg)) => assert!(msg.contains("no variant 7")),
+        e => panic!("{:?}", e),
+    }
+    assert!(rlua.eval::<Color>("return {}").is_err());
+    assert!(rlua.register_enum("Colour", &[("RED", Color::Red)]).is_err());
+}
+
+/* As bitflags! would declare it */
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Perms {
+    bits: u32,
+}
+
+impl Perms {
+    const READ: Perms = Perms{ bits: 1 };
+    const WRITE: Perms = Perms{ bits: 2 };
+    const EXEC: Perms = Perms{ bits: 4 };
+
+    fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    fn from_bits(bits: u32) -> Option<Perms> {
+        if bits & !7 == 0 { Some(Perms{ bits: bits }) } else { None }
+    }
+}
+lua_flags!(Perms);
+
+#[test]
+fn lua_flags() {
+    let mut rlua = RumLua::new();
+    rlua.register_flags("Perms", &[("READ", Perms::READ), ("WRITE", Perms::WRITE), ("EXEC", Perms::EXEC)]).unwrap();
+    rlua.set_global_value("p", Perms{ bits: 3 }).unwrap();
+    let (read, exec, set, cleared): (bool, bool, i64, i64) = rlua.eval(r#"
+        return Perms.has(p, Perms.READ), Perms.has(p, "EXEC"),
+               Perms.set(p, {"EXEC"}), Perms.clear(p, Perms.WRITE)
+    "#).unwrap();
+    assert_eq!((read, exec, set, cleared), (true, false, 7, 1));
+    assert_eq!(rlua.get_global_value::<i64>("p").unwrap(), 3);
+
+    /* Integers, names and arrays of names all convert. */
+    assert_eq!(rlua.eval::<Perms>("return 5").unwrap(), Perms{ bits: 5 });
+    assert_eq!(rlua.eval::<Perms>("return 'WRITE'").unwrap(), Perms::WRITE);
+    assert_eq!(rlua.eval::<Perms>("return {'READ', 'EXEC'}").unwrap().to_bits(), 5);
+    assert_eq!(rlua.eval::<Perms>("return {}").unwrap().bits(), 0);
+
+    match rlua.eval::<Perms>("return {'READ', 'DELETE'}") {
+        Err(LuaError::ConversionError(msg)) => assert!(msg.contains("no flag 'DELETE'")),
+        e => panic!("{:?}", e),
+    }
+    match rlua.eval::<Perms>("return 8") {
+        Err(LuaError::ConversionError(msg)) => assert!(msg.contains("0x8")),
+        e => panic!("{:?}", e),
+    }
+    assert!(rlua.do_string("Perms.has(p, true)").is_err());
+    assert!(rlua.do_string("Perms.READ = 8").is_err());
+}
+
+struct Account {
+    balance: i64,
+}
+
+impl Account {
+    fn withdraw(&mut self, amount: i64) -> Result<i64, TestError> {
+        if amount > self.balance {
+            return Err(TestError("insufficient funds".to_string()));
+        }
+        self.balance -= amount;
+        Ok(self.balance)
+    }
+    fn deposit(&mut self, amount: i64) -> Result<i64, LuaError> {
+        if amount < 0 {
+            return Err(LuaError::ConversionError("negative deposit".to_string()));
+        }
+        self.balance += amount;
+        Ok(self.balance)
+    }
+}
+
+rum_methods! {
+    static ACCOUNT_METHODS for Account {
+        fn withdraw(&mut self, amount: i64) -> Result<i64, TestError>;
+        fn deposit(&mut self, amount: i64) -> Result<i64, LuaError>;
+    }
+}
+
+#[test]
+fn lua_option_result() {
+    let mut rlua = RumLua::new();
+    assert_eq!(rlua.eval::<Option<i64>>("return nil").unwrap(), None);
+    assert_eq!(rlua.eval::<Option<i64>>("return 3").unwrap(), Some(3));
+    assert!(rlua.eval::<Option<i64>>("return 'x'").is_err());
+    rlua.set_global_value("none", None::<i64>).unwrap();
+    rlua.set_global_value("some", Some("x")).unwrap();
+    assert_eq!(rlua.eval::<(bool, String)>("return none == nil, some").unwrap(), (true, "x".to_string()));
+    assert!(rlua.eval::<Value>("return nil").unwrap().is_nil());
+    assert!(!rlua.eval::<Value>("return false").unwrap().is_nil());
+
+    /* Missing and nil arguments are both None, but has_arg tells them apart. */
+    rlua.push_function("given", |rl, (a, b): (Option<i64>, Option<i64>)| {
+        Ok((a.is_some(), b.is_some(), rl.has_arg(2)))
+    });
+    rlua.state.set_global("given");
+    let results: (bool, bool, bool) = rlua.eval("return given(1, nil)").unwrap();
+    assert_eq!(results, (true, false, true));
+    let results: (bool, bool, bool) = rlua.eval("return given(1)").unwrap();
+    assert_eq!(results, (true, false, false));
+
+    /* Errors returned from typed callbacks are raised, and come back as they were. */
+    rlua.register_type::<Account>("Account".to_string(), &ACCOUNT_METHODS).unwrap();
+    rlua.push(&LuaPtr::new(Account{ balance: 10 })).unwrap();
+    rlua.state.set_global("acct");
+    assert_eq!(rlua.eval::<i64>("return acct:withdraw(3)").unwrap(), 7);
+    match rlua.do_string("acct:withdraw(100)").unwrap_err() {
+        LuaError::CallbackError{ ref cause } => {
+            assert_eq!(cause.downcast_ref::<TestError>().unwrap().0, "insufficient funds");
+        },
+        ref e => panic!("Unexpected error {:?}", e),
+    }
+    match rlua.do_string("acct:deposit(-1)").unwrap_err() {
+        LuaError::ConversionError(ref msg) => assert!(msg.contains("negative deposit")),
+        ref e => panic!("Unexpected error {:?}", e),
+    }
+    let (ok, balance): (bool, i64) = rlua.eval("return pcall(acct.withdraw, acct, 100), acct:deposit(1)").unwrap();
+    assert_eq!((ok, balance), (false, 8));
+}
+
+#[test]
+fn lua_char_path_conversions() {
+    use std::ffi::OsString;
+    use std::path::{Path, PathBuf};
+    let mut rlua = RumLua::new();
+    rlua.set_global_value("c", 'é').unwrap();
+    assert_eq!(rlua.eval::<String>("return c").unwrap(), "é");
+    assert_eq!(rlua.eval::<char>("return 'x'").unwrap(), 'x');
+    assert!(rlua.eval::<char>("return 'xy'").is_err());
+    assert!(rlua.eval::<char>("return ''").is_err());
+    assert!(rlua.eval::<char>("return 1").is_err());
+
+    rlua.set_global_value("p", Path::new("/tmp/a.txt")).unwrap();
+    let path: PathBuf = rlua.eval("return p:gsub('a.txt', 'b.txt')").unwrap();
+    assert_eq!(path, PathBuf::from("/tmp/b.txt"));
+    rlua.set_global_value("o", OsString::from("name")).unwrap();
+    assert_eq!(rlua.eval::<OsString>("return o .. '2'").unwrap(), OsString::from("name2"));
+    assert!(rlua.eval::<PathBuf>("return {}").is_err());
+
+    /* Paths on Unix needn't be UTF-8. */
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let path: PathBuf = rlua.eval("return '/tmp/\\xff'").unwrap();
+        assert_eq!(path.as_os_str().as_bytes(), b"/tmp/\xff");
+        rlua.set_global_value("raw", path).unwrap();
+        assert_eq!(rlua.eval::<i64>("return #raw").unwrap(), 6);
+    }
+}
+
+#[test]
+fn lua_time() {
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    let mut rlua = RumLua::new();
+    rlua.set_global_value("d", Duration::from_millis(1500)).unwrap();
+    assert_eq!(rlua.eval::<f64>("return d").unwrap(), 1.5);
+    assert_eq!(rlua.eval::<Duration>("return 0.25").unwrap(), Duration::from_millis(250));
+    assert_eq!(rlua.eval::<Duration>("return {secs = 2, nanos = 5}").unwrap(), Duration::new(2, 5));
+    assert!(rlua.eval::<Duration>("return -1").is_err());
+    assert!(rlua.eval::<Duration>("return {nanos = 1e9}").is_err());
+
+    rlua.set_duration_format(DurationFormat::Table);
+    rlua.set_global_value("d", Duration::new(3, 7)).unwrap();
+    assert_eq!(rlua.eval::<(i64, i64)>("return d.secs, d.nanos").unwrap(), (3, 7));
+    let stamp = UNIX_EPOCH + Duration::new(1000, 1);
+    rlua.set_global_value("t", stamp).unwrap();
+    assert_eq!(rlua.get_global_value::<SystemTime>("t").unwrap(), stamp);
+
+    /* Scripts and the host see the same clock. */
+    let before = rlua.monotonic();
+    let (now, wall): (f64, f64) = rlua.eval("return rum.time.monotonic(), rum.time.now()").unwrap();
+    assert!(now >= before.as_secs() as f64 && now <= rlua.monotonic().as_secs() as f64 + 1.0);
+    assert!(wall > 1.0e9);
+    let start = Instant::now();
+    rlua.do_string("rum.time.sleep_until(rum.time.monotonic() + 0.01)").unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(10));
+
+    let woken = Rc::new(RefCell::new(Vec::new()));
+    let woken2 = woken.clone();
+    rlua.set_sleep_handler(move |_, deadline| {
+        woken2.borrow_mut().push(deadline);
+        Ok(())
+    });
+    let start = Instant::now();
+    rlua.do_string("rum.time.sleep_until(rum.time.monotonic() + 60)").unwrap();
+    assert!(start.elapsed() < Duration::from_secs(60));
+    assert_eq!(woken.borrow().len(), 1);
+    assert!(woken.borrow()[0] > start + Duration::from_secs(59));
+}
+
+#[test]
+fn lua_big_integers() {
+    let mut rlua = RumLua::new();
+    let id: u128 = 0xfedc_ba98_7654_3210_0123_4567_89ab_cdef;
+    rlua.set_global_value("id", id).unwrap();
+    assert_eq!(rlua.get_global_value::<u128>("id").unwrap(), id);
+    assert_eq!(rlua.eval::<String>("return tostring(id)").unwrap(), id.to_string());
+    assert!(rlua.get_global_value::<i128>("id").is_err());
+
+    /* Small values are plain integers. */
+    rlua.set_global_value("small", 5i128).unwrap();
+    assert_eq!(rlua.eval::<String>("return math.type(small)").unwrap(), "integer");
+    assert_eq!(rlua.eval::<i128>("return 7").unwrap(), 7);
+
+    /* Arithmetic gives plain integers again once the result fits. */
+    let max = i64::max_value() as i128;
+    rlua.set_global_value("big", max + 10).unwrap();
+    let (sum, diff, kind): (i128, i64, String) = rlua.eval("return big + big, big - 20, math.type(big - 20)").unwrap();
+    assert_eq!((sum, diff as i128, kind.as_str()), (2 * (max + 10), max - 10, "integer"));
+    let (q, r, neg): (i128, i64, i128) = rlua.eval("return big // 3, big % 3, -big").unwrap();
+    assert_eq!((q, r as i128, neg), ((max + 10) / 3, (max + 10) % 3, -(max + 10)));
+    let (lt, gt, eq): (bool, bool, bool) = rlua.eval("return 1 < big, big > 2^70, big == big + 0").unwrap();
+    assert_eq!((lt, gt, eq), (true, false, true));
+    assert_eq!(rlua.eval::<f64>("return big / 2").unwrap(), (max + 10) as f64 / 2.0);
+    assert!(rlua.do_string("return big // 0").is_err());
+    assert!(rlua.eval::<BigInt>("return 'x'").is_err());
+    assert_eq!(BigInt::from_i128(-7).checked_rem(BigInt::from_i128(3)), Some(BigInt::from_i128(2)));
+    assert_eq!(BigInt::from_i128(-7).checked_div(BigInt::from_i128(2)), Some(BigInt::from_i128(-4)));
+}
+
+#[cfg(feature = "decimal")]
+#[test]
+fn lua_decimal() {
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+    let mut rlua = RumLua::new();
+    rlua.set_global_value("price", Decimal::from_str("0.10").unwrap()).unwrap();
+    let total: Decimal = rlua.eval("return price + rum.decimal('0.20')").unwrap();
+    assert_eq!(total, Decimal::from_str("0.30").unwrap());
+    let (s, eq, lt): (String, bool, bool) = rlua.eval(r#"
+        local t = price * 3
+        return tostring(t), t == rum.decimal("0.3"), price < t
+    "#).unwrap();
+    assert_eq!((s.as_str(), eq, lt), ("0.30", true, true));
+    assert_eq!(rlua.eval::<String>("return 'cost: ' .. (price * 7 / 3):round(2)").unwrap(), "cost: 0.23");
+    assert_eq!(rlua.eval::<Decimal>("return 5").unwrap(), Decimal::from(5));
+    assert!(rlua.eval::<Decimal>("return 0.1").is_err());
+    assert!(rlua.eval::<Decimal>("return 'ten'").is_err());
+    assert!(rlua.do_string("return price / 0").is_err());
+}
+
+#[test]
+fn lua_iterators() {
+    let mut rlua = RumLua::new();
+    let dropped = Rc::new(RefCell::new(false));
+    struct Counter(i64, Rc<RefCell<bool>>);
+    impl Iterator for Counter {
+        type Item = i64;
+        fn next(&mut self) -> Option<i64> {
+            self.0 += 1;
+            if self.0 <= 3 { Some(self.0 * 10) } else { None }
+        }
+    }
+    impl Drop for Counter {
+        fn drop(&mut self) {
+            *self.1.borrow_mut() = true;
+        }
+    }
+    rlua.push_iterator(Counter(0, dropped.clone()));
+    rlua.state.set_global("items");
+    let sum: i64 = rlua.eval(r#"
+        local sum = 0
+        for v in items do sum = sum + v end
+        return sum
+    "#).unwrap();
+    assert_eq!(sum, 60);
+    assert!(*dropped.borrow());
+    assert_eq!(rlua.eval::<Value>("return items()").unwrap().is_nil(), true);
+
+    /* Items can be several values, and are produced lazily. */
+    let names = vec!["a".to_string(), "b".to_string()];
+    rlua.push_iterator(names.into_iter().enumerate().map(|(i, name)| (i + 1, name)));
+    rlua.state.set_global("pairs_of");
+    let joined: String = rlua.eval(r#"
+        local s = ""
+        for i, name in pairs_of do s = s .. i .. name end
+        return s
+    "#).unwrap();
+    assert_eq!(joined, "1a2b");
+    rlua.push_iterator((0..).map(|i: i64| i * i));
+    rlua.state.set_global("squares");
+    assert_eq!(rlua.eval::<i64>("for v in squares do if v > 50 then return v end end").unwrap(), 64);
+}
+
+#[test]
+fn lua_thread_iterators() {
+    let mut rlua = RumLua::new();
+    let f: LuaFunction = rlua.eval(r#"
+        return function()
+            for i = 1, 3 do coroutine.yield(i, "v" .. i) end
+        end
+    "#).unwrap();
+    let thread = rlua.create_thread(&f).unwrap();
+    let items: Vec<(i64, String)> = thread.into_iter(&mut rlua).collect::<Result<_, _>>().unwrap();
+    assert_eq!(items, vec![(1, "v1".to_string()), (2, "v2".to_string()), (3, "v3".to_string())]);
+
+    /* Returned values are the last item, and errors end the iteration. */
+    let f: LuaFunction = rlua.eval(r#"
+        return function()
+            coroutine.yield(1)
+            return 2
+        end
+    "#).unwrap();
+    let values: Vec<i64> = rlua.create_thread(&f).unwrap().into_iter(&mut rlua).map(|v| v.unwrap()).collect();
+    assert_eq!(values, vec![1, 2]);
+    let f: LuaFunction = rlua.eval(r#"
+        return function()
+            coroutine.yield(1)
+            coroutine.yield("x")
+            error("never reached")
+        end
+    "#).unwrap();
+    let mut iter = rlua.create_thread(&f).unwrap().into_iter::<i64>(&mut rlua);
+    assert_eq!(iter.next().unwrap().unwrap(), 1);
+    assert!(iter.next().unwrap().is_err());
+    assert!(iter.next().unwrap().is_err());
+    assert!(iter.next().is_none());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn lua_async_functions() {
+    use std::thread;
+    use std::time::Duration;
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut rlua = RumLua::new();
+    rlua.set_async_handle(runtime.handle().clone());
+    rlua.push_async_function("double", |_, x: i64| tokio::task::spawn_blocking(move || x * 2));
+    rlua.state.set_global("double");
+    rlua.push_async_function("stuck", |_, ()| {
+        tokio::time::timeout(Duration::from_millis(1), tokio::time::sleep(Duration::from_secs(60)))
+    });
+    rlua.state.set_global("stuck");
+
+    let f: LuaFunction = rlua.eval("return function(n) return double(n) + double(1) end").unwrap();
+    assert_eq!(rlua.run_async::<_, i64>(&f, 20).unwrap(), 42);
+
+    /* Tasks wait for their calls without blocking each other. */
+    rlua.do_string("done = {}").unwrap();
+    let g: LuaFunction = rlua.eval("return function(name) done[#done + 1] = name .. double(1) end").unwrap();
+    rlua.spawn_async(&g, "a").unwrap();
+    rlua.spawn_async(&g, "b").unwrap();
+    assert_eq!(rlua.eval::<i64>("return #done").unwrap(), 0);
+    while rlua.poll_async().unwrap() > 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let mut done: Vec<String> = rlua.get_global_value("done").unwrap();
+    done.sort();
+    assert_eq!(done, vec!["a2".to_string(), "b2".to_string()]);
+
+    /* Errors are raised in the task, and calls need a task. */
+    let h: LuaFunction = rlua.eval("return function() return pcall(stuck) end").unwrap();
+    assert_eq!(rlua.run_async::<_, bool>(&h, ()).unwrap(), false);
+    let h: LuaFunction = rlua.eval("return function() stuck() end").unwrap();
+    assert!(rlua.run_async::<_, ()>(&h, ()).is_err());
+    assert!(rlua.do_string("double(1)").is_err());
+    assert!(rlua.do_string("coroutine.wrap(function() double(1) end)()").is_err());
+}
+
+#[test]
+fn lua_scheduler() {
+    use std::time::Duration;
+    let mut rlua = RumLua::new();
+    let mut sched = Scheduler::new();
+    rlua.do_string("log = {}").unwrap();
+    let walker: LuaFunction = rlua.eval(r#"
+        return function(name, steps)
+            for i = 1, steps do
+                log[#log + 1] = name .. i
+                rum.sleep(100)
+            end
+        end
+    "#).unwrap();
+    let waiter: LuaFunction = rlua.eval(r#"
+        return function()
+            local door = rum.wait_for("door")
+            log[#log + 1] = "opened " .. door
+            rum.spawn(function() log[#log + 1] = "spawned" end)
+            rum.signal("done", 1)
+        end
+    "#).unwrap();
+    let a = sched.spawn(&mut rlua, &walker, ("a", 2)).unwrap();
+    sched.spawn(&mut rlua, &waiter, ()).unwrap();
+    assert_eq!(sched.len(), 2);
+
+    /* Nothing runs until the first tick. */
+    assert_eq!(rlua.eval::<i64>("return #log").unwrap(), 0);
+    sched.tick(&mut rlua, Duration::from_millis(0)).unwrap();
+    assert_eq!(rlua.get_global_value::<Vec<String>>("log").unwrap(), vec!["a1".to_string()]);
+    sched.tick(&mut rlua, Duration::from_millis(50)).unwrap();
+    assert_eq!(rlua.eval::<i64>("return #log").unwrap(), 1);
+    sched.tick(&mut rlua, Duration::from_millis(50)).unwrap();
+    assert_eq!(rlua.eval::<i64>("return #log").unwrap(), 2);
+
+    assert_eq!(sched.signal(&mut rlua, "door", "north").unwrap(), 1);
+    assert_eq!(sched.signal(&mut rlua, "window", ()).unwrap(), 0);
+    sched.run_until_idle(&mut rlua).unwrap();
+    assert!(sched.is_empty());
+    assert!(!sched.contains(a));
+    assert_eq!(sched.time(), Duration::from_millis(200));
+    assert_eq!(rlua.get_global_value::<Vec<String>>("log").unwrap(),
+               vec!["a1".to_string(), "a2".to_string(), "opened north".to_string(), "spawned".to_string()]);
+
+    /* Tasks waiting for events keep the scheduler from finishing; failing
+     * ones are removed. */
+    let f: LuaFunction = rlua.eval("return function() rum.wait_for('never') end").unwrap();
+    sched.spawn(&mut rlua, &f, ()).unwrap();
+    let f: LuaFunction = rlua.eval("return function() coroutine.yield(); error('boom') end").unwrap();
+    sched.spawn(&mut rlua, &f, ()).unwrap();
+    sched.tick(&mut rlua, Duration::from_millis(0)).unwrap();
+    assert!(sched.run_until_idle(&mut rlua).is_err());
+    assert_eq!(sched.len(), 1);
+    assert!(rlua.do_string("rum.sleep(-1)").is_err());
+}
+
+#[test]
+fn lua_events() {
+    let mut rlua = RumLua::new();
+    let hits = Rc::new(RefCell::new(Vec::new()));
+    let hits1 = hits.clone();
+    let id: HandlerId = rlua.on("damage", move |_, (who, amount): (String, i64)| {
+        hits1.borrow_mut().push(format!("{} {}", who, amount));
+        Ok(())
+    });
+    rlua.do_string(r#"
+        taken = 0
+        rum.on("damage", function(who, amount) taken = taken + amount end)
+        first = nil
+        rum.once("damage", function(who) first = who end)
+    "#).unwrap();
+    assert_eq!(rlua.emit("damage", ("orc", 5)).unwrap(), 3);
+    assert_eq!(rlua.eval::<i64>("return rum.emit('damage', 'elf', 2)").unwrap(), 2);
+    assert_eq!(rlua.eval::<(i64, String)>("return taken, first").unwrap(), (7, "orc".to_string()));
+    assert_eq!(*hits.borrow(), vec!["orc 5".to_string(), "elf 2".to_string()]);
+    assert_eq!(rlua.emit("heal", ()).unwrap(), 0);
+
+    assert!(rlua.off(id));
+    assert!(!rlua.off(id));
+    assert_eq!(rlua.emit("damage", ("orc", 1)).unwrap(), 1);
+    assert_eq!(hits.borrow().len(), 2);
+
+    /* A failing handler doesn't stop the rest, but its error is returned. */
+    rlua.do_string(r#"
+        bad = rum.on("tick", function() error("broken handler") end)
+        ticks = 0
+        rum.on("tick", function() ticks = ticks + 1 end)
+    "#).unwrap();
+    let err = rlua.emit("tick", ()).unwrap_err();
+    assert!(err.to_string().contains("broken handler"));
+    assert_eq!(rlua.eval::<i64>("return ticks").unwrap(), 1);
+    assert!(rlua.do_string("rum.emit('tick')").is_err());
+    assert_eq!(rlua.eval::<(bool, i64)>("return rum.off(bad), rum.emit('tick')").unwrap(), (true, 1));
+    assert_eq!(rlua.eval::<i64>("return ticks").unwrap(), 3);
+
+    /* Payloads which don't convert are errors of that handler. */
+    rlua.on("typed", |_, _n: i64| Ok(()));
+    assert!(rlua.emit("typed", "not a number").is_err());
+}
+
+#[test]
+fn lua_channels() {
+    use std::thread;
+    let mut rlua = RumLua::new();
+    let sender = rlua.create_channel::<i64>(4);
+    rlua.state.set_global("rx");
+    let worker = thread::spawn(move || {
+        for i in 1..4 {
+            sender.send(i * 10).unwrap();
+        }
+    });
+    worker.join().unwrap();
+    assert_eq!(rlua.eval::<i64>("return rx:recv()").unwrap(), 10);
+    assert_eq!(rlua.eval::<i64>("return rx:try_recv()").unwrap(), 20);
+    assert_eq!(rlua.eval::<i64>("return rx:recv()").unwrap(), 30);
+    /* Every sender has gone. */
+    assert_eq!(rlua.eval::<Option<i64>>("return rx:recv()").unwrap(), None);
+    assert_eq!(rlua.eval::<Option<i64>>("return rx:try_recv()").unwrap(), None);
+
+    /* In a coroutine, recv yields until there is something. */
+    let sender = rlua.create_channel::<String>(1);
+    rlua.state.set_global("names");
+    let f: LuaFunction = rlua.eval(r#"
+        return function()
+            local got = {}
+            for name in names.recv do
+                got[#got + 1] = name
+            end
+            return table.concat(got, ",")
+        end
+    "#).unwrap();
+    let thread = rlua.create_thread(&f).unwrap();
+    match thread.resume::<_, ()>(&mut rlua, ()) {
+        Resume::Yielded(()) => {},
+        _ => panic!("recv should have yielded"),
+    }
+    sender.send("ann".to_string()).unwrap();
+    match thread.resume::<_, ()>(&mut rlua, ()) {
+        Resume::Yielded(()) => {},
+        _ => panic!("recv should have yielded"),
+    }
+    let other = sender.clone();
+    thread::spawn(move || other.send("bob".to_string()).unwrap()).join().unwrap();
+    drop(sender);
+    match thread.resume::<_, String>(&mut rlua, ()) {
+        Resume::Finished(s) => assert_eq!(s, "ann,bob"),
+        _ => panic!("thread should have finished"),
+    }
+}
+
+#[test]
+fn lua_state_pool() {
+    let pool = StatePool::new(3, |rl| {
+        rl.do_string("function square(n) return n * n end")
+    }).unwrap();
+    assert_eq!(pool.size(), 3);
+    let jobs: Vec<_> = (1..11).map(|n| pool.execute::<_, i64>("return square(...)", n)).collect();
+    let results: Vec<i64> = jobs.into_iter().map(|job| job.recv().unwrap().unwrap()).collect();
+    assert_eq!(results, (1..11).map(|n| n * n).collect::<Vec<i64>>());
+
+    /* Jobs can fail without harming the pool. */
+    let job = pool.execute::<_, ()>("error('job failed')", ());
+    assert!(job.recv().unwrap().unwrap_err().to_string().contains("job failed"));
+    let job = pool.execute::<_, (String, i64)>("local s, n = ...; return s:upper(), square(n)", ("ok", 4));
+    assert_eq!(job.recv().unwrap().unwrap(), ("OK".to_string(), 16));
+    assert!(pool.execute::<_, ()>("syntax error(", ()).recv().unwrap().is_err());
+
+    assert!(StatePool::new(2, |rl| rl.do_string("error('bad setup')")).is_err());
+}
+
+#[test]
+fn lua_sync_state() {
+    use std::sync::Arc;
+    use std::thread;
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<RumLuaSync>();
+
+    let rlua = Arc::new(RumLuaBuilder::new().with_libs(StdLib::BASE).build_sync());
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+    rlua.set_global_function("bump", move |_, n: i64| {
+        calls2.fetch_add(1, Ordering::SeqCst);
+        Ok(n + 1)
+    });
+    rlua.do_string("total = 0").unwrap();
+    let workers: Vec<_> = (0..4).map(|_| {
+        let rlua = rlua.clone();
+        thread::spawn(move || {
+            for _ in 0..10 {
+                rlua.do_string("total = bump(total)").unwrap();
+            }
+        })
+    }).collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    assert_eq!(rlua.get_global_value::<i64>("total").unwrap(), 40);
+    assert_eq!(calls.load(Ordering::SeqCst), 40);
+    assert_eq!(rlua.call_global::<_, i64>("bump", 1).unwrap(), 2);
+
+    /* A state can be moved to another thread and back. */
+    let rlua = Arc::try_unwrap(rlua).ok().unwrap();
+    let rlua = thread::spawn(move || {
+        rlua.set_global_value("name", "moved").unwrap();
+        rlua
+    }).join().unwrap();
+    assert_eq!(rlua.eval::<String>("return name").unwrap(), "moved");
+    let mut rl = rlua.into_inner();
+    assert_eq!(rl.get_global_value::<i64>("total").unwrap(), 40);
+}
+
+#[test]
+fn lua_snapshots() {
+    fn setup() -> RumLua<'static> {
+        let mut rlua = RumLua::new();
+        rlua.register_type_builder(TypeBuilder::<Point>::new("Point")
+                                       .getter("x", test_point_get_x)).unwrap();
+        rlua.register_snapshot_type("Point", |p: &Point| format!("{} {}", p.x, p.y).into_bytes(), |bytes| {
+            let s = String::from_utf8_lossy(bytes).into_owned();
+            let mut parts = s.split(' ').map(|n| n.parse::<f64>().unwrap());
+            Ok(Point{ x: parts.next().unwrap(), y: parts.next().unwrap() })
+        });
+        rlua.do_string("game = {}; function game.level_up() game.level = game.level + 1 end").unwrap();
+        rlua
+    }
+    let mut rlua = setup();
+    rlua.push(&LuaPtr::new(Point{ x: 1.5, y: 2.0 })).unwrap();
+    rlua.state.set_global("origin");
+    rlua.do_string(r#"
+        game.level = 3
+        player = { name = "ann", hp = 7.5, alive = true, tags = {"a", "b"}, bytes = "\0\255" }
+        player.self = player
+        shared = player.tags
+        where = origin
+        skipped = function() end
+        player.callback = print
+    "#).unwrap();
+    let blob = rlua.snapshot().unwrap();
+
+    let mut restored = setup();
+    restored.restore(&blob).unwrap();
+    let check = r#"
+        assert(player.name == "ann" and player.hp == 7.5 and math.type(player.hp) == "float")
+        assert(player.alive and player.self == player and shared == player.tags)
+        assert(#player.tags == 2 and player.bytes == "\0\255" and player.callback == nil)
+        assert(skipped == nil and where.x == 1.5)
+        game.level_up()
+        return game.level
+    "#;
+    assert_eq!(restored.eval::<i64>(check).unwrap(), 4);
+    /* Standard globals are left alone. */
+    assert_eq!(restored.eval::<String>("return string.upper('x')").unwrap(), "X");
+
+    /* Restoring in place rolls back changes. */
+    rlua.do_string("player.name = 'bob'; game.level = 10").unwrap();
+    rlua.restore(&blob).unwrap();
+    assert_eq!(rlua.eval::<(String, i64)>("return player.name, game.level").unwrap(), ("ann".to_string(), 3));
+
+    assert!(rlua.restore(b"junk").is_err());
+    assert!(rlua.restore(&blob[..blob.len() - 3]).is_err());
+    /* Userdata without a codec can't be restored. */
+    assert!(RumLua::new().restore(&blob).is_err());
+}
+
+#[test]
+fn lua_snapshot_depth() {
+    /* A global "t" holding 100000 nested tables */
+    let mut blob = b"RUMS\x01\x05\x01\x00\x00\x00t".to_vec();
+    blob.extend(vec![6u8; 100000]);
+    blob.extend(vec![0u8; 100001]);
+    let mut rlua = RumLua::new();
+    let err = rlua.restore(&blob).unwrap_err();
+    assert!(err.to_string().contains("nested too deeply"));
+    assert_eq!(rlua.state.get_top(), 0);
+
+    rlua.do_string("t = {} for i = 1, 500 do t = { t } end").unwrap();
+    assert!(rlua.snapshot().is_err());
+    rlua.do_string("t = {} for i = 1, 100 do t = { t } end").unwrap();
+    let blob = rlua.snapshot().unwrap();
+    let mut restored = RumLua::new();
+    restored.restore(&blob).unwrap();
+    assert!(restored.eval::<bool>("return type(t[1][1][1]) == 'table'").unwrap());
+}
+
+#[test]
+fn lua_hot_reload() {
+    use std::fs;
+    let path = ::std::env::temp_dir().join(format!("rumlua-reload-{}.lua", ::std::process::id()));
+    let name = path.display().to_string();
+    fs::write(&path, "version = 1; count = count or 0; function bump() count = count + 1; return version end").unwrap();
+    let mut rlua = RumLua::new();
+    let mut reload = HotReload::new();
+    reload.preserve("count");
+    let migrated = Rc::new(RefCell::new(Vec::new()));
+    let migrated2 = migrated.clone();
+    reload.on_reload(move |rl, chunk, old, new| {
+        let (from, to): (i64, i64) = (old.get(rl, "version")?, new.get(rl, "version")?);
+        migrated2.borrow_mut().push(format!("{}: {} -> {}", chunk, from, to));
+        Ok(())
+    });
+    reload.watch(&mut rlua, &[&path]).unwrap();
+    let env = reload.env(&name).unwrap();
+    let bump: LuaFunction = env.get(&mut rlua, "bump").unwrap();
+    assert_eq!(bump.call::<_, i64>(&mut rlua, ()).unwrap(), 1);
+    /* Scripts don't touch the globals. */
+    assert!(rlua.get_global_value::<Option<i64>>("version").unwrap().is_none());
+    assert_eq!(reload.poll(&mut rlua).unwrap(), Vec::<String>::new());
+
+    fs::write(&path, "version = 22; count = count or 0; seen = count; function bump() count = count + 10; return version end").unwrap();
+    assert_eq!(reload.poll(&mut rlua).unwrap(), vec![name.clone()]);
+    let env = reload.env(&name).unwrap();
+    let bump: LuaFunction = env.get(&mut rlua, "bump").unwrap();
+    assert_eq!(bump.call::<_, i64>(&mut rlua, ()).unwrap(), 22);
+    assert_eq!(env.get::<_, i64>(&mut rlua, "count").unwrap(), 11);
+    /* The preserved value was there as the script reran. */
+    assert_eq!(env.get::<_, i64>(&mut rlua, "seen").unwrap(), 1);
+    assert_eq!(*migrated.borrow(), vec![format!("{}: 1 -> 22", name)]);
+
+    /* A broken script leaves the old environment in place. */
+    fs::write(&path, "version = 3; error('broken')").unwrap();
+    assert!(reload.reload(&mut rlua, &name).is_err());
+    assert_eq!(reload.env(&name).unwrap().get::<_, i64>(&mut rlua, "version").unwrap(), 22);
+    assert!(reload.reload(&mut rlua, "unknown.lua").is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn lua_record_replay() {
+    use std::io::{self, Write};
+    struct SharedLog(Rc<RefCell<Vec<u8>>>);
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    fn setup(live: bool) -> RumLua<'static> {
+        let mut rlua = RumLua::new();
+        let mut next = 0;
+        rlua.push_function("roll", move |_, sides: i64| {
+            assert!(live, "callback ran during replay");
+            next += 1;
+            Ok((next % sides + 1, vec!["x".to_string(); next as usize]))
+        });
+        rlua.state.set_global("roll");
+        rlua.push_function("fetch", move |_, key: String| -> Result<i64, LuaError> {
+            assert!(live, "callback ran during replay");
+            Err(LuaError::ConversionError(format!("no such key {}", key)))
+        });
+        rlua.state.set_global("fetch");
+        rlua
+    }
+    let script = r#"
+        local a, xs = roll(6)
+        local b = roll(6)
+        local ok, err = pcall(fetch, "gold")
+        return a + b, #xs, ok, err:match("no such key gold") ~= nil
+    "#;
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut rlua = setup(true);
+    rlua.record_callbacks(SharedLog(log.clone()));
+    let live: (i64, i64, bool, bool) = rlua.eval(script).unwrap();
+    assert_eq!(live, (5, 1, false, true));
+    rlua.stop_call_log();
+    assert_eq!(rlua.eval::<i64>("return roll(100)").unwrap(), 4);
+
+    let mut replayed = setup(false);
+    replayed.replay_callbacks(Cursor::new(log.borrow().clone()));
+    assert_eq!(replayed.eval::<(i64, i64, bool, bool)>(script).unwrap(), live);
+    /* The log has run out. */
+    assert!(replayed.eval::<i64>("return roll(6)").is_err());
+
+    /* Calls must come in the recorded order. */
+    let mut replayed = setup(false);
+    replayed.replay_callbacks(Cursor::new(log.borrow().clone()));
+    let err = replayed.do_string("fetch('gold')").unwrap_err();
+    assert!(err.to_string().contains("'roll'"));
+
+    /* A corrupt length doesn't allocate what it claims. */
+    let mut replayed = setup(false);
+    replayed.replay_callbacks(Cursor::new(vec![0xff, 0xff, 0xff, 0xff, 1, 2, 3]));
+    let err = replayed.do_string("roll(6)").unwrap_err();
+    assert!(err.to_string().contains("truncated"));
+}
+
+#[test]
+fn lua_file_args() {
+    use std::fs;
+    let path = ::std::env::temp_dir().join(format!("rumlua-args-{}.lua", ::std::process::id()));
+    let name = path.display().to_string();
+    fs::write(&path, "#!/usr/bin/env lua\nlocal first, second = ...\nreturn arg[0], #arg, first .. arg[2], second + 1").unwrap();
+    let mut rlua = RumLua::new();
+    let results = rlua.do_file_with_args(&name, &[Value::String("a".to_string()), Value::Integer(2)]).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(results.len(), 4);
+    match (&results[0], &results[1], &results[2], &results[3]) {
+        (&Value::String(ref script), &Value::Integer(2), &Value::String(ref joined), &Value::Integer(3)) => {
+            assert_eq!(script, &name);
+            assert_eq!(joined, "a2");
+        },
+        _ => panic!("unexpected results"),
+    }
+    assert_eq!(rlua.eval::<String>("return arg[1]").unwrap(), "a");
+    match rlua.do_file_with_args(&name, &[]) {
+        Err(LuaError::FileError(_)) => {},
+        _ => panic!("expected a file error"),
+    }
+}
+
+#[test]
+fn lua_eval_expr() {
+    let mut rlua = RumLua::new();
+    rlua.do_string("cfg = { width = 40, name = 'main' }").unwrap();
+    assert_eq!(rlua.eval_expr::<i64>("cfg.width * 2").unwrap(), 80);
+    assert_eq!(rlua.eval_expr::<String>("cfg.name .. '!' -- shout").unwrap(), "main!");
+    /* Only the first value is kept. */
+    assert_eq!(rlua.eval_expr::<i64>("string.find('abc', 'b')").unwrap(), 2);
+    match rlua.eval_expr::<i64>("cfg.width *") {
+        Err(LuaError::SyntaxError(_)) => {},
+        _ => panic!("expected a syntax error"),
+    }
+    match rlua.eval_expr::<i64>("cfg.height * 2") {
+        Err(LuaError::RuntimeError { .. }) => {},
+        _ => panic!("expected a runtime error"),
+    }
+    match rlua.eval_expr::<i64>("cfg.name") {
+        Err(LuaError::ConversionError(msg)) => assert!(msg.starts_with("cfg.name: ")),
+        _ => panic!("expected a conversion error"),
+    }
+}
+
+#[test]
+fn lua_repl() {
+    let mut rlua = RumLua::new();
+    {
+        let mut repl = Repl::new(&mut rlua);
+        assert_eq!(repl.feed("x = 20").unwrap(), Some(vec![]));
+        assert_eq!(repl.feed("x + 1, 'n'").unwrap(), Some(vec!["21".to_string(), "n".to_string()]));
+        assert_eq!(repl.feed("=x * 2").unwrap(), Some(vec!["40".to_string()]));
+        assert_eq!(repl.feed("function add(a, b)").unwrap(), None);
+        assert_eq!(repl.prompt(), ">> ");
+        assert_eq!(repl.feed("  return a + b").unwrap(), None);
+        assert_eq!(repl.feed("end").unwrap(), Some(vec![]));
+        assert!(!repl.is_incomplete());
+        assert!(repl.feed("error('oops')").is_err());
+        assert!(repl.feed("x = = 1").is_err());
+        assert_eq!(repl.history().len(), 6);
+        assert_eq!(repl.history()[3], "function add(a, b)\n  return a + b\nend");
+        assert_eq!(repl.feed("for i = 1, 2 do").unwrap(), None);
+        repl.cancel();
+        assert_eq!(repl.prompt(), "> ");
+    }
+    assert_eq!(rlua.eval::<i64>("return add(x, 2)").unwrap(), 22);
+
+    let mut repl = Repl::new(&mut rlua);
+    let mut output = Vec::new();
+    repl.run("t = {}\nfor i = 1, 3 do\nt[i] = i * i end\n#t, t[3]\nnil + 1\n".as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with("> > >> > 3\t9\n> "));
+    assert!(output.contains("arithmetic"));
+    assert!(output.ends_with("\n> "));
+}
+
+#[test]
+fn lua_app_data() {
+    struct Assets {
+        loaded: Vec<String>,
+    }
+    let mut rlua = RumLua::new();
+    assert!(rlua.app_data::<Assets>().is_none());
+    rlua.set_app_data(Assets { loaded: Vec::new() });
+    rlua.set_app_data(7u32);
+    rlua.push_function("load", |rl, name: String| {
+        let mut assets = rl.app_data::<Assets>().unwrap();
+        assets.loaded.push(name);
+        Ok(assets.loaded.len())
+    });
+    rlua.state.set_global("load");
+    /* A callback can't borrow it while it is borrowed further out. */
+    rlua.push_function("nested", |rl, ()| {
+        let _assets = rl.app_data::<Assets>().unwrap();
+        Ok(rl.app_data::<Assets>().is_none())
+    });
+    rlua.state.set_global("nested");
+    assert!(rlua.eval::<bool>("return nested()").unwrap());
+    assert_eq!(rlua.eval::<usize>("load('ship.png'); return load('sea.ogg')").unwrap(), 2);
+    assert_eq!(rlua.app_data::<Assets>().unwrap().loaded, vec!["ship.png", "sea.ogg"]);
+    *rlua.app_data::<u32>().unwrap() += 1;
+    assert_eq!(rlua.remove_app_data::<u32>(), Some(8));
+    assert!(rlua.app_data::<u32>().is_none());
+    assert!(rlua.remove_app_data::<u32>().is_none());
+}
+
+#[test]
+fn lua_named_values() {
+    struct Renderer {
+        frames: u32,
+    }
+    let mut rlua = RumLua::new();
+    assert!(rlua.fetch_named::<Renderer>("renderer").is_none());
+    let renderer = rlua.store_named("renderer", Renderer { frames: 0 });
+    rlua.push_function("draw", |rl, ()| {
+        let mut renderer = rl.fetch_named::<Renderer>("renderer").unwrap();
+        renderer.borrow_mut().frames += 1;
+        Ok(())
+    });
+    rlua.state.set_global("draw");
+    rlua.do_string("draw(); draw()").unwrap();
+    assert_eq!(renderer.borrow().frames, 2);
+    /* Scripts can't see it, and the wrong type isn't found. */
+    assert!(rlua.eval::<bool>("return renderer == nil").unwrap());
+    assert!(rlua.fetch_named::<String>("renderer").is_none());
+
+    rlua.store_named("renderer", Renderer { frames: 100 });
+    assert_eq!(rlua.fetch_named::<Renderer>("renderer").unwrap().borrow().frames, 100);
+    rlua.remove_named("renderer");
+    assert!(rlua.fetch_named::<Renderer>("renderer").is_none());
+    rlua.do_string("collectgarbage()").unwrap();
+    assert!(renderer.try_unwrap().is_ok());
+}
+
+#[test]
+fn lua_light_userdata() {
+    let mut counter = 5i32;
+    let mut rlua = RumLua::new();
+    rlua.push_light(&mut counter as *mut i32);
+    rlua.state.set_global("handle");
+    rlua.push_light::<i32>(::std::ptr::null_mut());
+    rlua.state.set_global("null");
+    rlua.push_function("bump", |rl, ()| {
+        unsafe { rl.with_light::<i32, _, _>(1, |n| { *n += 1; *n }) }
+    });
+    rlua.state.set_global("bump");
+    assert_eq!(rlua.eval::<i32>("return bump(handle)").unwrap(), 6);
+    assert!(rlua.eval::<bool>("return handle == handle and handle ~= null").unwrap());
+    assert!(rlua.do_string("bump(null)").is_err());
+    assert!(rlua.do_string("bump({})").is_err());
+
+    rlua.state.get_global("handle");
+    let p = rlua.to_light::<i32>(-1).unwrap();
+    rlua.state.pop(1);
+    assert_eq!(p, &mut counter as *mut i32);
+    rlua.state.push_integer(1);
+    assert!(rlua.to_light::<i32>(-1).is_err());
+    rlua.state.pop(1);
+    assert_eq!(counter, 6);
+}
+
+#[test]
+fn lua_c_functions() {
+    unsafe extern "C" fn c_mul(l: *mut lua::ffi::lua_State) -> ::libc::c_int {
+        let mut state = lua::State::from_ptr(l);
+        let product = state.to_integer(1) * state.to_integer(2);
+        state.push_integer(product);
+        1
+    }
+    let mut rlua = RumLua::new();
+    rlua.register_c_function("mul", c_mul);
+    assert_eq!(rlua.eval::<i64>("return mul(6, 7)").unwrap(), 42);
+    rlua.register_mixed_func_table("ops", vec![
+        ("add", TableFunction::Rust(raw_add)),
+        ("mul", TableFunction::C(c_mul)),
+    ]);
+    assert_eq!(rlua.eval::<i64>("return ops.mul(ops.add(1, 2), 5)").unwrap(), 15);
+}
+
+#[test]
+fn lua_runtime_version() {
+    let rlua = RumLua::new();
+    assert_eq!(rlua.lua_version(), (5, 3));
 }
@@ -0,0 +1,219 @@
+/* Handle type for Lua threads (coroutines) */
+use ::{RumLua, LuaError, LuaFunction, MultiValue, ToLua, FromLua, ToLuaMulti, FromLuaMulti, lerror};
+use convert::conversion_error;
+use reference::LuaRef;
+use hook;
+use lua;
+use lua::{Index, ThreadStatus};
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+#[cfg(feature = "async")]
+use futures_core::Stream;
+
+/// The outcome of resuming a coroutine.
+#[derive(Debug)]
+pub enum Resume<R> {
+    /// The coroutine yielded these values, and can be resumed again.
+    Yielded(R),
+    /// The coroutine's function returned these values.
+    Finished(R),
+    /// The coroutine raised an error (or couldn't be resumed), and is
+    /// now dead.
+    Error(LuaError),
+}
+
+/// A handle to a Lua thread, which can be resumed step by step from Rust.
+#[derive(Clone)]
+pub struct LuaThread {
+    reference: LuaRef,
+}
+
+impl LuaThread {
+    /* Create a new thread with `f` ready to run on its stack. */
+    pub fn new(rl: &mut RumLua, f: &LuaFunction) -> Result<LuaThread, LuaError> {
+        let base = rl.state.get_top();
+        let thread_ptr = unsafe { lua::ffi::lua_newthread(rl.state.as_ptr()) };
+        if let Err(e) = f.to_lua(rl) {
+            rl.state.set_top(base);
+            return Err(e);
+        }
+        let mut thread = unsafe { lua::State::from_ptr(thread_ptr) };
+        rl.state.xmove(&mut thread, 1);
+        let result = LuaThread{ reference: LuaRef::new(rl, -1) };
+        rl.state.set_top(base);
+        Ok(result)
+    }
+
+    /// Resume the coroutine, passing `args` to it (as the function's
+    /// arguments the first time, or as the results of `coroutine.yield`
+    /// afterwards), and convert the values it yields or returns to `R`.
+    pub fn resume<A, R>(&self, rl: &mut RumLua, args: A) -> Resume<R>
+                  where A: ToLuaMulti, R: FromLuaMulti
+    {
+        let base = rl.state.get_top();
+        if let Err(e) = self.reference.push(rl) {
+            return Resume::Error(e);
+        }
+        /* The registry keeps the thread alive once it's popped. */
+        let thread_ptr = unsafe { lua::ffi::lua_tothread(rl.state.as_ptr(), -1) };
+        rl.state.set_top(base);
+        let mut thread = unsafe { lua::State::from_ptr(thread_ptr) };
+        let resumable = match thread.status() {
+            ThreadStatus::Yield => true,
+            /* Finished threads have an empty stack */
+            ThreadStatus::Ok => thread.get_top() > 0,
+            _ => false,
+        };
+        if !resumable {
+            return Resume::Error(lerror("cannot resume dead coroutine"));
+        }
+
+        /* Conversions need to use the thread's stack. */
+        let mut from = mem::replace(&mut rl.state, thread);
+        let result = resume_current(rl, &mut from, args);
+        rl.state = from;
+        if let Some(payload) = rl.shared.pending_panic.take() {
+            ::std::panic::resume_unwind(payload);
+        }
+        result
+    }
+
+    /// Iterate over the values the coroutine yields, converted to `T`, by
+    /// resuming it with no arguments each time.  Iteration ends when the
+    /// coroutine finishes, after any values it returns, or after an error.
+    pub fn into_iter<'rl, 'lua, T>(self, rl: &'rl mut RumLua<'lua>) -> Yields<'rl, 'lua, T>
+                  where T: FromLuaMulti
+    {
+        Yields {
+            rl: rl,
+            thread: self,
+            done: false,
+            marker: PhantomData,
+        }
+    }
+}
+
+/// An iterator over the values yielded by a coroutine, from
+/// `LuaThread::into_iter`.  With the "async" feature it is also a
+/// `Stream`, whose items are always ready.
+pub struct Yields<'rl, 'lua: 'rl, T> {
+    rl: &'rl mut RumLua<'lua>,
+    thread: LuaThread,
+    done: bool,
+    marker: PhantomData<fn() -> T>,
+}
+
+/* Convert values from the coroutine, by pushing them back. */
+pub fn convert_values<T: FromLuaMulti>(rl: &mut RumLua, values: MultiValue) -> Result<T, LuaError> {
+    let base = rl.state.get_top();
+    let result = values.to_lua_multi(rl).and_then(|n| T::from_lua_multi(rl, n));
+    rl.state.set_top(base);
+    result
+}
+
+impl<'rl, 'lua, T: FromLuaMulti> Iterator for Yields<'rl, 'lua, T> {
+    type Item = Result<T, LuaError>;
+
+    fn next(&mut self) -> Option<Result<T, LuaError>> {
+        if self.done {
+            return None;
+        }
+        match self.thread.resume::<_, MultiValue>(self.rl, ()) {
+            Resume::Yielded(values) => Some(convert_values(self.rl, values)),
+            Resume::Finished(values) => {
+                self.done = true;
+                if values.0.is_empty() {
+                    None
+                } else {
+                    Some(convert_values(self.rl, values))
+                }
+            },
+            Resume::Error(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'rl, 'lua, T: FromLuaMulti> Stream for Yields<'rl, 'lua, T> {
+    type Item = Result<T, LuaError>;
+
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context) -> Poll<Option<Result<T, LuaError>>> {
+        Poll::Ready(self.get_mut().next())
+    }
+}
+
+/* Resume rl.state (a thread) from the thread `from`. */
+fn resume_current<A, R>(rl: &mut RumLua, from: &mut lua::State, args: A) -> Resume<R>
+              where A: ToLuaMulti, R: FromLuaMulti
+{
+    let base = rl.state.get_top();
+    let nargs = match args.to_lua_multi(rl) {
+        Ok(nargs) => nargs,
+        Err(e) => {
+            rl.state.set_top(base);
+            return Resume::Error(e);
+        },
+    };
+    /* The thread may predate the current limits. */
+    hook::apply(rl.state.as_ptr(), &rl.shared.hooks);
+    rl.shared.hooks.enter();
+    let status = unsafe { lua::ffi::lua_resume(rl.state.as_ptr(), from.as_ptr(), nargs) };
+    rl.shared.hooks.leave();
+    match status {
+        lua::ffi::LUA_OK | lua::ffi::LUA_YIELD => {
+            /* The stack holds exactly the yielded or returned values. */
+            let nresults = rl.state.get_top();
+            let result = R::from_lua_multi(rl, nresults);
+            rl.state.set_top(0);
+            match result {
+                Ok(values) if status == lua::ffi::LUA_YIELD => Resume::Yielded(values),
+                Ok(values) => Resume::Finished(values),
+                Err(e) => Resume::Error(e),
+            }
+        },
+        _ => {
+            let err_msg = rl.state.to_str(-1).map(|s| s.to_string());
+            /* The dead thread's stack still shows where the error
+             * happened. */
+            let traceback = unsafe {
+                lua::ffi::luaL_traceback(from.as_ptr(), rl.state.as_ptr(), ptr::null(), 0);
+                let tb = lua::ffi::lua_tolstring(from.as_ptr(), -1, ptr::null_mut());
+                let tb = CStr::from_ptr(tb).to_string_lossy().into_owned();
+                from.pop(1);
+                tb
+            };
+            let kind = if status == lua::ffi::LUA_ERRMEM {
+                ThreadStatus::MemoryError
+            } else {
+                ThreadStatus::RuntimeError
+            };
+            let err_msg = err_msg.map(|msg| format!("{}\n{}", msg, traceback));
+            Resume::Error(rl.error_from_lua(kind, err_msg))
+        },
+    }
+}
+
+impl ToLua for LuaThread {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        self.reference.push(rl)
+    }
+}
+
+impl FromLua for LuaThread {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<LuaThread, LuaError> {
+        if rl.state.type_of(index) == Some(lua::Type::Thread) {
+            Ok(LuaThread{ reference: LuaRef::new(rl, index) })
+        } else {
+            conversion_error(rl, index, "thread")
+        }
+    }
+}
@@ -0,0 +1,141 @@
+/* Durations, timestamps and the rum.time library */
+use ::{RumLua, LuaError, LuaRet, CallbackFn, ToLua, FromLua};
+use convert::conversion_error;
+use lua;
+use lua::Index;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How durations and timestamps are pushed to Lua.  Either is accepted
+/// when converting back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DurationFormat {
+    /// A number of seconds, with a fraction.
+    Seconds,
+    /// A table `{secs = <integer>, nanos = <integer>}`, which keeps the
+    /// full precision.
+    Table,
+}
+
+/// Called by `rum.time.sleep_until` with the time to wake up.
+pub type SleepHandler = Box<dyn FnMut(&mut RumLua, Instant) -> Result<(), LuaError>>;
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+impl ToLua for Duration {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        match rl.shared.duration_format {
+            DurationFormat::Seconds => rl.state.push_number(seconds(*self)),
+            DurationFormat::Table => {
+                rl.state.create_table(0, 2);
+                rl.state.push_integer(self.as_secs() as lua::Integer);
+                rl.state.set_field(-2, "secs");
+                rl.state.push_integer(self.subsec_nanos() as lua::Integer);
+                rl.state.set_field(-2, "nanos");
+            },
+        }
+        Ok(())
+    }
+}
+
+/* An integer field of the duration table at `index`, or `default` if nil. */
+fn duration_field(rl: &mut RumLua, index: Index, name: &str, default: u64) -> Result<u64, LuaError> {
+    rl.state.get_field(index, name);
+    let result = match rl.state.type_of(-1) {
+        Some(lua::Type::Nil) => Ok(default),
+        _ if rl.state.is_integer(-1) && rl.state.to_integer(-1) >= 0 => Ok(rl.state.to_integer(-1) as u64),
+        _ => Err(LuaError::ConversionError(format!("duration field '{}' must be a non-negative integer", name))),
+    };
+    rl.state.pop(1);
+    result
+}
+
+impl FromLua for Duration {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<Duration, LuaError> {
+        match rl.state.type_of(index) {
+            Some(lua::Type::Number) => {
+                let secs = rl.state.to_number(index);
+                if !(secs >= 0.0) || secs >= u64::max_value() as f64 {
+                    return Err(LuaError::ConversionError(format!("invalid duration {}", secs)));
+                }
+                Ok(Duration::new(secs as u64, (secs.fract() * 1e9) as u32))
+            },
+            Some(lua::Type::Table) => {
+                let index = rl.state.abs_index(index);
+                let secs = duration_field(rl, index, "secs", 0)?;
+                let nanos = duration_field(rl, index, "nanos", 0)?;
+                if nanos >= 1_000_000_000 {
+                    return Err(LuaError::ConversionError(format!("duration nanos {} out of range", nanos)));
+                }
+                Ok(Duration::new(secs, nanos as u32))
+            },
+            _ => conversion_error(rl, index, "duration"),
+        }
+    }
+}
+
+/* Timestamps are the duration since the Unix epoch. */
+impl ToLua for SystemTime {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        match self.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.to_lua(rl),
+            Err(_) => Err(LuaError::ConversionError("time is before 1970".to_string())),
+        }
+    }
+}
+
+impl FromLua for SystemTime {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<SystemTime, LuaError> {
+        Duration::from_lua(rl, index).map(|d| UNIX_EPOCH + d)
+    }
+}
+
+/* rum.time.monotonic(): seconds on the state's clock */
+fn time_monotonic(rl: &mut RumLua) -> LuaRet {
+    let now = rl.monotonic();
+    rl.state.push_number(seconds(now));
+    Ok(1)
+}
+
+/* rum.time.now(): seconds since the Unix epoch */
+fn time_now(rl: &mut RumLua) -> LuaRet {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+    rl.state.push_number(seconds(now));
+    Ok(1)
+}
+
+/* rum.time.sleep_until(t): wait until rum.time.monotonic() reaches t */
+fn time_sleep_until(rl: &mut RumLua) -> LuaRet {
+    let t: Duration = rl.check_arg(1)?;
+    let deadline = rl.shared.clock_start + t;
+    match rl.shared.sleep_handler.take() {
+        Some(mut handler) => {
+            let result = handler(rl, deadline);
+            if rl.shared.sleep_handler.is_none() {
+                rl.shared.sleep_handler = Some(handler);
+            }
+            result?;
+        },
+        None => {
+            let now = Instant::now();
+            if deadline > now {
+                thread::sleep(deadline - now);
+            }
+        },
+    }
+    Ok(0)
+}
+
+/* Add the time table to the rum table on top of the stack. */
+pub fn open(rl: &mut RumLua) {
+    rl.state.new_table();
+    rl.push_registered_closure(CallbackFn::Plain(time_monotonic), "time.monotonic");
+    rl.state.set_field(-2, "monotonic");
+    rl.push_registered_closure(CallbackFn::Plain(time_now), "time.now");
+    rl.state.set_field(-2, "now");
+    rl.push_registered_closure(CallbackFn::Plain(time_sleep_until), "time.sleep_until");
+    rl.state.set_field(-2, "sleep_until");
+    rl.state.set_field(-2, "time");
+}
@@ -0,0 +1,519 @@
+/* Describing Rust types to be exposed to Lua */
+use ::{RumLua, LuaRet, LuaType, LuaPtr, LuaError, Callback, CallbackFn, generic_gc, lfail};
+use scope::ScopedRef;
+use lua;
+use std::any::{Any, TypeId};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use libc::c_void;
+
+/* Key (by address) marking the metatables of registered types, which
+ * stay marked after the type is unregistered. */
+static PAYLOAD_MT_KEY: u8 = 0;
+
+fn payload_mt_key() -> *const c_void {
+    &PAYLOAD_MT_KEY as *const u8 as *const c_void
+}
+
+/// Metamethods which registered types can implement with Rust callbacks.
+///
+/// There is deliberately no `__close`: to-be-closed variables
+/// (`local f <close> = ...`) are new in Lua 5.4, and rust-lua53 binds 5.3,
+/// which neither parses `<close>` nor calls the metamethod.  Rust
+/// resources held by userdata are released when it is collected, or
+/// sooner by a method the script calls, such as `f:close()`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MetaMethod {
+    ToString,
+    Eq,
+    Lt,
+    Le,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    IDiv,
+    Mod,
+    Pow,
+    Unm,
+    Concat,
+    Len,
+    Call,
+}
+
+impl MetaMethod {
+    /// The metatable key, such as `"__add"`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MetaMethod::ToString => "__tostring",
+            MetaMethod::Eq => "__eq",
+            MetaMethod::Lt => "__lt",
+            MetaMethod::Le => "__le",
+            MetaMethod::Add => "__add",
+            MetaMethod::Sub => "__sub",
+            MetaMethod::Mul => "__mul",
+            MetaMethod::Div => "__div",
+            MetaMethod::IDiv => "__idiv",
+            MetaMethod::Mod => "__mod",
+            MetaMethod::Pow => "__pow",
+            MetaMethod::Unm => "__unm",
+            MetaMethod::Concat => "__concat",
+            MetaMethod::Len => "__len",
+            MetaMethod::Call => "__call",
+        }
+    }
+}
+
+/// A Rust type which extends the registered type `B`, for use with
+/// `TypeBuilder::base`.  The base part is shared, so that Lua and Rust
+/// code expecting a `B` can use it.
+pub trait Inherits<B> {
+    /// The base part of this object.
+    fn base(&self) -> &LuaPtr<B>;
+}
+
+/* Convert a userdata payload to a Box<LuaPtr<B>> for some base type B. */
+pub type Upcast = Rc<dyn Fn(&dyn Any) -> Result<Box<dyn Any>, LuaError>>;
+
+/// Describes how a Rust type `T` is exposed to Lua, built at runtime and
+/// passed to `RumLua::register_type_builder`.
+pub struct TypeBuilder<T> {
+    name: String,
+    methods: Vec<(String, CallbackFn)>,
+    raw_methods: Vec<(String, CallbackFn)>,
+    metamethods: Vec<(MetaMethod, CallbackFn)>,
+    getters: HashMap<String, CallbackFn>,
+    setters: HashMap<String, CallbackFn>,
+    statics: Vec<(String, CallbackFn)>,
+    statics_in_rum: bool,
+    base: Option<(TypeId, Upcast)>,
+    on_collect: Option<Box<dyn FnMut(&mut T)>>,
+    instance_fields: bool,
+    marker: PhantomData<T>,
+}
+
+impl<T: Any> TypeBuilder<T> {
+    /// Start describing a type, with `name` as its metatable name.
+    pub fn new(name: &str) -> TypeBuilder<T> {
+        TypeBuilder {
+            name: name.to_string(),
+            methods: Vec::new(),
+            raw_methods: Vec::new(),
+            metamethods: Vec::new(),
+            getters: HashMap::new(),
+            setters: HashMap::new(),
+            statics: Vec::new(),
+            statics_in_rum: false,
+            base: None,
+            on_collect: None,
+            instance_fields: false,
+            marker: PhantomData,
+        }
+    }
+
+    /// Add a method.
+    pub fn method(mut self, name: &str, f: Callback) -> TypeBuilder<T> {
+        self.methods.push((name.to_string(), CallbackFn::Plain(f)));
+        self
+    }
+
+    /// Add a method which is called without the Lua shim, as with
+    /// `RumLua::register_raw`, for methods called very often.
+    pub fn raw_method(mut self, name: &str, f: Callback) -> TypeBuilder<T> {
+        self.raw_methods.push((name.to_string(), CallbackFn::Plain(f)));
+        self
+    }
+
+    /// Add a method implemented by a closure, which may capture state.
+    pub fn closure<F>(mut self, name: &str, f: F) -> TypeBuilder<T>
+                  where F: FnMut(&mut RumLua) -> LuaRet + 'static
+    {
+        self.methods.push((name.to_string(), CallbackFn::Boxed(RefCell::new(Box::new(f)))));
+        self
+    }
+
+    /// Implement a metamethod, such as `MetaMethod::Add` to support `+`.
+    /// Binary operators may be called with the userdata as either
+    /// operand.
+    pub fn metamethod(mut self, mm: MetaMethod, f: Callback) -> TypeBuilder<T> {
+        self.metamethods.push((mm, CallbackFn::Plain(f)));
+        self
+    }
+
+    /// Implement a metamethod with a closure.
+    pub fn metamethod_closure<F>(mut self, mm: MetaMethod, f: F) -> TypeBuilder<T>
+                  where F: FnMut(&mut RumLua) -> LuaRet + 'static
+    {
+        self.metamethods.push((mm, CallbackFn::Boxed(RefCell::new(Box::new(f)))));
+        self
+    }
+
+    /// Add a field, so that `obj.name` in Lua calls `f` with the object
+    /// as its only argument.  Methods take precedence over fields.
+    pub fn getter(mut self, name: &str, f: Callback) -> TypeBuilder<T> {
+        self.getters.insert(name.to_string(), CallbackFn::Plain(f));
+        self
+    }
+
+    /// Add a settable field, so that `obj.name = value` in Lua calls `f`
+    /// with the object and the value as arguments.
+    pub fn setter(mut self, name: &str, f: Callback) -> TypeBuilder<T> {
+        self.setters.insert(name.to_string(), CallbackFn::Plain(f));
+        self
+    }
+
+    /// Add a static function, such as a constructor, which Lua calls as
+    /// `Name.f(...)`.  Static functions are published in a global table
+    /// with the type's name.
+    pub fn static_fn(mut self, name: &str, f: Callback) -> TypeBuilder<T> {
+        self.statics.push((name.to_string(), CallbackFn::Plain(f)));
+        self
+    }
+
+    /// Add a static function implemented by a closure.
+    pub fn static_closure<F>(mut self, name: &str, f: F) -> TypeBuilder<T>
+                  where F: FnMut(&mut RumLua) -> LuaRet + 'static
+    {
+        self.statics.push((name.to_string(), CallbackFn::Boxed(RefCell::new(Box::new(f)))));
+        self
+    }
+
+    /// Publish the static functions in `rum.<Name>` instead of a global.
+    pub fn statics_in_rum(mut self) -> TypeBuilder<T> {
+        self.statics_in_rum = true;
+        self
+    }
+
+    /// Let scripts set fields of their own on each userdata, such as extra
+    /// methods.  They are kept in the userdata's uservalue table, and
+    /// found before the type's methods; fields with setters still call
+    /// the setter.
+    pub fn instance_fields(mut self) -> TypeBuilder<T> {
+        self.instance_fields = true;
+        self
+    }
+
+    /// Inherit the methods of the registered type `B`, and allow
+    /// userdata of this type to be used where a `B` is expected.
+    pub fn base<B: Any>(mut self) -> TypeBuilder<T> where T: Inherits<B> {
+        let upcast: Upcast = Rc::new(|payload: &dyn Any| {
+            if let Some(p) = payload.downcast_ref::<LuaPtr<T>>() {
+                p.try_borrow().map(|obj| Box::new(obj.base().clone()) as Box<dyn Any>)
+            } else if let Some(r) = payload.downcast_ref::<ScopedRef<T>>() {
+                r.with_ref(|obj| Box::new(obj.base().clone()) as Box<dyn Any>)
+            } else {
+                Err(LuaError::TypeError("Userdata does not contain the expected type".to_string()))
+            }
+        });
+        self.base = Some((TypeId::of::<B>(), upcast));
+        self
+    }
+
+    /// Run `f` on the value when Lua collects a userdata of this type,
+    /// before the userdata's reference to it is dropped.  It isn't run if
+    /// the value is borrowed at the time, or has already gone.
+    pub fn on_collect<F>(mut self, f: F) -> TypeBuilder<T>
+                  where F: FnMut(&mut T) + 'static
+    {
+        self.on_collect = Some(Box::new(f));
+        self
+    }
+
+    /// Add all the methods from a static `LuaType`.
+    pub fn methods(mut self, typeinfo: &LuaType) -> TypeBuilder<T> {
+        for &(name, f) in typeinfo.methods {
+            self.methods.push((name.to_string(), CallbackFn::Plain(f)));
+        }
+        self
+    }
+}
+
+impl<T: Any + Debug> TypeBuilder<T> {
+    /// Implement `__tostring` using `T`'s `Debug` formatting, so that
+    /// `print(obj)` shows the value.
+    pub fn debug_tostring(self) -> TypeBuilder<T> {
+        self.metamethod(MetaMethod::ToString, debug_tostring::<T>)
+    }
+}
+
+fn debug_tostring<T: Any + Debug>(rl: &mut RumLua) -> LuaRet {
+    let s = rl.with_ref::<T, _, _>(1, |obj| format!("{:?}", obj))?;
+    rl.state.push_string(&s);
+    Ok(1)
+}
+
+/* Create the metatable for `T`, as described by `builder`, failing if
+ * `T` or its name is already registered, or its base type isn't. */
+pub fn register<T: Any>(rl: &mut RumLua, builder: TypeBuilder<T>) -> Result<(), LuaError> {
+    check_unregistered::<T>(rl, &builder.name)?;
+    check_base(rl, &builder)?;
+    let mt_name = builder.name;
+
+    /* Create the metatable */
+    rl.state.new_metatable(&mt_name);
+    rl.state.push_bool(true);
+    unsafe { lua::ffi::lua_rawsetp(rl.state.as_ptr(), -2, payload_mt_key()) };
+    if let Some((base_id, upcast)) = builder.base {
+        let base_name = rl.shared.types_id_to_str[&base_id].clone();
+        /* Look up anything missing in the base's metatable */
+        rl.state.new_table();
+        rl.state.get_field(lua::REGISTRYINDEX, &base_name);
+        rl.state.set_field(-2, "__index");
+        rl.state.set_metatable(-2);
+
+        /* Upcast to the base, and through it to its ancestors */
+        let mut upcasts = vec![(base_id, upcast.clone())];
+        for (&ancestor, derived) in rl.shared.upcasts.iter() {
+            for &(ref name, ref base_upcast) in derived {
+                if *name == base_name {
+                    let upcast = upcast.clone();
+                    let base_upcast = base_upcast.clone();
+                    upcasts.push((ancestor, Rc::new(move |payload: &dyn Any| {
+                        upcast(payload).and_then(|base| base_upcast(&*base))
+                    }) as Upcast));
+                }
+            }
+        }
+        for (ancestor, upcast) in upcasts {
+            rl.shared.upcasts.entry(ancestor).or_insert(Vec::new()).push((mt_name.clone(), upcast));
+        }
+    }
+    /* Kept by this registration's __gc, so that userdata made before the
+     * type is replaced aren't counted against the new registration. */
+    let live = Rc::new(Cell::new(0));
+    let collected = live.clone();
+    match builder.on_collect {
+        Some(mut on_collect) => {
+            rl.push_registered_closure(CallbackFn::Boxed(RefCell::new(Box::new(move |rl| {
+                let _ = rl.with_mut::<T, _, _>(1, |obj| on_collect(obj));
+                generic_gc(rl, &collected)
+            }))), "__gc");
+        },
+        None => {
+            rl.push_registered_closure(CallbackFn::Boxed(RefCell::new(Box::new(move |rl| {
+                generic_gc(rl, &collected)
+            }))), "__gc");
+        },
+    }
+    rl.state.set_field(-2, "__gc");
+
+    for (name, f) in builder.methods {
+        rl.push_registered_closure(f, &name);
+        rl.state.set_field(-2, &name);
+    }
+    for (name, f) in builder.raw_methods {
+        rl.push_raw_closure(f, &name);
+        rl.state.set_field(-2, &name);
+    }
+    for (mm, f) in builder.metamethods {
+        rl.push_registered_closure(f, mm.name());
+        rl.state.set_field(-2, mm.name());
+    }
+    let instance_fields = builder.instance_fields;
+    if builder.getters.is_empty() && builder.setters.is_empty() && !instance_fields {
+        // And set the metatable as its own __index
+        rl.state.set_field(-1, "__index");
+    } else {
+        let getters = builder.getters;
+        rl.push_registered_closure(CallbackFn::Boxed(RefCell::new(Box::new(move |rl| {
+            field_index(rl, &getters, instance_fields)
+        }))), "__index");
+        rl.state.set_field(-2, "__index");
+        let setters = builder.setters;
+        rl.push_registered_closure(CallbackFn::Boxed(RefCell::new(Box::new(move |rl| {
+            field_newindex(rl, &setters, instance_fields)
+        }))), "__newindex");
+        rl.state.set_field(-2, "__newindex");
+        rl.state.pop(1);
+    }
+
+    if !builder.statics.is_empty() {
+        if builder.statics_in_rum {
+            rl.state.get_global("rum");
+        }
+        rl.state.new_table();
+        for (name, f) in builder.statics {
+            rl.push_registered_closure(f, &name);
+            rl.state.set_field(-2, &name);
+        }
+        if builder.statics_in_rum {
+            rl.state.set_field(-2, &mt_name);
+            rl.state.pop(1);
+        } else {
+            rl.state.set_global(&mt_name);
+        }
+    }
+
+    rl.shared.types_str_to_id.insert(mt_name.clone(), TypeId::of::<T>());
+    rl.shared.types_id_to_str.insert(TypeId::of::<T>(), mt_name);
+    rl.shared.live_userdata.insert(TypeId::of::<T>(), live);
+    Ok(())
+}
+
+/* Whether the value at `index` is a userdata with a payload, even of a
+ * type which has since been unregistered. */
+pub fn has_payload(rl: &mut RumLua, index: lua::Index) -> bool {
+    if !rl.state.is_userdata(index) || !rl.state.get_metatable(index) {
+        return false;
+    }
+    unsafe { lua::ffi::lua_rawgetp(rl.state.as_ptr(), -1, payload_mt_key()) };
+    let marked = rl.state.to_bool(-1);
+    rl.state.pop(2);
+    marked
+}
+
+/* Fail if `T` or `name` is already registered. */
+fn check_unregistered<T: Any>(rl: &RumLua, name: &str) -> Result<(), LuaError> {
+    if rl.shared.types_str_to_id.contains_key(name) {
+        return Err(LuaError::TypeError(format!("Type name '{}' is already registered", name)));
+    }
+    if let Some(existing) = rl.shared.types_id_to_str.get(&TypeId::of::<T>()) {
+        return Err(LuaError::TypeError(format!("Type is already registered as '{}'", existing)));
+    }
+    Ok(())
+}
+
+/* Fail if the base type `builder` names isn't registered. */
+fn check_base<T: Any>(rl: &RumLua, builder: &TypeBuilder<T>) -> Result<(), LuaError> {
+    match builder.base {
+        Some((ref base_id, _)) if !rl.shared.types_id_to_str.contains_key(base_id) => {
+            Err(LuaError::TypeError(format!("Base type of '{}' must be registered first", builder.name)))
+        },
+        _ => Ok(()),
+    }
+}
+
+/* As `register`, under the name `name`. */
+pub fn register_as<T: Any>(rl: &mut RumLua, name: &str, mut builder: TypeBuilder<T>) -> Result<(), LuaError> {
+    builder.name = name.to_string();
+    register(rl, builder)
+}
+
+/* As `register`, first unregistering `T` if needed. */
+pub fn register_or_replace<T: Any>(rl: &mut RumLua, builder: TypeBuilder<T>) -> Result<(), LuaError> {
+    let id = TypeId::of::<T>();
+    match rl.shared.types_str_to_id.get(&builder.name) {
+        Some(other) if *other != id => {
+            return Err(LuaError::TypeError(format!("Type name '{}' is already registered", builder.name)));
+        },
+        _ => {},
+    }
+    check_base(rl, &builder)?;
+    if rl.shared.types_id_to_str.contains_key(&id) {
+        unregister::<T>(rl)?;
+    }
+    register(rl, builder)
+}
+
+/* Forget the type `T`.  Its userdata keep their payloads, which are still
+ * dropped when collected, but lose their methods and no longer convert
+ * to `T`. */
+pub fn unregister<T: Any>(rl: &mut RumLua) -> Result<(), LuaError> {
+    let id = TypeId::of::<T>();
+    let mt_name = match rl.shared.types_id_to_str.remove(&id) {
+        Some(name) => name,
+        None => return Err(LuaError::TypeError("Type is not registered".to_string())),
+    };
+    rl.shared.types_str_to_id.remove(&mt_name);
+    rl.shared.live_userdata.remove(&id);
+    rl.shared.upcasts.remove(&id);
+    for derived in rl.shared.upcasts.values_mut() {
+        derived.retain(|&(ref name, _)| *name != mt_name);
+    }
+
+    /* Empty the metatable, except for __gc and the payload mark. */
+    rl.state.get_field(lua::REGISTRYINDEX, &mt_name);
+    rl.state.push_nil();
+    while rl.state.next(-2) {
+        rl.state.pop(1);
+        let keep = rl.state.is_light_userdata(-1) ||
+                   (rl.state.type_of(-1) == Some(lua::Type::String) && rl.state.to_str(-1) == Some("__gc"));
+        if !keep {
+            rl.state.push_value(-1);
+            rl.state.push_nil();
+            rl.state.raw_set(-4);
+        }
+    }
+    rl.state.pop(1);
+    /* So that the name can be registered again with a new metatable */
+    rl.state.push_nil();
+    rl.state.set_field(lua::REGISTRYINDEX, &mt_name);
+    Ok(())
+}
+
+/* __index for types with fields: (obj, key) */
+fn field_index(rl: &mut RumLua, getters: &HashMap<String, CallbackFn>, instance_fields: bool) -> LuaRet {
+    if instance_fields {
+        if rl.state.get_uservalue(1) == lua::Type::Table {
+            rl.state.push_value(2);
+            if rl.state.raw_get(-2) != lua::Type::Nil {
+                return Ok(1);
+            }
+            rl.state.pop(1);
+        }
+        rl.state.pop(1);
+    }
+    /* Methods are in the metatable, or inherited by it */
+    if rl.state.get_metatable(1) {
+        rl.state.push_value(2);
+        if rl.state.get_table(-2) != lua::Type::Nil {
+            return Ok(1);
+        }
+        rl.state.pop(2);
+    }
+    let getter = match rl.state.type_of(2) {
+        Some(lua::Type::String) => rl.state.to_str(2).and_then(|key| getters.get(key)),
+        _ => None,
+    };
+    match getter {
+        Some(f) => {
+            rl.state.set_top(1);
+            f.call(rl)
+        },
+        None => {
+            rl.state.push_nil();
+            Ok(1)
+        },
+    }
+}
+
+/* __newindex for types with fields: (obj, key, value) */
+fn field_newindex(rl: &mut RumLua, setters: &HashMap<String, CallbackFn>, instance_fields: bool) -> LuaRet {
+    let key = match rl.state.type_of(2) {
+        Some(lua::Type::String) => rl.state.to_str(2).map(|s| s.to_string()),
+        _ => None,
+    };
+    let setter = match key {
+        Some(ref key) => setters.get(key),
+        None => None,
+    };
+    match setter {
+        Some(f) => {
+            rl.state.remove(2);
+            f.call(rl)
+        },
+        None if instance_fields => set_instance_field(rl),
+        None => lfail(&format!("Cannot set field '{}'", key.unwrap_or("?".to_string()))),
+    }
+}
+
+/* Set a field in the uservalue table of the userdata: (obj, key, value) */
+fn set_instance_field(rl: &mut RumLua) -> LuaRet {
+    match rl.state.get_uservalue(1) {
+        lua::Type::Table => {},
+        lua::Type::Nil => {
+            rl.state.pop(1);
+            rl.state.new_table();
+            rl.state.push_value(-1);
+            rl.state.set_uservalue(1);
+        },
+        _ => return lfail("Cannot set a field: the userdata's uservalue is not a table"),
+    }
+    rl.state.push_value(2);
+    rl.state.push_value(3);
+    rl.state.raw_set(-3);
+    Ok(0)
+}
@@ -0,0 +1,141 @@
+/* Dynamically typed Lua values */
+use ::{RumLua, LuaError, LuaRef, LuaTable, LuaFunction, LuaThread, ToLua, FromLua, ToLuaMulti, FromLuaMulti};
+use string::push_bytes;
+use lua;
+use lua::Index;
+use libc::{c_int, c_void};
+use std::fmt;
+
+/// Any Lua value.  Tables, functions, userdata and threads are held by
+/// registry handles, so stay alive while the `Value` exists.
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Integer(lua::Integer),
+    Number(lua::Number),
+    String(String),
+    Table(LuaTable),
+    Function(LuaFunction),
+    Userdata(LuaRef),
+    Thread(LuaThread),
+    LightUserdata(*mut c_void),
+}
+
+impl Value {
+    /// Whether this is nil.
+    pub fn is_nil(&self) -> bool {
+        match *self {
+            Value::Nil => true,
+            _ => false,
+        }
+    }
+
+    /// The Lua name of this value's type.
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Integer(_) | Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+            Value::Userdata(_) | Value::LightUserdata(_) => "userdata",
+            Value::Thread(_) => "thread",
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Number(n) => write!(f, "{:?}", n),
+            Value::String(ref s) => write!(f, "{:?}", s),
+            Value::LightUserdata(p) => write!(f, "lightuserdata: {:p}", p),
+            _ => write!(f, "<{}>", self.type_name()),
+        }
+    }
+}
+
+impl ToLua for Value {
+    fn to_lua(&self, rl: &mut RumLua) -> Result<(), LuaError> {
+        match *self {
+            Value::Nil => rl.state.push_nil(),
+            Value::Boolean(b) => rl.state.push_bool(b),
+            Value::Integer(i) => rl.state.push_integer(i),
+            Value::Number(n) => rl.state.push_number(n),
+            Value::String(ref s) => push_bytes(rl, s.as_bytes()),
+            Value::Table(ref t) => return t.to_lua(rl),
+            Value::Function(ref f) => return f.to_lua(rl),
+            Value::Userdata(ref r) => return r.push(rl),
+            Value::Thread(ref t) => return t.to_lua(rl),
+            Value::LightUserdata(p) => rl.state.push_light_userdata(p),
+        }
+        Ok(())
+    }
+}
+
+impl FromLua for Value {
+    fn from_lua(rl: &mut RumLua, index: Index) -> Result<Value, LuaError> {
+        let value = match rl.state.type_of(index) {
+            None | Some(lua::Type::None) | Some(lua::Type::Nil) => Value::Nil,
+            Some(lua::Type::Boolean) => Value::Boolean(rl.state.to_bool(index)),
+            Some(lua::Type::Number) => {
+                if rl.state.is_integer(index) {
+                    Value::Integer(rl.state.to_integer(index))
+                } else {
+                    Value::Number(rl.state.to_number(index))
+                }
+            },
+            Some(lua::Type::String) => {
+                match rl.state.to_str(index) {
+                    Some(s) => Value::String(s.to_string()),
+                    None => return Err(LuaError::ConversionError("string is not valid UTF-8".to_string())),
+                }
+            },
+            Some(lua::Type::Table) => Value::Table(LuaTable::from_lua(rl, index)?),
+            Some(lua::Type::Function) => Value::Function(LuaFunction::from_lua(rl, index)?),
+            Some(lua::Type::Userdata) => Value::Userdata(LuaRef::new(rl, index)),
+            Some(lua::Type::Thread) => Value::Thread(LuaThread::from_lua(rl, index)?),
+            Some(lua::Type::LightUserdata) => Value::LightUserdata(rl.state.to_userdata(index)),
+        };
+        Ok(value)
+    }
+}
+
+/// Any number of Lua values, such as the arguments or results of a
+/// function.  (A plain `Vec` converts to a single table instead.)
+#[derive(Clone, Debug)]
+pub struct MultiValue(pub Vec<Value>);
+
+impl ToLuaMulti for MultiValue {
+    fn to_lua_multi(&self, rl: &mut RumLua) -> Result<c_int, LuaError> {
+        rl.ensure_stack(self.0.len())?;
+        for v in &self.0 {
+            v.to_lua(rl)?;
+        }
+        Ok(self.0.len() as c_int)
+    }
+}
+
+/* Convert values to keep and pass to Lua later, by pushing them. */
+pub fn to_values<A: ToLuaMulti>(rl: &mut RumLua, args: A) -> Result<MultiValue, LuaError> {
+    let base = rl.state.get_top();
+    let result = args.to_lua_multi(rl).and_then(|n| MultiValue::from_lua_multi(rl, n));
+    rl.state.set_top(base);
+    result
+}
+
+impl FromLuaMulti for MultiValue {
+    fn from_lua_multi(rl: &mut RumLua, count: c_int) -> Result<MultiValue, LuaError> {
+        let first = rl.state.get_top() - count + 1;
+        let mut values = Vec::with_capacity(count as usize);
+        for index in first..(first + count) {
+            values.push(Value::from_lua(rl, index)?);
+        }
+        Ok(MultiValue(values))
+    }
+}
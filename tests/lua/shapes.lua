@@ -0,0 +1,8 @@
+-- Module embedded by the bundle! test
+local shapes = {}
+
+function shapes.area(w, h)
+    return w * h
+end
+
+return shapes